@@ -1,32 +1,39 @@
+use crate::eviction::{EvictionPolicy, KeyAccess};
 use crate::prelude::*;
 use common::SizeOf;
 use fnv::FnvBuildHasher;
+use indexmap::IndexMap;
 use rand::prelude::*;
 use std::borrow::Cow;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 /// Allocate a new end-user facing result table.
 pub(crate) fn new(cols: usize, key: &[usize]) -> (SingleReadHandle, WriteHandle) {
-    new_inner(cols, key, None)
+    new_inner(cols, key, EvictionPolicy::Random, None)
 }
 
 /// Allocate a new partially materialized end-user facing result table.
 ///
 /// Misses in this table will call `trigger` to populate the entry, and retry until successful.
+/// When memory runs low, keys are evicted from it according to `eviction`.
 pub(crate) fn new_partial<F>(
     cols: usize,
     key: &[usize],
+    eviction: EvictionPolicy,
     trigger: F,
 ) -> (SingleReadHandle, WriteHandle)
 where
     F: Fn(&mut dyn Iterator<Item = &[DataType]>) -> bool + 'static + Send + Sync,
 {
-    new_inner(cols, key, Some(Arc::new(trigger)))
+    new_inner(cols, key, eviction, Some(Arc::new(trigger)))
 }
 
 fn new_inner(
     cols: usize,
     key: &[usize],
+    eviction: EvictionPolicy,
     trigger: Option<Arc<dyn Fn(&mut dyn Iterator<Item = &[DataType]>) -> bool + Send + Sync>>,
 ) -> (SingleReadHandle, WriteHandle) {
     let contiguous = {
@@ -63,18 +70,34 @@ fn new_inner(
         _ => make!(Many),
     };
 
+    let partial = trigger.is_some();
+    let reads = if partial && eviction.tracks_reads() {
+        Some(Arc::new(ReadLog::default()))
+    } else {
+        None
+    };
     let w = WriteHandle {
-        partial: trigger.is_some(),
+        partial,
         handle: w,
         key: Vec::from(key),
         cols,
         contiguous,
         mem_size: 0,
+        eviction,
+        filled: if partial && eviction != EvictionPolicy::Random {
+            Some(IndexMap::default())
+        } else {
+            None
+        },
+        reads: reads.clone(),
+        clock: 0,
     };
     let r = SingleReadHandle {
         handle: r,
         trigger,
         key: Vec::from(key),
+        reads,
+        requests: Default::default(),
     };
 
     (r, w)
@@ -104,6 +127,77 @@ fn key_to_double(k: Key) -> Cow<(DataType, DataType)> {
     }
 }
 
+/// Only one in this many reads from a partial reader is logged for its eviction policy.
+const READ_SAMPLE_RATE: usize = 8;
+
+/// The maximum number of logged reads kept between evictions. Once there are this many, each new
+/// read replaces the oldest one.
+const MAX_LOGGED_READS: usize = 4096;
+
+/// A sample of the most recent keys read from a partial reader, which its writer consults when
+/// choosing keys to evict.
+///
+/// The sampled reads go into a ring of slots without taking any locks, so readers never wait for
+/// each other or for the writer. Every slot owns the key it points to, if any.
+struct ReadLog {
+    reads: AtomicUsize,
+    slots: Box<[AtomicPtr<Vec<DataType>>]>,
+}
+
+impl Default for ReadLog {
+    fn default() -> Self {
+        ReadLog {
+            reads: AtomicUsize::new(0),
+            slots: (0..MAX_LOGGED_READS)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+        }
+    }
+}
+
+impl ReadLog {
+    fn record(&self, key: &[DataType]) {
+        let read = self.reads.fetch_add(1, Ordering::Relaxed);
+        if read % READ_SAMPLE_RATE != 0 {
+            return;
+        }
+        let slot = &self.slots[read / READ_SAMPLE_RATE % MAX_LOGGED_READS];
+        let key = Box::into_raw(Box::new(key.to_vec()));
+        let old = slot.swap(key, Ordering::AcqRel);
+        if !old.is_null() {
+            // the slot gave up the key it owned when it was swapped out
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+
+    /// Take the logged reads, oldest first.
+    ///
+    /// Reads that are logged while the log is drained may or may not be taken, and may be taken
+    /// out of order.
+    fn drain(&self) -> Vec<Vec<DataType>> {
+        let read = self.reads.load(Ordering::Relaxed);
+        let next = (read + READ_SAMPLE_RATE - 1) / READ_SAMPLE_RATE;
+        (0..MAX_LOGGED_READS)
+            .map(|i| &self.slots[(next + i) % MAX_LOGGED_READS])
+            .filter_map(|slot| {
+                let key = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+                if key.is_null() {
+                    None
+                } else {
+                    // the slot gave up the key it owned when it was swapped out
+                    Some(*unsafe { Box::from_raw(key) })
+                }
+            })
+            .collect()
+    }
+}
+
+impl Drop for ReadLog {
+    fn drop(&mut self) {
+        self.drain();
+    }
+}
+
 pub(crate) struct WriteHandle {
     handle: multiw::Handle,
     partial: bool,
@@ -111,6 +205,11 @@ pub(crate) struct WriteHandle {
     key: Vec<usize>,
     contiguous: bool,
     mem_size: usize,
+    eviction: EvictionPolicy,
+    /// The filled keys and their reads, for eviction policies that sample keys.
+    filled: Option<IndexMap<Vec<DataType>, KeyAccess, FnvBuildHasher>>,
+    reads: Option<Arc<ReadLog>>,
+    clock: u64,
 }
 
 type Key<'a> = Cow<'a, [DataType]>;
@@ -130,6 +229,9 @@ impl<'a> MutWriteHandleEntry<'a> {
             .handle
             .meta_get_and(Cow::Borrowed(&*self.key), |rs| rs.is_empty())
         {
+            if let Some(ref mut filled) = self.handle.filled {
+                filled.insert(self.key.to_vec(), KeyAccess::new(self.handle.clock));
+            }
             self.handle.handle.clear(self.key)
         } else {
             unreachable!("attempted to fill already-filled key");
@@ -137,14 +239,10 @@ impl<'a> MutWriteHandleEntry<'a> {
     }
 
     pub(crate) fn mark_hole(self) {
-        let size = self
-            .handle
-            .handle
-            .meta_get_and(Cow::Borrowed(&*self.key), |rs| {
-                rs.iter().map(SizeOf::deep_size_of).sum()
-            })
-            .map(|r| r.0.unwrap_or(0))
-            .unwrap_or(0);
+        let size = size_of_key(&self.handle.handle, &self.key);
+        if let Some(ref mut filled) = self.handle.filled {
+            filled.swap_remove(&self.key[..]);
+        }
         self.handle.mem_size = self.handle.mem_size.checked_sub(size as usize).unwrap();
         self.handle.handle.empty(self.key)
     }
@@ -162,6 +260,16 @@ impl<'a> WriteHandleEntry<'a> {
     }
 }
 
+/// The number of bytes the records for `key` take up.
+fn size_of_key(handle: &multiw::Handle, key: &[DataType]) -> u64 {
+    handle
+        .meta_get_and(Cow::Borrowed(key), |rs| {
+            rs.iter().map(SizeOf::deep_size_of).sum()
+        })
+        .and_then(|r| r.0)
+        .unwrap_or(0)
+}
+
 fn key_from_record<'a, R>(key: &[usize], contiguous: bool, record: R) -> Key<'a>
 where
     R: Into<Cow<'a, [DataType]>>,
@@ -254,20 +362,24 @@ impl WriteHandle {
         self.partial
     }
 
-    /// Evict `count` randomly selected keys from state and return them along with the number of
-    /// bytes that will be freed once the underlying `evmap` applies the operation.
-    pub(crate) fn evict_random_key(&mut self, rng: &mut ThreadRng) -> u64 {
+    /// Evict a key chosen by the eviction policy from state and return the number of bytes that
+    /// will be freed once the underlying `evmap` applies the operation.
+    pub(crate) fn evict_some_key<R: Rng>(&mut self, rng: &mut R) -> u64 {
         let mut bytes_to_be_freed = 0;
         if self.mem_size > 0 {
             if self.handle.is_empty() {
                 unreachable!("mem size is {}, but map is empty", self.mem_size);
             }
 
-            match self.handle.empty_at_index(rng.gen()) {
-                None => (),
-                Some(vs) => {
-                    let size: u64 = vs.iter().map(|r| r.deep_size_of() as u64).sum();
-                    bytes_to_be_freed += size;
+            if self.filled.is_some() {
+                bytes_to_be_freed = self.evict_sampled_key(rng);
+            } else {
+                match self.handle.empty_at_index(rng.gen()) {
+                    None => (),
+                    Some(vs) => {
+                        let size: u64 = vs.iter().map(|r| r.deep_size_of() as u64).sum();
+                        bytes_to_be_freed += size;
+                    }
                 }
             }
             self.mem_size = self
@@ -277,6 +389,39 @@ impl WriteHandle {
         }
         bytes_to_be_freed
     }
+
+    /// Evict the filled key that the eviction policy ranks lowest among a sample, and return the
+    /// number of bytes that will be freed.
+    fn evict_sampled_key<R: Rng>(&mut self, rng: &mut R) -> u64 {
+        let filled = self.filled.as_mut().unwrap();
+        if let Some(ref reads) = self.reads {
+            for key in reads.drain() {
+                self.clock += 1;
+                if let Some(access) = filled.get_mut(&key[..]) {
+                    access.touch(self.clock);
+                }
+            }
+        }
+        if filled.is_empty() {
+            return 0;
+        }
+
+        let eviction = self.eviction;
+        let handle = &self.handle;
+        let index = eviction.choose(filled.len(), rng, |i| {
+            let (key, &access) = filled.get_index(i).unwrap();
+            let size = if eviction.needs_sizes() {
+                size_of_key(handle, key)
+            } else {
+                0
+            };
+            (access, size)
+        });
+        let (key, _) = filled.swap_remove_index(index).unwrap();
+        let size = size_of_key(&self.handle, &key);
+        self.handle.empty(Cow::Owned(key));
+        size
+    }
 }
 
 impl SizeOf for WriteHandle {
@@ -297,9 +442,22 @@ pub struct SingleReadHandle {
     handle: multir::Handle,
     trigger: Option<Arc<dyn Fn(&mut dyn Iterator<Item = &[DataType]>) -> bool + Send + Sync>>,
     key: Vec<usize>,
+    reads: Option<Arc<ReadLog>>,
+    /// The number of read requests served from this shard, shared by all clones of the handle.
+    requests: Arc<AtomicUsize>,
 }
 
 impl SingleReadHandle {
+    /// Count a read request served from this shard.
+    pub fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of read requests served from this shard since it was created.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
     /// Trigger a replay of a missing key from a partially materialized view.
     pub fn trigger<'a, I>(&self, keys: I) -> bool
     where
@@ -328,6 +486,9 @@ impl SingleReadHandle {
     where
         F: FnMut(&evmap::Values<Vec<DataType>, fnv::FnvBuildHasher>) -> T,
     {
+        if let Some(ref reads) = self.reads {
+            reads.record(key);
+        }
         self.handle
             .meta_get_and(key, &mut then)
             .ok_or(())
@@ -339,10 +500,28 @@ impl SingleReadHandle {
             })
     }
 
+    /// All the rows in this shard of the reader, or `Err` if it is not yet ready.
+    ///
+    /// Only the rows of filled keys are returned for partially materialized readers.
+    pub fn all_rows(&self) -> Result<Vec<Vec<DataType>>, ()> {
+        self.handle.all_rows().ok_or(())
+    }
+
+    /// Whether keys of this reader may be missing, and have to be filled through replays.
+    pub fn is_partial(&self) -> bool {
+        self.trigger.is_some()
+    }
+
     pub fn len(&self) -> usize {
         self.handle.len()
     }
 
+    /// Whether the writer of this shard has gone away, for example because it moved to another
+    /// worker.
+    pub fn is_destroyed(&self) -> bool {
+        self.handle.is_destroyed()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.handle.len() == 0
     }
@@ -384,6 +563,25 @@ mod tests {
             .unwrap());
     }
 
+    #[test]
+    fn all_rows_works() {
+        let a = vec![1.into(), "a".into()];
+        let b = vec![1.into(), "b".into()];
+        let c = vec![2.into(), "c".into()];
+
+        let (r, mut w) = new(2, &[0]);
+        w.add(vec![
+            Record::Positive(a.clone()),
+            Record::Positive(b.clone()),
+            Record::Positive(c.clone()),
+        ]);
+        w.swap();
+
+        let mut rows = r.all_rows().unwrap();
+        rows.sort();
+        assert_eq!(rows, vec![a, b, c]);
+    }
+
     #[test]
     fn busybusybusy() {
         use std::thread;
@@ -547,4 +745,70 @@ mod tests {
             .0
             .unwrap());
     }
+
+    #[test]
+    fn it_evicts_least_recently_read() {
+        let mut kept = 0;
+        for seed in 0..100 {
+            let (r, mut w) = new_partial(1, &[0], EvictionPolicy::LeastRecentlyUsed, |_| true);
+            w.swap();
+            for i in 0..2 {
+                let key = vec![i.into()];
+                w.mut_with_key(&key[..]).mark_filled();
+                w.add(vec![Record::Positive(key)]);
+            }
+            w.swap();
+
+            let hot = vec![DataType::from(1)];
+            assert_eq!(r.try_find_and(&hot, |rs| rs.len()).unwrap().0, Some(1));
+            assert!(w.evict_some_key(&mut StdRng::seed_from_u64(seed)) > 0);
+            w.swap();
+
+            if r.try_find_and(&hot, |rs| rs.len()).unwrap().0 == Some(1) {
+                kept += 1;
+            }
+        }
+        // both keys are sampled unless all samples land on the same one
+        assert!(kept > 80);
+    }
+
+    #[test]
+    fn it_keeps_the_most_recent_reads() {
+        let log = ReadLog::default();
+        let reads = 2 * MAX_LOGGED_READS * READ_SAMPLE_RATE;
+        for i in 0..reads {
+            log.record(&[(i as i64).into()]);
+        }
+
+        let keys = log.drain();
+        assert_eq!(keys.len(), MAX_LOGGED_READS);
+        let first = (reads - MAX_LOGGED_READS * READ_SAMPLE_RATE) as i64;
+        assert_eq!(keys[0], vec![DataType::from(first)]);
+        let last = (reads - READ_SAMPLE_RATE) as i64;
+        assert_eq!(keys.last().unwrap(), &vec![DataType::from(last)]);
+        assert!(log.drain().is_empty());
+    }
+
+    #[test]
+    fn it_logs_reads_from_many_threads() {
+        let log = Arc::new(ReadLog::default());
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    for i in 0..MAX_LOGGED_READS {
+                        log.record(&[((t * MAX_LOGGED_READS + i) as i64).into()]);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // all 4 * MAX_LOGGED_READS reads were logged, and one in every READ_SAMPLE_RATE was kept
+        let keys = log.drain();
+        assert_eq!(keys.len(), 4 * MAX_LOGGED_READS / READ_SAMPLE_RATE);
+        assert!(log.drain().is_empty());
+    }
 }
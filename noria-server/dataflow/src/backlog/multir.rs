@@ -18,6 +18,35 @@ impl Handle {
         }
     }
 
+    pub(super) fn is_destroyed(&self) -> bool {
+        match *self {
+            Handle::Single(ref h) => h.is_destroyed(),
+            Handle::Double(ref h) => h.is_destroyed(),
+            Handle::Many(ref h) => h.is_destroyed(),
+        }
+    }
+
+    /// All the rows in the map, or `None` if the map is not yet ready.
+    pub(super) fn all_rows(&self) -> Option<Vec<Vec<DataType>>> {
+        match *self {
+            Handle::Single(ref h) => {
+                let map = h.read();
+                map.meta()?;
+                Some(map.iter().flat_map(|(_, rs)| rs.iter().cloned()).collect())
+            }
+            Handle::Double(ref h) => {
+                let map = h.read();
+                map.meta()?;
+                Some(map.iter().flat_map(|(_, rs)| rs.iter().cloned()).collect())
+            }
+            Handle::Many(ref h) => {
+                let map = h.read();
+                map.meta()?;
+                Some(map.iter().flat_map(|(_, rs)| rs.iter().cloned()).collect())
+            }
+        }
+    }
+
     pub(super) fn meta_get_and<F, T>(&self, key: &[DataType], then: F) -> Option<(Option<T>, i64)>
     where
         F: FnOnce(&evmap::Values<Vec<DataType>, fnv::FnvBuildHasher>) -> T,
@@ -0,0 +1,118 @@
+//! Expiry of the rows of base tables that have a retention policy.
+//!
+//! A domain with such tables periodically looks for rows that have outlived their table's
+//! time-to-live, and deletes them: the deletions are written to the table like any other write,
+//! through its group commit queue and write-ahead log, so that everything downstream of the table
+//! stays consistent with it and the rows stay deleted when the table is recovered.
+
+use super::Domain;
+use crate::prelude::*;
+use noria::internal::LocalOrNot;
+use std::cmp;
+use std::time;
+
+/// The number of times a base table is checked for expired rows during its time-to-live.
+const CHECKS_PER_TTL: u32 = 10;
+/// Tables with a short time-to-live are checked at most this often.
+const MIN_CHECK_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// Tables with a long time-to-live are checked at least this often.
+const MAX_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+impl Domain {
+    /// Schedule the next check for expired rows, if any base table here has a retention policy.
+    pub(super) fn schedule_expiry(&mut self) {
+        let ttl = self
+            .nodes
+            .values()
+            .filter_map(|n| {
+                let n = n.borrow();
+                if n.is_dropped() {
+                    return None;
+                }
+                n.get_base().and_then(|b| b.retention()).map(|r| r.ttl)
+            })
+            .min();
+        self.next_expiry = ttl.map(|ttl| {
+            let interval = cmp::min(
+                cmp::max(ttl / CHECKS_PER_TTL, MIN_CHECK_INTERVAL),
+                MAX_CHECK_INTERVAL,
+            );
+            time::Instant::now() + interval
+        });
+    }
+
+    /// How long until the next check for expired rows.
+    pub(super) fn duration_until_expiry(&self) -> Option<time::Duration> {
+        let now = time::Instant::now();
+        self.next_expiry.map(|at| {
+            if at > now {
+                at - now
+            } else {
+                time::Duration::from_millis(0)
+            }
+        })
+    }
+
+    /// Remove the rows that have expired from base tables, if it is time to check for them.
+    pub(super) fn expire_if_due(&mut self, ex: &mut dyn Executor) {
+        match self.next_expiry {
+            Some(at) if at <= time::Instant::now() => {}
+            _ => return,
+        }
+        self.schedule_expiry();
+
+        if self.paused_inputs.is_some() {
+            // removals are writes too, so they wait for the snapshot or backup to finish
+            return;
+        }
+
+        let bases: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|&(local, n)| {
+                let n = n.borrow();
+                !n.is_dropped()
+                    && !self.not_ready.contains(&local)
+                    && n.get_base().and_then(|b| b.retention()).is_some()
+            })
+            .map(|(local, _)| local)
+            .collect();
+
+        let now = chrono::Local::now();
+        for local in bases {
+            // writes that are waiting to be committed may refresh rows that have expired
+            if let Some(m) = self.group_commit_queues.flush(local) {
+                self.handle(m, ex, true);
+            }
+
+            let ops = self.nodes[local]
+                .borrow_mut()
+                .get_base_mut()
+                .unwrap()
+                .expire(local, &self.state, now);
+            if ops.is_empty() {
+                continue;
+            }
+            debug!(self.log, "expiring base table rows";
+                   "local" => local.id(),
+                   "rows" => ops.len());
+
+            let p = Box::new(Packet::Input {
+                inner: LocalOrNot::new(Input {
+                    dst: local,
+                    data: ops,
+                    tracer: None,
+                }),
+                src: None,
+                senders: Vec::new(),
+            });
+            let merged = self.group_commit_queues.append(p);
+            for m in merged
+                .into_iter()
+                .chain(self.group_commit_queues.flush(local))
+            {
+                self.handle(m, ex, true);
+            }
+        }
+    }
+}
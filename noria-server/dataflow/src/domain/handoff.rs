@@ -0,0 +1,937 @@
+//! Moving a domain shard, state included, to another worker.
+//!
+//! The controller first brings the data-flow to a standstill with a snapshot that isn't
+//! persisted, so that no updates are on their way to the shard. It then tells the shard to hand
+//! itself off with `Packet::HandOff`. The shard applies the writes it has accepted, and from then
+//! on holds back everything that arrives for it, but keeps its state and keeps serving reads, so
+//! that it stays the authoritative copy until the shard that takes its place is ready.
+//!
+//! The controller starts the new shard from the nodes in its graph, and replays the packets that
+//! set up the old shard to it. It then tells the old shard to stream the contents of its fully
+//! materialized state to the new one in batches of `Packet::TakeOver`. Partially materialized
+//! state is not passed on; the new shard starts out with only holes, which are filled again by
+//! replays as they are needed. Should any of this fail, the new shard is stopped and the old one
+//! carries on with `Packet::CancelHandOff`.
+//!
+//! The controller says whether the new shard runs on the same machine. If it does, the old shard
+//! closes the storage of base tables that is kept on disk for the new one to open, and doesn't
+//! send their rows. If it doesn't, the old shard deletes that storage once it forwards, so that
+//! it isn't mistaken for the table's should the shard ever come back.
+//!
+//! Once the new shard has all of the state, the old one is told where it is with
+//! `Packet::Forward`. It drops its state, and forwards all it held back, as well as anything that
+//! is still sent to it afterwards by clients and domains that haven't learned that the shard
+//! moved. Writes are forwarded as `Packet::ForwardedInput`, and their clients are only
+//! acknowledged once the new shard confirms that it applied them; clients of writes that may not
+//! have been applied have their connections closed. Readers that come looking for the shard's
+//! views are sent to the worker the views now live on. The old shard stops once nothing has come
+//! its way for a while, and if the shard moves again before then, the controller points it to
+//! the newest shard with `Packet::Reforward`, so that forwarders never forward to each other.
+//!
+//! Resharding a domain works the same way, except that all of its shards are handed off at once,
+//! and they split their state and what they forward among the new shards by sharding key.
+
+use super::Domain;
+use crate::backlog::SingleReadHandle;
+use crate::group_commit::GroupCommitQueueSet;
+use crate::payload::{ControlReplyPacket, InitialState, SourceSelection, TriggerEndpoint};
+use crate::prelude::*;
+use crate::wal::WriteAheadLog;
+use crate::ReaderMoved;
+use noria::channel::{DomainConnectionBuilder, TcpSender};
+use noria::internal::LocalOrNot;
+use noria::TableOperation;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// How long a domain shard that forwards to the shards that took its place keeps doing so after
+/// the last packet came its way.
+const FORWARD_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many rows of a node's state are sent to the shards that take over in a single packet.
+const TAKE_OVER_BATCH: usize = 1024;
+
+/// What a domain shard that was handed off does with the packets that still arrive for it.
+pub(super) enum Moved {
+    /// Hold them back until the shards that take over are ready, or the hand-off is cancelled.
+    HeldBack {
+        held: Vec<Box<Packet>>,
+        /// The base tables whose storage was closed for the shard that takes over to open.
+        closed: Vec<LocalNodeIndex>,
+        /// The shard's readers, to send their contents to the shards that take over.
+        readers: Vec<(LocalNodeIndex, SingleReadHandle)>,
+    },
+    /// Forward them to the shards that took over, split among them by sharding key.
+    Forwarded {
+        txs: Vec<TcpSender<Box<Packet>>>,
+        sharding: Map<Sharding>,
+        /// The views that readers are sent elsewhere for.
+        readers: Vec<NodeIndex>,
+        /// Where this shard is reached, for the shards that took over to confirm writes.
+        reply_to: SocketAddr,
+        /// The writes that were forwarded, but not yet confirmed, by the id they were sent with.
+        unconfirmed: HashMap<u64, Unconfirmed>,
+        next_id: u64,
+        last_active: Instant,
+    },
+}
+
+/// A write that was forwarded to the shards that took over, split into `parts` parts.
+pub(super) struct Unconfirmed {
+    parts: usize,
+    applied: bool,
+    waiters: Vec<Waiter>,
+}
+
+/// Who waits for a forwarded write to be confirmed.
+enum Waiter {
+    /// A client that wrote to this shard.
+    Client(SourceChannelIdentifier),
+    /// A shard that was handed off earlier, and that forwarded the write with the given id.
+    Forwarder(SocketAddr, u64),
+}
+
+/// How far a domain shard is in receiving the state of the shards it takes the place of.
+#[derive(Default)]
+pub(super) struct TakingOver {
+    /// How many shards send their state, once the controller has said so.
+    from: Option<usize>,
+    /// How many shards have sent all of their state.
+    done: usize,
+}
+
+impl Domain {
+    pub(super) fn hand_off(&mut self, share_storage: bool, ex: &mut dyn Executor) {
+        // writes that were accepted but held back have to be applied here, since the clients that
+        // sent them are waiting for this shard to acknowledge them
+        let paused = self.paused_inputs.is_some();
+        for m in self.group_commit_queues.flush_all() {
+            self.handle(m, ex, true);
+        }
+        for m in self.paused_inputs.take().unwrap_or_default() {
+            self.handle(m, ex, true);
+        }
+        if paused {
+            self.paused_inputs = Some(Vec::new());
+        }
+
+        // close the storage of base tables that is kept on disk, so that a new shard on the same
+        // machine can open it; their rows aren't sent, since the new shard finds them there
+        let mut closed = Vec::new();
+        if share_storage {
+            for (local, node) in self.nodes.iter() {
+                let n = node.borrow();
+                if !n.is_base() || n.is_dropped() {
+                    continue;
+                }
+                let was_open = match self.persistence_parameters.mode {
+                    DurabilityMode::Permanent => self.state.remove(local).is_some(),
+                    DurabilityMode::Logged => self.group_commit_queues.remove_log(local).is_some(),
+                    _ => false,
+                };
+                if was_open {
+                    closed.push(local);
+                }
+            }
+        }
+
+        let shard = self.shard.unwrap_or(0);
+        let all_readers = self.readers.lock().unwrap();
+        let readers = self
+            .nodes
+            .iter()
+            .filter(|(_, n)| n.borrow().is_reader() && !n.borrow().is_dropped())
+            .filter_map(|(local, n)| {
+                let gid = n.borrow().global_addr();
+                all_readers.get(&(gid, shard)).map(|r| (local, r.clone()))
+            })
+            .collect();
+        drop(all_readers);
+
+        info!(self.log, "handed off domain shard"; "closed" => closed.len());
+        self.moved = Some(Moved::HeldBack {
+            held: Vec::new(),
+            closed,
+            readers,
+        });
+        self.control_reply_tx
+            .send(ControlReplyPacket::HandOff(Ok(())))
+            .unwrap();
+    }
+
+    pub(super) fn stream_state(&mut self, to: Vec<SocketAddr>) {
+        let sent = self.send_state(&to);
+        if let Err(ref e) = sent {
+            error!(self.log, "failed to send state of handed off domain shard"; "error" => e);
+        }
+        self.control_reply_tx
+            .send(ControlReplyPacket::HandOff(sent))
+            .unwrap();
+    }
+
+    /// Send the fully materialized state of this handed off shard to the shards at `to`.
+    fn send_state(&mut self, to: &[SocketAddr]) -> Result<(), String> {
+        let mut txs = connect(to)?;
+        let (closed, readers) = match self.moved {
+            Some(Moved::HeldBack {
+                ref closed,
+                ref readers,
+                ..
+            }) => (closed, readers),
+            _ => return Err("domain shard doesn't hold back packets".to_owned()),
+        };
+
+        let mut rows_sent = 0;
+        let mut next = 0;
+        for (local, node) in self.nodes.iter() {
+            let n = node.borrow();
+            if n.is_dropped() || closed.contains(&local) {
+                continue;
+            }
+            let rows = if let Some(state) = self.state.get(local) {
+                if state.is_partial() {
+                    continue;
+                }
+                state.cloned_records()
+            } else if let Some(&(_, ref handle)) = readers.iter().find(|&&(l, _)| l == local) {
+                if handle.is_partial() {
+                    continue;
+                }
+                handle.all_rows().unwrap_or_default()
+            } else {
+                continue;
+            };
+            rows_sent += rows.len();
+
+            let sharding = n.sharded_by();
+            let mut batches = vec![Vec::new(); txs.len()];
+            for r in rows {
+                let i = match sharding {
+                    _ if txs.len() == 1 => 0,
+                    Sharding::ByColumn(col, _) => crate::shard_by(&r[col], txs.len()),
+                    Sharding::Random(_) => {
+                        next = (next + 1) % txs.len();
+                        next
+                    }
+                    Sharding::None | Sharding::ForcedNone => 0,
+                };
+                batches[i].push(r);
+                if batches[i].len() >= TAKE_OVER_BATCH {
+                    let rows = mem::replace(&mut batches[i], Vec::new());
+                    send_rows(&mut txs[i], local, rows)?;
+                }
+            }
+            for (i, rows) in batches.into_iter().enumerate() {
+                if !rows.is_empty() {
+                    send_rows(&mut txs[i], local, rows)?;
+                }
+            }
+        }
+
+        // only once all the rest is on its way may the shards that take over be told it is
+        for tx in &mut txs {
+            tx.send(Box::new(Packet::TakeOver {
+                rows: Vec::new(),
+                last: true,
+            }))
+            .map_err(|e| format!("failed to send state to domain shard: {:?}", e))?;
+        }
+        info!(self.log, "sent state of handed off domain shard";
+              "shards" => txs.len(),
+              "rows" => rows_sent);
+        Ok(())
+    }
+
+    pub(super) fn take_over(
+        &mut self,
+        rows: Vec<(LocalNodeIndex, Vec<Vec<DataType>>)>,
+        last: bool,
+    ) {
+        let t = self.taking_over.get_or_insert_with(Default::default);
+        for (node, rows) in rows {
+            let mut records: Records = rows.into_iter().collect();
+            if let Some(state) = self.state.get_mut(node) {
+                if let Some(log) = self.group_commit_queues.log_mut(node) {
+                    let ops: Vec<_> = records
+                        .iter()
+                        .map(|r| TableOperation::Insert(r.rec().to_vec()))
+                        .collect();
+                    log.append(&ops);
+                }
+                state.process_records(&mut records, None);
+                if let Some(b) = self.nodes[node].borrow_mut().get_base_mut() {
+                    b.reset_expiry_index();
+                }
+            } else {
+                let mut n = self.nodes[node].borrow_mut();
+                n.with_reader_mut(|r| {
+                    if let Some(w) = r.writer_mut() {
+                        w.add(records);
+                        w.swap();
+                    }
+                })
+                .expect("told to fill node without state");
+            }
+        }
+        if last {
+            t.done += 1;
+            self.finish_take_over();
+        }
+    }
+
+    pub(super) fn await_take_over(&mut self, shards: usize) {
+        self.taking_over.get_or_insert_with(Default::default).from = Some(shards);
+        self.finish_take_over();
+    }
+
+    /// Tell the controller once all the shards this one takes the place of have sent their state.
+    fn finish_take_over(&mut self) {
+        let done = match self.taking_over {
+            Some(ref t) => t.from.map_or(false, |from| t.done >= from),
+            None => false,
+        };
+        if done {
+            self.taking_over = None;
+            debug!(self.log, "took over state of handed off domain shards");
+            self.control_reply_tx
+                .send(ControlReplyPacket::HandOff(Ok(())))
+                .unwrap();
+        }
+    }
+
+    pub(super) fn cancel_hand_off(&mut self, ex: &mut dyn Executor) {
+        let (held, closed) = match self.moved.take() {
+            Some(Moved::HeldBack { held, closed, .. }) => (held, closed),
+            moved => {
+                self.moved = moved;
+                let e = "domain shard doesn't hold back packets".to_owned();
+                self.control_reply_tx
+                    .send(ControlReplyPacket::HandOff(Err(e)))
+                    .unwrap();
+                return;
+            }
+        };
+
+        let mut reopened = Ok(());
+        for node in closed {
+            if self.persistence_parameters.mode == DurabilityMode::Permanent {
+                let index = self
+                    .setup
+                    .iter()
+                    .find_map(|p| match *p {
+                        Packet::Ready {
+                            node: n, ref index, ..
+                        } if n == node => Some(index.clone()),
+                        _ => None,
+                    })
+                    .unwrap_or_default();
+                self.open_state(node, index, ex);
+            } else {
+                // the table's rows never left memory
+                let path = self.base_log_path(node);
+                match WriteAheadLog::open(&path, self.persistence_parameters.fsync) {
+                    Ok((log, _)) => self.group_commit_queues.add_log(node, log),
+                    Err(e) => {
+                        reopened = Err(format!("failed to reopen {:?}: {}", path, e));
+                    }
+                }
+            }
+        }
+
+        info!(self.log, "cancelled hand-off of domain shard"; "held" => held.len());
+        for m in held {
+            self.process(m, ex);
+        }
+        self.control_reply_tx
+            .send(ControlReplyPacket::HandOff(reopened))
+            .unwrap();
+    }
+
+    pub(super) fn forward_to(
+        &mut self,
+        to: Vec<SocketAddr>,
+        readers_to: Vec<SocketAddr>,
+        writes_to: Vec<SocketAddr>,
+        from: SocketAddr,
+        ex: &mut dyn Executor,
+    ) {
+        let txs = match self.moved {
+            Some(Moved::HeldBack { .. }) => connect(&to),
+            _ => Err("domain shard doesn't hold back packets".to_owned()),
+        };
+        let txs = match txs {
+            Ok(txs) => txs,
+            Err(e) => {
+                // the shard stays as it is, so that the hand-off can still be cancelled
+                self.control_reply_tx
+                    .send(ControlReplyPacket::HandOff(Err(e)))
+                    .unwrap();
+                return;
+            }
+        };
+        let (held, closed) = match self.moved.take() {
+            Some(Moved::HeldBack { held, closed, .. }) => (held, closed),
+            _ => unreachable!(),
+        };
+
+        let mut sharding = Map::default();
+        let mut readers = Vec::new();
+        for (local, node) in self.nodes.iter() {
+            let n = node.borrow();
+            if n.is_dropped() {
+                continue;
+            }
+            if n.is_base() || n.is_ingress() {
+                sharding.insert(local, n.sharded_by());
+            }
+            if n.is_reader() {
+                readers.push(n.global_addr());
+            }
+        }
+
+        // readers are sent on before this shard's views go away, so that they always find them
+        self.move_readers(&readers, readers_to);
+        ex.redirect_writes(writes_to);
+
+        // storage that the shards that took over didn't open is of no use anymore, and would
+        // otherwise be mistaken for the table's should the shard ever come back to this machine
+        let mut stale = Vec::new();
+        for (local, node) in self.nodes.iter() {
+            let n = node.borrow();
+            if n.is_base() && !n.is_dropped() && !closed.contains(&local) {
+                stale.push((local, self.state_name(local)));
+            }
+        }
+        for (local, name) in stale {
+            let removed = match self.persistence_parameters.mode {
+                DurabilityMode::Permanent => {
+                    // the table's state has to be closed before its files can go
+                    self.state.remove(local);
+                    PersistentState::destroy(&name, &self.persistence_parameters)
+                }
+                DurabilityMode::Logged => match self.group_commit_queues.remove_log(local) {
+                    Some(log) => log.remove().map_err(|e| e.to_string()),
+                    None => Ok(()),
+                },
+                _ => Ok(()),
+            };
+            if let Err(e) = removed {
+                warn!(self.log, "failed to remove storage of handed off base table";
+                      "name" => name,
+                      "error" => e);
+            }
+        }
+
+        let shard = self.shard.unwrap_or(0);
+        let all_readers = self.readers.clone();
+        let mut all_readers = all_readers.lock().unwrap();
+        self.nodes = Default::default();
+        for &gid in &readers {
+            // unless a shard that took over on this worker has taken its place already
+            if all_readers
+                .get(&(gid, shard))
+                .map_or(false, SingleReadHandle::is_destroyed)
+            {
+                all_readers.remove(&(gid, shard));
+            }
+        }
+        drop(all_readers);
+
+        self.state = Default::default();
+        self.group_commit_queues = GroupCommitQueueSet::new(&self.persistence_parameters);
+        self.replay_paths.clear();
+        self.replay_paths_by_dst = Default::default();
+        self.reader_triggered = Default::default();
+        self.waiting = Default::default();
+        self.buffered_replay_requests.clear();
+        self.replay_request_queue.clear();
+        self.timed_purges.clear();
+        self.memory_budgets = Default::default();
+        self.not_ready.clear();
+        self.next_expiry = None;
+        self.pending_snapshot = None;
+        self.taken_snapshot = None;
+        self.paused_inputs = None;
+        self.setup.clear();
+        self.state_size.store(0, Ordering::Relaxed);
+
+        info!(self.log, "forwarding packets to domain shards that took over";
+              "addrs" => ?to,
+              "held" => held.len());
+        self.moved = Some(Moved::Forwarded {
+            txs,
+            sharding,
+            readers,
+            reply_to: from,
+            unconfirmed: HashMap::new(),
+            next_id: 0,
+            last_active: Instant::now(),
+        });
+        for m in held {
+            self.forward(m, ex);
+        }
+        self.control_reply_tx
+            .send(ControlReplyPacket::HandOff(Ok(())))
+            .unwrap();
+    }
+
+    pub(super) fn reforward_to(
+        &mut self,
+        to: Vec<SocketAddr>,
+        readers_to: Vec<SocketAddr>,
+        writes_to: Vec<SocketAddr>,
+        ex: &mut dyn Executor,
+    ) {
+        let readers = match self.moved {
+            Some(Moved::Forwarded {
+                ref mut txs,
+                ref readers,
+                ..
+            }) => match connect(&to) {
+                Ok(new) => {
+                    *txs = new;
+                    readers.clone()
+                }
+                Err(e) => {
+                    // the shards forwarded to will forward in turn
+                    warn!(self.log, "failed to forward to new domain shards"; "error" => e);
+                    return;
+                }
+            },
+            _ => {
+                warn!(
+                    self.log,
+                    "told to forward anew by domain shard that doesn't forward"
+                );
+                return;
+            }
+        };
+        self.move_readers(&readers, readers_to);
+        ex.redirect_writes(writes_to);
+        info!(self.log, "forwarding packets to other domain shards"; "addrs" => ?to);
+    }
+
+    /// Send readers that come looking for the given views to the workers at `to`.
+    fn move_readers(&mut self, readers: &[NodeIndex], to: Vec<SocketAddr>) {
+        let to = if to.len() == 1 {
+            ReaderMoved::To(to[0])
+        } else {
+            ReaderMoved::Resharded(to)
+        };
+        let shard = self.shard.unwrap_or(0);
+        let mut moved_readers = self.moved_readers.lock().unwrap();
+        for &gid in readers {
+            moved_readers.insert((gid, shard), to.clone());
+        }
+    }
+
+    /// Pass on a packet that arrived after this shard was handed off.
+    pub(super) fn forward(&mut self, m: Box<Packet>, ex: &mut dyn Executor) {
+        match self.moved {
+            Some(Moved::HeldBack { ref mut held, .. }) => {
+                held.push(m);
+                return;
+            }
+            Some(Moved::Forwarded {
+                ref mut last_active,
+                ..
+            }) => *last_active = Instant::now(),
+            None => unreachable!(),
+        }
+
+        match *m {
+            Packet::Input {
+                inner,
+                src,
+                senders,
+            } => {
+                let waiters = src.into_iter().chain(senders).map(Waiter::Client).collect();
+                self.forward_input(unsafe { inner.take() }, waiters, ex);
+            }
+            Packet::ForwardedInput {
+                inner,
+                reply_to,
+                id,
+            } => {
+                let waiters = vec![Waiter::Forwarder(reply_to, id)];
+                self.forward_input(unsafe { inner.take() }, waiters, ex);
+            }
+            Packet::WriteConfirmed { id, applied } => self.write_confirmed(id, applied, ex),
+            Packet::Evict { .. } | Packet::UpdateStateSize | Packet::Spin => {
+                // meant for the state this shard no longer has
+            }
+            m => self.forward_other(Box::new(m)),
+        }
+    }
+
+    /// Forward a write to the shards that took over, and have those that wait for it find out
+    /// whether it was applied once they have confirmed it.
+    fn forward_input(&mut self, input: Input, waiters: Vec<Waiter>, ex: &mut dyn Executor) {
+        let (txs, sharding, reply_to, unconfirmed, next_id) = match self.moved {
+            Some(Moved::Forwarded {
+                ref mut txs,
+                ref sharding,
+                reply_to,
+                ref mut unconfirmed,
+                ref mut next_id,
+                ..
+            }) => (txs, sharding, reply_to, unconfirmed, next_id),
+            _ => unreachable!(),
+        };
+
+        let parts = if txs.len() == 1 {
+            vec![(0, input)]
+        } else {
+            split_input(input, sharding, txs.len())
+        };
+        let id = *next_id;
+        *next_id += 1;
+
+        let mut sent = 0;
+        let mut failed = false;
+        for (shard, input) in parts {
+            let m = if waiters.is_empty() {
+                Packet::Input {
+                    inner: LocalOrNot::new(input),
+                    src: None,
+                    senders: Vec::new(),
+                }
+            } else {
+                Packet::ForwardedInput {
+                    inner: LocalOrNot::new(input),
+                    reply_to,
+                    id,
+                }
+            };
+            match txs[shard].send(Box::new(m)) {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    error!(self.log, "failed to forward write to moved domain shard";
+                           "error" => ?e);
+                    failed = true;
+                }
+            }
+        }
+
+        if waiters.is_empty() {
+            return;
+        }
+        if sent == 0 {
+            self.finish_forwarded(waiters, !failed, ex);
+        } else {
+            let u = Unconfirmed {
+                parts: sent,
+                applied: !failed,
+                waiters,
+            };
+            unconfirmed.insert(id, u);
+        }
+    }
+
+    fn write_confirmed(&mut self, id: u64, applied: bool, ex: &mut dyn Executor) {
+        let done = match self.moved {
+            Some(Moved::Forwarded {
+                ref mut unconfirmed,
+                ..
+            }) => match unconfirmed.get_mut(&id) {
+                Some(u) => {
+                    u.parts -= 1;
+                    u.applied &= applied;
+                    if u.parts == 0 {
+                        unconfirmed.remove(&id)
+                    } else {
+                        None
+                    }
+                }
+                None => {
+                    warn!(self.log, "confirmation of unknown forwarded write"; "id" => id);
+                    None
+                }
+            },
+            _ => None,
+        };
+        if let Some(u) = done {
+            self.finish_forwarded(u.waiters, u.applied, ex);
+        }
+    }
+
+    /// Tell those that wait for a forwarded write whether it was applied.
+    fn finish_forwarded(&mut self, waiters: Vec<Waiter>, applied: bool, ex: &mut dyn Executor) {
+        for w in waiters {
+            match w {
+                Waiter::Client(id) if applied => ex.ack(id),
+                Waiter::Client(id) => ex.fail(id),
+                Waiter::Forwarder(addr, id) => self.confirm_write(addr, id, applied),
+            }
+        }
+    }
+
+    /// Tell the handed off shard at `addr` whether the write it forwarded as `id` was applied.
+    fn confirm_write(&mut self, addr: SocketAddr, id: u64, applied: bool) {
+        if !self.write_confirmations.contains_key(&addr) {
+            match DomainConnectionBuilder::for_domain(addr).build_sync() {
+                Ok(tx) => {
+                    self.write_confirmations.insert(addr, tx);
+                }
+                Err(e) => {
+                    error!(self.log, "failed to connect to handed off domain shard";
+                           "addr" => ?addr,
+                           "error" => ?e);
+                    return;
+                }
+            }
+        }
+        let tx = self.write_confirmations.get_mut(&addr).unwrap();
+        if let Err(e) = tx.send(Box::new(Packet::WriteConfirmed { id, applied })) {
+            error!(self.log, "failed to confirm forwarded write";
+                   "addr" => ?addr,
+                   "error" => ?e);
+            self.write_confirmations.remove(&addr);
+        }
+    }
+
+    /// Apply a write that a handed off shard forwarded, and confirm to it that it was.
+    pub(super) fn apply_forwarded(
+        &mut self,
+        input: Input,
+        reply_to: SocketAddr,
+        id: u64,
+        ex: &mut dyn Executor,
+    ) {
+        let dst = input.dst;
+        let m = Box::new(Packet::Input {
+            inner: LocalOrNot::new(input),
+            src: None,
+            senders: Vec::new(),
+        });
+        // the write is applied right away rather than with the next group commit, so that it can
+        // be confirmed
+        if let Some(m) = self.group_commit_queues.append(m) {
+            self.handle(m, ex, true);
+        }
+        if let Some(m) = self.group_commit_queues.flush(dst) {
+            self.handle(m, ex, true);
+        }
+        self.confirm_write(reply_to, id, true);
+    }
+
+    fn forward_other(&mut self, m: Box<Packet>) {
+        let (txs, sharding) = match self.moved {
+            Some(Moved::Forwarded {
+                ref mut txs,
+                ref sharding,
+                ..
+            }) => (txs, sharding),
+            _ => unreachable!(),
+        };
+
+        let mut sent = Ok(());
+        if txs.len() == 1 {
+            sent = txs[0].send(m);
+        } else {
+            match split_message(m, sharding, txs.len()) {
+                Ok(split) => {
+                    for (shard, m) in split {
+                        if let Err(e) = txs[shard].send(m) {
+                            sent = Err(e);
+                        }
+                    }
+                }
+                Err(m) => {
+                    // replays that were under way when the domain was resharded are requested
+                    // anew by the shards that took over
+                    debug!(self.log, "dropping packet for resharded domain shard";
+                           "packet" => ?m);
+                }
+            }
+        }
+        if let Err(e) = sent {
+            error!(self.log, "failed to forward packet to moved domain shard";
+                   "error" => ?e);
+        }
+    }
+
+    /// Whether this shard, which forwards to the shards that took its place, has had nothing come
+    /// its way for long enough that it can stop. Writes it forwarded that still haven't been
+    /// confirmed by then are failed.
+    pub(super) fn stop_forwarding_if_idle(&mut self, ex: &mut dyn Executor) -> bool {
+        let unconfirmed = match self.moved {
+            Some(Moved::Forwarded {
+                last_active,
+                ref mut unconfirmed,
+                ..
+            }) if last_active.elapsed() >= FORWARD_IDLE_TIMEOUT => mem::take(unconfirmed),
+            _ => return false,
+        };
+        for (_, u) in unconfirmed {
+            self.finish_forwarded(u.waiters, false, ex);
+        }
+        true
+    }
+
+    /// How long until this shard may stop forwarding, if it forwards.
+    pub(super) fn duration_until_idle(&self) -> Option<Duration> {
+        match self.moved {
+            Some(Moved::Forwarded { last_active, .. }) => Some(
+                FORWARD_IDLE_TIMEOUT
+                    .checked_sub(last_active.elapsed())
+                    .unwrap_or_default(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Connect to the domain shards at `to`.
+fn connect(to: &[SocketAddr]) -> Result<Vec<TcpSender<Box<Packet>>>, String> {
+    to.iter()
+        .map(|&addr| {
+            DomainConnectionBuilder::for_domain(addr)
+                .build_sync()
+                .map_err(|e| format!("failed to connect to domain shard at {:?}: {}", addr, e))
+        })
+        .collect()
+}
+
+fn send_rows(
+    tx: &mut TcpSender<Box<Packet>>,
+    node: LocalNodeIndex,
+    rows: Vec<Vec<DataType>>,
+) -> Result<(), String> {
+    tx.send(Box::new(Packet::TakeOver {
+        rows: vec![(node, rows)],
+        last: false,
+    }))
+    .map_err(|e| format!("failed to send state to domain shard: {:?}", e))
+}
+
+/// The shard among `shards` that a write to a table sharded by column `col` goes to.
+fn shard_of(op: &TableOperation, col: usize, shards: usize) -> usize {
+    let key = match *op {
+        TableOperation::Insert(ref r) => &r[col],
+        TableOperation::Delete { ref key } => &key[0],
+        TableOperation::Update { ref key, .. } => &key[0],
+        TableOperation::InsertOrUpdate { ref row, .. } => &row[col],
+    };
+    crate::shard_by(key, shards)
+}
+
+/// Split up a write among `shards` shards by the sharding of the table it is for.
+fn split_input(input: Input, sharding: &Map<Sharding>, shards: usize) -> Vec<(usize, Input)> {
+    let col = match sharding.get(input.dst) {
+        Some(&Sharding::ByColumn(col, _)) => col,
+        _ => return vec![(0, input)],
+    };
+    let mut split: HashMap<usize, Vec<TableOperation>> = HashMap::new();
+    for op in input.data {
+        split
+            .entry(shard_of(&op, col, shards))
+            .or_default()
+            .push(op);
+    }
+    split
+        .into_iter()
+        .map(|(shard, data)| {
+            let input = Input {
+                dst: input.dst,
+                data,
+                tracer: input.tracer.clone(),
+            };
+            (shard, input)
+        })
+        .collect()
+}
+
+/// Split up the updates in `m` among `shards` shards by the sharding of the node they are for, or
+/// give it back if it isn't a packet that can be split up.
+fn split_message(
+    m: Box<Packet>,
+    sharding: &Map<Sharding>,
+    shards: usize,
+) -> Result<Vec<(usize, Box<Packet>)>, Box<Packet>> {
+    match *m {
+        Packet::Message { link, data, tracer } => {
+            let col = match sharding.get(link.dst) {
+                Some(&Sharding::ByColumn(col, _)) => col,
+                _ => return Ok(vec![(0, Box::new(Packet::Message { link, data, tracer }))]),
+            };
+            let mut split: HashMap<usize, Records> = HashMap::new();
+            for r in data {
+                split
+                    .entry(crate::shard_by(&r[col], shards))
+                    .or_default()
+                    .push(r);
+            }
+            Ok(split
+                .into_iter()
+                .map(|(shard, data)| {
+                    let m = Packet::Message {
+                        link,
+                        data,
+                        tracer: tracer.clone(),
+                    };
+                    (shard, Box::new(m))
+                })
+                .collect())
+        }
+        m => Err(Box::new(m)),
+    }
+}
+
+impl Packet {
+    /// A copy of this packet that set up a domain shard, for shard `shard` of the domain once it
+    /// has been resharded, with the domains in `sharded` now split into `shards` shards.
+    pub fn resharded(&self, shard: usize, shards: usize, sharded: &HashSet<DomainIndex>) -> Packet {
+        let mut p = self.clone();
+        match p {
+            Packet::PrepareState {
+                state:
+                    InitialState::PartialGlobal {
+                        ref mut trigger_domain,
+                        ..
+                    },
+                ..
+            } => {
+                if sharded.contains(&trigger_domain.0) {
+                    trigger_domain.1 = shards;
+                }
+            }
+            Packet::SetupReplayPath {
+                trigger: TriggerEndpoint::End(ref mut selection, domain),
+                ..
+            } => {
+                if sharded.contains(&domain) {
+                    match *selection {
+                        SourceSelection::AllShards(ref mut n)
+                        | SourceSelection::KeyShard {
+                            nshards: ref mut n, ..
+                        } => *n = shards,
+                        SourceSelection::SameShard => {}
+                    }
+                }
+            }
+            Packet::UpdateEgress {
+                new_tx: Some((_, _, ref mut addr)),
+                ..
+            } => {
+                // a sharded egress sends to the same shard of the next domain
+                if sharded.contains(&addr.0) {
+                    addr.1 = shard;
+                }
+            }
+            Packet::UpdateSharder {
+                new_txs: (_, ref mut txs),
+                ..
+            } => {
+                if let Some(&(domain, _)) = txs.first().filter(|&&(d, _)| sharded.contains(&d)) {
+                    *txs = (0..shards).map(|i| (domain, i)).collect();
+                }
+            }
+            _ => {}
+        }
+        p
+    }
+}
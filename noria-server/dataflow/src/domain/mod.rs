@@ -5,23 +5,30 @@ use std::cmp;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time;
 
+use crate::eviction::EvictionPolicy;
 use crate::group_commit::GroupCommitQueueSet;
 use crate::payload::{ControlReplyPacket, ReplayPieceContext, SourceSelection};
 use crate::prelude::*;
+use crate::wal::WriteAheadLog;
 use futures_util::{future::FutureExt, stream::StreamExt};
 use noria::channel::{self, TcpSender};
 pub use noria::internal::DomainIndex as Index;
 use slog::Logger;
 use stream_cancel::Valve;
 
-use crate::Readers;
+use crate::{MovedReaders, Readers};
 use timekeeper::{RealTime, SimpleTracker, ThreadTime, Timer, TimerSet};
 use tokio;
 
+mod expiry;
+mod handoff;
+mod snapshot;
+
 #[derive(Debug)]
 pub enum PollEvent {
     ResumePolling,
@@ -40,10 +47,25 @@ pub enum ProcessResult {
 pub struct Config {
     pub concurrent_replays: usize,
     pub replay_batch_timeout: time::Duration,
+    /// How keys are chosen for eviction from partially materialized state.
+    pub eviction_policy: EvictionPolicy,
+    /// Eviction policies for individual nodes, by node name, overriding `eviction_policy`.
+    pub node_eviction_policies: HashMap<String, EvictionPolicy>,
 }
 
 const BATCH_SIZE: usize = 256;
 
+/// How long an export may go without reading from a base table before the rows it hasn't read
+/// yet are dropped.
+const EXPORT_IDLE_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// An export reading from a base table, with the rows it hasn't read yet.
+struct Export {
+    id: u64,
+    rows: std::vec::IntoIter<Vec<DataType>>,
+    last_read: time::Instant,
+}
+
 #[derive(Debug)]
 enum DomainMode {
     Forwarding,
@@ -68,6 +90,7 @@ enum TriggerEndpoint {
     Start(Vec<usize>),
     End {
         source: SourceSelection,
+        domain: DomainIndex,
         options: Vec<Box<dyn channel::Sender<Item = Box<Packet>> + Send>>,
     },
     Local(Vec<usize>),
@@ -140,6 +163,7 @@ impl DomainBuilder {
         self,
         log: Logger,
         readers: Readers,
+        moved_readers: MovedReaders,
         channel_coordinator: Arc<ChannelCoordinator>,
         control_addr: SocketAddr,
         shutdown_valve: &Valve,
@@ -176,6 +200,7 @@ impl DomainBuilder {
 
             shutdown_valve: shutdown_valve.clone(),
             readers,
+            moved_readers,
             control_reply_tx,
             channel_coordinator,
 
@@ -186,8 +211,23 @@ impl DomainBuilder {
             concurrent_replays: 0,
             max_concurrent_replays: self.config.concurrent_replays,
             replay_request_queue: Default::default(),
+            eviction_policy: self.config.eviction_policy,
+            node_eviction_policies: self.config.node_eviction_policies,
+            memory_budgets: Default::default(),
             delayed_for_self: Default::default(),
 
+            pending_snapshot: None,
+            snapshot_markers: Default::default(),
+            taken_snapshot: None,
+            paused_inputs: None,
+            next_expiry: None,
+            exports: Default::default(),
+
+            setup: Vec::new(),
+            moved: None,
+            taking_over: None,
+            write_confirmations: Default::default(),
+
             group_commit_queues,
 
             state_size,
@@ -238,8 +278,13 @@ pub struct Domain {
     max_concurrent_replays: usize,
     replay_request_queue: VecDeque<(Tag, Vec<Vec<DataType>>)>,
 
+    eviction_policy: EvictionPolicy,
+    node_eviction_policies: HashMap<String, EvictionPolicy>,
+    memory_budgets: Map<usize>,
+
     shutdown_valve: Valve,
     readers: Readers,
+    moved_readers: MovedReaders,
     control_reply_tx: TcpSender<ControlReplyPacket>,
     channel_coordinator: Arc<ChannelCoordinator>,
 
@@ -247,6 +292,31 @@ pub struct Domain {
     replay_batch_timeout: time::Duration,
     delayed_for_self: VecDeque<Box<Packet>>,
 
+    /// The id of the snapshot we've been told to take, the number of markers to wait for,
+    /// whether to write it to disk, and the pause of the data-flow to reply to.
+    pending_snapshot: Option<(u64, usize, bool, u64)>,
+    /// The number of markers that have arrived for each snapshot not yet taken.
+    snapshot_markers: HashMap<u64, usize>,
+    taken_snapshot: Option<snapshot::TakenSnapshot>,
+    /// Writes to base tables that arrived while a snapshot was being taken.
+    paused_inputs: Option<Vec<Box<Packet>>>,
+    /// When to next check base tables for rows that have outlived their retention.
+    next_expiry: Option<time::Instant>,
+    /// The export reading from each base table.
+    exports: Map<Export>,
+
+    /// The packets that set up state and replay paths, so that the domain can be set up anew on
+    /// another worker.
+    setup: Vec<Packet>,
+    /// Set once the domain has been handed off to another worker.
+    moved: Option<handoff::Moved>,
+    /// How far along this shard is in receiving the state of the handed off shards it takes the
+    /// place of.
+    taking_over: Option<handoff::TakingOver>,
+    /// Connections to the handed off domain shards that forward writes to this one, to confirm
+    /// that the writes were applied.
+    write_confirmations: HashMap<SocketAddr, TcpSender<Box<Packet>>>,
+
     group_commit_queues: GroupCommitQueueSet,
 
     state_size: Arc<AtomicUsize>,
@@ -263,6 +333,30 @@ pub struct Domain {
 }
 
 impl Domain {
+    /// The policy for choosing which keys to evict from the given node's partial state.
+    fn eviction_policy(&self, node: LocalNodeIndex) -> EvictionPolicy {
+        let n = self.nodes[node].borrow();
+        self.node_eviction_policies
+            .get(n.name())
+            .cloned()
+            .unwrap_or(self.eviction_policy)
+    }
+
+    /// The number of keys whose replays are queued behind other replays or buffered for batching.
+    fn replay_backlog(&self) -> usize {
+        let queued: usize = self
+            .replay_request_queue
+            .iter()
+            .map(|(_, keys)| keys.len())
+            .sum();
+        let buffered: usize = self
+            .buffered_replay_requests
+            .values()
+            .map(|(_, keys, _)| keys.len())
+            .sum();
+        queued + buffered
+    }
+
     fn find_tags_and_replay(
         &mut self,
         miss_keys: Vec<Vec<DataType>>,
@@ -711,6 +805,17 @@ impl Domain {
             m => unreachable!("dispatch process got {:?}", m),
         }
 
+        self.dispatch_to_children(me, m.take().unwrap(), executor);
+    }
+
+    /// Send an update that the given node produced to each of its children.
+    fn dispatch_to_children(
+        &mut self,
+        me: LocalNodeIndex,
+        m: Box<Packet>,
+        executor: &mut dyn Executor,
+    ) {
+        let mut m = Some(m);
         // NOTE: we can't directly iterate over .children due to self.dispatch in the loop
         let nchildren = self.nodes[me].borrow().children().len();
         for i in 0..nchildren {
@@ -739,6 +844,270 @@ impl Domain {
         }
     }
 
+    /// Create the state of the given node with the given indices. The state of a base table is
+    /// opened from where it is kept on disk, if it is.
+    fn open_state(
+        &mut self,
+        node: LocalNodeIndex,
+        index: HashSet<Vec<usize>>,
+        executor: &mut dyn Executor,
+    ) {
+        let mut s: Box<dyn State> = {
+            let n = self.nodes[node].borrow();
+            let params = &self.persistence_parameters;
+            match (n.get_base(), &params.mode) {
+                (Some(base), &DurabilityMode::DeleteOnExit)
+                | (Some(base), &DurabilityMode::Permanent) => {
+                    let base_name = self.state_name(node);
+
+                    // the recipe may tune RocksDB differently for this table
+                    let params = match base.rocksdb_options() {
+                        Some(rocksdb) => PersistenceParameters {
+                            rocksdb: rocksdb.clone(),
+                            ..params.clone()
+                        },
+                        None => params.clone(),
+                    };
+                    Box::new(PersistentState::new(base_name, base.key(), &params))
+                }
+                _ => Box::new(MemoryState::default()),
+            }
+        };
+        for idx in index {
+            s.add_key(&idx[..], None);
+        }
+        assert!(self.state.insert(node, s).is_none());
+
+        if self.persistence_parameters.mode == DurabilityMode::Logged
+            && self.nodes[node].borrow().is_base()
+        {
+            self.open_base_log(node, executor);
+        }
+
+        let expires = self.nodes[node]
+            .borrow()
+            .get_base()
+            .and_then(|b| b.retention())
+            .is_some();
+        if expires && self.next_expiry.is_none() {
+            self.schedule_expiry();
+        }
+    }
+
+    /// The name that the state of the given node is kept on disk under.
+    fn state_name(&self, node: LocalNodeIndex) -> String {
+        format!(
+            "{}-{}-{}",
+            self.persistence_parameters.log_prefix,
+            self.nodes[node].borrow().name(),
+            self.shard.unwrap_or(0)
+        )
+    }
+
+    /// Where the write-ahead log of the given base table is kept.
+    fn base_log_path(&self, node: LocalNodeIndex) -> PathBuf {
+        let params = &self.persistence_parameters;
+        let name = format!("{}.wal", self.state_name(node));
+        match params.log_dir {
+            Some(ref dir) => dir.join(name),
+            None => name.into(),
+        }
+    }
+
+    /// Open the write-ahead log of the given base table, and apply the writes it holds to the
+    /// table's state. The table's children aren't ready yet, and are filled by replays later.
+    fn open_base_log(&mut self, node: LocalNodeIndex, executor: &mut dyn Executor) {
+        let path = self.base_log_path(node);
+        let (log, batches) = WriteAheadLog::open(&path, self.persistence_parameters.fsync)
+            .unwrap_or_else(|e| panic!("failed to open write-ahead log {:?}: {}", path, e));
+        debug!(self.log, "replaying write-ahead log";
+               "local" => node.id(),
+               "batches" => batches.len());
+
+        let mut n = self.nodes[node].borrow_mut();
+        for data in batches {
+            let mut m = Some(Box::new(Packet::Input {
+                inner: LocalOrNot::new(Input {
+                    dst: node,
+                    data,
+                    tracer: None,
+                }),
+                src: None,
+                senders: Vec::new(),
+            }));
+            n.process(
+                &mut m,
+                None,
+                &mut self.state,
+                &self.nodes,
+                self.shard,
+                false,
+                executor,
+            );
+        }
+        drop(n);
+
+        self.group_commit_queues.add_log(node, log);
+    }
+
+    /// Replace the write-ahead logs that have grown much larger than the base tables they log with
+    /// ones that hold only the tables' current rows.
+    ///
+    /// Must only be called once every batch appended to the logs has been applied to the tables.
+    fn compact_base_logs(&mut self) {
+        if self.paused_inputs.is_some() {
+            // the writes that are held back have been logged, but not applied
+            return;
+        }
+        for node in self.group_commit_queues.logs_to_compact() {
+            let rows = match self.state.get(node) {
+                Some(state) => state.cloned_records(),
+                None => continue,
+            };
+            debug!(self.log, "compacting write-ahead log";
+                   "local" => node.id(),
+                   "rows" => rows.len());
+            let log = self.group_commit_queues.log_mut(node).unwrap();
+            if let Err(e) = log.compact(rows) {
+                error!(self.log, "failed to compact write-ahead log";
+                       "local" => node.id(),
+                       "error" => %e);
+            }
+        }
+    }
+
+    /// Write a checkpoint of the state of every base table in this domain to `dir`.
+    ///
+    /// Writes to base tables must be held back by a snapshot, which every domain has taken before
+    /// any of them is told to back up its tables, so that the checkpoints of all domains are
+    /// consistent with each other.
+    fn backup_bases(&mut self, dir: &Path) -> Result<(), String> {
+        if self.paused_inputs.is_none() {
+            return Err("writes to base tables must be held back during a backup".to_owned());
+        }
+
+        let shard = self.shard.unwrap_or(0);
+        for (local, node) in self.nodes.iter() {
+            let n = node.borrow();
+            if !n.is_base() || n.is_dropped() {
+                continue;
+            }
+            if let Some(state) = self.state.get(local) {
+                state.checkpoint(&dir.join(format!("{}-{}.db", n.name(), shard)))?;
+            }
+        }
+        debug!(self.log, "backed up base tables"; "dir" => ?dir);
+        Ok(())
+    }
+
+    /// The next `count` rows of base table `node` that export `export` hasn't read yet. Only one
+    /// export reads from a table at a time; one that reads from it anew takes its place.
+    fn export_base(
+        &mut self,
+        node: LocalNodeIndex,
+        export: u64,
+        count: usize,
+    ) -> Vec<Vec<DataType>> {
+        if self.exports.get(node).map_or(true, |e| e.id != export) {
+            let rows = self
+                .state
+                .get(node)
+                .map(|state| state.cloned_records())
+                .unwrap_or_default();
+            self.exports.insert(
+                node,
+                Export {
+                    id: export,
+                    rows: rows.into_iter(),
+                    last_read: time::Instant::now(),
+                },
+            );
+        }
+
+        let e = &mut self.exports[node];
+        e.last_read = time::Instant::now();
+        let rows: Vec<_> = e.rows.by_ref().take(count).collect();
+        if rows.is_empty() {
+            self.exports.remove(node);
+        }
+        rows
+    }
+
+    /// Drop the rows that export `export` hasn't read yet from base table `node`, if it is the
+    /// one reading from it.
+    fn cancel_export(&mut self, node: LocalNodeIndex, export: u64) {
+        if self.exports.get(node).map_or(false, |e| e.id == export) {
+            self.exports.remove(node);
+            debug!(self.log, "cancelled export"; "local" => node.id(), "export" => export);
+        }
+    }
+
+    /// Drop the rows of exports that have stopped reading, such as because their client went
+    /// away.
+    fn expire_exports(&mut self) {
+        let idle: Vec<_> = self
+            .exports
+            .iter()
+            .filter(|(_, e)| e.last_read.elapsed() >= EXPORT_IDLE_TIMEOUT)
+            .map(|(node, _)| node)
+            .collect();
+        for node in idle {
+            warn!(self.log, "dropping idle export"; "local" => node.id());
+            self.exports.remove(node);
+        }
+    }
+
+    /// How long until the export that has gone without reading the longest is dropped.
+    fn duration_until_export_expiry(&self) -> Option<time::Duration> {
+        self.exports
+            .values()
+            .map(|e| {
+                EXPORT_IDLE_TIMEOUT
+                    .checked_sub(e.last_read.elapsed())
+                    .unwrap_or_default()
+            })
+            .min()
+    }
+
+    /// Connect anew to domain shard `to`, which was started again on another worker after the one
+    /// it ran on failed, both for updates and for the replays this shard asks it for.
+    fn reconnect(&mut self, to: ReplicaAddr, ex: &mut dyn Executor) {
+        ex.reconnect(to);
+        for path in self.replay_paths.values_mut() {
+            if let TriggerEndpoint::End {
+                ref source,
+                domain,
+                ref mut options,
+            } = path.trigger
+            {
+                if domain != to.0 {
+                    continue;
+                }
+                let i = match *source {
+                    SourceSelection::SameShard if self.shard == Some(to.1) => 0,
+                    SourceSelection::SameShard => continue,
+                    _ => to.1,
+                };
+                options[i] = self
+                    .channel_coordinator
+                    .builder_for(&to)
+                    .unwrap()
+                    .build_sync()
+                    .unwrap();
+            }
+        }
+        debug!(self.log, "reconnected to recovered domain shard";
+               "domain" => to.0.index(),
+               "shard" => to.1);
+    }
+
+    /// Process the writes to base tables that were held back, and stop holding back new ones.
+    fn resume_inputs(&mut self) {
+        for m in self.paused_inputs.take().unwrap_or_default() {
+            self.delayed_for_self.push_back(m);
+        }
+    }
+
     #[allow(clippy::cognitive_complexity)]
     fn handle(&mut self, m: Box<Packet>, executor: &mut dyn Executor, top: bool) {
         if self.wait_time.is_running() {
@@ -747,6 +1116,19 @@ impl Domain {
         m.trace(PacketEvent::Handle);
 
         match *m {
+            Packet::Input { .. } | Packet::ForwardedInput { .. }
+                if self.paused_inputs.is_some() =>
+            {
+                // writes to base tables are held back while a snapshot is being taken
+                self.paused_inputs.as_mut().unwrap().push(m);
+            }
+            Packet::ForwardedInput {
+                inner,
+                reply_to,
+                id,
+            } => {
+                self.apply_forwarded(unsafe { inner.take() }, reply_to, id, executor);
+            }
             Packet::Message { .. } | Packet::Input { .. } => {
                 // WO for https://github.com/rust-lang/rfcs/issues/1403
                 self.total_forward_time.start();
@@ -761,7 +1143,19 @@ impl Domain {
             Packet::Evict { .. } | Packet::EvictKeys { .. } => {
                 self.handle_eviction(m, executor);
             }
+            Packet::SnapshotMarker { id, .. } => {
+                self.on_snapshot_marker(id, executor);
+            }
             consumed => {
+                match consumed {
+                    Packet::PrepareState { .. }
+                    | Packet::SetupReplayPath { .. }
+                    | Packet::Ready { .. } => {
+                        self.setup.push(consumed.clone());
+                    }
+                    _ => {}
+                }
+
                 match consumed {
                     // workaround #16223
                     Packet::AddNode { node, parents } => {
@@ -782,6 +1176,16 @@ impl Domain {
                         for &node in &nodes {
                             self.nodes[node].borrow_mut().remove();
                             self.state.remove(node);
+                            self.memory_budgets.remove(node);
+                            self.exports.remove(node);
+                            if let Some(log) = self.group_commit_queues.remove_log(node) {
+                                // the table is gone, so nothing will ever read its log again
+                                if let Err(e) = log.remove() {
+                                    warn!(self.log, "failed to delete write-ahead log";
+                                          "local" => node.id(),
+                                          "error" => %e);
+                                }
+                            }
                             trace!(self.log, "node removed"; "local" => node.id());
                         }
 
@@ -824,15 +1228,28 @@ impl Domain {
                             .send(ControlReplyPacket::ack())
                             .unwrap();
                     }
+                    Packet::Reconnect { to, addr } => {
+                        self.channel_coordinator.insert_remote(to, addr);
+                        self.reconnect(to, executor);
+                    }
+                    Packet::UpdateController { addr } => match TcpSender::connect(&addr) {
+                        Ok(tx) => self.control_reply_tx = tx,
+                        Err(e) => warn!(self.log, "failed to connect to new controller: {:?}", e),
+                    },
                     Packet::UpdateEgress {
                         node,
                         new_tx,
                         new_tag,
                     } => {
                         let mut n = self.nodes[node].borrow_mut();
-                        n.with_egress_mut(move |e| {
+                        n.with_egress_mut(|e| {
                             if let Some((node, local, addr)) = new_tx {
-                                e.add_tx(node, local, addr);
+                                if e.has_tx(node, addr) {
+                                    // the child's domain shard moved to another worker
+                                    executor.reconnect(addr);
+                                } else {
+                                    e.add_tx(node, local, addr);
+                                }
                             }
                             if let Some(new_tag) = new_tag {
                                 e.add_tag(new_tag.0, new_tag.1);
@@ -841,8 +1258,17 @@ impl Domain {
                     }
                     Packet::UpdateSharder { node, new_txs } => {
                         let mut n = self.nodes[node].borrow_mut();
-                        n.with_sharder_mut(move |s| {
-                            s.add_sharded_child(new_txs.0, new_txs.1);
+                        n.with_sharder_mut(|s| {
+                            if s.has_sharded_child(new_txs.0) {
+                                // the child's domain shards moved to other workers, or the child
+                                // was resharded
+                                for &addr in &new_txs.1 {
+                                    executor.reconnect(addr);
+                                }
+                                s.set_sharded_child(new_txs.0, new_txs.1);
+                            } else {
+                                s.add_sharded_child(new_txs.0, new_txs.1);
+                            }
                         });
                     }
                     Packet::AddStreamer { node, new_streamer } => {
@@ -850,6 +1276,19 @@ impl Domain {
                         n.with_reader_mut(|r| r.add_streamer(new_streamer).unwrap())
                             .unwrap();
                     }
+                    Packet::UpdateMemoryBudget { node, budget } => {
+                        trace!(self.log, "memory budget updated";
+                               "local" => node.id(),
+                               "budget" => ?budget);
+                        match budget {
+                            Some(budget) => {
+                                self.memory_budgets.insert(node, budget);
+                            }
+                            None => {
+                                self.memory_budgets.remove(node);
+                            }
+                        }
+                    }
                     Packet::StateSizeProbe { node } => {
                         let row_count = self.state.get(node).map(|r| r.rows()).unwrap_or(0);
                         let mem_size = self.state.get(node).map(|s| s.deep_size_of()).unwrap_or(0);
@@ -862,7 +1301,21 @@ impl Domain {
                         match state {
                             InitialState::PartialLocal(index) => {
                                 if !self.state.contains_key(node) {
-                                    self.state.insert(node, Box::new(MemoryState::default()));
+                                    let params = &self.persistence_parameters;
+                                    let eviction = self.eviction_policy(node);
+                                    let name = self.state_name(node);
+                                    let s: Box<dyn State> = match params.partial_storage {
+                                        PartialStorage::Memory => {
+                                            Box::new(MemoryState::with_eviction_policy(eviction))
+                                        }
+                                        PartialStorage::Disk => Box::new(
+                                            PersistentState::new_partial(name, params, eviction),
+                                        ),
+                                        PartialStorage::Tiered => {
+                                            Box::new(TieredState::new(name, params, eviction))
+                                        }
+                                    };
+                                    self.state.insert(node, s);
                                 }
                                 let state = self.state.get_mut(node).unwrap();
                                 for (key, tags) in index {
@@ -930,6 +1383,7 @@ impl Domain {
                                 let (r_part, w_part) = backlog::new_partial(
                                     cols,
                                     &k[..],
+                                    self.eviction_policy(node),
                                     move |misses: &mut dyn Iterator<Item = &[DataType]>| {
                                         let n = txs.len();
                                         if n == 1 {
@@ -962,12 +1416,12 @@ impl Domain {
 
                                 let mut n = self.nodes[node].borrow_mut();
                                 n.with_reader_mut(|r| {
-                                    assert!(self
-                                        .readers
+                                    // a shard that was handed off on this worker may still serve
+                                    // reads until this one has taken its place
+                                    self.readers
                                         .lock()
                                         .unwrap()
-                                        .insert((gid, *self.shard.as_ref().unwrap_or(&0)), r_part)
-                                        .is_none());
+                                        .insert((gid, *self.shard.as_ref().unwrap_or(&0)), r_part);
 
                                     // make sure Reader is actually prepared to receive state
                                     r.set_write_handle(w_part)
@@ -980,12 +1434,10 @@ impl Domain {
 
                                 let mut n = self.nodes[node].borrow_mut();
                                 n.with_reader_mut(|r| {
-                                    assert!(self
-                                        .readers
+                                    self.readers
                                         .lock()
                                         .unwrap()
-                                        .insert((gid, *self.shard.as_ref().unwrap_or(&0)), r_part)
-                                        .is_none());
+                                        .insert((gid, *self.shard.as_ref().unwrap_or(&0)), r_part);
 
                                     // make sure Reader is actually prepared to receive state
                                     r.set_write_handle(w_part)
@@ -1044,6 +1496,7 @@ impl Domain {
 
                                 TriggerEndpoint::End {
                                     source: selection,
+                                    domain,
                                     options,
                                 }
                             }
@@ -1279,32 +1732,7 @@ impl Domain {
                         self.nodes[node].borrow_mut().purge = purge;
 
                         if !index.is_empty() {
-                            let mut s: Box<dyn State> = {
-                                let n = self.nodes[node].borrow();
-                                let params = &self.persistence_parameters;
-                                match (n.get_base(), &params.mode) {
-                                    (Some(base), &DurabilityMode::DeleteOnExit)
-                                    | (Some(base), &DurabilityMode::Permanent) => {
-                                        let base_name = format!(
-                                            "{}-{}-{}",
-                                            params.log_prefix,
-                                            n.name(),
-                                            self.shard.unwrap_or(0),
-                                        );
-
-                                        Box::new(PersistentState::new(
-                                            base_name,
-                                            base.key(),
-                                            &params,
-                                        ))
-                                    }
-                                    _ => Box::new(MemoryState::default()),
-                                }
-                            };
-                            for idx in index {
-                                s.add_key(&idx[..], None);
-                            }
-                            assert!(self.state.insert(node, s).is_none());
+                            self.open_state(node, index, executor);
                         } else {
                             // NOTE: just because index_on is None does *not* mean we're not
                             // materialized
@@ -1340,6 +1768,7 @@ impl Domain {
                             total_replay_time: self.total_replay_time.num_nanoseconds(),
                             total_forward_time: self.total_forward_time.num_nanoseconds(),
                             wait_time: self.wait_time.num_nanoseconds(),
+                            replay_backlog: self.replay_backlog() as u64,
                         };
 
                         let node_stats = self
@@ -1364,6 +1793,19 @@ impl Domain {
                                         .unwrap_or(0)
                                 };
 
+                                let (rows, key_counts) = self
+                                    .state
+                                    .get(local_index)
+                                    .map(|s| {
+                                        let key_counts = s
+                                            .key_counts()
+                                            .into_iter()
+                                            .map(|(k, c)| (k, c as u64))
+                                            .collect();
+                                        (s.rows() as u64, key_counts)
+                                    })
+                                    .unwrap_or_default();
+
                                 let mat_state = if !n.is_reader() {
                                     match self.state.get(local_index) {
                                         Some(ref s) => {
@@ -1404,6 +1846,8 @@ impl Domain {
                                             process_time: time.unwrap(),
                                             process_ptime: ptime.unwrap(),
                                             mem_size,
+                                            rows,
+                                            key_counts,
                                             materialized: mat_state,
                                             probe_result,
                                         },
@@ -1421,6 +1865,86 @@ impl Domain {
                     Packet::UpdateStateSize => {
                         self.update_state_sizes();
                     }
+                    Packet::TakeSnapshot {
+                        id,
+                        markers,
+                        persist,
+                        pause,
+                    } => {
+                        self.take_snapshot(id, markers, persist, pause, executor);
+                    }
+                    Packet::FinishSnapshot { id, complete } => {
+                        self.finish_snapshot(id, complete);
+                    }
+                    Packet::RestoreBase { node, seq } => {
+                        self.restore_base(node, seq);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::LoadSnapshot { node, id, probe } => {
+                        let loaded = self.load_snapshot(node, id, probe);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::SnapshotLoaded(loaded))
+                            .unwrap();
+                    }
+                    Packet::ReapplyBaseLog { node } => {
+                        self.reapply_base_log(node, executor);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::Backup { dir } => {
+                        let taken = self.backup_bases(&dir);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::BackupTaken(taken))
+                            .unwrap();
+                    }
+                    Packet::ExportBase {
+                        node,
+                        export,
+                        count,
+                    } => {
+                        let rows = self.export_base(node, export, count);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::BaseRows(rows))
+                            .unwrap();
+                    }
+                    Packet::CancelExport { node, export } => {
+                        self.cancel_export(node, export);
+                    }
+                    Packet::Compact { node } => {
+                        for (local, n) in self.nodes.iter() {
+                            if !n.borrow().is_base() || node.map_or(false, |node| node != local) {
+                                continue;
+                            }
+                            if let Some(state) = self.state.get_mut(local) {
+                                debug!(self.log, "compacting base table"; "local" => local.id());
+                                state.compact();
+                            }
+                        }
+                    }
+                    Packet::HandOff { share_storage } => {
+                        self.hand_off(share_storage, executor);
+                    }
+                    Packet::TakeOver { rows, last } => {
+                        self.take_over(rows, last);
+                    }
+                    Packet::AwaitTakeOver { shards } => {
+                        self.await_take_over(shards);
+                    }
+                    Packet::StreamState { .. } | Packet::Forward { .. } | Packet::CancelHandOff => {
+                        let e = "domain shard wasn't handed off".to_owned();
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::HandOff(Err(e)))
+                            .unwrap();
+                    }
+                    Packet::Reforward { .. } | Packet::WriteConfirmed { .. } => {
+                        warn!(
+                            self.log,
+                            "ignoring packet meant for a handed off domain shard"
+                        );
+                    }
                     Packet::Quit => unreachable!("Quit messages are handled by event loop"),
                     Packet::Spin => {
                         // spinning as instructed
@@ -1431,6 +1955,7 @@ impl Domain {
         }
 
         if top {
+            let mut checked_budgets = false;
             loop {
                 while let Some(m) = self.delayed_for_self.pop_front() {
                     trace!(self.log, "handling local transmission");
@@ -1498,6 +2023,13 @@ impl Domain {
                         .unwrap();
                 }
 
+                if !checked_budgets {
+                    // only once, since a node that has nothing left to evict would otherwise keep
+                    // us in this loop forever
+                    checked_budgets = true;
+                    self.enforce_memory_budgets();
+                }
+
                 if self.delayed_for_self.is_empty() {
                     break;
                 }
@@ -1754,6 +2286,14 @@ impl Domain {
             } = self.replay_paths[&tag];
 
             match self.mode {
+                DomainMode::Forwarding
+                    if notify_done && !self.not_ready.contains(&path.last().unwrap().node) =>
+                {
+                    // the node's state is already complete. all shards of this domain see the
+                    // replays that fill in the state of one of them that was started anew after
+                    // its worker failed, so those meant for another shard end up here.
+                    return;
+                }
                 DomainMode::Forwarding if notify_done => {
                     // this is the first message we receive for this tagged replay path. only at
                     // this point should we start buffering messages for the target node. since the
@@ -2672,7 +3212,7 @@ impl Domain {
                             // the same individual key twice if we batch evictions here.
                             let freed_now = self.nodes[node]
                                 .borrow_mut()
-                                .with_reader_mut(|r| r.evict_random_key())
+                                .with_reader_mut(|r| r.evict_some_key())
                                 .unwrap();
 
                             freed += freed_now;
@@ -2681,7 +3221,7 @@ impl Domain {
                             }
                         } else {
                             let (key_columns, keys, bytes) = {
-                                let k = self.state[node].evict_random_keys(100);
+                                let k = self.state[node].evict_some_keys(100);
                                 (k.0.to_vec(), k.1, k.2)
                             };
                             freed += bytes;
@@ -2772,39 +3312,82 @@ impl Domain {
             .unwrap();
     }
 
+    /// The number of bytes of partial state held by the given node.
+    fn partial_state_size(&self, node: LocalNodeIndex) -> u64 {
+        let n = &*self.nodes[node].borrow();
+        if n.is_reader() {
+            // We are a reader, which has its own kind of state
+            let mut size = 0;
+            n.with_reader(|r| {
+                if r.is_partial() {
+                    size = r.state_size().unwrap_or(0)
+                }
+            })
+            .unwrap();
+            size
+        } else {
+            // Not a reader, state is with domain
+            self.state
+                .get(node)
+                .filter(|state| state.is_partial())
+                .map(|s| s.deep_size_of())
+                .unwrap_or(0)
+        }
+    }
+
+    /// Queue evictions from all nodes whose partial state exceeds their memory budget.
+    fn enforce_memory_budgets(&mut self) {
+        for (node, &budget) in self.memory_budgets.iter() {
+            let size = self.partial_state_size(node) as usize;
+            if size > budget {
+                trace!(self.log, "node exceeds its memory budget";
+                       "local" => node.id(),
+                       "size" => size,
+                       "budget" => budget);
+                self.delayed_for_self.push_back(Box::new(Packet::Evict {
+                    node: Some(node),
+                    num_bytes: size - budget,
+                }));
+            }
+        }
+    }
+
+    /// Publish the size of this domain's partial state for the worker to read. Every node's size
+    /// is kept up to date as it changes, so this is cheap enough to do after every event.
     pub fn update_state_sizes(&mut self) {
         let total: u64 = self
             .nodes
-            .values()
-            .map(|nd| {
-                let n = &*nd.borrow();
-                let local_index = n.local_addr();
-
-                if n.is_reader() {
-                    // We are a reader, which has its own kind of state
-                    let mut size = 0;
-                    n.with_reader(|r| {
-                        if r.is_partial() {
-                            size = r.state_size().unwrap_or(0)
-                        }
-                    })
-                    .unwrap();
-                    size
-                } else {
-                    // Not a reader, state is with domain
-                    self.state
-                        .get(local_index)
-                        .filter(|state| state.is_partial())
-                        .map(|s| s.deep_size_of())
-                        .unwrap_or(0)
-                }
-            })
+            .iter()
+            .map(|(node, _)| self.partial_state_size(node))
             .sum();
 
         self.state_size.store(total as usize, Ordering::Relaxed);
         // no response sent, as worker will read the atomic
     }
 
+    /// Process a packet that arrived for this domain shard.
+    fn process(&mut self, packet: Box<Packet>, executor: &mut dyn Executor) {
+        // TODO: Initialize tracer here, and when flushing group commit
+        // queue.
+        if self.group_commit_queues.should_append(&packet, &self.nodes) {
+            packet.trace(PacketEvent::ExitInputChannel);
+            if let Some(packet) = self.group_commit_queues.append(packet) {
+                self.handle(packet, executor, true);
+            }
+        } else {
+            self.handle(packet, executor, true);
+        }
+
+        while let Some(m) = self.group_commit_queues.flush_if_necessary() {
+            self.handle(m, executor, true);
+        }
+        self.expire_if_due(executor);
+        self.expire_exports();
+        self.compact_base_logs();
+        self.group_commit_queues.sync_logs_if_due();
+        self.update_state_sizes();
+    }
+
     pub fn on_event(&mut self, executor: &mut dyn Executor, event: PollEvent) -> ProcessResult {
         if self.wait_time.is_running() {
             self.wait_time.stop();
@@ -2812,6 +3395,14 @@ impl Domain {
         //self.total_time.start();
         //self.total_ptime.start();
         let res = match event {
+            PollEvent::ResumePolling if self.moved.is_some() => {
+                if self.stop_forwarding_if_idle(executor) {
+                    info!(self.log, "stopping handed off domain shard");
+                    ProcessResult::StopPolling
+                } else {
+                    ProcessResult::KeepPolling(self.duration_until_idle())
+                }
+            }
             PollEvent::ResumePolling => {
                 // when do we need to be woken up again?
                 let now = time::Instant::now();
@@ -2834,13 +3425,26 @@ impl Domain {
                     }
                 });
 
-                let mut timeout = opt1.or(opt2).or(opt3);
+                let opt4 = self.duration_until_expiry();
+                let opt5 = self.group_commit_queues.duration_until_sync();
+                let opt6 = self.duration_until_export_expiry();
+
+                let mut timeout = opt1.or(opt2).or(opt3).or(opt4).or(opt5).or(opt6);
                 if let Some(opt2) = opt2 {
                     timeout = Some(std::cmp::min(timeout.unwrap(), opt2));
                 }
                 if let Some(opt3) = opt3 {
                     timeout = Some(std::cmp::min(timeout.unwrap(), opt3));
                 }
+                if let Some(opt4) = opt4 {
+                    timeout = Some(std::cmp::min(timeout.unwrap(), opt4));
+                }
+                if let Some(opt5) = opt5 {
+                    timeout = Some(std::cmp::min(timeout.unwrap(), opt5));
+                }
+                if let Some(opt6) = opt6 {
+                    timeout = Some(std::cmp::min(timeout.unwrap(), opt6));
+                }
                 ProcessResult::KeepPolling(timeout)
             }
             PollEvent::Process(packet) => {
@@ -2848,27 +3452,42 @@ impl Domain {
                     return ProcessResult::StopPolling;
                 }
 
-                // TODO: Initialize tracer here, and when flushing group commit
-                // queue.
-                if self.group_commit_queues.should_append(&packet, &self.nodes) {
-                    packet.trace(PacketEvent::ExitInputChannel);
-                    if let Some(packet) = self.group_commit_queues.append(packet) {
-                        self.handle(packet, executor, true);
+                if self.moved.is_some() {
+                    match *packet {
+                        Packet::StreamState { to } => self.stream_state(to),
+                        Packet::CancelHandOff => self.cancel_hand_off(executor),
+                        Packet::Forward {
+                            to,
+                            readers_to,
+                            writes_to,
+                            from,
+                        } => self.forward_to(to, readers_to, writes_to, from, executor),
+                        Packet::Reforward {
+                            to,
+                            readers_to,
+                            writes_to,
+                        } => self.reforward_to(to, readers_to, writes_to, executor),
+                        _ => self.forward(packet, executor),
                     }
-                } else {
-                    self.handle(packet, executor, true);
-                }
-
-                while let Some(m) = self.group_commit_queues.flush_if_necessary() {
-                    self.handle(m, executor, true);
+                    return ProcessResult::Processed;
                 }
 
+                self.process(packet, executor);
+                ProcessResult::Processed
+            }
+            PollEvent::Timeout if self.moved.is_some() => {
+                // nothing is done on the shard's own time until it either carries on or stops
                 ProcessResult::Processed
             }
             PollEvent::Timeout => {
                 while let Some(m) = self.group_commit_queues.flush_if_necessary() {
                     self.handle(m, executor, true);
                 }
+                self.expire_if_due(executor);
+                self.expire_exports();
+                self.compact_base_logs();
+                self.group_commit_queues.sync_logs_if_due();
+                self.update_state_sizes();
 
                 if !self.buffered_replay_requests.is_empty() || !self.timed_purges.is_empty() {
                     self.handle(Box::new(Packet::Spin), executor, true);
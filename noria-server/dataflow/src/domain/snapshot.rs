@@ -0,0 +1,292 @@
+//! Consistent snapshots of fully materialized state, which recovery can start from instead of
+//! replaying entire base tables.
+//!
+//! The controller asks every domain to take a snapshot with `Packet::TakeSnapshot`. A domain that
+//! is asked to do so immediately stops processing writes to its base tables, and then waits for a
+//! `Packet::SnapshotMarker` to arrive along every edge from an upstream domain. Upstream domains
+//! only send those markers once they have stopped processing writes themselves, so once all of
+//! them have arrived, the domain has seen every update that it will see until the snapshot is
+//! finished. At that point, it writes its fully materialized state to disk, sends markers to its
+//! downstream domains, and tells the controller how far each of its base tables got, or why its
+//! state couldn't be written. Writes resume once the controller sends `Packet::FinishSnapshot`.
+//!
+//! A snapshot that isn't persisted writes nothing to disk. It is only taken to bring the data-flow
+//! to a standstill, such as while a domain shard is moved to another worker.
+
+use super::Domain;
+use crate::payload::ControlReplyPacket;
+use crate::prelude::*;
+use crate::state::State;
+use std::fs;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// The part of a snapshot that a domain has taken, but that the controller hasn't finished yet.
+pub(super) struct TakenSnapshot {
+    id: u64,
+    /// The sequence number of the last change to each base table in the snapshot.
+    bases: Vec<(LocalNodeIndex, u64)>,
+    /// The nodes whose state was written to disk.
+    nodes: Vec<LocalNodeIndex>,
+}
+
+impl Domain {
+    pub(super) fn take_snapshot(
+        &mut self,
+        id: u64,
+        markers: usize,
+        persist: bool,
+        pause: u64,
+        ex: &mut dyn Executor,
+    ) {
+        trace!(self.log, "pausing writes for snapshot"; "id" => id, "markers" => markers);
+        if self.paused_inputs.is_none() {
+            self.paused_inputs = Some(Vec::new());
+        }
+        self.pending_snapshot = Some((id, markers, persist, pause));
+        self.try_take_snapshot(ex);
+    }
+
+    pub(super) fn on_snapshot_marker(&mut self, id: u64, ex: &mut dyn Executor) {
+        *self.snapshot_markers.entry(id).or_insert(0) += 1;
+        self.try_take_snapshot(ex);
+    }
+
+    /// Take the pending snapshot if all the markers it waits for have arrived.
+    fn try_take_snapshot(&mut self, ex: &mut dyn Executor) {
+        let (id, markers, persist, pause) = match self.pending_snapshot {
+            Some(pending) => pending,
+            None => return,
+        };
+        if self.snapshot_markers.get(&id).cloned().unwrap_or(0) < markers {
+            return;
+        }
+        self.pending_snapshot = None;
+        self.snapshot_markers.remove(&id);
+
+        let mut taken = TakenSnapshot {
+            id,
+            bases: Vec::new(),
+            nodes: Vec::new(),
+        };
+        let mut seqs = Vec::new();
+        let mut written = Ok(());
+        // a snapshot that isn't persisted only holds the data-flow still
+        for (local, node) in self.nodes.iter().filter(|_| persist) {
+            let n = node.borrow();
+            let state = match self.state.get(local) {
+                Some(state) => state,
+                None => continue,
+            };
+
+            if n.is_base() {
+                if let Some(seq) = state.log_seq() {
+                    taken.bases.push((local, seq));
+                    seqs.push((n.global_addr(), seq));
+                }
+            } else if !state.is_partial() && written.is_ok() {
+                written = self.write_snapshot(&n, &**state, id);
+                if written.is_ok() {
+                    taken.nodes.push(local);
+                }
+            }
+        }
+        match written {
+            Ok(()) => debug!(self.log, "took snapshot"; "id" => id, "nodes" => taken.nodes.len()),
+            Err(ref e) => error!(self.log, "failed to take snapshot"; "id" => id, "error" => e),
+        }
+
+        // only now may downstream domains take their part of the snapshot. they must do so even if
+        // ours failed, since the controller waits for every domain shard to reply.
+        let shard = self.shard.unwrap_or(0);
+        for (_, node) in self.nodes.iter() {
+            let mut n = node.borrow_mut();
+            if n.is_egress() {
+                n.with_egress_mut(|e| e.send_marker(id, shard, ex));
+            } else if n.is_sharder() {
+                let src = n.local_addr();
+                n.with_sharder_mut(|s| s.send_marker(id, src, ex));
+            }
+        }
+
+        self.taken_snapshot = Some(taken);
+        self.control_reply_tx
+            .send(ControlReplyPacket::SnapshotTaken(
+                pause,
+                shard,
+                written.map(|_| seqs),
+            ))
+            .unwrap();
+    }
+
+    pub(super) fn finish_snapshot(&mut self, id: u64, complete: bool) {
+        trace!(self.log, "resuming writes after snapshot"; "id" => id, "complete" => complete);
+        if let Some(taken) = self.taken_snapshot.take() {
+            assert_eq!(taken.id, id);
+            if complete {
+                for (base, seq) in taken.bases {
+                    if let Some(state) = self.state.get_mut(base) {
+                        if let Err(e) = state.truncate_log(seq) {
+                            warn!(self.log, "failed to truncate base table log";
+                                  "local" => base.id(),
+                                  "error" => e);
+                        }
+                    }
+                }
+                if id > 0 {
+                    for node in taken.nodes {
+                        let path = self.snapshot_path(&self.nodes[node].borrow(), id - 1);
+                        // the node may not have been materialized in the previous snapshot
+                        let _ = fs::remove_file(path);
+                    }
+                }
+            }
+        }
+
+        // a snapshot that was aborted may not have been taken here at all
+        self.pending_snapshot = None;
+        self.snapshot_markers.remove(&id);
+        self.resume_inputs();
+    }
+
+    pub(super) fn restore_base(&mut self, node: LocalNodeIndex, seq: u64) {
+        debug!(self.log, "rewinding base table to snapshot"; "local" => node.id(), "seq" => seq);
+        let rewound = self
+            .state
+            .get_mut(node)
+            .expect("told to restore base table without state")
+            .rewind(seq);
+        if let Some(b) = self.nodes[node].borrow_mut().get_base_mut() {
+            b.reset_expiry_index();
+        }
+        if let Err(e) = rewound {
+            error!(self.log, "failed to rewind base table to snapshot";
+                   "local" => node.id(),
+                   "error" => e);
+        }
+    }
+
+    /// Fill the given node's state from a snapshot, and return whether the snapshot had its
+    /// state. If `probe` is set, the state is left untouched.
+    pub(super) fn load_snapshot(&mut self, node: LocalNodeIndex, id: u64, probe: bool) -> bool {
+        let n = self.nodes[node].borrow();
+        let path = self.snapshot_path(&n, id);
+        let mut file = match fs::File::open(&path) {
+            Ok(file) => BufReader::new(file),
+            Err(_) => return false,
+        };
+
+        // a different node with the same name may have been in the snapshot
+        let fields: Vec<String> = match bincode::deserialize_from(&mut file) {
+            Ok(fields) => fields,
+            Err(e) => {
+                warn!(self.log, "ignoring unreadable snapshot";
+                      "path" => ?path,
+                      "error" => ?e);
+                return false;
+            }
+        };
+        if fields != n.fields() {
+            return false;
+        }
+        if probe {
+            return true;
+        }
+
+        let rows: Vec<Vec<DataType>> = match bincode::deserialize_from(&mut file) {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!(self.log, "ignoring unreadable snapshot";
+                      "path" => ?path,
+                      "error" => ?e);
+                return false;
+            }
+        };
+        debug!(self.log, "loading state from snapshot";
+               "local" => node.id(),
+               "rows" => rows.len());
+        let mut records: Records = rows.into();
+        drop(n);
+        if let Some(b) = self.nodes[node].borrow_mut().get_base_mut() {
+            b.reset_expiry_index();
+        }
+        self.state
+            .get_mut(node)
+            .expect("told to load snapshot into node without state")
+            .process_records(&mut records, None);
+        true
+    }
+
+    pub(super) fn reapply_base_log(&mut self, node: LocalNodeIndex, ex: &mut dyn Executor) {
+        let changes = match self
+            .state
+            .get_mut(node)
+            .expect("told to reapply changes to base table without state")
+            .roll_forward()
+        {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!(self.log, "failed to reapply base table changes since snapshot";
+                       "local" => node.id(),
+                       "error" => e);
+                return;
+            }
+        };
+        debug!(self.log, "reapplying base table changes since snapshot";
+               "local" => node.id(),
+               "changes" => changes.len());
+
+        for data in changes {
+            // the base table has already processed these, so they go straight to its children
+            let m = Box::new(Packet::Message {
+                link: Link::new(node, node),
+                data,
+                tracer: None,
+            });
+            self.dispatch_to_children(node, m, ex);
+        }
+    }
+
+    fn write_snapshot(&self, node: &Node, state: &dyn State, id: u64) -> Result<(), String> {
+        let path = self.snapshot_path(node, id);
+        let failed = |e: &dyn std::fmt::Display| format!("failed to write {:?}: {}", path, e);
+
+        // write to a temporary file first, so that we never leave behind a partial snapshot
+        let tmp = path.with_extension("tmp");
+        let mut file = BufWriter::new(fs::File::create(&tmp).map_err(|e| failed(&e))?);
+        bincode::serialize_into(&mut file, node.fields()).map_err(|e| failed(&e))?;
+
+        // the rows are streamed from the state rather than copied first, and laid out the way
+        // bincode lays out a `Vec` of them: their number, which is filled in once it is known,
+        // followed by each of them
+        let start = file.seek(SeekFrom::Current(0)).map_err(|e| failed(&e))?;
+        bincode::serialize_into(&mut file, &0u64).map_err(|e| failed(&e))?;
+        let mut rows = 0u64;
+        state.for_each_record(&mut |r| {
+            rows += 1;
+            bincode::serialize_into(&mut file, r).map_err(|e| failed(&e))
+        })?;
+        file.seek(SeekFrom::Start(start)).map_err(|e| failed(&e))?;
+        bincode::serialize_into(&mut file, &rows).map_err(|e| failed(&e))?;
+        file.into_inner()
+            .map_err(|e| failed(e.error()))?
+            .sync_all()
+            .map_err(|e| failed(&e))?;
+        fs::rename(&tmp, &path).map_err(|e| failed(&e))
+    }
+
+    fn snapshot_path(&self, node: &Node, id: u64) -> PathBuf {
+        let params = &self.persistence_parameters;
+        let name = format!(
+            "{}-{}-{}-{}.snapshot",
+            params.log_prefix,
+            node.name(),
+            self.shard.unwrap_or(0),
+            id
+        );
+        match params.log_dir {
+            Some(ref dir) => dir.join(name),
+            None => PathBuf::from(name),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use rand::Rng;
+
+/// How keys are chosen for eviction from partially materialized state when memory runs low.
+///
+/// Apart from `Random`, policies are approximate: for every key to evict, a handful of keys are
+/// sampled at random, and the one the policy ranks lowest among them is evicted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// Evict keys chosen uniformly at random.
+    Random,
+    /// Evict the keys that were read the longest time ago.
+    LeastRecentlyUsed,
+    /// Evict the keys that have been read the fewest times.
+    LeastFrequentlyUsed,
+    /// Evict the keys that hold the most data.
+    Largest,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Random
+    }
+}
+
+/// The number of keys sampled for every key that is evicted.
+const SAMPLES: usize = 5;
+
+/// When, and how often, a key has been read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct KeyAccess {
+    /// The logical time of the last read, or of when the key was filled.
+    last: u64,
+    /// The number of reads since the key was filled.
+    hits: u32,
+}
+
+impl KeyAccess {
+    pub(crate) fn new(now: u64) -> Self {
+        KeyAccess { last: now, hits: 0 }
+    }
+
+    pub(crate) fn touch(&mut self, now: u64) {
+        self.last = now;
+        self.hits = self.hits.saturating_add(1);
+    }
+}
+
+impl EvictionPolicy {
+    /// Returns whether this policy needs to know when keys are read.
+    pub(crate) fn tracks_reads(self) -> bool {
+        match self {
+            EvictionPolicy::LeastRecentlyUsed | EvictionPolicy::LeastFrequentlyUsed => true,
+            EvictionPolicy::Random | EvictionPolicy::Largest => false,
+        }
+    }
+
+    /// Returns whether this policy needs to know how much data each key holds.
+    pub(crate) fn needs_sizes(self) -> bool {
+        self == EvictionPolicy::Largest
+    }
+
+    /// Choose which of the `len` keys of some state to evict.
+    ///
+    /// `stats` gives the access history and size in bytes of the key at a given position. It is
+    /// only called for sampled keys, and the size it returns is ignored unless `needs_sizes`.
+    pub(crate) fn choose<R, F>(self, len: usize, rng: &mut R, mut stats: F) -> usize
+    where
+        R: Rng,
+        F: FnMut(usize) -> (KeyAccess, u64),
+    {
+        assert_ne!(len, 0);
+        if self == EvictionPolicy::Random {
+            return rng.gen_range(0, len);
+        }
+
+        let candidates = (0..SAMPLES).map(|_| {
+            let i = rng.gen_range(0, len);
+            let (access, size) = stats(i);
+            (i, access, size)
+        });
+        let victim = match self {
+            EvictionPolicy::Random => unreachable!(),
+            EvictionPolicy::LeastRecentlyUsed => candidates.min_by_key(|&(_, a, _)| a.last),
+            EvictionPolicy::LeastFrequentlyUsed => {
+                candidates.min_by_key(|&(_, a, _)| (a.hits, a.last))
+            }
+            EvictionPolicy::Largest => candidates.max_by_key(|&(_, _, size)| size),
+        };
+        victim.unwrap().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // key i was last read at time 10 - i, has been read i times, and holds i bytes
+    fn stats(i: usize) -> (KeyAccess, u64) {
+        let access = KeyAccess {
+            last: 10 - i as u64,
+            hits: i as u32,
+        };
+        (access, i as u64)
+    }
+
+    fn evicts(policy: EvictionPolicy, len: usize, expected: usize) -> bool {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..100).any(|_| policy.choose(len, &mut rng, stats) == expected)
+    }
+
+    #[test]
+    fn it_samples_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for &policy in &[
+            EvictionPolicy::Random,
+            EvictionPolicy::LeastRecentlyUsed,
+            EvictionPolicy::LeastFrequentlyUsed,
+            EvictionPolicy::Largest,
+        ] {
+            assert_eq!(policy.choose(1, &mut rng, stats), 0);
+            assert!(policy.choose(5, &mut rng, stats) < 5);
+        }
+    }
+
+    #[test]
+    fn it_evicts_by_policy() {
+        assert!(evicts(EvictionPolicy::LeastRecentlyUsed, 3, 2));
+        assert!(evicts(EvictionPolicy::LeastFrequentlyUsed, 3, 0));
+        assert!(evicts(EvictionPolicy::Largest, 3, 2));
+    }
+
+    #[test]
+    fn it_prefers_lower_ranked_keys() {
+        // with two keys, both are sampled nearly every time, so the worse one should win
+        let mut rng = StdRng::seed_from_u64(0);
+        let lru = (0..100)
+            .filter(|_| EvictionPolicy::LeastRecentlyUsed.choose(2, &mut rng, stats) == 1)
+            .count();
+        assert!(lru > 80);
+        let lfu = (0..100)
+            .filter(|_| EvictionPolicy::LeastFrequentlyUsed.choose(2, &mut rng, stats) == 0)
+            .count();
+        assert!(lfu > 80);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::prelude::*;
+use crate::wal::WriteAheadLog;
 use noria::internal::LocalOrNot;
 use std::time;
 
@@ -6,6 +7,8 @@ pub struct GroupCommitQueueSet {
     /// Packets that are queued to be persisted.
     #[allow(clippy::vec_box)]
     pending_packets: Map<(time::Instant, Vec<Box<Packet>>)>,
+    /// Write-ahead logs that merged packets are appended to, by base table.
+    logs: Map<WriteAheadLog>,
     params: PersistenceParameters,
 }
 
@@ -14,10 +17,16 @@ impl GroupCommitQueueSet {
     pub fn new(params: &PersistenceParameters) -> Self {
         Self {
             pending_packets: Map::default(),
+            logs: Map::default(),
             params: params.clone(),
         }
     }
 
+    /// Append all packets that are merged for the given base table to `log` from now on.
+    pub(crate) fn add_log(&mut self, node: LocalNodeIndex, log: WriteAheadLog) {
+        assert!(self.logs.insert(node, log).is_none());
+    }
+
     /// Returns whether the given packet should be persisted.
     pub fn should_append(&self, p: &Packet, nodes: &DomainNodes) -> bool {
         if let Packet::Input { .. } = *p {
@@ -47,7 +56,65 @@ impl GroupCommitQueueSet {
 
     /// Merge any pending packets.
     fn flush_internal(&mut self, node: LocalNodeIndex) -> Option<Box<Packet>> {
-        Self::merge_packets(&mut self.pending_packets[node].1)
+        Self::merge_packets(&mut self.pending_packets[node].1, self.logs.get_mut(node))
+    }
+
+    /// Merge the pending packets for the given base table, whether or not they have timed out.
+    pub(crate) fn flush(&mut self, node: LocalNodeIndex) -> Option<Box<Packet>> {
+        match self.pending_packets.get_mut(node) {
+            Some((_, ps)) => Self::merge_packets(ps, self.logs.get_mut(node)),
+            None => None,
+        }
+    }
+
+    /// Merge the pending packets of every queue, whether or not they have timed out.
+    pub fn flush_all(&mut self) -> Vec<Box<Packet>> {
+        let nodes: Vec<_> = self
+            .pending_packets
+            .iter()
+            .filter(|(_, &(_, ref ps))| !ps.is_empty())
+            .map(|(n, _)| n)
+            .collect();
+        nodes
+            .into_iter()
+            .filter_map(|node| self.flush_internal(node))
+            .collect()
+    }
+
+    /// The write-ahead log of the given base table, if it has one.
+    pub(crate) fn log_mut(&mut self, node: LocalNodeIndex) -> Option<&mut WriteAheadLog> {
+        self.logs.get_mut(node)
+    }
+
+    /// Stop persisting packets to the given base table, such as when it is dropped, and return
+    /// its write-ahead log. Packets that are still queued for it are discarded.
+    pub(crate) fn remove_log(&mut self, node: LocalNodeIndex) -> Option<WriteAheadLog> {
+        self.pending_packets.remove(node);
+        self.logs.remove(node)
+    }
+
+    /// The base tables whose write-ahead logs should be compacted.
+    pub(crate) fn logs_to_compact(&self) -> Vec<LocalNodeIndex> {
+        self.logs
+            .iter()
+            .filter(|(_, log)| log.needs_compaction())
+            .map(|(n, _)| n)
+            .collect()
+    }
+
+    /// Sync the write-ahead logs whose fsync interval has passed since they were appended to.
+    pub(crate) fn sync_logs_if_due(&mut self) {
+        for (_, log) in self.logs.iter_mut() {
+            log.sync_if_due();
+        }
+    }
+
+    /// Returns how long until a write-ahead log must be synced.
+    pub(crate) fn duration_until_sync(&self) -> Option<time::Duration> {
+        self.logs
+            .values()
+            .filter_map(|log| log.duration_until_sync())
+            .min()
     }
 
     /// Add a new packet to be persisted, and if this triggered a flush return an iterator over the
@@ -85,7 +152,10 @@ impl GroupCommitQueueSet {
             .min()
     }
 
-    fn merge_committed_packets<I>(packets: I) -> Option<Box<Packet>>
+    fn merge_committed_packets<I>(
+        packets: I,
+        log: Option<&mut WriteAheadLog>,
+    ) -> Option<Box<Packet>>
     where
         I: Iterator<Item = Box<Packet>>,
     {
@@ -132,6 +202,10 @@ impl GroupCommitQueueSet {
             acc
         });
 
+        if let Some(log) = log {
+            log.append(&merged_data);
+        }
+
         Some(Box::new(Packet::Input {
             inner: LocalOrNot::new(Input {
                 dst: merged_dst,
@@ -143,13 +217,17 @@ impl GroupCommitQueueSet {
         }))
     }
 
-    /// Merge the contents of packets into a single packet, emptying packets in the process.
+    /// Merge the contents of packets into a single packet, emptying packets in the process. The
+    /// merged operations are appended to `log` before the packet is returned.
     #[allow(clippy::vec_box)]
-    fn merge_packets(packets: &mut Vec<Box<Packet>>) -> Option<Box<Packet>> {
+    fn merge_packets(
+        packets: &mut Vec<Box<Packet>>,
+        log: Option<&mut WriteAheadLog>,
+    ) -> Option<Box<Packet>> {
         if packets.is_empty() {
             return None;
         }
 
-        Self::merge_committed_packets(packets.drain(..))
+        Self::merge_committed_packets(packets.drain(..), log)
     }
 }
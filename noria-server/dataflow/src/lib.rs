@@ -21,10 +21,13 @@ pub mod prelude;
 pub(crate) mod state;
 
 mod domain;
+mod eviction;
 mod group_commit;
 mod processing;
+mod wal;
 
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time;
@@ -32,9 +35,22 @@ use std::time;
 pub use crate::backlog::SingleReadHandle;
 pub type Readers =
     Arc<Mutex<HashMap<(petgraph::graph::NodeIndex, usize), backlog::SingleReadHandle>>>;
+/// Where readers that have moved away from a worker are now read from.
+pub type MovedReaders = Arc<Mutex<HashMap<(petgraph::graph::NodeIndex, usize), ReaderMoved>>>;
+
+/// Where a reader shard that has moved away from a worker went.
+#[derive(Clone, Debug)]
+pub enum ReaderMoved {
+    /// The shard is now read from the worker with the given read address.
+    To(SocketAddr),
+    /// The reader was resharded, and its shards are now read from the workers with these read
+    /// addresses, in shard order.
+    Resharded(Vec<SocketAddr>),
+}
 pub type DomainConfig = domain::Config;
 
 pub use crate::domain::{Domain, DomainBuilder, Index, PollEvent, ProcessResult};
+pub use crate::eviction::EvictionPolicy;
 pub use crate::payload::Packet;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -59,6 +75,15 @@ impl Sharding {
             Sharding::Random(shards) | Sharding::ByColumn(_, shards) => Some(shards),
         }
     }
+
+    /// The same sharding, but across `shards` shards.
+    pub fn with_shards(self, shards: usize) -> Self {
+        match self {
+            Sharding::None | Sharding::ForcedNone => self,
+            Sharding::Random(_) => Sharding::Random(shards),
+            Sharding::ByColumn(c, _) => Sharding::ByColumn(c, shards),
+        }
+    }
 }
 
 /// Indicates to what degree updates should be persisted.
@@ -70,6 +95,133 @@ pub enum DurabilityMode {
     DeleteOnExit,
     /// Persist updates to disk, and don't delete them later.
     Permanent,
+    /// Keep base tables in memory, and append all writes to them to a write-ahead log that is
+    /// replayed on startup. Unlike `Permanent`, writes don't pay for keeping an index on disk.
+    Logged,
+}
+
+/// When the write-ahead log of a base table is synced to disk.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Sync after every batch of writes, before the writes are acknowledged.
+    EveryBatch,
+    /// Sync once per interval, if anything was written since the last sync. Writes acknowledged
+    /// since the last sync may be lost in a crash.
+    Interval(time::Duration),
+    /// Leave it to the operating system to write the log out.
+    Never,
+}
+
+/// Where partially materialized state is kept.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PartialStorage {
+    /// Keep all state in memory, and evict keys when memory runs low.
+    Memory,
+    /// Keep all state in RocksDB.
+    Disk,
+    /// Keep recently used keys in memory, and move other keys to RocksDB when memory runs low.
+    Tiered,
+}
+
+/// A compression algorithm for the files that RocksDB writes.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression \"{}\"", s.trim())),
+        }
+    }
+}
+
+/// Tuning options for the RocksDB instances that keep state on disk.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RocksDbOptions {
+    /// Size in bytes of the cache for uncompressed blocks that each index of a table gets.
+    ///
+    /// Files are written in RocksDB's plain table format unless this or `compression_per_level`
+    /// is set, in which case they are written in its block-based format. A table whose files were
+    /// written in one format can't be opened with the other.
+    pub block_cache_size: Option<usize>,
+    /// The compression used at each level, starting with level 0. Levels past the end of the list
+    /// use its last entry. Files are only compressed if this isn't empty.
+    pub compression_per_level: Vec<Compression>,
+    /// Bits per key of the bloom filters that let lookups skip files that don't have their key.
+    pub bloom_bits_per_key: i32,
+    /// Size in bytes that a memtable can grow to before it is flushed to disk.
+    pub write_buffer_size: Option<usize>,
+    /// Limit on the rate, in bytes per second, at which flushes and compactions write to disk.
+    pub compaction_rate_limit: Option<usize>,
+}
+
+impl Default for RocksDbOptions {
+    fn default() -> Self {
+        Self {
+            block_cache_size: None,
+            compression_per_level: Vec::new(),
+            bloom_bits_per_key: 10,
+            write_buffer_size: None,
+            compaction_rate_limit: None,
+        }
+    }
+}
+
+impl RocksDbOptions {
+    /// Set the option with the given name from its textual value, as given in a recipe or on the
+    /// command line.
+    ///
+    /// Sizes may have a unit such as `KB`, `MB` or `GB`, and compression is given as a list of
+    /// algorithms (`none`, `lz4` or `zstd`) separated by colons, one for each level.
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let size = || {
+            parse_size(value).ok_or_else(|| format!("invalid size \"{}\" for {}", value, option))
+        };
+        match option.trim() {
+            "block_cache_size" => self.block_cache_size = Some(size()?),
+            "compression" => {
+                self.compression_per_level = value
+                    .split(':')
+                    .map(str::parse)
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            "bloom_bits_per_key" => {
+                self.bloom_bits_per_key = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid number of bloom filter bits \"{}\"", value))?
+            }
+            "write_buffer_size" => self.write_buffer_size = Some(size()?),
+            "compaction_rate_limit" => self.compaction_rate_limit = Some(size()?),
+            option => return Err(format!("unknown RocksDB option \"{}\"", option)),
+        }
+        Ok(())
+    }
+}
+
+/// Parses a size such as `512MB` or `2GB` into a number of bytes. Units are powers of 1024.
+fn parse_size(size: &str) -> Option<usize> {
+    let size = size.trim();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| size.len());
+    let (number, unit) = size.split_at(digits);
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" => 10,
+        "M" | "MB" => 20,
+        "G" | "GB" => 30,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
 }
 
 /// Parameters to control the operation of GroupCommitQueue.
@@ -85,6 +237,19 @@ pub struct PersistenceParameters {
     pub log_dir: Option<PathBuf>,
     /// Number of background threads PersistentState can use (shared acrosss all worker threads).
     pub persistence_threads: i32,
+    /// Where partially materialized internal nodes keep their state. Reader state is always kept
+    /// in memory.
+    pub partial_storage: PartialStorage,
+    /// How often to take a consistent snapshot of all fully materialized state, so that recovery
+    /// only has to replay the changes made to base tables since then. Snapshots are only taken
+    /// with `DurabilityMode::Permanent`, and base tables only keep a log of their changes when
+    /// this is set.
+    pub snapshot_interval: Option<time::Duration>,
+    /// When base tables sync their write-ahead log to disk with `DurabilityMode::Logged`.
+    pub fsync: FsyncPolicy,
+    /// How RocksDB is tuned for base tables, unless the recipe overrides it for a table, and for
+    /// partial state kept on disk.
+    pub rocksdb: RocksDbOptions,
 }
 
 impl Default for PersistenceParameters {
@@ -95,6 +260,10 @@ impl Default for PersistenceParameters {
             log_prefix: String::from("soup"),
             log_dir: None,
             persistence_threads: 1,
+            partial_storage: PartialStorage::Memory,
+            snapshot_interval: None,
+            fsync: FsyncPolicy::EveryBatch,
+            rocksdb: RocksDbOptions::default(),
         }
     }
 }
@@ -102,13 +271,15 @@ impl Default for PersistenceParameters {
 impl PersistenceParameters {
     /// Parameters to control the persistence mode, and parameters related to persistence.
     ///
-    /// Three modes are available:
+    /// Four modes are available:
     ///
     ///  1. `DurabilityMode::Permanent`: all writes to base nodes should be written to disk.
     ///  2. `DurabilityMode::DeleteOnExit`: all writes to base nodes are written to disk, but the
     ///     persistent files are deleted once the `ControllerHandle` is dropped. Useful for tests.
     ///  3. `DurabilityMode::MemoryOnly`: no writes to disk, store all writes in memory.
     ///     Useful for baseline numbers.
+    ///  4. `DurabilityMode::Logged`: store base tables in memory, but also append all writes to
+    ///     them to a log on disk.
     pub fn new(
         mode: DurabilityMode,
         flush_timeout: time::Duration,
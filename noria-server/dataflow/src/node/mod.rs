@@ -187,6 +187,15 @@ impl Node {
     pub fn shard_by(&mut self, s: Sharding) {
         self.sharded_by = s;
     }
+
+    /// Split this node across `shards` shards if it is sharded, and if it merges the shards of its
+    /// ancestor, merge `shards` shards.
+    pub fn reshard(&mut self, shards: usize) {
+        self.sharded_by = self.sharded_by.with_shards(shards);
+        if let NodeType::Internal(NodeOperator::Union(ref mut u)) = self.inner {
+            u.reshard(shards);
+        }
+    }
 }
 
 // events
@@ -203,11 +212,31 @@ impl Node {
         n.index = self.index;
         n.domain = self.domain;
         n.purge = self.purge;
+        n.sharded_by = self.sharded_by;
         self.taken = true;
 
         DanglingDomainNode(n)
     }
 
+    /// Take the node once more, for a domain shard that is started anew after the worker it ran on
+    /// failed. An egress node comes without the children and replay paths its domain was told
+    /// about since it was first taken, so those have to be sent to the new shard again.
+    pub fn retake(&self) -> DanglingDomainNode {
+        assert!(self.taken);
+        let inner = match self.inner {
+            NodeType::Egress(_) => NodeType::Egress(Some(special::Egress::default())),
+            NodeType::Dropped => NodeType::Dropped,
+            ref inner => inner.clone().take(),
+        };
+        let mut n = self.mirror(inner);
+        n.index = self.index;
+        n.domain = self.domain;
+        n.purge = self.purge;
+        n.sharded_by = self.sharded_by;
+
+        DanglingDomainNode(n)
+    }
+
     pub fn remove(&mut self) {
         self.inner = NodeType::Dropped;
     }
@@ -45,6 +45,7 @@ impl Node {
                         // So: only materialize if the message we're processing is not a replay!
                         if keyed_by.is_none() {
                             materialize(&mut rs, None, state.get_mut(addr));
+                            b.index_for_expiry(&rs);
                         }
 
                         // Send write-ACKs to all the clients with updates that made
@@ -2,9 +2,50 @@ use crate::prelude::*;
 use noria::{Modification, Operation, TableOperation};
 use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 use vec_map::VecMap;
 
+/// How long the rows of a base table are kept for.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Retention {
+    /// The column that holds the time that each row's time-to-live counts from. It may hold either
+    /// timestamps or the number of seconds since the Unix epoch.
+    pub column: usize,
+    /// How long after that time a row expires.
+    pub ttl: Duration,
+}
+
+/// The rows of a base table with a retention policy, ordered by the time that their time-to-live
+/// counts from, so that finding the expired ones doesn't take a scan of the whole table. Rows are
+/// identified by their primary key, or by their contents if the table has none.
+#[derive(Debug, Default)]
+struct ExpiryIndex {
+    timestamps: BTreeMap<chrono::NaiveDateTime, Vec<Vec<DataType>>>,
+    seconds: BTreeMap<i128, Vec<Vec<DataType>>>,
+}
+
+impl ExpiryIndex {
+    fn update<K: Ord>(
+        ids: &mut BTreeMap<K, Vec<Vec<DataType>>>,
+        at: K,
+        id: Vec<DataType>,
+        r: &Record,
+    ) {
+        if r.is_positive() {
+            ids.entry(at).or_default().push(id);
+        } else if let Entry::Occupied(mut entry) = ids.entry(at) {
+            if let Some(i) = entry.get().iter().position(|other| *other == id) {
+                entry.get_mut().swap_remove(i);
+            }
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+}
+
 /// Base is used to represent the root nodes of the Noria data flow graph.
 ///
 /// These nodes perform no computation, and their job is merely to persist all received updates and
@@ -17,6 +58,13 @@ pub struct Base {
     defaults: Vec<DataType>,
     dropped: Vec<usize>,
     unmodified: bool,
+
+    rocksdb: Option<RocksDbOptions>,
+    retention: Option<Retention>,
+    /// Built from the table's state the first time it is checked for expired rows, and kept up to
+    /// date with the writes to the table from then on.
+    #[serde(skip)]
+    expiry_index: Option<ExpiryIndex>,
 }
 
 impl Base {
@@ -37,6 +85,27 @@ impl Base {
         self.primary_key.as_ref().map(|cols| &cols[..])
     }
 
+    /// Tune the RocksDB instance that keeps this base table's state differently from those of
+    /// other tables.
+    pub fn set_rocksdb_options(&mut self, options: RocksDbOptions) {
+        self.rocksdb = Some(options);
+    }
+
+    /// The RocksDB options for this base table, if they differ from the deployment's.
+    pub fn rocksdb_options(&self) -> Option<&RocksDbOptions> {
+        self.rocksdb.as_ref()
+    }
+
+    /// Expire rows of this base table once they are older than the given retention allows.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = Some(retention);
+    }
+
+    /// How long rows of this base table are kept for, if they ever expire.
+    pub fn retention(&self) -> Option<&Retention> {
+        self.retention.as_ref()
+    }
+
     /// Add a new column to this base node.
     pub fn add_column(&mut self, default: DataType) -> usize {
         assert!(
@@ -95,6 +164,10 @@ impl Clone for Base {
             defaults: self.defaults.clone(),
             dropped: self.dropped.clone(),
             unmodified: self.unmodified,
+
+            rocksdb: self.rocksdb.clone(),
+            retention: self.retention.clone(),
+            expiry_index: None,
         }
     }
 }
@@ -107,6 +180,10 @@ impl Default for Base {
             defaults: Vec::new(),
             dropped: Vec::new(),
             unmodified: true,
+
+            rocksdb: None,
+            retention: None,
+            expiry_index: None,
         }
     }
 }
@@ -141,13 +218,18 @@ impl Base {
         if self.primary_key.is_none() || ops.is_empty() {
             return ops
                 .into_iter()
-                .map(|r| {
-                    if let TableOperation::Insert(mut r) = r {
+                .map(|r| match r {
+                    TableOperation::Insert(mut r) => {
                         self.fix(&mut r);
                         Record::Positive(r)
-                    } else {
-                        unreachable!("unkeyed base got non-insert operation {:?}", r);
                     }
+                    // without a primary key, a row can only be deleted by its full contents, which
+                    // is how expired rows are removed
+                    TableOperation::Delete { key: mut r } => {
+                        self.fix(&mut r);
+                        Record::Negative(r)
+                    }
+                    r => unreachable!("unkeyed base got non-insert operation {:?}", r),
                 })
                 .collect();
         }
@@ -270,11 +352,98 @@ impl Base {
         results.into()
     }
 
+    /// Deletions of the rows of this base table that have expired by `now`.
+    ///
+    /// Rows whose retention column holds neither a timestamp nor an integer never expire.
+    pub(crate) fn expire(
+        &mut self,
+        us: LocalNodeIndex,
+        state: &StateMap,
+        now: chrono::DateTime<chrono::Local>,
+    ) -> Vec<TableOperation> {
+        let retention = match self.retention {
+            Some(ref retention) => retention,
+            None => return Vec::new(),
+        };
+
+        // a time-to-live too long to subtract from now means that no row has expired yet
+        let cutoff = match chrono::Duration::from_std(retention.ttl)
+            .ok()
+            .and_then(|ttl| now.checked_sub_signed(ttl))
+        {
+            Some(cutoff) => cutoff,
+            None => return Vec::new(),
+        };
+
+        if self.expiry_index.is_none() {
+            let rows = match state.get(us) {
+                Some(db) => db.cloned_records(),
+                None => return Vec::new(),
+            };
+            self.expiry_index = Some(ExpiryIndex::default());
+            self.index_for_expiry(&rows.into_iter().map(Record::Positive).collect());
+        }
+
+        let index = self.expiry_index.as_ref().unwrap();
+        index
+            .timestamps
+            .range(..cutoff.naive_local())
+            .map(|(_, ids)| ids)
+            .chain(
+                index
+                    .seconds
+                    .range(..i128::from(cutoff.timestamp()))
+                    .map(|(_, ids)| ids),
+            )
+            .flatten()
+            .map(|id| TableOperation::Delete { key: id.clone() })
+            .collect()
+    }
+
+    /// Keep the index of rows by their expiry time up to date with the given changes to this
+    /// base table, if it has been built.
+    pub(crate) fn index_for_expiry(&mut self, rs: &Records) {
+        let retention = match self.retention {
+            Some(ref retention) => retention,
+            None => return,
+        };
+        let index = match self.expiry_index {
+            Some(ref mut index) => index,
+            None => return,
+        };
+
+        for r in rs.iter() {
+            let row = r.rec();
+            let id = match self.primary_key {
+                Some(ref key) => key.iter().map(|&col| row[col].clone()).collect(),
+                None => row.to_vec(),
+            };
+            let v = &row[retention.column];
+            match *v {
+                DataType::Timestamp(ts) => ExpiryIndex::update(&mut index.timestamps, ts, id, r),
+                DataType::Int(_)
+                | DataType::BigInt(_)
+                | DataType::UnsignedInt(_)
+                | DataType::UnsignedBigInt(_) => {
+                    ExpiryIndex::update(&mut index.seconds, v.into(), id, r)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Forget the index of rows by their expiry time, so that it is built again from the table's
+    /// state. This has to happen whenever the state changes without the table seeing the change.
+    pub(crate) fn reset_expiry_index(&mut self) {
+        self.expiry_index = None;
+    }
+
     pub(in crate::node) fn suggest_indexes(&self, n: NodeIndex) -> HashMap<NodeIndex, Vec<usize>> {
-        if self.primary_key.is_some() {
-            Some((n, self.primary_key.as_ref().unwrap().clone()))
-                .into_iter()
-                .collect()
+        if let Some(ref key) = self.primary_key {
+            Some((n, key.clone())).into_iter().collect()
+        } else if let Some(ref retention) = self.retention {
+            // expiry needs the rows to be around
+            Some((n, vec![retention.column])).into_iter().collect()
         } else {
             HashMap::new()
         }
@@ -307,6 +476,64 @@ mod tests {
         assert_eq!(b.unmodified, true);
     }
 
+    #[test]
+    fn it_expires_rows() {
+        let mut b = Base::new(vec![]);
+        b.set_retention(Retention {
+            column: 1,
+            ttl: Duration::from_secs(60),
+        });
+
+        let local = unsafe { LocalNodeIndex::make(0 as u32) };
+        let mut state = MemoryState::default();
+        for (_, col) in b.suggest_indexes(NodeIndex::new(0)) {
+            state.add_key(&col[..], None);
+        }
+
+        let now = chrono::Local::now();
+        let old = now - chrono::Duration::seconds(120);
+        let recent = now - chrono::Duration::seconds(30);
+        let rows: Vec<Vec<DataType>> = vec![
+            vec![1.into(), DataType::Timestamp(old.naive_local())],
+            vec![2.into(), DataType::Timestamp(recent.naive_local())],
+            vec![3.into(), old.timestamp().into()],
+            vec![4.into(), recent.timestamp().into()],
+            vec![5.into(), DataType::None],
+        ];
+        state.process_records(&mut rows.clone().into(), None);
+        let mut states = StateMap::new();
+        states.insert(local, Box::new(state) as Box<dyn State>);
+
+        // without a primary key, expired rows are deleted by their contents
+        let deleted = |row: &Vec<DataType>| TableOperation::Delete { key: row.clone() };
+        let expired = b.expire(local, &states, now);
+        assert_eq!(expired.len(), 2);
+        assert!(expired.contains(&deleted(&rows[0])));
+        assert!(expired.contains(&deleted(&rows[2])));
+
+        // once the deletions have been written, the rows no longer expire
+        b.index_for_expiry(&vec![Record::Negative(rows[0].clone())].into());
+        assert_eq!(b.expire(local, &states, now), vec![deleted(&rows[2])]);
+
+        // with one, they are deleted by their key
+        let mut b = Base::new(vec![]).with_key(vec![0]);
+        b.set_retention(Retention {
+            column: 1,
+            ttl: Duration::from_secs(60),
+        });
+        let expired = b.expire(local, &states, now);
+        assert_eq!(expired.len(), 2);
+        assert!(expired.contains(&TableOperation::Delete {
+            key: vec![1.into()]
+        }));
+        assert!(expired.contains(&TableOperation::Delete {
+            key: vec![3.into()]
+        }));
+
+        // without a retention policy, nothing expires
+        assert!(Base::new(vec![]).expire(local, &states, now).is_empty());
+    }
+
     fn test_lots_of_changes_in_same_batch(mut state: Box<dyn State>) {
         use crate::node;
         use crate::prelude::*;
@@ -43,10 +43,26 @@ impl Egress {
         });
     }
 
+    /// Whether updates for `dst_g` already go to the given domain shard.
+    pub fn has_tx(&self, dst_g: NodeIndex, addr: ReplicaAddr) -> bool {
+        self.txs
+            .iter()
+            .any(|tx| tx.node == dst_g && tx.dest == addr)
+    }
+
     pub fn add_tag(&mut self, tag: Tag, dst: NodeIndex) {
         self.tags.insert(tag, dst);
     }
 
+    /// Send a marker for the snapshot with the given id to every external child.
+    pub fn send_marker(&mut self, id: u64, shard: usize, output: &mut dyn Executor) {
+        for tx in &self.txs {
+            // like for regular updates, src identifies the shard the marker came from
+            let link = Link::new(unsafe { LocalNodeIndex::make(shard as u32) }, tx.local);
+            output.send(tx.dest, Box::new(Packet::SnapshotMarker { link, id }));
+        }
+    }
+
     pub fn process(
         &mut self,
         m: &mut Option<Box<Packet>>,
@@ -35,6 +35,8 @@ pub struct Reader {
     streamers: Vec<channel::StreamSender<Vec<StreamUpdate>>>,
 
     for_node: NodeIndex,
+    /// The reader that this one is a replica of, if any.
+    replica_of: Option<NodeIndex>,
     state: Option<Vec<usize>>,
 }
 
@@ -46,6 +48,7 @@ impl Clone for Reader {
             streamers: self.streamers.clone(),
             state: self.state.clone(),
             for_node: self.for_node,
+            replica_of: self.replica_of,
         }
     }
 }
@@ -57,6 +60,16 @@ impl Reader {
             streamers: Vec::new(),
             state: None,
             for_node,
+            replica_of: None,
+        }
+    }
+
+    /// A reader for `for_node` that serves the same reads as the reader `of`, so that they can be
+    /// spread across workers.
+    pub fn replica(for_node: NodeIndex, of: NodeIndex) -> Self {
+        Reader {
+            replica_of: Some(of),
+            ..Reader::new(for_node)
         }
     }
 
@@ -66,6 +79,10 @@ impl Reader {
         self.for_node
     }
 
+    pub fn replica_of(&self) -> Option<NodeIndex> {
+        self.replica_of
+    }
+
     #[allow(dead_code)]
     fn writer(&self) -> Option<&backlog::WriteHandle> {
         self.writer.as_ref()
@@ -82,6 +99,7 @@ impl Reader {
             streamers: mem::replace(&mut self.streamers, Vec::new()),
             state: self.state.clone(),
             for_node: self.for_node,
+            replica_of: self.replica_of,
         }
     }
 
@@ -125,14 +143,14 @@ impl Reader {
         self.writer.as_ref().map(SizeOf::deep_size_of)
     }
 
-    /// Evict a randomly selected key, returning the number of bytes evicted.
+    /// Evict a key chosen by the reader's eviction policy, returning the number of bytes evicted.
     /// Note that due to how `evmap` applies the evictions asynchronously, we can only evict a
     /// single key at a time here.
-    pub(crate) fn evict_random_key(&mut self) -> u64 {
+    pub(crate) fn evict_some_key(&mut self) -> u64 {
         let mut bytes_freed = 0;
         if let Some(ref mut handle) = self.writer {
             let mut rng = rand::thread_rng();
-            bytes_freed = handle.evict_random_key(&mut rng);
+            bytes_freed = handle.evict_some_key(&mut rng);
             handle.swap();
         }
         bytes_freed
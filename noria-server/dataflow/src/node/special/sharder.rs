@@ -48,6 +48,18 @@ impl Sharder {
         }
     }
 
+    /// Whether this sharder already sends to `dst`.
+    pub fn has_sharded_child(&self, dst: LocalNodeIndex) -> bool {
+        self.txs.iter().any(|&(d, _)| d == dst)
+    }
+
+    /// Send to the shards at `txs` instead of the ones that `dst` was reached at before, since
+    /// they moved or the child was resharded.
+    pub fn set_sharded_child(&mut self, dst: LocalNodeIndex, txs: Vec<ReplicaAddr>) {
+        self.txs.clear();
+        self.add_sharded_child(dst, txs);
+    }
+
     pub fn sharded_by(&self) -> usize {
         self.shard_by
     }
@@ -127,6 +139,19 @@ impl Sharder {
         }
     }
 
+    /// Send a marker for the snapshot with the given id to every shard of the child domain.
+    pub fn send_marker(&mut self, id: u64, src: LocalNodeIndex, output: &mut dyn Executor) {
+        for &(dst, addr) in &self.txs {
+            output.send(
+                addr,
+                Box::new(Packet::SnapshotMarker {
+                    link: Link { src, dst },
+                    id,
+                }),
+            );
+        }
+    }
+
     pub fn process_eviction(
         &mut self,
         key_columns: &[usize],
@@ -117,6 +117,14 @@ impl Union {
             false
         }
     }
+
+    /// Merge `shards` shards from now on, after the sharded subtree was resharded.
+    pub fn reshard(&mut self, shards: usize) {
+        if let Emit::AllFrom(_, ref mut sharding) = self.emit {
+            *sharding = sharding.with_shards(shards);
+            self.required = shards;
+        }
+    }
 }
 
 impl Ingredient for Union {
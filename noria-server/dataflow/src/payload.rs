@@ -11,6 +11,7 @@ use noria::internal::LocalOrNot;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -183,6 +184,13 @@ pub enum Packet {
         node: LocalNodeIndex,
     },
 
+    /// Set the number of bytes of partial state that the given node may hold before keys are
+    /// evicted from it, or lift that limit if `budget` is `None`.
+    UpdateMemoryBudget {
+        node: LocalNodeIndex,
+        budget: Option<usize>,
+    },
+
     /// Inform domain about a new replay path.
     SetupReplayPath {
         tag: Tag,
@@ -226,6 +234,165 @@ pub enum Packet {
     /// A packet used solely to drive the event loop forward.
     Spin,
 
+    /// Stop processing writes to base tables, and take a snapshot of all fully materialized state
+    /// once `markers` markers for the snapshot have arrived from upstream domains.
+    ///
+    /// If `persist` is not set, nothing is written to disk, and the snapshot only serves to bring
+    /// the data-flow to a standstill until it is finished. The reply carries `pause`, which is
+    /// different every time the controller pauses the data-flow, so that it can tell late replies
+    /// to a pause that it gave up on apart.
+    TakeSnapshot {
+        id: u64,
+        markers: usize,
+        persist: bool,
+        pause: u64,
+    },
+
+    /// Sent along every edge to a downstream domain once the sender has taken its part of the
+    /// snapshot with the given id.
+    SnapshotMarker {
+        link: Link,
+        id: u64,
+    },
+
+    /// Resume processing writes to base tables after taking a snapshot.
+    ///
+    /// If the snapshot is `complete`, recovery will start from it, so changes that base tables
+    /// logged before it, and older snapshots, can be discarded.
+    FinishSnapshot {
+        id: u64,
+        complete: bool,
+    },
+
+    /// Undo all changes made to a base table after the one with the given sequence number, so
+    /// that it matches the snapshot that recovery starts from.
+    RestoreBase {
+        node: LocalNodeIndex,
+        seq: u64,
+    },
+
+    /// Fill a node's (empty) state from the snapshot with the given id, and reply with whether
+    /// that was possible. If `probe` is set, only reply with whether it would be.
+    LoadSnapshot {
+        node: LocalNodeIndex,
+        id: u64,
+        probe: bool,
+    },
+
+    /// Redo the changes undone by `RestoreBase`, and send them through the data-flow.
+    ReapplyBaseLog {
+        node: LocalNodeIndex,
+    },
+
+    /// Write a checkpoint of every base table's state to the given directory. Writes to base
+    /// tables must already be held back by `TakeSnapshot`.
+    Backup {
+        dir: PathBuf,
+    },
+
+    /// Reply with the next `count` rows of the given base table that export `export` hasn't read
+    /// yet. The rows are those the table held when the export first read from it, and an empty
+    /// reply means that it has read them all.
+    ExportBase {
+        node: LocalNodeIndex,
+        export: u64,
+        count: usize,
+    },
+
+    /// Drop the rows of the given base table that export `export` hasn't read yet, since it
+    /// stopped part-way.
+    CancelExport {
+        node: LocalNodeIndex,
+        export: u64,
+    },
+
+    /// Start compacting the on-disk state of the given base table, or of all base tables in the
+    /// domain, in the background.
+    Compact {
+        node: Option<LocalNodeIndex>,
+    },
+
+    /// Stop processing so that this domain shard can be moved to another worker, and reply once
+    /// everything it had accepted has been applied. Packets that arrive from then on are held
+    /// back, but the shard keeps its state until it is told to forward them with `Forward`, or
+    /// to carry on with `CancelHandOff`. If `share_storage` is set, the shard that takes its place
+    /// runs on the same machine, and the shard closes the storage of its base tables that is kept
+    /// on disk for that shard to open instead of sending it their rows. Otherwise, that storage
+    /// is deleted once the shard forwards.
+    HandOff {
+        share_storage: bool,
+    },
+
+    /// Send the contents of the fully materialized state of this handed off domain shard to the
+    /// shards that take its place at `to`, split among them by sharding key, and reply once all
+    /// of it is on its way.
+    StreamState {
+        to: Vec<SocketAddr>,
+    },
+
+    /// Part of the state of a handed off domain shard, for a shard that takes its place. `last`
+    /// is set on the final batch the handed off shard sends, once it has sent all the others.
+    TakeOver {
+        rows: Vec<(LocalNodeIndex, Vec<Vec<DataType>>)>,
+        last: bool,
+    },
+
+    /// Reply once `shards` handed off domain shards have sent all of their state with `TakeOver`.
+    AwaitTakeOver {
+        shards: usize,
+    },
+
+    /// Carry on as this domain shard did before it was handed off, since the shards that were to
+    /// take its place couldn't, and process the packets that were held back.
+    CancelHandOff,
+
+    /// Drop the state of this handed off domain shard, which is now at `from`, and forward all
+    /// packets for it to the shards that took its place at `to`, split among them by sharding key
+    /// if there are several. Readers that come looking for its views are sent to the workers that
+    /// reads for the new shards are served from at `readers_to`, in shard order, and clients that
+    /// write to it are told that the domain's shards are at `writes_to`.
+    Forward {
+        to: Vec<SocketAddr>,
+        readers_to: Vec<SocketAddr>,
+        writes_to: Vec<SocketAddr>,
+        from: SocketAddr,
+    },
+
+    /// Like `Forward`, but for a domain shard that already forwards, to shards that took the place
+    /// of the ones it forwards to. Nothing is replied, since the shard may have stopped.
+    Reforward {
+        to: Vec<SocketAddr>,
+        readers_to: Vec<SocketAddr>,
+        writes_to: Vec<SocketAddr>,
+    },
+
+    /// A write that a handed off domain shard at `reply_to` forwarded, and that it waits for this
+    /// shard to confirm that it applied with `WriteConfirmed`.
+    ForwardedInput {
+        inner: LocalOrNot<Input>,
+        reply_to: SocketAddr,
+        id: u64,
+    },
+
+    /// Whether the write that this handed off domain shard forwarded as `id` was applied.
+    WriteConfirmed {
+        id: u64,
+        applied: bool,
+    },
+
+    /// Connect anew to domain shard `to`, which was started again at `addr` on another worker
+    /// after the one it ran on failed.
+    Reconnect {
+        to: ReplicaAddr,
+        addr: SocketAddr,
+    },
+
+    /// Send control replies to the controller at `addr` from now on, since it has taken over from
+    /// the one that started the domain.
+    UpdateController {
+        addr: SocketAddr,
+    },
+
     /// Request that a domain send usage statistics on the control reply channel.
     /// Argument specifies if we wish to get the full state size or just the partial nodes.
     GetStatistics,
@@ -411,6 +578,18 @@ pub enum ControlReplyPacket {
         HashMap<petgraph::graph::NodeIndex, noria::debug::stats::NodeStats>,
     ),
     Booted(usize, SocketAddr),
+    /// (pause, shard, sequence number of the last change to each base table included in the
+    /// snapshot, or why the shard's part of the snapshot couldn't be written)
+    SnapshotTaken(
+        u64,
+        usize,
+        Result<Vec<(petgraph::graph::NodeIndex, u64)>, String>,
+    ),
+    SnapshotLoaded(bool),
+    BackupTaken(Result<(), String>),
+    BaseRows(Vec<Vec<DataType>>),
+    /// How a step of handing off a domain shard went.
+    HandOff(Result<(), String>),
 }
 
 impl ControlReplyPacket {
@@ -21,7 +21,7 @@ pub(crate) use noria::Input;
 
 // domain local state
 pub(crate) use crate::state::{
-    LookupResult, MemoryState, PersistentState, RecordResult, Row, Rows, State,
+    LookupResult, MemoryState, PersistentState, RecordResult, Row, Rows, State, TieredState,
 };
 pub(crate) type StateMap = Map<Box<dyn State>>;
 pub(crate) type DomainNodes = Map<cell::RefCell<Node>>;
@@ -37,7 +37,10 @@ pub use noria::internal::*;
 pub use petgraph::graph::NodeIndex;
 pub type Graph = petgraph::Graph<Node, Edge>;
 pub use crate::DurabilityMode;
+pub use crate::FsyncPolicy;
+pub use crate::PartialStorage;
 pub use crate::PersistenceParameters;
+pub use crate::RocksDbOptions;
 
 /// Channel coordinator type specialized for domains
 pub type ChannelCoordinator = noria::channel::ChannelCoordinator<(DomainIndex, usize), Box<Packet>>;
@@ -45,4 +48,16 @@ pub trait Executor {
     fn ack(&mut self, tag: SourceChannelIdentifier);
     fn create_universe(&mut self, req: HashMap<String, DataType>);
     fn send(&mut self, dest: ReplicaAddr, m: Box<Packet>);
+
+    /// Connect to the given domain shard anew once everything already sent to it has gone out,
+    /// since it may have moved to another worker.
+    fn reconnect(&mut self, _dest: ReplicaAddr) {}
+
+    /// Tell the clients that write to this domain shard that the table they write to has moved,
+    /// and now lives on the domain shards at `to`.
+    fn redirect_writes(&mut self, _to: Vec<std::net::SocketAddr>) {}
+
+    /// Fail a write that can't be acknowledged, because it may not have been applied, by closing
+    /// the connection of the client that sent it.
+    fn fail(&mut self, _id: SourceChannelIdentifier) {}
 }
@@ -19,91 +19,111 @@ pub(super) enum KeyedState {
 }
 
 impl KeyedState {
-    pub(super) fn lookup<'a>(&'a self, key: &KeyType) -> Option<&'a Rows> {
+    pub(super) fn len(&self) -> usize {
+        match *self {
+            KeyedState::Single(ref m) => m.len(),
+            KeyedState::Double(ref m) => m.len(),
+            KeyedState::Tri(ref m) => m.len(),
+            KeyedState::Quad(ref m) => m.len(),
+            KeyedState::Quin(ref m) => m.len(),
+            KeyedState::Sex(ref m) => m.len(),
+        }
+    }
+
+    /// Returns the rows for the given key, along with the key's position in the map.
+    pub(super) fn lookup<'a>(&'a self, key: &KeyType) -> Option<(usize, &'a Rows)> {
         match (self, key) {
-            (&KeyedState::Single(ref m), &KeyType::Single(k)) => m.get(k),
-            (&KeyedState::Double(ref m), &KeyType::Double(ref k)) => m.get(k),
-            (&KeyedState::Tri(ref m), &KeyType::Tri(ref k)) => m.get(k),
-            (&KeyedState::Quad(ref m), &KeyType::Quad(ref k)) => m.get(k),
-            (&KeyedState::Quin(ref m), &KeyType::Quin(ref k)) => m.get(k),
-            (&KeyedState::Sex(ref m), &KeyType::Sex(ref k)) => m.get(k),
+            (&KeyedState::Single(ref m), &KeyType::Single(k)) => m.get_full(k),
+            (&KeyedState::Double(ref m), &KeyType::Double(ref k)) => m.get_full(k),
+            (&KeyedState::Tri(ref m), &KeyType::Tri(ref k)) => m.get_full(k),
+            (&KeyedState::Quad(ref m), &KeyType::Quad(ref k)) => m.get_full(k),
+            (&KeyedState::Quin(ref m), &KeyType::Quin(ref k)) => m.get_full(k),
+            (&KeyedState::Sex(ref m), &KeyType::Sex(ref k)) => m.get_full(k),
             _ => unreachable!(),
         }
+        .map(|(i, _, rs)| (i, rs))
     }
 
-    /// Remove all rows for a randomly chosen key seeded by `seed`, returning that key along with
-    /// the number of bytes freed. Returns `None` if map is empty.
-    pub(super) fn evict_with_seed(&mut self, seed: usize) -> Option<(u64, Vec<DataType>)> {
+    /// Returns the number of bytes that evicting the key at position `index` would free.
+    pub(super) fn size_at(&self, index: usize) -> u64 {
+        match *self {
+            KeyedState::Single(ref m) => m.get_index(index).map(|(_, rs)| rs),
+            KeyedState::Double(ref m) => m.get_index(index).map(|(_, rs)| rs),
+            KeyedState::Tri(ref m) => m.get_index(index).map(|(_, rs)| rs),
+            KeyedState::Quad(ref m) => m.get_index(index).map(|(_, rs)| rs),
+            KeyedState::Quin(ref m) => m.get_index(index).map(|(_, rs)| rs),
+            KeyedState::Sex(ref m) => m.get_index(index).map(|(_, rs)| rs),
+        }
+        .map(freed_by)
+        .unwrap_or(0)
+    }
+
+    /// Remove all rows for the key at position `index`, returning that key and its rows along with
+    /// the number of bytes freed. Returns `None` if there is no such key.
+    ///
+    /// The last key takes the position of the removed one.
+    pub(super) fn evict_at(&mut self, index: usize) -> Option<(u64, Vec<DataType>, Rows)> {
         let (rs, key) = match *self {
             KeyedState::Single(ref mut m) => {
-                let index = seed % m.len();
                 m.swap_remove_index(index).map(|(k, rs)| (rs, vec![k]))
             }
-            KeyedState::Double(ref mut m) => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1]))
-            }
-            KeyedState::Tri(ref mut m) => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1, k.2]))
-            }
-            KeyedState::Quad(ref mut m) => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3]))
-            }
-            KeyedState::Quin(ref mut m) => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4]))
-            }
-            KeyedState::Sex(ref mut m) => {
-                let index = seed % m.len();
-                m.swap_remove_index(index)
-                    .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4, k.5]))
-            }
+            KeyedState::Double(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1])),
+            KeyedState::Tri(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1, k.2])),
+            KeyedState::Quad(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3])),
+            KeyedState::Quin(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4])),
+            KeyedState::Sex(ref mut m) => m
+                .swap_remove_index(index)
+                .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4, k.5])),
         }?;
-        Some((
-            rs.iter()
-                .filter(|r| Rc::strong_count(&r.0) == 1)
-                .map(SizeOf::deep_size_of)
-                .sum(),
-            key,
-        ))
+        Some((freed_by(&rs), key, rs))
     }
 
-    /// Remove all rows for the given key, returning the number of bytes freed.
-    pub(super) fn evict(&mut self, key: &[DataType]) -> u64 {
+    /// Remove all rows for the given key, returning the position the key had along with the
+    /// number of bytes freed. Returns `None` if the key is not present.
+    ///
+    /// The last key takes the position of the removed one.
+    pub(super) fn evict(&mut self, key: &[DataType]) -> Option<(usize, u64)> {
         match *self {
-            KeyedState::Single(ref mut m) => m.swap_remove(&(key[0])),
-            KeyedState::Double(ref mut m) => {
-                m.swap_remove::<(DataType, _)>(&MakeKey::from_key(key))
-            }
-            KeyedState::Tri(ref mut m) => {
-                m.swap_remove::<(DataType, _, _)>(&MakeKey::from_key(key))
-            }
-            KeyedState::Quad(ref mut m) => {
-                m.swap_remove::<(DataType, _, _, _)>(&MakeKey::from_key(key))
-            }
-            KeyedState::Quin(ref mut m) => {
-                m.swap_remove::<(DataType, _, _, _, _)>(&MakeKey::from_key(key))
-            }
-            KeyedState::Sex(ref mut m) => {
-                m.swap_remove::<(DataType, _, _, _, _, _)>(&MakeKey::from_key(key))
+            KeyedState::Single(ref mut m) => {
+                m.swap_remove_full(&(key[0])).map(|(i, _, rs)| (i, rs))
             }
+            KeyedState::Double(ref mut m) => m
+                .swap_remove_full::<(DataType, _)>(&MakeKey::from_key(key))
+                .map(|(i, _, rs)| (i, rs)),
+            KeyedState::Tri(ref mut m) => m
+                .swap_remove_full::<(DataType, _, _)>(&MakeKey::from_key(key))
+                .map(|(i, _, rs)| (i, rs)),
+            KeyedState::Quad(ref mut m) => m
+                .swap_remove_full::<(DataType, _, _, _)>(&MakeKey::from_key(key))
+                .map(|(i, _, rs)| (i, rs)),
+            KeyedState::Quin(ref mut m) => m
+                .swap_remove_full::<(DataType, _, _, _, _)>(&MakeKey::from_key(key))
+                .map(|(i, _, rs)| (i, rs)),
+            KeyedState::Sex(ref mut m) => m
+                .swap_remove_full::<(DataType, _, _, _, _, _)>(&MakeKey::from_key(key))
+                .map(|(i, _, rs)| (i, rs)),
         }
-        .map(|rows| {
-            rows.iter()
-                .filter(|r| Rc::strong_count(&r.0) == 1)
-                .map(SizeOf::deep_size_of)
-                .sum()
-        })
-        .unwrap_or(0)
+        .map(|(i, rows)| (i, freed_by(&rows)))
     }
 }
 
+/// The number of bytes freed by dropping `rows`, not counting rows still shared with other
+/// indices.
+fn freed_by(rows: &Rows) -> u64 {
+    rows.iter()
+        .filter(|r| Rc::strong_count(&r.0) == 1)
+        .map(SizeOf::deep_size_of)
+        .sum()
+}
+
 impl<'a> Into<KeyedState> for &'a [usize] {
     fn into(self) -> KeyedState {
         match self.len() {
@@ -3,6 +3,7 @@ use std::rc::Rc;
 
 use rand::{self, Rng};
 
+use crate::eviction::EvictionPolicy;
 use crate::prelude::*;
 use crate::state::single_state::SingleState;
 use common::SizeOf;
@@ -12,6 +13,7 @@ pub struct MemoryState {
     state: Vec<SingleState>,
     by_tag: HashMap<Tag, usize>,
     mem_size: u64,
+    eviction: EvictionPolicy,
 }
 
 impl SizeOf for MemoryState {
@@ -47,7 +49,7 @@ impl State for MemoryState {
         }
 
         self.state
-            .push(SingleState::new(columns, partial.is_some()));
+            .push(SingleState::new(columns, partial.is_some(), self.eviction));
 
         if !self.state.is_empty() && partial.is_none() {
             // we need to *construct* the index!
@@ -135,6 +137,13 @@ impl State for MemoryState {
         self.state[index].lookup(key)
     }
 
+    fn key_counts(&self) -> Vec<(Vec<usize>, usize)> {
+        self.state
+            .iter()
+            .map(|s| (s.key().to_vec(), s.key_count()))
+            .collect()
+    }
+
     fn keys(&self) -> Vec<Vec<usize>> {
         self.state.iter().map(|s| s.key().to_vec()).collect()
     }
@@ -149,12 +158,23 @@ impl State for MemoryState {
         self.state[0].values().flat_map(fix).collect()
     }
 
-    fn evict_random_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
-        let mut rng = rand::thread_rng();
-        let index = rng.gen_range(0, self.state.len());
-        let (bytes_freed, keys) = self.state[index].evict_random_keys(count, &mut rng);
-        self.mem_size = self.mem_size.saturating_sub(bytes_freed);
-        (self.state[index].key(), keys, bytes_freed)
+    fn for_each_record(
+        &self,
+        f: &mut dyn FnMut(&[DataType]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        assert!(!self.state[0].partial());
+        for rs in self.state[0].values() {
+            for r in rs.iter() {
+                f(&r[..])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn evict_some_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        let (columns, evicted, bytes_freed) = self.take_some_keys(count);
+        let keys = evicted.into_iter().map(|(key, _)| key).collect();
+        (columns, keys, bytes_freed)
     }
 
     fn evict_keys(&mut self, tag: Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
@@ -177,6 +197,31 @@ impl State for MemoryState {
 }
 
 impl MemoryState {
+    /// Create an empty state whose partial indices evict keys according to `eviction`.
+    pub(crate) fn with_eviction_policy(eviction: EvictionPolicy) -> Self {
+        MemoryState {
+            eviction,
+            ..Default::default()
+        }
+    }
+
+    /// Like `State::evict_some_keys`, but also returns the rows that each evicted key held.
+    pub(crate) fn take_some_keys(
+        &mut self,
+        count: usize,
+    ) -> (&[usize], Vec<(Vec<DataType>, Vec<Vec<DataType>>)>, u64) {
+        let mut rng = rand::thread_rng();
+        let index = rng.gen_range(0, self.state.len());
+        let (bytes_freed, evicted) =
+            self.state[index].evict_some_keys(count, self.eviction, &mut rng);
+        self.mem_size = self.mem_size.saturating_sub(bytes_freed);
+        let evicted = evicted
+            .into_iter()
+            .map(|(key, rows)| (key, rows.iter().map(|r| Vec::clone(&**r)).collect()))
+            .collect();
+        (self.state[index].key(), evicted, bytes_freed)
+    }
+
     /// Returns the index in `self.state` of the index keyed on `cols`, or None if no such index
     /// exists.
     fn state_for(&self, cols: &[usize]) -> Option<usize> {
@@ -227,6 +272,8 @@ impl MemoryState {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     fn insert<S: State>(state: &mut S, row: Vec<DataType>) {
         let record: Record = row.into();
@@ -280,4 +327,63 @@ mod tests {
             _ => unreachable!(),
         };
     }
+
+    #[test]
+    fn memory_state_for_each_record() {
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        for i in 0..10 {
+            insert(&mut state, vec![i.into(), "Cat".into()]);
+        }
+        insert(&mut state, vec![3.into(), "Cat".into()]);
+
+        let mut rows = Vec::new();
+        state
+            .for_each_record(&mut |r| {
+                rows.push(r.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        let mut cloned = state.cloned_records();
+        rows.sort();
+        cloned.sort();
+        assert_eq!(rows, cloned);
+        assert_eq!(rows.len(), 11);
+
+        // the first error stops the iteration
+        let mut seen = 0;
+        let e = state.for_each_record(&mut |_| {
+            seen += 1;
+            Err("full".to_owned())
+        });
+        assert_eq!(e, Err("full".to_owned()));
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn memory_state_evicts_least_recently_read() {
+        let tag = Tag(0);
+        let policy = EvictionPolicy::LeastRecentlyUsed;
+        let mut kept = 0;
+        for seed in 0..100 {
+            let mut state = MemoryState::with_eviction_policy(policy);
+            state.add_key(&[0], Some(vec![tag]));
+            for i in 0..2 {
+                state.mark_filled(vec![i.into()], tag);
+                let record: Record = vec![i.into(), "Cat".into()].into();
+                state.process_records(&mut record.into(), Some(tag));
+            }
+            state.lookup(&[0], &KeyType::Single(&1.into()));
+
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (bytes, evicted) = state.state[0].evict_some_keys(1, policy, &mut rng);
+            assert_eq!(evicted.len(), 1);
+            assert!(bytes > 0);
+            if evicted[0].0 == vec![DataType::from(0)] {
+                kept += 1;
+            }
+        }
+        // both keys are sampled unless all samples land on the same one
+        assert!(kept > 80);
+    }
 }
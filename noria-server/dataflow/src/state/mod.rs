@@ -3,9 +3,11 @@ mod memory_state;
 mod mk_key;
 mod persistent_state;
 mod single_state;
+mod tiered_state;
 
 use std::borrow::Cow;
 use std::ops::Deref;
+use std::path::Path;
 use std::rc::Rc;
 use std::vec;
 
@@ -15,6 +17,7 @@ use hashbag::HashBag;
 
 pub(crate) use self::memory_state::MemoryState;
 pub(crate) use self::persistent_state::PersistentState;
+pub(crate) use self::tiered_state::TieredState;
 
 pub(crate) trait State: SizeOf + Send {
     /// Add an index keyed by the given columns and replayed to by the given partial tags.
@@ -38,20 +41,67 @@ pub(crate) trait State: SizeOf + Send {
 
     fn rows(&self) -> usize;
 
+    /// Returns the number of distinct keys in each index whose key count is cheap to determine,
+    /// along with the columns that index is keyed on.
+    fn key_counts(&self) -> Vec<(Vec<usize>, usize)>;
+
     fn keys(&self) -> Vec<Vec<usize>>;
 
     /// Return a copy of all records. Panics if the state is only partially materialized.
     fn cloned_records(&self) -> Vec<Vec<DataType>>;
 
-    /// Evict `count` randomly selected keys, returning key colunms of the index chosen to evict
-    /// from along with the keys evicted and the number of bytes evicted.
-    fn evict_random_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64);
+    /// Call `f` with every record, without copying them all first, and stop at the first error
+    /// it returns. Panics if the state is only partially materialized.
+    fn for_each_record(
+        &self,
+        f: &mut dyn FnMut(&[DataType]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.cloned_records().iter().try_for_each(|r| f(r))
+    }
+
+    /// Evict `count` keys chosen by the state's eviction policy, returning key colunms of the index
+    /// chosen to evict from along with the keys evicted and the number of bytes evicted.
+    fn evict_some_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64);
 
     /// Evict the listed keys from the materialization targeted by `tag`, returning the key columns
     /// of the index that was evicted from and the number of bytes evicted.
     fn evict_keys(&mut self, tag: Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)>;
 
     fn clear(&mut self);
+
+    /// Returns the sequence number of the last change that this state reflects, if it keeps a log
+    /// of the changes made to it.
+    fn log_seq(&self) -> Option<u64> {
+        None
+    }
+
+    /// Undo every logged change made after the one with the given sequence number.
+    ///
+    /// The undone changes stay in the log, and can be redone with `roll_forward`. No other
+    /// changes may be made to the state until they are.
+    fn rewind(&mut self, _seq: u64) -> Result<(), String> {
+        Err("state doesn't keep a log of its changes".to_owned())
+    }
+
+    /// Redo every change undone by `rewind`, and return them in the order they were first made.
+    fn roll_forward(&mut self) -> Result<Vec<Records>, String> {
+        Err("state doesn't keep a log of its changes".to_owned())
+    }
+
+    /// Forget the logged changes up to and including the one with the given sequence number.
+    fn truncate_log(&mut self, _seq: u64) -> Result<(), String> {
+        Err("state doesn't keep a log of its changes".to_owned())
+    }
+
+    /// Write a consistent copy of this state to a new directory at `path`, which can later be
+    /// opened in its place.
+    fn checkpoint(&self, _path: &Path) -> Result<(), String> {
+        Err("only persistent state can be checkpointed".to_owned())
+    }
+
+    /// Start compacting the files that keep this state on disk, if there are any, without
+    /// waiting for the compaction to finish.
+    fn compact(&mut self) {}
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
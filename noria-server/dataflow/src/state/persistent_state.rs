@@ -1,11 +1,20 @@
 use bincode;
+use indexmap::IndexMap;
 use itertools::Itertools;
-use rocksdb::{self, PlainTableFactoryOptions, SliceTransform, WriteBatch};
+use rand::{self, Rng};
+use rocksdb::{self, BlockBasedOptions, PlainTableFactoryOptions, SliceTransform, WriteBatch};
 use serde;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
 use tempfile::{tempdir, TempDir};
 
+use crate::eviction::{EvictionPolicy, KeyAccess};
 use crate::prelude::*;
 use crate::state::{RecordResult, State};
+use crate::Compression;
 use common::SizeOf;
 
 // Incremented on each PersistentState initialization so that IndexSeq
@@ -24,6 +33,11 @@ const DEFAULT_CF: &str = "default";
 
 // Maximum rows per WriteBatch when building new indices for existing rows.
 const INDEX_BATCH_SIZE: usize = 100_000;
+// The column family that holds the change log, keyed by big-endian sequence numbers.
+const LOG_CF: &str = "log";
+// RocksDB key (in the default column family) for the sequence number of the last change in the
+// log that the stored rows reflect.
+const LOG_APPLIED_KEY: &[u8] = b"log-applied";
 
 // Store index information in RocksDB to avoid rebuilding indices on recovery.
 #[derive(Default, Serialize, Deserialize)]
@@ -38,13 +52,70 @@ struct PersistentIndex {
     columns: Vec<usize>,
 }
 
+impl PersistentIndex {
+    // The side column family that records which keys are filled in a partial index.
+    fn filled_family(&self) -> String {
+        format!("{}-filled", self.column_family)
+    }
+}
+
+// Book-keeping for partially materialized state.
+//
+// Unlike the indices of full state, every index of partial state stores its own rows: those whose
+// keys have been filled in that index. The rows are keyed like those of a non-unique primary
+// index, and the keys that are filled are recorded in a side column family for each index (see
+// `PersistentIndex::filled_family`). Every key that isn't recorded there is a hole.
+//
+// The filled keys are also kept in memory, by their serialized prefix, so that writes don't have
+// to read from disk to find out whether their keys are holes, and so that eviction can sample keys
+// and rank them by when and how often they were read.
+#[derive(Default)]
+struct PartialIndices {
+    // The index in `PersistentState::indices` that each replay path fills.
+    by_tag: HashMap<Tag, usize>,
+    // How keys are chosen for eviction.
+    eviction: EvictionPolicy,
+    // The filled keys of each index, and their reads.
+    filled: Vec<IndexMap<Vec<u8>, Cell<KeyAccess>>>,
+    // The logical time of the last read.
+    clock: Cell<u64>,
+}
+
+impl PartialIndices {
+    // Records a read of a filled key, if the eviction policy ranks keys by their reads.
+    fn read(&self, access: &Cell<KeyAccess>) {
+        if self.eviction.tracks_reads() {
+            let now = self.clock.get() + 1;
+            self.clock.set(now);
+            let mut a = access.get();
+            a.touch(now);
+            access.set(a);
+        }
+    }
+}
+
+// Book-keeping for the change log of a base table.
+//
+// Every write to the base is recorded in the log along with its sequence number, in the same
+// WriteBatch as the write itself. This lets recovery undo the writes made after a snapshot was
+// taken (see `State::rewind`), and then send them through the data-flow again.
+struct ChangeLog {
+    // The sequence number of the last change that the stored rows reflect.
+    applied: u64,
+    // The sequence number of the last change in the log. This is only ever ahead of `applied`
+    // while the state is rewound.
+    last: u64,
+}
+
 /// PersistentState stores data in RocksDB.
 pub struct PersistentState {
     db_opts: rocksdb::Options,
     // We don't really want DB to be an option, but doing so lets us drop it manually in
     // PersistenState's Drop by setting `self.db = None` - after which we can then discard the
-    // persisted files if we want to.
-    db: Option<rocksdb::DB>,
+    // persisted files if we want to. It is shared with the thread of a running compaction.
+    db: Option<Arc<rocksdb::DB>>,
+    // The thread that compacts the files in the background, if `State::compact` started one.
+    compaction: Option<thread::JoinHandle<()>>,
     // The first element is always considered the primary index, where the actual data is stored.
     // Subsequent indices maintain pointers to the data in the first index, and cause an additional
     // read during lookups. When `self.has_unique_index` is true the first index is a primary key,
@@ -53,6 +124,11 @@ pub struct PersistentState {
     seq: IndexSeq,
     epoch: IndexEpoch,
     has_unique_index: bool,
+    // Set if the state is partially materialized, in which case `self.indices` are laid out as
+    // described for `PartialIndices`.
+    partial: Option<PartialIndices>,
+    // Set if every change is recorded in a log, as described for `ChangeLog`.
+    log: Option<ChangeLog>,
     // With DurabilityMode::DeleteOnExit,
     // RocksDB files are stored in a temporary directory.
     _directory: Option<TempDir>,
@@ -60,7 +136,16 @@ pub struct PersistentState {
 
 impl State for PersistentState {
     fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
-        assert!(partial_tag.is_none(), "PersistentState can't be partial");
+        if self.partial.is_some() {
+            // Records that miss every index are dropped, just like for partial MemoryState.
+            records.retain(|r| match *r {
+                Record::Positive(ref r) => self.insert_partial(r, partial_tag),
+                Record::Negative(ref r) => self.remove_partial(r),
+            });
+            return;
+        }
+
+        assert!(partial_tag.is_none(), "PersistentState isn't partial");
         if records.len() == 0 {
             return;
         }
@@ -77,10 +162,20 @@ impl State for PersistentState {
             }
         }
 
-        // Sync the writes to RocksDB's WAL:
-        let mut opts = rocksdb::WriteOptions::default();
-        opts.set_sync(true);
-        self.db.as_ref().unwrap().write_opt(batch, &opts).unwrap();
+        if let Some(ref mut log) = self.log {
+            assert_eq!(log.applied, log.last, "can't write to rewound base table");
+            log.last += 1;
+            log.applied = log.last;
+            let db = self.db.as_ref().unwrap();
+            let cf = db.cf_handle(LOG_CF).unwrap();
+            let change = bincode::serialize(&*records).unwrap();
+            batch.put_cf(cf, &log.last.to_be_bytes(), &change).unwrap();
+            batch
+                .put(LOG_APPLIED_KEY, &log.applied.to_be_bytes())
+                .unwrap();
+        }
+
+        self.write_synced(batch);
     }
 
     fn lookup(&self, columns: &[usize], key: &KeyType) -> LookupResult {
@@ -92,6 +187,13 @@ impl State for PersistentState {
             .expect("lookup on non-indexed column set");
         let cf = db.cf_handle(&self.indices[index_id].column_family).unwrap();
         let prefix = Self::serialize_prefix(&key);
+        if let Some(ref partial) = self.partial {
+            match partial.filled[index_id].get(&prefix) {
+                Some(access) => partial.read(access),
+                None => return LookupResult::Missing,
+            }
+        }
+
         let data = if index_id == 0 && self.has_unique_index {
             // This is a primary key, so we know there's only one row to retrieve
             // (no need to use prefix_iterator).
@@ -114,13 +216,22 @@ impl State for PersistentState {
     }
 
     fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>) {
-        assert!(partial.is_none(), "Bases can't be partial");
+        assert_eq!(
+            partial.is_some(),
+            self.partial.is_some(),
+            "PersistentState can't mix full and partial indices"
+        );
         let existing = self
             .indices
             .iter()
-            .any(|index| &index.columns[..] == columns);
+            .position(|index| &index.columns[..] == columns);
+
+        if let Some(tags) = partial {
+            self.add_partial_key(columns, existing, tags);
+            return;
+        }
 
-        if existing {
+        if existing.is_some() {
             return;
         }
 
@@ -128,7 +239,8 @@ impl State for PersistentState {
         // We'll store all the pointers (or values if this is index 0) for
         // this index in its own column family:
         let index_id = self.indices.len().to_string();
-        let db = self.db.as_mut().unwrap();
+        self.finish_compaction();
+        let db = Arc::get_mut(self.db.as_mut().unwrap()).unwrap();
         db.create_cf(&index_id, &self.db_opts).unwrap();
 
         // Build the new index for existing values:
@@ -159,6 +271,25 @@ impl State for PersistentState {
         self.persist_meta();
     }
 
+    // Only a unique primary index has as many keys as there are rows; RocksDB cannot tell us how
+    // many distinct keys any other index has.
+    fn key_counts(&self) -> Vec<(Vec<usize>, usize)> {
+        if let Some(ref partial) = self.partial {
+            return self
+                .indices
+                .iter()
+                .zip(&partial.filled)
+                .map(|(index, filled)| (index.columns.clone(), filled.len()))
+                .collect();
+        }
+
+        if self.has_unique_index {
+            vec![(self.indices[0].columns.clone(), self.rows())]
+        } else {
+            vec![]
+        }
+    }
+
     fn keys(&self) -> Vec<Vec<usize>> {
         self.indices
             .iter()
@@ -167,21 +298,47 @@ impl State for PersistentState {
     }
 
     fn cloned_records(&self) -> Vec<Vec<DataType>> {
+        assert!(
+            self.partial.is_none(),
+            "can't clone records of partial state"
+        );
         self.all_rows()
             .map(|(_, ref value)| bincode::deserialize(&value).unwrap())
             .collect()
     }
 
+    fn for_each_record(
+        &self,
+        f: &mut dyn FnMut(&[DataType]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        assert!(
+            self.partial.is_none(),
+            "can't iterate over records of partial state"
+        );
+        for (_, value) in self.all_rows() {
+            let row: Vec<DataType> = bincode::deserialize(&value).unwrap();
+            f(&row)?;
+        }
+        Ok(())
+    }
+
     // Returns a row count estimate from RocksDB.
     fn rows(&self) -> usize {
         let db = self.db.as_ref().unwrap();
-        let cf = db.cf_handle("0").unwrap();
-        let total_keys = db
-            .property_int_value_cf(cf, "rocksdb.estimate-num-keys")
-            .unwrap()
-            .unwrap() as usize;
+        if self.partial.is_some() {
+            // Every index stores its own rows, so count them all, like MemoryState does.
+            return self
+                .indices
+                .iter()
+                .map(|index| {
+                    let cf = db.cf_handle(&index.column_family).unwrap();
+                    Self::estimate_num_keys(db, cf)
+                })
+                .sum();
+        }
 
-        total_keys / self.indices.len()
+        let cf = db.cf_handle("0").unwrap();
+        Self::estimate_num_keys(db, cf) / self.indices.len()
     }
 
     fn is_useful(&self) -> bool {
@@ -189,27 +346,184 @@ impl State for PersistentState {
     }
 
     fn is_partial(&self) -> bool {
-        false
+        self.partial.is_some()
     }
 
-    fn mark_filled(&mut self, _: Vec<DataType>, _: Tag) {
-        unreachable!("PersistentState can't be partial")
+    fn mark_filled(&mut self, key: Vec<DataType>, tag: Tag) {
+        let partial = self
+            .partial
+            .as_mut()
+            .expect("PersistentState isn't partial");
+        let index_id = partial.by_tag[&tag];
+        let index = &self.indices[index_id];
+        let db = self.db.as_ref().unwrap();
+        let cf = db.cf_handle(&index.filled_family()).unwrap();
+        let prefix = Self::serialize_prefix(&KeyType::from(&key[..]));
+        db.put_cf(cf, &prefix, &bincode::serialize(&key).unwrap())
+            .unwrap();
+        let access = Cell::new(KeyAccess::new(partial.clock.get()));
+        partial.filled[index_id].insert(prefix, access);
     }
 
-    fn mark_hole(&mut self, _: &[DataType], _: Tag) {
-        unreachable!("PersistentState can't be partial")
+    fn mark_hole(&mut self, key: &[DataType], tag: Tag) {
+        let partial = self
+            .partial
+            .as_ref()
+            .expect("PersistentState isn't partial");
+        let index = partial.by_tag[&tag];
+        self.evict_key(index, key);
     }
 
-    fn evict_random_keys(&mut self, _: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
-        unreachable!("can't evict keys from PersistentState")
+    fn evict_some_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        assert!(
+            self.partial.is_some(),
+            "can't evict keys from full PersistentState"
+        );
+        let mut rng = rand::thread_rng();
+        let index = rng.gen_range(0, self.indices.len());
+        let mut keys = Vec::with_capacity(count);
+        let mut bytes = 0;
+        for _ in 0..count {
+            let (prefix, key) = match self.choose_key_to_evict(index, &mut rng) {
+                Some(chosen) => chosen,
+                None => break,
+            };
+            bytes += self.evict_prefix(index, &prefix);
+            keys.push(key);
+        }
+        (&self.indices[index].columns[..], keys, bytes)
     }
 
-    fn evict_keys(&mut self, _: Tag, _: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
-        unreachable!("can't evict keys from PersistentState")
+    fn evict_keys(&mut self, tag: Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
+        let partial = self
+            .partial
+            .as_ref()
+            .expect("can't evict keys from full PersistentState");
+        // we may be told to evict from a tag that add_key hasn't been called for yet (see
+        // MemoryState::evict_keys)
+        let index = partial.by_tag.get(&tag).cloned()?;
+        let bytes = keys.iter().map(|key| self.evict_key(index, key)).sum();
+        Some((&self.indices[index].columns[..], bytes))
     }
 
     fn clear(&mut self) {
-        unreachable!("can't clear PersistentState")
+        let partial = self
+            .partial
+            .as_mut()
+            .expect("can't clear full PersistentState");
+        for filled in &mut partial.filled {
+            filled.clear();
+        }
+        let db = self.db.as_ref().unwrap();
+        for index in &self.indices {
+            for family in &[index.column_family.clone(), index.filled_family()] {
+                let cf = db.cf_handle(family).unwrap();
+                let iter = db
+                    .full_iterator_cf(cf, rocksdb::IteratorMode::Start)
+                    .unwrap();
+                for chunk in iter.chunks(INDEX_BATCH_SIZE).into_iter() {
+                    let mut batch = WriteBatch::default();
+                    for (key, _) in chunk {
+                        batch.delete_cf(cf, &key).unwrap();
+                    }
+                    db.write(batch).unwrap();
+                }
+            }
+        }
+    }
+
+    fn log_seq(&self) -> Option<u64> {
+        self.log.as_ref().map(|log| log.applied)
+    }
+
+    fn rewind(&mut self, seq: u64) -> Result<(), String> {
+        let applied = match self.log {
+            Some(ref log) => log.applied,
+            None => return Err("PersistentState doesn't keep a change log".to_owned()),
+        };
+        if seq > applied {
+            return Err(format!(
+                "can't rewind to change {}, since only {} were made",
+                seq, applied
+            ));
+        }
+
+        // Undo one change at a time, so that the rows always match the applied sequence number.
+        for (s, change) in self.logged_changes(seq, applied).into_iter().rev() {
+            self.apply_logged(&change, true, s - 1);
+        }
+        Ok(())
+    }
+
+    fn roll_forward(&mut self) -> Result<Vec<Records>, String> {
+        let (applied, last) = match self.log {
+            Some(ref log) => (log.applied, log.last),
+            None => return Err("PersistentState doesn't keep a change log".to_owned()),
+        };
+
+        let changes = self.logged_changes(applied, last);
+        for &(s, ref change) in &changes {
+            self.apply_logged(change, false, s);
+        }
+        Ok(changes.into_iter().map(|(_, change)| change).collect())
+    }
+
+    fn truncate_log(&mut self, seq: u64) -> Result<(), String> {
+        if self.log.is_none() {
+            return Err("PersistentState doesn't keep a change log".to_owned());
+        }
+        let db = self.db.as_ref().unwrap();
+        let cf = db.cf_handle(LOG_CF).unwrap();
+        let iter = db
+            .full_iterator_cf(cf, rocksdb::IteratorMode::Start)
+            .unwrap()
+            .take_while(|(key, _)| decode_seq(key) <= seq);
+        for chunk in iter.chunks(INDEX_BATCH_SIZE).into_iter() {
+            let mut batch = WriteBatch::default();
+            for (key, _) in chunk {
+                batch.delete_cf(cf, &key).unwrap();
+            }
+            db.write(batch).unwrap();
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<(), String> {
+        let db = self.db.as_ref().unwrap();
+        rocksdb::checkpoint::Checkpoint::new(db)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(path))
+            .map_err(|e| format!("failed to checkpoint {:?}: {}", db.path(), e))
+    }
+
+    fn compact(&mut self) {
+        if Arc::strong_count(self.db.as_ref().unwrap()) > 1 {
+            // the last compaction is still running
+            return;
+        }
+        self.finish_compaction();
+
+        // compacting can take a long time, during which the domain has to keep processing
+        let db = self.db.as_ref().unwrap().clone();
+        let families: Vec<_> = self
+            .indices
+            .iter()
+            .map(|index| index.column_family.clone())
+            .collect();
+        self.compaction = Some(thread::spawn(move || {
+            db.compact_range(None::<&[u8]>, None::<&[u8]>);
+            for cf in families {
+                if let Some(cf) = db.cf_handle(&cf) {
+                    db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+                }
+            }
+        }));
+    }
+}
+
+impl Drop for PersistentState {
+    fn drop(&mut self) {
+        // the database is only closed, and its files may only go, once the compaction is done
+        self.finish_compaction();
     }
 }
 
@@ -243,7 +557,12 @@ impl PersistentState {
             column_families
                 .iter()
                 .map(|cf| {
-                    ColumnFamilyDescriptor::new(cf.clone(), Self::build_options(&name, &params))
+                    let opts = if cf == LOG_CF {
+                        Self::build_ordered_options()
+                    } else {
+                        Self::build_options(&name, &params)
+                    };
+                    ColumnFamilyDescriptor::new(cf.clone(), opts)
                 })
                 .collect()
         };
@@ -268,29 +587,57 @@ impl PersistentState {
             })
             .collect();
 
-        // If there are more column families than indices (-1 to account for the default column
-        // family) we probably crashed while trying to build the last index (in Self::add_key), so
-        // we'll throw away our progress and try re-building it again later:
-        if column_families.len() - 1 > indices.len() {
+        // If there are more index column families than indices we probably crashed while trying
+        // to build the last index (in Self::add_key), so we'll throw away our progress and try
+        // re-building it again later:
+        let index_families = column_families
+            .iter()
+            .filter(|cf| cf.parse::<usize>().is_ok())
+            .count();
+        if index_families > indices.len() {
             db.drop_cf(&indices.len().to_string()).unwrap();
         }
 
+        let log = if params.snapshot_interval.is_some() {
+            if !column_families.iter().any(|cf| cf == LOG_CF) {
+                db.create_cf(LOG_CF, &Self::build_ordered_options())
+                    .unwrap();
+            }
+
+            let applied = db
+                .get(LOG_APPLIED_KEY)
+                .unwrap()
+                .map(|raw| decode_seq(&raw))
+                .unwrap_or(0);
+            let cf = db.cf_handle(LOG_CF).unwrap();
+            let last = db
+                .iterator_cf(cf, rocksdb::IteratorMode::End)
+                .unwrap()
+                .next()
+                .map(|(key, _)| decode_seq(&key))
+                .unwrap_or(applied);
+            Some(ChangeLog { applied, last })
+        } else {
+            None
+        };
+
         let mut state = Self {
             seq: 0,
             indices,
             has_unique_index: primary_key.is_some(),
             epoch: meta.epoch,
+            partial: None,
+            log,
             db_opts: opts,
-            db: Some(db),
+            db: Some(Arc::new(db)),
+            compaction: None,
             _directory: directory,
         };
 
         if primary_key.is_some() && state.indices.is_empty() {
             // This is the first time we're initializing this PersistentState,
             // so persist the primary key index right away.
-            state
-                .db
-                .as_mut()
+            Arc::get_mut(state.db.as_mut().unwrap())
                 .unwrap()
                 .create_cf("0", &state.db_opts)
                 .unwrap();
@@ -304,25 +651,114 @@ impl PersistentState {
             state.persist_meta();
         }
 
+        if state
+            .log
+            .as_ref()
+            .map_or(false, |log| log.applied < log.last)
+        {
+            // We crashed while recovering from a snapshot, after rewinding this base table. Redo
+            // the undone changes, since recovery will rewind it again anyway.
+            state
+                .roll_forward()
+                .expect("state with a change log couldn't roll forward");
+        }
+
+        state
+    }
+
+    /// Create partially materialized state for an internal node, so that it can be kept on disk
+    /// rather than in memory.
+    ///
+    /// Partial state is rebuilt through replays after a restart, so its files are always deleted
+    /// once it is dropped, whatever `params.mode` says. Keys are evicted as `eviction` says.
+    pub fn new_partial(
+        name: String,
+        params: &PersistenceParameters,
+        eviction: EvictionPolicy,
+    ) -> Self {
+        let params = PersistenceParameters {
+            mode: DurabilityMode::DeleteOnExit,
+            snapshot_interval: None,
+            ..params.clone()
+        };
+
+        let mut state = Self::new(name, None, &params);
+        state.partial = Some(PartialIndices {
+            eviction,
+            ..Default::default()
+        });
         state
     }
 
+    /// Wait for a compaction that runs in the background to finish, so that nothing else holds
+    /// on to the database.
+    fn finish_compaction(&mut self) {
+        if let Some(compaction) = self.compaction.take() {
+            compaction
+                .join()
+                .expect("compaction of persistent state panicked");
+        }
+    }
+
+    /// Delete the files that a base table's state named `name` is kept in with
+    /// `DurabilityMode::Permanent`. The state must have been dropped first.
+    pub fn destroy(name: &str, params: &PersistenceParameters) -> Result<(), String> {
+        let full_name = format!("{}.db", name);
+        rocksdb::DB::destroy(&Self::build_options(name, params), &full_name)
+            .map_err(|e| format!("failed to destroy {}: {}", full_name, e))
+    }
+
     fn build_options(name: &str, params: &PersistenceParameters) -> rocksdb::Options {
         let mut opts = rocksdb::Options::default();
-        opts.set_compression_type(rocksdb::DBCompressionType::Lz4);
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
 
-        let user_key_length = 0; // variable key length
-        let bloom_bits_per_key = 10;
-        let hash_table_ratio = 0.75;
-        let index_sparseness = 16;
-        opts.set_plain_table_factory(&PlainTableFactoryOptions {
-            user_key_length,
-            bloom_bits_per_key,
-            hash_table_ratio,
-            index_sparseness,
-        });
+        let tuning = &params.rocksdb;
+        let compression: Vec<_> = tuning
+            .compression_per_level
+            .iter()
+            .map(|c| match *c {
+                Compression::None => rocksdb::DBCompressionType::None,
+                Compression::Lz4 => rocksdb::DBCompressionType::Lz4,
+                Compression::Zstd => rocksdb::DBCompressionType::Zstd,
+            })
+            .collect();
+        match compression.last() {
+            Some(&last) => {
+                opts.set_compression_type(last);
+                opts.set_compression_per_level(&compression);
+            }
+            None => opts.set_compression_type(rocksdb::DBCompressionType::None),
+        }
+
+        // the plain table format is kept unless asked otherwise, since tables that were written
+        // in it can't be opened in the block-based format
+        if tuning.block_cache_size.is_some() || !compression.is_empty() {
+            let mut table_opts = BlockBasedOptions::default();
+            if let Some(size) = tuning.block_cache_size {
+                table_opts.set_lru_cache(size);
+            }
+            table_opts.set_bloom_filter(tuning.bloom_bits_per_key, false);
+            opts.set_block_based_table_factory(&table_opts);
+        } else {
+            let user_key_length = 0; // variable key length
+            let hash_table_ratio = 0.75;
+            let index_sparseness = 16;
+            opts.set_plain_table_factory(&PlainTableFactoryOptions {
+                user_key_length,
+                bloom_bits_per_key: tuning.bloom_bits_per_key,
+                hash_table_ratio,
+                index_sparseness,
+            });
+        }
+
+        if let Some(size) = tuning.write_buffer_size {
+            opts.set_write_buffer_size(size);
+        }
+        if let Some(rate) = tuning.compaction_rate_limit {
+            // refill every 100ms, and treat reads and writes equally
+            opts.set_ratelimiter(rate as i64, 100_000, 10);
+        }
 
         if let Some(ref path) = params.log_dir {
             // Append the db name to the WAL path to ensure
@@ -366,6 +802,66 @@ impl PersistentState {
         opts
     }
 
+    // The side column families of partial state and the change log are only ever used for point
+    // lookups and for iterating over all of their keys in order, so they use RocksDB's default
+    // (ordered) table format.
+    fn build_ordered_options() -> rocksdb::Options {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts
+    }
+
+    fn write_synced(&self, batch: WriteBatch) {
+        // Sync the writes to RocksDB's WAL:
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        self.db.as_ref().unwrap().write_opt(batch, &opts).unwrap();
+    }
+
+    // Returns the logged changes with sequence numbers in `(after, upto]`, in order.
+    fn logged_changes(&self, after: u64, upto: u64) -> Vec<(u64, Records)> {
+        let db = self.db.as_ref().unwrap();
+        let cf = db.cf_handle(LOG_CF).unwrap();
+        let from = (after + 1).to_be_bytes();
+        let mode = rocksdb::IteratorMode::From(&from, rocksdb::Direction::Forward);
+        db.iterator_cf(cf, mode)
+            .unwrap()
+            .map(|(key, value)| (decode_seq(&key), bincode::deserialize(&value).unwrap()))
+            .take_while(|&(seq, _)| seq <= upto)
+            .collect()
+    }
+
+    // Applies or undoes a logged change, and records `applied` as the sequence number of the last
+    // change that the rows reflect. The change isn't logged again.
+    fn apply_logged(&mut self, change: &Records, undo: bool, applied: u64) {
+        let mut batch = WriteBatch::default();
+        if undo {
+            for r in change.iter().rev() {
+                match *r {
+                    Record::Positive(ref r) => self.remove(&mut batch, r),
+                    Record::Negative(ref r) => self.insert(&mut batch, r),
+                }
+            }
+        } else {
+            for r in change.iter() {
+                match *r {
+                    Record::Positive(ref r) => self.insert(&mut batch, r),
+                    Record::Negative(ref r) => self.remove(&mut batch, r),
+                }
+            }
+        }
+
+        batch.put(LOG_APPLIED_KEY, &applied.to_be_bytes()).unwrap();
+        self.write_synced(batch);
+        self.log.as_mut().unwrap().applied = applied;
+    }
+
+    fn estimate_num_keys(db: &rocksdb::DB, cf: &rocksdb::ColumnFamily) -> usize {
+        db.property_int_value_cf(cf, "rocksdb.estimate-num-keys")
+            .unwrap()
+            .unwrap() as usize
+    }
+
     fn build_key<'a>(row: &'a [DataType], columns: &[usize]) -> KeyType<'a> {
         KeyType::from(columns.iter().map(|i| &row[*i]))
     }
@@ -528,6 +1024,166 @@ impl PersistentState {
             do_remove(&key[..]);
         };
     }
+
+    fn add_partial_key(&mut self, columns: &[usize], existing: Option<usize>, tags: Vec<Tag>) {
+        let index_id = match existing {
+            Some(index_id) => index_id,
+            None => {
+                // A new partial index starts out as all holes, so there's nothing to build.
+                let index = PersistentIndex {
+                    column_family: self.indices.len().to_string(),
+                    columns: Vec::from(columns),
+                };
+                self.finish_compaction();
+                let db = Arc::get_mut(self.db.as_mut().unwrap()).unwrap();
+                db.create_cf(&index.column_family, &self.db_opts).unwrap();
+                db.create_cf(&index.filled_family(), &Self::build_ordered_options())
+                    .unwrap();
+                self.indices.push(index);
+                self.partial.as_mut().unwrap().filled.push(IndexMap::new());
+                self.indices.len() - 1
+            }
+        };
+
+        let partial = self.partial.as_mut().unwrap();
+        for tag in tags {
+            partial.by_tag.insert(tag, index_id);
+        }
+    }
+
+    fn is_filled(&self, index: usize, prefix: &[u8]) -> bool {
+        self.partial.as_ref().unwrap().filled[index].contains_key(prefix)
+    }
+
+    // Partial state is rebuilt through replays after a restart, so there's no need to sync
+    // writes to it. They are applied one record at a time, so that the holes (and rows) that
+    // each record sees reflect those before it in the same batch.
+    fn insert_partial(&mut self, r: &[DataType], partial_tag: Option<Tag>) -> bool {
+        let targets: Vec<usize> = match partial_tag {
+            Some(tag) => match self.partial.as_ref().unwrap().by_tag.get(&tag) {
+                Some(&index) => vec![index],
+                None => {
+                    // see MemoryState::insert
+                    return true;
+                }
+            },
+            None => (0..self.indices.len())
+                .filter(|&i| {
+                    let index = &self.indices[i];
+                    let prefix = Self::serialize_prefix(&Self::build_key(r, &index.columns));
+                    self.is_filled(i, &prefix)
+                })
+                .collect(),
+        };
+
+        if targets.is_empty() {
+            return false;
+        }
+
+        let serialized_row = bincode::serialize(&r).unwrap();
+        let db = self.db.as_ref().unwrap();
+        let mut batch = WriteBatch::default();
+        for i in targets {
+            let index = &self.indices[i];
+            self.seq += 1;
+            let key = Self::serialize_raw_key(
+                &Self::build_key(r, &index.columns),
+                (self.epoch, self.seq),
+            );
+            let cf = db.cf_handle(&index.column_family).unwrap();
+            batch.put_cf(cf, &key, &serialized_row).unwrap();
+        }
+
+        db.write(batch).unwrap();
+        true
+    }
+
+    fn remove_partial(&mut self, r: &[DataType]) -> bool {
+        let db = self.db.as_ref().unwrap();
+        let mut batch = WriteBatch::default();
+        let mut hit = false;
+        for (i, index) in self.indices.iter().enumerate() {
+            let prefix = Self::serialize_prefix(&Self::build_key(r, &index.columns));
+            if !self.is_filled(i, &prefix) {
+                continue;
+            }
+
+            hit = true;
+            let cf = db.cf_handle(&index.column_family).unwrap();
+            let found = db
+                .prefix_iterator_cf(cf, &prefix)
+                .unwrap()
+                .find(|(_, raw_value)| {
+                    let value: Vec<DataType> = bincode::deserialize(&*raw_value).unwrap();
+                    r == &value[..]
+                });
+            if let Some((key, _)) = found {
+                batch.delete_cf(cf, &key).unwrap();
+            }
+        }
+
+        db.write(batch).unwrap();
+        hit
+    }
+
+    // Turns `key` back into a hole in the given index, returning the number of bytes freed.
+    fn evict_key(&mut self, index: usize, key: &[DataType]) -> u64 {
+        let prefix = Self::serialize_prefix(&KeyType::from(key));
+        self.evict_prefix(index, &prefix)
+    }
+
+    // Like `evict_key`, but for a key that is already serialized.
+    fn evict_prefix(&mut self, index_id: usize, prefix: &[u8]) -> u64 {
+        self.partial.as_mut().unwrap().filled[index_id].swap_remove(prefix);
+        let db = self.db.as_ref().unwrap();
+        let index = &self.indices[index_id];
+        let mut batch = WriteBatch::default();
+        let filled_cf = db.cf_handle(&index.filled_family()).unwrap();
+        batch.delete_cf(filled_cf, prefix).unwrap();
+
+        let mut freed = 0;
+        let cf = db.cf_handle(&index.column_family).unwrap();
+        for (key, value) in db.prefix_iterator_cf(cf, &prefix).unwrap() {
+            batch.delete_cf(cf, &key).unwrap();
+            freed += value.len() as u64;
+        }
+
+        db.write(batch).unwrap();
+        freed
+    }
+
+    // Picks a filled key of the given index to evict, as the eviction policy says, returning it
+    // both serialized and deserialized.
+    fn choose_key_to_evict<R: Rng>(
+        &self,
+        index: usize,
+        rng: &mut R,
+    ) -> Option<(Vec<u8>, Vec<DataType>)> {
+        let partial = self.partial.as_ref().unwrap();
+        let filled = &partial.filled[index];
+        if filled.is_empty() {
+            return None;
+        }
+        let db = self.db.as_ref().unwrap();
+        let cf = db.cf_handle(&self.indices[index].column_family).unwrap();
+        let chosen = partial.eviction.choose(filled.len(), rng, |i| {
+            let (prefix, access) = filled.get_index(i).unwrap();
+            let size = if partial.eviction.needs_sizes() {
+                db.prefix_iterator_cf(cf, prefix)
+                    .unwrap()
+                    .map(|(_, value)| value.len() as u64)
+                    .sum()
+            } else {
+                0
+            };
+            (access.get(), size)
+        });
+
+        let (prefix, _) = filled.get_index(chosen).unwrap();
+        let filled_cf = db.cf_handle(&self.indices[index].filled_family()).unwrap();
+        let key = db.get_cf(filled_cf, prefix).unwrap().unwrap();
+        Some((prefix.clone(), bincode::deserialize(&*key).unwrap()))
+    }
 }
 
 // SliceTransforms are used to create prefixes of all inserted keys, which can then be used for
@@ -559,6 +1215,12 @@ impl PersistentState {
 // when we reached the end, and could then with certainty say whether we'd already
 // prefix transformed this key before or not
 // (without including the byte size of Vec<DataType>).
+fn decode_seq(raw: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(raw);
+    u64::from_be_bytes(bytes)
+}
+
 fn prefix_transform<'a>(key: &'a [u8]) -> &'a [u8] {
     // We'll have to make sure this isn't the META_KEY even when we're filtering it out
     // in Self::in_domain_fn, as the SliceTransform is used to make hashed keys for our
@@ -588,6 +1250,13 @@ impl SizeOf for PersistentState {
     }
 
     fn deep_size_of(&self) -> u64 {
+        if self.partial.is_some() {
+            // Only memory use counts for partial state, since that is what eviction is meant to
+            // bound. The rows of spilled partial state are all on disk, and evicting them frees
+            // no memory.
+            return 0;
+        }
+
         let db = self.db.as_ref().unwrap();
         db.property_int_value("rocksdb.estimate-live-data-size")
             .unwrap()
@@ -599,6 +1268,8 @@ impl SizeOf for PersistentState {
 mod tests {
     use super::*;
     use bincode;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
     use std::path::PathBuf;
 
     fn insert<S: State>(state: &mut S, row: Vec<DataType>) {
@@ -620,10 +1291,150 @@ mod tests {
         )
     }
 
+    fn missing(state: &PersistentState, columns: &[usize], key: &KeyType) -> bool {
+        match state.lookup(columns, key) {
+            LookupResult::Missing => true,
+            LookupResult::Some(_) => false,
+        }
+    }
+
+    fn setup_partial(prefix: &str) -> PersistentState {
+        let params = PersistenceParameters::default();
+        PersistentState::new_partial(String::from(prefix), &params, EvictionPolicy::Random)
+    }
+
     #[test]
     fn persistent_state_is_partial() {
         let state = setup_persistent("persistent_state_is_partial");
         assert!(!state.is_partial());
+        let state = setup_partial("persistent_state_is_partial_partial");
+        assert!(state.is_partial());
+    }
+
+    #[test]
+    fn persistent_state_partial_holes() {
+        let tag = Tag(0);
+        let mut state = setup_partial("persistent_state_partial_holes");
+        state.add_key(&[0], Some(vec![tag]));
+        let key = KeyType::Single(&1.into());
+
+        // records for holes are dropped
+        let mut records: Records = vec![(vec![1.into(), "Cat".into()], true)].into();
+        state.process_records(&mut records, None);
+        assert!(records.is_empty());
+        assert!(missing(&state, &[0], &key));
+
+        state.mark_filled(vec![1.into()], tag);
+        match state.lookup(&[0], &key) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert!(rows.is_empty()),
+            _ => unreachable!(),
+        }
+
+        let mut records: Records = vec![
+            (vec![1.into(), "Cat".into()], true),
+            (vec![1.into(), "Dog".into()], true),
+            (vec![1.into(), "Cat".into()], false),
+        ]
+        .into();
+        state.process_records(&mut records, None);
+        assert_eq!(records.len(), 3);
+        match state.lookup(&[0], &key) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows, vec![vec![1.into(), "Dog".into()]]);
+            }
+            _ => unreachable!(),
+        }
+        // the rows are on disk, so they take up no memory that eviction could free
+        assert_eq!(state.deep_size_of(), 0);
+
+        state.mark_hole(&[1.into()], tag);
+        assert!(missing(&state, &[0], &key));
+    }
+
+    #[test]
+    fn persistent_state_partial_indices() {
+        let mut state = setup_partial("persistent_state_partial_indices");
+        state.add_key(&[0], Some(vec![Tag(0)]));
+        state.add_key(&[1], Some(vec![Tag(1)]));
+        state.mark_filled(vec![1.into()], Tag(0));
+
+        // a replay only fills the index it targets
+        let row: Vec<DataType> = vec![1.into(), "Cat".into()];
+        let record: Record = row.clone().into();
+        state.process_records(&mut record.into(), Some(Tag(0)));
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows, vec![row]),
+            _ => unreachable!(),
+        }
+        assert!(missing(&state, &[1], &KeyType::Single(&"Cat".into())));
+    }
+
+    #[test]
+    fn persistent_state_partial_evict() {
+        let tag = Tag(0);
+        let mut state = setup_partial("persistent_state_partial_evict");
+        state.add_key(&[0], Some(vec![tag]));
+        for i in 0..10 {
+            state.mark_filled(vec![i.into()], tag);
+            let record: Record = vec![i.into(), "Cat".into()].into();
+            state.process_records(&mut record.into(), Some(tag));
+        }
+
+        let (columns, keys, bytes) = state.evict_some_keys(3);
+        assert_eq!(columns, &[0]);
+        assert_eq!(keys.len(), 3);
+        assert!(bytes > 0);
+        for key in &keys {
+            assert!(missing(&state, &[0], &KeyType::from(key)));
+        }
+
+        let (_, bytes) = state
+            .evict_keys(tag, &[vec![0.into()], vec![1.into()]])
+            .unwrap();
+        assert!(missing(&state, &[0], &KeyType::Single(&0.into())));
+        assert!(missing(&state, &[0], &KeyType::Single(&1.into())));
+        if !keys.contains(&vec![0.into()]) || !keys.contains(&vec![1.into()]) {
+            assert!(bytes > 0);
+        }
+        assert!(state.evict_keys(Tag(1), &[vec![2.into()]]).is_none());
+
+        state.clear();
+        for i in 0..10 {
+            assert!(missing(&state, &[0], &KeyType::Single(&i.into())));
+        }
+    }
+
+    #[test]
+    fn persistent_state_evicts_by_policy() {
+        let tag = Tag(0);
+        let params = PersistenceParameters::default();
+        let name = String::from("persistent_state_evicts_by_policy");
+        let mut state =
+            PersistentState::new_partial(name, &params, EvictionPolicy::LeastRecentlyUsed);
+        state.add_key(&[0], Some(vec![tag]));
+        for i in 0..2 {
+            state.mark_filled(vec![i.into()], tag);
+            let record: Record = vec![i.into(), "Cat".into()].into();
+            state.process_records(&mut record.into(), Some(tag));
+        }
+        state.lookup(&[0], &KeyType::Single(&1.into()));
+
+        // both keys are sampled unless all samples land on the same one
+        let kept = (0..100)
+            .filter(|&seed| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let (_, key) = state.choose_key_to_evict(0, &mut rng).unwrap();
+                key == vec![DataType::from(0)]
+            })
+            .count();
+        assert!(kept > 80);
+
+        let (_, keys, bytes) = state.evict_some_keys(2);
+        assert_eq!(keys.len(), 2);
+        assert!(bytes > 0);
+        assert_eq!(state.key_counts(), vec![(vec![0], 0)]);
+        let (_, keys, _) = state.evict_some_keys(1);
+        assert!(keys.is_empty());
     }
 
     #[test]
@@ -696,6 +1507,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn persistent_state_compacts_in_background() {
+        let mut state = setup_persistent("persistent_state_compacts_in_background");
+        state.add_key(&[0], None);
+        for i in 0..100 {
+            insert(&mut state, vec![i.into(), "Cat".into()]);
+        }
+        state.compact();
+        // the state is still used while the compaction runs, and indices can still be added
+        insert(&mut state, vec![100.into(), "Dog".into()]);
+        state.add_key(&[1], None);
+        assert!(state.compaction.is_none());
+        match state.lookup(&[1], &KeyType::Single(&"Dog".into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows, vec![vec![100.into(), "Dog".into()]]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
     #[test]
     fn persistent_state_primary_key() {
         let pk = &[0, 1];
@@ -865,6 +1696,128 @@ mod tests {
         }
     }
 
+    #[test]
+    fn persistent_state_checkpoint() {
+        let (_dir, name) = get_tmp_path();
+        let checkpoint = format!("{}-checkpoint", name);
+        let mut params = PersistenceParameters::default();
+        params.mode = DurabilityMode::Permanent;
+        let first: Vec<DataType> = vec![10.into(), "Cat".into()];
+        let second: Vec<DataType> = vec![20.into(), "Bob".into()];
+        {
+            let mut state = PersistentState::new(name, None, &params);
+            state.add_key(&[0], None);
+            state.process_records(&mut vec![first.clone()].into(), None);
+            let path = format!("{}.db", checkpoint);
+            state.checkpoint(Path::new(&path)).unwrap();
+
+            // later changes aren't part of the checkpoint
+            state.process_records(&mut vec![second].into(), None);
+        }
+
+        let state = PersistentState::new(checkpoint, None, &params);
+        match state.lookup(&[0], &KeyType::Single(&10.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows, vec![first]),
+            _ => unreachable!(),
+        }
+        match state.lookup(&[0], &KeyType::Single(&20.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert!(rows.is_empty()),
+            _ => unreachable!(),
+        }
+    }
+
+    fn setup_logged(name: String, mode: DurabilityMode) -> PersistentState {
+        let mut params = PersistenceParameters::default();
+        params.mode = mode;
+        params.snapshot_interval = Some(std::time::Duration::from_secs(1));
+        let mut state = PersistentState::new(name, Some(&[0]), &params);
+        state.add_key(&[0], None);
+        state
+    }
+
+    fn rows(state: &PersistentState, key: i32) -> Vec<Vec<DataType>> {
+        match state.lookup(&[0], &KeyType::Single(&key.into())) {
+            LookupResult::Some(rows) => rows.into_iter().map(|r| r.into_owned()).collect(),
+            LookupResult::Missing => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn persistent_state_log_rewind() {
+        let mut state = setup_logged(
+            String::from("persistent_state_log_rewind"),
+            DurabilityMode::DeleteOnExit,
+        );
+        let first: Vec<DataType> = vec![1.into(), "Cat".into()];
+        let second: Vec<DataType> = vec![2.into(), "Dog".into()];
+        let updated: Vec<DataType> = vec![1.into(), "Bob".into()];
+        insert(&mut state, first.clone());
+        insert(&mut state, second.clone());
+        let mut update: Records = vec![(first.clone(), false), (updated.clone(), true)]
+            .into_iter()
+            .map(Record::from)
+            .collect();
+        state.process_records(&mut update, None);
+        assert_eq!(state.log_seq(), Some(3));
+
+        state.rewind(1).unwrap();
+        assert_eq!(state.log_seq(), Some(1));
+        assert_eq!(rows(&state, 1), vec![first.clone()]);
+        assert!(rows(&state, 2).is_empty());
+
+        let changes = state.roll_forward().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[1].len(), 2);
+        assert_eq!(state.log_seq(), Some(3));
+        assert_eq!(rows(&state, 1), vec![updated]);
+        assert_eq!(rows(&state, 2), vec![second]);
+    }
+
+    #[test]
+    fn persistent_state_log_truncate() {
+        let mut state = setup_logged(
+            String::from("persistent_state_log_truncate"),
+            DurabilityMode::DeleteOnExit,
+        );
+        for i in 0..3 {
+            insert(&mut state, vec![i.into(), "Cat".into()]);
+        }
+
+        state.truncate_log(2).unwrap();
+        assert_eq!(state.logged_changes(0, 3).len(), 1);
+        assert_eq!(state.log_seq(), Some(3));
+
+        // the changes after the truncated ones can still be undone
+        state.rewind(2).unwrap();
+        assert!(rows(&state, 2).is_empty());
+        assert_eq!(rows(&state, 1).len(), 1);
+        assert_eq!(state.roll_forward().unwrap().len(), 1);
+
+        // changes that were never made can't be undone, and a state without a log has none
+        assert!(state.rewind(4).is_err());
+        let mut unlogged = setup_persistent("persistent_state_log_truncate_unlogged");
+        assert!(unlogged.rewind(0).is_err());
+        assert!(unlogged.roll_forward().is_err());
+    }
+
+    #[test]
+    fn persistent_state_log_recover_rewound() {
+        let (_dir, name) = get_tmp_path();
+        {
+            let mut state = setup_logged(name.clone(), DurabilityMode::Permanent);
+            insert(&mut state, vec![1.into(), "Cat".into()]);
+            insert(&mut state, vec![2.into(), "Dog".into()]);
+            state.rewind(0).unwrap();
+            assert!(rows(&state, 1).is_empty());
+        }
+
+        // changes that were undone when we went away are redone on recovery
+        let state = setup_logged(name, DurabilityMode::Permanent);
+        assert_eq!(state.log_seq(), Some(2));
+        assert_eq!(rows(&state, 1).len(), 1);
+        assert_eq!(rows(&state, 2).len(), 1);
+    }
+
     #[test]
     fn persistent_state_recover_unique_key() {
         let (_dir, name) = get_tmp_path();
@@ -1,15 +1,46 @@
 use super::mk_key::MakeKey;
+use crate::eviction::{EvictionPolicy, KeyAccess};
 use crate::prelude::*;
 use crate::state::keyed_state::KeyedState;
-use common::SizeOf;
 use rand::prelude::*;
-use std::rc::Rc;
+use std::cell::Cell;
 
 pub(super) struct SingleState {
     key: Vec<usize>,
     state: KeyedState,
     partial: bool,
     rows: usize,
+    reads: Option<Reads>,
+}
+
+/// When each key in a `KeyedState` was last read, and how often, kept in the same order as the
+/// keys themselves.
+#[derive(Default)]
+struct Reads {
+    clock: Cell<u64>,
+    keys: Vec<Cell<KeyAccess>>,
+}
+
+impl Reads {
+    fn added(&mut self) {
+        self.keys.push(Cell::new(KeyAccess::new(self.clock.get())));
+    }
+
+    fn removed(&mut self, index: usize) {
+        self.keys.swap_remove(index);
+    }
+
+    fn read(&self, index: usize) {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        let mut access = self.keys[index].get();
+        access.touch(now);
+        self.keys[index].set(access);
+    }
+
+    fn get(&self, index: usize) -> KeyAccess {
+        self.keys[index].get()
+    }
 }
 
 macro_rules! insert_row_match_impl {
@@ -22,6 +53,9 @@ macro_rules! insert_row_match_impl {
             Entry::Vacant(..) if $self.partial => return false,
             rs @ Entry::Vacant(..) => {
                 rs.or_default().insert($r);
+                if let Some(ref mut reads) = $self.reads {
+                    reads.added();
+                }
             }
         }
     }};
@@ -38,12 +72,19 @@ macro_rules! remove_row_match_impl {
 }
 
 impl SingleState {
-    pub(super) fn new(columns: &[usize], partial: bool) -> Self {
+    /// Create an index on `columns`, remembering key reads if it is partial and `eviction` needs
+    /// them.
+    pub(super) fn new(columns: &[usize], partial: bool, eviction: EvictionPolicy) -> Self {
         Self {
             key: Vec::from(columns),
             state: columns.into(),
             partial,
             rows: 0,
+            reads: if partial && eviction.tracks_reads() {
+                Some(Reads::default())
+            } else {
+                None
+            },
         }
     }
 
@@ -66,6 +107,9 @@ impl SingleState {
                     return false;
                 }
                 map.insert(r[self.key[0]].clone(), std::iter::once(r).collect());
+                if let Some(ref mut reads) = self.reads {
+                    reads.added();
+                }
             }
             KeyedState::Double(ref mut map) => insert_row_match_impl!(self, r, map),
             KeyedState::Tri(ref mut map) => insert_row_match_impl!(self, r, map),
@@ -178,34 +222,18 @@ impl SingleState {
             ),
         };
         assert!(replaced.is_none());
+        if let Some(ref mut reads) = self.reads {
+            reads.added();
+        }
     }
 
     pub(super) fn mark_hole(&mut self, key: &[DataType]) -> u64 {
-        let removed = match self.state {
-            KeyedState::Single(ref mut m) => m.swap_remove(&(key[0])),
-            KeyedState::Double(ref mut m) => {
-                m.swap_remove::<(DataType, _)>(&MakeKey::from_key(key))
-            }
-            KeyedState::Tri(ref mut m) => {
-                m.swap_remove::<(DataType, _, _)>(&MakeKey::from_key(key))
-            }
-            KeyedState::Quad(ref mut m) => {
-                m.swap_remove::<(DataType, _, _, _)>(&MakeKey::from_key(key))
-            }
-            KeyedState::Quin(ref mut m) => {
-                m.swap_remove::<(DataType, _, _, _, _)>(&MakeKey::from_key(key))
-            }
-            KeyedState::Sex(ref mut m) => {
-                m.swap_remove::<(DataType, _, _, _, _, _)>(&MakeKey::from_key(key))
-            }
-        };
         // mark_hole should only be called on keys we called mark_filled on
-        removed
-            .unwrap()
-            .iter()
-            .filter(|r| Rc::strong_count(&r.0) == 1)
-            .map(SizeOf::deep_size_of)
-            .sum()
+        let (index, bytes) = self.state.evict(key).unwrap();
+        if let Some(ref mut reads) = self.reads {
+            reads.removed(index);
+        }
+        bytes
     }
 
     pub(super) fn clear(&mut self) {
@@ -218,31 +246,61 @@ impl SingleState {
             KeyedState::Quin(ref mut map) => map.clear(),
             KeyedState::Sex(ref mut map) => map.clear(),
         };
+        if let Some(ref mut reads) = self.reads {
+            reads.keys.clear();
+        }
     }
 
-    /// Evict `count` randomly selected keys from state and return them along with the number of
-    /// bytes freed.
-    pub(super) fn evict_random_keys(
+    /// Evict `count` keys chosen by `policy` from state and return them and their rows, along with
+    /// the number of bytes freed.
+    pub(super) fn evict_some_keys<R: Rng>(
         &mut self,
         count: usize,
-        rng: &mut ThreadRng,
-    ) -> (u64, Vec<Vec<DataType>>) {
+        policy: EvictionPolicy,
+        rng: &mut R,
+    ) -> (u64, Vec<(Vec<DataType>, Rows)>) {
         let mut bytes_freed = 0;
         let mut keys = Vec::with_capacity(count);
         for _ in 0..count {
-            if let Some((n, key)) = self.state.evict_with_seed(rng.gen()) {
-                bytes_freed += n;
-                keys.push(key);
-            } else {
+            let len = self.state.len();
+            if len == 0 {
                 break;
             }
+
+            let index = {
+                let (state, reads) = (&self.state, &self.reads);
+                policy.choose(len, rng, |i| {
+                    let access = reads.as_ref().map(|r| r.get(i)).unwrap_or_default();
+                    let size = if policy.needs_sizes() {
+                        state.size_at(i)
+                    } else {
+                        0
+                    };
+                    (access, size)
+                })
+            };
+            let (n, key, rows) = self.state.evict_at(index).unwrap();
+            if let Some(ref mut reads) = self.reads {
+                reads.removed(index);
+            }
+            bytes_freed += n;
+            keys.push((key, rows));
         }
         (bytes_freed, keys)
     }
 
     /// Evicts a specified key from this state, returning the number of bytes freed.
     pub(super) fn evict_keys(&mut self, keys: &[Vec<DataType>]) -> u64 {
-        keys.iter().map(|k| self.state.evict(k)).sum()
+        let mut bytes_freed = 0;
+        for key in keys {
+            if let Some((index, bytes)) = self.state.evict(key) {
+                if let Some(ref mut reads) = self.reads {
+                    reads.removed(index);
+                }
+                bytes_freed += bytes;
+            }
+        }
+        bytes_freed
     }
 
     pub(super) fn values<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Rows> + 'a> {
@@ -264,8 +322,14 @@ impl SingleState {
     pub(super) fn rows(&self) -> usize {
         self.rows
     }
+    pub(super) fn key_count(&self) -> usize {
+        self.state.len()
+    }
     pub(super) fn lookup<'a>(&'a self, key: &KeyType) -> LookupResult<'a> {
-        if let Some(rs) = self.state.lookup(key) {
+        if let Some((index, rs)) = self.state.lookup(key) {
+            if let Some(ref reads) = self.reads {
+                reads.read(index);
+            }
             LookupResult::Some(RecordResult::Borrowed(rs))
         } else if self.partial() {
             // partially materialized, so this is a hole (empty results would be vec![])
@@ -0,0 +1,338 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+
+use crate::eviction::EvictionPolicy;
+use crate::prelude::*;
+use common::SizeOf;
+
+/// Partially materialized state that keeps recently used keys in memory, and moves other keys to
+/// RocksDB when memory runs low instead of evicting them.
+///
+/// Every filled key lives in exactly one of the two tiers. A lookup that finds its key on disk is
+/// answered from there, and the key is moved back into memory the next time the state is
+/// modified. Unlike evicted keys, keys that were moved to disk never need to be replayed again.
+pub(crate) struct TieredState {
+    hot: MemoryState,
+    cold: PersistentState,
+    // A tag that fills each index, by the columns the index is keyed on. Any such tag will do when
+    // moving a key between the tiers, since both tiers map all of an index's tags to it.
+    tags: HashMap<Vec<usize>, Tag>,
+    // Keys that lookups have found on disk, along with the columns of the index they were in.
+    promotions: RefCell<Vec<(Vec<usize>, Vec<DataType>)>>,
+}
+
+impl SizeOf for TieredState {
+    fn size_of(&self) -> u64 {
+        use std::mem::size_of;
+
+        size_of::<Self>() as u64
+    }
+
+    // Only memory use counts, since that is what eviction is meant to bound.
+    fn deep_size_of(&self) -> u64 {
+        self.hot.deep_size_of()
+    }
+}
+
+impl State for TieredState {
+    fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>) {
+        let tags = partial.expect("TieredState can't be fully materialized");
+        if let Some(&tag) = tags.first() {
+            self.tags.entry(columns.to_vec()).or_insert(tag);
+        }
+        self.hot.add_key(columns, Some(tags.clone()));
+        self.cold.add_key(columns, Some(tags));
+    }
+
+    fn is_useful(&self) -> bool {
+        self.hot.is_useful()
+    }
+
+    fn is_partial(&self) -> bool {
+        true
+    }
+
+    fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
+        self.promote();
+        if partial_tag.is_some() {
+            // replays only fill keys that were just marked as filled, which happens in memory
+            self.hot.process_records(records, partial_tag);
+            return;
+        }
+
+        // each tier keeps the records whose keys it holds, in order, and a record is forwarded if
+        // either tier kept it
+        let mut in_hot = records.clone();
+        let mut in_cold = records.clone();
+        self.hot.process_records(&mut in_hot, None);
+        self.cold.process_records(&mut in_cold, None);
+        let mut in_hot = in_hot.into_iter().peekable();
+        let mut in_cold = in_cold.into_iter().peekable();
+        records.retain(|r| {
+            let hot = in_hot.peek() == Some(r);
+            if hot {
+                in_hot.next();
+            }
+            let cold = in_cold.peek() == Some(r);
+            if cold {
+                in_cold.next();
+            }
+            hot || cold
+        });
+    }
+
+    fn mark_hole(&mut self, key: &[DataType], tag: Tag) {
+        self.promote();
+        self.hot.evict_keys(tag, &[key.to_vec()]);
+        self.cold.mark_hole(key, tag);
+    }
+
+    fn mark_filled(&mut self, key: Vec<DataType>, tag: Tag) {
+        self.promote();
+        self.hot.mark_filled(key, tag);
+    }
+
+    fn lookup<'a>(&'a self, columns: &[usize], key: &KeyType) -> LookupResult<'a> {
+        if let hit @ LookupResult::Some(_) = self.hot.lookup(columns, key) {
+            return hit;
+        }
+
+        let hit = self.cold.lookup(columns, key);
+        if let LookupResult::Some(_) = hit {
+            self.promotions
+                .borrow_mut()
+                .push((columns.to_vec(), key_to_vec(key)));
+        }
+        hit
+    }
+
+    fn rows(&self) -> usize {
+        self.hot.rows() + self.cold.rows()
+    }
+
+    fn key_counts(&self) -> Vec<(Vec<usize>, usize)> {
+        self.hot
+            .key_counts()
+            .into_iter()
+            .zip(self.cold.key_counts())
+            .map(|((columns, hot), (_, cold))| (columns, hot + cold))
+            .collect()
+    }
+
+    fn keys(&self) -> Vec<Vec<usize>> {
+        self.hot.keys()
+    }
+
+    fn cloned_records(&self) -> Vec<Vec<DataType>> {
+        unreachable!("can't clone records of partial state")
+    }
+
+    // The chosen keys are moved to disk rather than evicted, so none are returned: their rows are
+    // still here, and so downstream state derived from them stays valid.
+    fn evict_some_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        self.promote();
+        let (columns, demoted, bytes) = self.hot.take_some_keys(count);
+        let tag = match self.tags.get(columns) {
+            Some(&tag) => tag,
+            None => {
+                // no replay path fills the index, so its keys can't be moved to disk as filled
+                let evicted = demoted.into_iter().map(|(key, _)| key).collect();
+                return (columns, evicted, bytes);
+            }
+        };
+        for (key, rows) in demoted {
+            self.cold.mark_filled(key, tag);
+            let mut records: Records = rows.into();
+            self.cold.process_records(&mut records, Some(tag));
+        }
+        (columns, Vec::new(), bytes)
+    }
+
+    fn evict_keys(&mut self, tag: Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
+        self.promote();
+        self.cold.evict_keys(tag, keys);
+        self.hot.evict_keys(tag, keys)
+    }
+
+    fn clear(&mut self) {
+        self.promotions.get_mut().clear();
+        self.hot.clear();
+        self.cold.clear();
+    }
+}
+
+impl TieredState {
+    /// Create empty state that evicts keys from memory according to `eviction`, and keeps them in
+    /// RocksDB under the given name.
+    pub(crate) fn new(
+        name: String,
+        params: &PersistenceParameters,
+        eviction: EvictionPolicy,
+    ) -> Self {
+        TieredState {
+            hot: MemoryState::with_eviction_policy(eviction),
+            cold: PersistentState::new_partial(name, params, eviction),
+            tags: HashMap::new(),
+            promotions: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Move the keys that lookups have found on disk back into memory.
+    fn promote(&mut self) {
+        let promotions = mem::replace(self.promotions.get_mut(), Vec::new());
+        for (columns, key) in promotions {
+            let rows: Vec<_> = match self.cold.lookup(&columns, &KeyType::from(&key)) {
+                LookupResult::Some(rows) => rows.into_iter().map(|r| r.into_owned()).collect(),
+                // the same key was looked up more than once
+                LookupResult::Missing => continue,
+            };
+
+            let tag = match self.tags.get(&columns) {
+                Some(&tag) => tag,
+                None => continue,
+            };
+            self.cold.mark_hole(&key, tag);
+            self.hot.mark_filled(key, tag);
+            let mut records: Records = rows.into();
+            self.hot.process_records(&mut records, Some(tag));
+        }
+    }
+}
+
+fn key_to_vec(key: &KeyType) -> Vec<DataType> {
+    match *key {
+        KeyType::Single(k) => vec![k.clone()],
+        KeyType::Double(ref k) => vec![k.0.clone(), k.1.clone()],
+        KeyType::Tri(ref k) => vec![k.0.clone(), k.1.clone(), k.2.clone()],
+        KeyType::Quad(ref k) => vec![k.0.clone(), k.1.clone(), k.2.clone(), k.3.clone()],
+        KeyType::Quin(ref k) => vec![
+            k.0.clone(),
+            k.1.clone(),
+            k.2.clone(),
+            k.3.clone(),
+            k.4.clone(),
+        ],
+        KeyType::Sex(ref k) => vec![
+            k.0.clone(),
+            k.1.clone(),
+            k.2.clone(),
+            k.3.clone(),
+            k.4.clone(),
+            k.5.clone(),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(prefix: &str) -> TieredState {
+        let mut state = TieredState::new(
+            String::from(prefix),
+            &PersistenceParameters::default(),
+            EvictionPolicy::Random,
+        );
+        state.add_key(&[0], Some(vec![Tag(0)]));
+        for i in 0..10 {
+            state.mark_filled(vec![i.into()], Tag(0));
+            let record: Record = vec![i.into(), "Cat".into()].into();
+            state.process_records(&mut record.into(), Some(Tag(0)));
+        }
+        state
+    }
+
+    fn rows(state: &TieredState, key: i32) -> Option<Vec<Vec<DataType>>> {
+        match state.lookup(&[0], &KeyType::Single(&key.into())) {
+            LookupResult::Some(rows) => Some(rows.into_iter().map(|r| r.into_owned()).collect()),
+            LookupResult::Missing => None,
+        }
+    }
+
+    #[test]
+    fn tiered_state_demotes_instead_of_evicting() {
+        let mut state = setup("tiered_state_demotes_instead_of_evicting");
+        let size = state.deep_size_of();
+
+        let (columns, evicted, bytes) = state.evict_some_keys(10);
+        assert_eq!(columns, &[0]);
+        assert!(evicted.is_empty());
+        assert_eq!(bytes, size);
+        assert_eq!(state.deep_size_of(), 0);
+
+        for i in 0..10 {
+            assert_eq!(rows(&state, i), Some(vec![vec![i.into(), "Cat".into()]]));
+        }
+    }
+
+    #[test]
+    fn tiered_state_promotes_on_lookup() {
+        let mut state = setup("tiered_state_promotes_on_lookup");
+        state.evict_some_keys(10);
+        assert!(rows(&state, 1).is_some());
+
+        // writes to keys on disk are not lost
+        let record: Record = vec![2.into(), "Dog".into()].into();
+        let mut records: Records = record.into();
+        state.process_records(&mut records, None);
+        assert_eq!(records.len(), 1);
+        assert_eq!(rows(&state, 2).unwrap().len(), 2);
+
+        // the key that was looked up is back in memory
+        assert!(state.deep_size_of() > 0);
+        match state.hot.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(rows) => assert_eq!(rows.len(), 1),
+            LookupResult::Missing => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn tiered_state_forwards_writes_to_keys_in_either_tier() {
+        let mut state = setup("tiered_state_forwards_writes_to_keys_in_either_tier");
+        state.evict_some_keys(5);
+
+        let mut records: Records = vec![
+            (vec![1.into(), "Dog".into()], true),
+            (vec![42.into(), "Dog".into()], true),
+            (vec![7.into(), "Dog".into()], true),
+        ]
+        .into();
+        state.process_records(&mut records, None);
+        // the key that was never filled is a hole in both tiers
+        let expected: Records = vec![
+            (vec![1.into(), "Dog".into()], true),
+            (vec![7.into(), "Dog".into()], true),
+        ]
+        .into();
+        assert_eq!(records, expected);
+        assert_eq!(rows(&state, 1).unwrap().len(), 2);
+        assert_eq!(rows(&state, 7).unwrap().len(), 2);
+        assert!(rows(&state, 42).is_none());
+    }
+
+    #[test]
+    fn tiered_state_evicts_keys_of_untagged_indices() {
+        let mut state = TieredState::new(
+            String::from("tiered_state_evicts_keys_of_untagged_indices"),
+            &PersistenceParameters::default(),
+            EvictionPolicy::Random,
+        );
+        state.add_key(&[0], Some(vec![]));
+        let (columns, evicted, bytes) = state.evict_some_keys(1);
+        assert_eq!(columns, &[0]);
+        assert!(evicted.is_empty());
+        assert_eq!(bytes, 0);
+    }
+
+    #[test]
+    fn tiered_state_evicts_from_both_tiers() {
+        let mut state = setup("tiered_state_evicts_from_both_tiers");
+        state.evict_some_keys(5);
+        let keys: Vec<_> = (0..10).map(|i| vec![i.into()]).collect();
+        assert!(state.evict_keys(Tag(0), &keys).is_some());
+        for i in 0..10 {
+            assert!(rows(&state, i).is_none());
+        }
+    }
+}
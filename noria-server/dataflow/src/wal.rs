@@ -0,0 +1,410 @@
+//! An append-only write-ahead log of the writes to a base table.
+//!
+//! Each entry holds one batch of operations, as merged by the group commit queue, and starts with
+//! the batch's length and a CRC-32 checksum of its encoding. A crash may leave a partially written
+//! entry at the end of the log, which is discarded when the log is opened again. An entry that
+//! doesn't check out anywhere else means the log is corrupt, and it is not opened at all, since the
+//! writes after that entry can't be applied without the ones in it.
+//!
+//! Once the log has grown to twice the size it had when it last held only the table's rows, it is
+//! compacted: it is replaced by a log that inserts the table's current rows.
+
+use crate::FsyncPolicy;
+use noria::{DataType, TableOperation};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time;
+
+/// The length of the header of each entry: a 32-bit length, followed by a 32-bit checksum.
+const HEADER_LEN: usize = 8;
+
+/// Logs smaller than this are never compacted.
+const MIN_COMPACTION_LEN: u64 = 16 << 20;
+
+/// The number of rows in each entry of a compacted log.
+const COMPACTED_ENTRY_ROWS: usize = 1024;
+
+pub(crate) struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    fsync: FsyncPolicy,
+    last_sync: time::Instant,
+    unsynced: bool,
+    /// The length of the log.
+    len: u64,
+    /// The length of the log after it was last compacted, or when it was opened.
+    compacted_len: u64,
+}
+
+impl WriteAheadLog {
+    /// Open the log at the given path, creating it if it doesn't exist, and return it along with
+    /// the batches of operations that it already holds.
+    pub(crate) fn open(
+        path: &Path,
+        fsync: FsyncPolicy,
+    ) -> io::Result<(Self, Vec<Vec<TableOperation>>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut batches = Vec::new();
+        let mut valid = 0;
+        while valid < data.len() {
+            match decode_entry(&data[valid..]) {
+                Decoded::Entry(batch, len) => {
+                    batches.push(batch);
+                    valid += len;
+                }
+                Decoded::Partial => break,
+                // the last entry was torn, or the file grew before its bytes made it to disk
+                Decoded::Corrupt(len)
+                    if valid + len == data.len() || data[valid..].iter().all(|&b| b == 0) =>
+                {
+                    break
+                }
+                Decoded::Corrupt(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "corrupt entry at byte {} of {}, before the end of the log",
+                            valid,
+                            data.len()
+                        ),
+                    ));
+                }
+            }
+        }
+        if valid < data.len() {
+            // the last write was cut short, so new entries have to go where it started
+            file.set_len(valid as u64)?;
+            file.sync_all()?;
+        }
+
+        let log = WriteAheadLog {
+            path: path.to_owned(),
+            file,
+            fsync,
+            last_sync: time::Instant::now(),
+            unsynced: false,
+            len: valid as u64,
+            compacted_len: valid as u64,
+        };
+        Ok((log, batches))
+    }
+
+    /// Append a batch of operations to the log, and sync it to disk if the fsync policy says so.
+    pub(crate) fn append(&mut self, batch: &[TableOperation]) {
+        let entry = encode_entry(batch);
+        // a single write, so that a crash can only ever tear the last entry
+        self.file.write_all(&entry).unwrap();
+        self.len += entry.len() as u64;
+
+        match self.fsync {
+            FsyncPolicy::EveryBatch => self.sync(),
+            FsyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            FsyncPolicy::Interval(_) => self.unsynced = true,
+            FsyncPolicy::Never => {}
+        }
+    }
+
+    /// How long until appended entries that haven't been synced yet must be, if there are any.
+    pub(crate) fn duration_until_sync(&self) -> Option<time::Duration> {
+        match self.fsync {
+            FsyncPolicy::Interval(interval) if self.unsynced => Some(
+                interval
+                    .checked_sub(self.last_sync.elapsed())
+                    .unwrap_or(time::Duration::from_millis(0)),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Sync the entries appended since the last sync, if the fsync policy says they are due.
+    pub(crate) fn sync_if_due(&mut self) {
+        if self.duration_until_sync() == Some(time::Duration::from_millis(0)) {
+            self.sync();
+        }
+    }
+
+    fn sync(&mut self) {
+        self.file.sync_data().unwrap();
+        self.last_sync = time::Instant::now();
+        self.unsynced = false;
+    }
+
+    /// Whether the log has grown enough since it was last compacted to be compacted again.
+    pub(crate) fn needs_compaction(&self) -> bool {
+        self.len >= MIN_COMPACTION_LEN && self.len >= 2 * self.compacted_len
+    }
+
+    /// Replace the log with one that only inserts the given rows, which must be the table's
+    /// current contents.
+    ///
+    /// The new log is written next to the old one, and then moved in its place, so that a crash
+    /// leaves one of them intact.
+    pub(crate) fn compact(&mut self, rows: Vec<Vec<DataType>>) -> io::Result<()> {
+        let tmp = self.path.with_extension("wal.tmp");
+        let mut len = 0;
+        {
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            let mut rows = rows.into_iter().peekable();
+            while rows.peek().is_some() {
+                let batch: Vec<_> = rows
+                    .by_ref()
+                    .take(COMPACTED_ENTRY_ROWS)
+                    .map(TableOperation::Insert)
+                    .collect();
+                let entry = encode_entry(&batch);
+                file.write_all(&entry)?;
+                len += entry.len() as u64;
+            }
+            file.into_inner()?.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            // make the rename itself durable
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.last_sync = time::Instant::now();
+        self.unsynced = false;
+        self.len = len;
+        self.compacted_len = len;
+        Ok(())
+    }
+
+    /// Delete the log, such as when its table is dropped.
+    pub(crate) fn remove(mut self) -> io::Result<()> {
+        self.unsynced = false;
+        fs::remove_file(&self.path)
+    }
+}
+
+impl Drop for WriteAheadLog {
+    fn drop(&mut self) {
+        if self.unsynced {
+            let _ = self.file.sync_data();
+        }
+    }
+}
+
+/// Encode a batch of operations as an entry of the log.
+fn encode_entry(batch: &[TableOperation]) -> Vec<u8> {
+    let payload = bincode::serialize(batch).unwrap();
+    let mut entry = Vec::with_capacity(HEADER_LEN + payload.len());
+    entry.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    entry.extend_from_slice(&crc32(&payload).to_le_bytes());
+    entry.extend_from_slice(&payload);
+    entry
+}
+
+/// What the rest of a log starts with.
+enum Decoded {
+    /// An intact entry, along with its encoded length.
+    Entry(Vec<TableOperation>, usize),
+    /// An entry that the log ends in the middle of.
+    Partial,
+    /// A complete entry of the given encoded length, which doesn't match its checksum or doesn't
+    /// decode.
+    Corrupt(usize),
+}
+
+/// Decode the entry at the start of `buf`.
+fn decode_entry(buf: &[u8]) -> Decoded {
+    let header = match buf.get(..HEADER_LEN) {
+        Some(header) => header,
+        None => return Decoded::Partial,
+    };
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = match buf.get(HEADER_LEN..HEADER_LEN + len) {
+        Some(payload) => payload,
+        None => return Decoded::Partial,
+    };
+    if crc32fast::hash(payload) != checksum {
+        return Decoded::Corrupt(HEADER_LEN + len);
+    }
+    match bincode::deserialize(payload) {
+        Ok(batch) => Decoded::Entry(batch, HEADER_LEN + len),
+        Err(_) => Decoded::Corrupt(HEADER_LEN + len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn batch(i: i32) -> Vec<TableOperation> {
+        vec![TableOperation::Insert(vec![i.into(), "Cat".into()])]
+    }
+
+    #[test]
+    fn it_reads_back_appended_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.wal");
+        {
+            let (mut log, batches) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+            assert!(batches.is_empty());
+            log.append(&batch(1));
+            log.append(&batch(2));
+        }
+
+        let (_, batches) = WriteAheadLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(batches, vec![batch(1), batch(2)]);
+    }
+
+    #[test]
+    fn it_discards_torn_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.wal");
+        {
+            let (mut log, _) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+            log.append(&batch(1));
+            log.append(&batch(2));
+        }
+
+        // cut the last entry short, as a crash in the middle of writing it would
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        {
+            let (mut log, batches) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+            assert_eq!(batches, vec![batch(1)]);
+            log.append(&batch(3));
+        }
+
+        let (_, batches) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+        assert_eq!(batches, vec![batch(1), batch(3)]);
+    }
+
+    #[test]
+    fn it_compacts_to_the_current_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.wal");
+        {
+            let (mut log, _) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+            for i in 0..3 {
+                log.append(&batch(i));
+            }
+            log.append(&[TableOperation::Delete {
+                key: vec![0.into()],
+            }]);
+            assert!(!log.needs_compaction());
+
+            let rows: Vec<_> = (1..3)
+                .map(|i| vec![DataType::from(i), "Cat".into()])
+                .collect();
+            log.compact(rows).unwrap();
+            assert_eq!(log.len, fs::metadata(&path).unwrap().len());
+            log.append(&batch(3));
+        }
+
+        let (_, batches) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+        let mut compacted = batch(1);
+        compacted.extend(batch(2));
+        assert_eq!(batches, vec![compacted, batch(3)]);
+    }
+
+    #[test]
+    fn it_syncs_on_a_timer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.wal");
+        let interval = time::Duration::from_millis(20);
+        let (mut log, _) = WriteAheadLog::open(&path, FsyncPolicy::Interval(interval)).unwrap();
+        assert_eq!(log.duration_until_sync(), None);
+
+        log.last_sync = time::Instant::now();
+        log.append(&batch(1));
+        assert!(log.duration_until_sync().unwrap() <= interval);
+
+        std::thread::sleep(interval);
+        assert_eq!(
+            log.duration_until_sync(),
+            Some(time::Duration::from_millis(0))
+        );
+        log.sync_if_due();
+        assert_eq!(log.duration_until_sync(), None);
+    }
+
+    #[test]
+    fn it_removes_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.wal");
+        let (mut log, _) = WriteAheadLog::open(&path, FsyncPolicy::Never).unwrap();
+        log.append(&batch(1));
+        log.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_discards_corrupt_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.wal");
+        {
+            let (mut log, _) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+            log.append(&batch(1));
+        }
+
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let (_, batches) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+        assert!(batches.is_empty());
+    }
+
+    #[test]
+    fn it_discards_zeroed_tails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.wal");
+        {
+            let (mut log, _) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+            log.append(&batch(1));
+        }
+
+        // the file grew, but the last entry's bytes never made it to disk
+        let mut data = fs::read(&path).unwrap();
+        data.extend(vec![0; 64]);
+        fs::write(&path, data).unwrap();
+
+        let (_, batches) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+        assert_eq!(batches, vec![batch(1)]);
+    }
+
+    #[test]
+    fn it_refuses_logs_corrupt_before_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.wal");
+        {
+            let (mut log, _) = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch).unwrap();
+            log.append(&batch(1));
+            log.append(&batch(2));
+        }
+
+        let mut data = fs::read(&path).unwrap();
+        let len = data.len();
+        data[HEADER_LEN] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let e = WriteAheadLog::open(&path, FsyncPolicy::EveryBatch)
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        // the log is left as it was, for someone to look at
+        assert_eq!(fs::metadata(&path).unwrap().len(), len as u64);
+    }
+}
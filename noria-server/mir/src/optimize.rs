@@ -1,23 +1,413 @@
+use crate::column::Column;
 use crate::node::{MirNode, MirNodeType};
 use crate::query::MirQuery;
 use crate::MirNodeRef;
-use dataflow::ops::filter::FilterCondition;
+use dataflow::ops::filter::{FilterCondition, Value};
 use dataflow::ops::grouped::aggregate::Aggregation;
 use dataflow::ops::grouped::filteraggregate::FilterAggregation;
+use nom_sql::ArithmeticBase;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+/// A single rewrite of a MIR query. Passes mutate the query in place and return any nodes they
+/// created, so that the caller can register them.
+type Pass = fn(&mut MirQuery) -> Vec<MirNodeRef>;
+
+/// Passes run over a newly converted query, before we look for reuse opportunities.
+const PASSES: &[Pass] = &[
+    find_and_merge_filter_aggregates,
+    push_filters_below_joins,
+    prune_unused_columns,
+    remove_extraneous_projections,
+];
+
+/// Passes run once reuse has been decided; these must not create new nodes.
+const POST_REUSE_PASSES: &[Pass] = &[remove_extraneous_projections];
+
+fn run_passes(q: &mut MirQuery, passes: &[Pass]) -> Vec<MirNodeRef> {
+    let mut new_nodes = Vec::new();
+    for pass in passes {
+        new_nodes.extend(pass(q));
+    }
+    new_nodes
+}
 
 // Mutate the given MirQuery in order to optimize it,
 // for example by merging certain nodes together.
 // Return a list of any new nodes created so that the caller
 // can add them to any other internal representations.
-pub fn optimize(mut q: &mut MirQuery) -> Vec<MirNodeRef> {
-    //remove_extraneous_projections(&mut q);
-    find_and_merge_filter_aggregates(&mut q)
+pub fn optimize(q: &mut MirQuery) -> Vec<MirNodeRef> {
+    run_passes(q, PASSES)
+}
+
+pub fn optimize_post_reuse(q: &mut MirQuery) {
+    let new_nodes = run_passes(q, POST_REUSE_PASSES);
+    assert!(new_nodes.is_empty());
+}
+
+/// Returns the nodes of `q` in topological order. We walk up from the leaf rather than down from
+/// the roots, since the roots may be shared with other queries whose nodes we must not visit.
+fn query_nodes(q: &MirQuery) -> Vec<MirNodeRef> {
+    fn visit(n: &MirNodeRef, seen: &mut HashSet<String>, nodes: &mut Vec<MirNodeRef>) {
+        if !seen.insert(n.borrow().versioned_name()) {
+            return;
+        }
+        for a in n.borrow().ancestors() {
+            visit(a, seen, nodes);
+        }
+        nodes.push(n.clone());
+    }
+
+    let mut nodes = Vec::new();
+    visit(&q.leaf, &mut HashSet::new(), &mut nodes);
+    nodes
+}
+
+/// Whether `n` belongs to the query being optimized and may be rewritten. Base nodes, reused nodes
+/// and nodes that already exist in the data-flow graph are shared with other queries.
+fn is_rewritable(n: &MirNodeRef) -> bool {
+    let n = n.borrow();
+    match n.inner {
+        MirNodeType::Base { .. } | MirNodeType::Reuse { .. } | MirNodeType::Leaf { .. } => false,
+        _ => n.flow_node.is_none(),
+    }
+}
+
+/// The node directly above a query's leaf carries the query's name, so we never remove or move it.
+fn feeds_leaf(n: &MirNodeRef) -> bool {
+    n.borrow()
+        .children()
+        .iter()
+        .any(|c| match c.borrow().inner {
+            MirNodeType::Leaf { .. } => true,
+            _ => false,
+        })
+}
+
+fn same_node(a: &MirNodeRef, b: &MirNodeRef) -> bool {
+    a.borrow().versioned_name() == b.borrow().versioned_name()
+}
+
+/// Replace `old` with `new` in `list`, keeping its position.
+fn replace_in(list: &mut Vec<MirNodeRef>, old: &MirNodeRef, new: &MirNodeRef) {
+    let pos = list
+        .iter()
+        .position(|n| same_node(n, old))
+        .expect("node not linked to its neighbour");
+    list[pos] = new.clone();
+}
+
+/// Moves filters that sit directly below a join up to the side of the join that produces all the
+/// columns they filter on, so that rows are dropped before they are joined. We only move filters
+/// to the left side of left joins, since a filter on the right side would change which rows end
+/// up padded with NULLs.
+fn push_filters_below_joins(q: &mut MirQuery) -> Vec<MirNodeRef> {
+    // every push moves a filter one join further up, so this terminates
+    while query_nodes(q).iter().any(push_filter_below_join) {}
+    vec![]
+}
+
+fn push_filter_below_join(f: &MirNodeRef) -> bool {
+    if !is_rewritable(f) || f.borrow().ancestors.len() != 1 || feeds_leaf(f) {
+        return false;
+    }
+    let j = f.borrow().ancestors[0].clone();
+    if !is_rewritable(&j) || j.borrow().children.len() != 1 || j.borrow().ancestors.len() != 2 {
+        return false;
+    }
+    let allow_right = match j.borrow().inner {
+        MirNodeType::Join { .. } => true,
+        MirNodeType::LeftJoin { .. } => false,
+        _ => return false,
+    };
+    let (left, right) = {
+        let jn = j.borrow();
+        (jn.ancestors[0].clone(), jn.ancestors[1].clone())
+    };
+    if same_node(&left, &right) || f.borrow().columns.len() != j.borrow().columns.len() {
+        return false;
+    }
+
+    // figure out which side every column the filter touches comes from, and where it lives there
+    let mut side = None;
+    let mut reindex = |i: usize| -> Option<usize> {
+        let col = j.borrow().columns[i].clone();
+        let (s, pos) = match left.borrow().columns.iter().position(|c| *c == col) {
+            Some(pos) => (0, pos),
+            None if allow_right => (1, right.borrow().columns.iter().position(|c| *c == col)?),
+            None => return None,
+        };
+        if *side.get_or_insert(s) != s {
+            return None;
+        }
+        Some(pos)
+    };
+    let conditions = match f.borrow().inner {
+        MirNodeType::Filter { ref conditions } => conditions.clone(),
+        _ => return false,
+    };
+    let mut new_conditions = Vec::with_capacity(conditions.len());
+    for (i, cond) in conditions {
+        let cond = match cond {
+            FilterCondition::Comparison(op, Value::Column(k)) => match reindex(k) {
+                Some(k) => FilterCondition::Comparison(op, Value::Column(k)),
+                None => return false,
+            },
+            cond => cond,
+        };
+        match reindex(i) {
+            Some(i) => new_conditions.push((i, cond)),
+            None => return false,
+        }
+    }
+    let (side, s) = match side {
+        Some(0) => (0, left),
+        Some(_) => (1, right),
+        None => return false,
+    };
+
+    // splice the filter out from below the join...
+    let children = f.borrow().children.clone();
+    for c in &children {
+        replace_in(&mut c.borrow_mut().ancestors, f, &j);
+    }
+    j.borrow_mut().children = children;
+
+    // ...and back in between the join and its input
+    replace_in(&mut s.borrow_mut().children, &j, f);
+    j.borrow_mut().ancestors[side] = f.clone();
+    {
+        let mut fnode = f.borrow_mut();
+        fnode.columns = s.borrow().columns.clone();
+        fnode.inner = MirNodeType::Filter {
+            conditions: new_conditions,
+        };
+        fnode.ancestors = vec![s.clone()];
+        fnode.children = vec![j.clone()];
+    }
+    true
 }
 
-pub fn optimize_post_reuse(_q: &mut MirQuery) {
-    // find_and_merge_filter_chains(q);
+/// Returns the columns of its parent that `n` reads, or `None` if `n` needs all of them (e.g.,
+/// because it refers to them by position).
+fn required_parent_columns(n: &MirNodeRef) -> Option<Vec<Column>> {
+    let n = n.borrow();
+    let cols = match n.inner {
+        MirNodeType::Project {
+            ref emit,
+            ref arithmetic,
+            ..
+        } => {
+            let mut cols = emit.clone();
+            for (_, e) in arithmetic {
+                for b in &[&e.left, &e.right] {
+                    if let ArithmeticBase::Column(c) = b {
+                        cols.push(Column::from(c));
+                    }
+                }
+            }
+            cols
+        }
+        MirNodeType::Join {
+            ref on_left,
+            ref on_right,
+            ref project,
+        }
+        | MirNodeType::LeftJoin {
+            ref on_left,
+            ref on_right,
+            ref project,
+        } => {
+            let mut cols = project.clone();
+            cols.extend(on_left.iter().cloned());
+            cols.extend(on_right.iter().cloned());
+            cols
+        }
+        MirNodeType::Aggregation { .. }
+        | MirNodeType::Extremum { .. }
+        | MirNodeType::GroupConcat { .. } => n.referenced_columns(),
+        _ => return None,
+    };
+    Some(cols)
+}
+
+/// Drops columns from joins and projections that nothing further down the query reads, so that
+/// narrower records flow through the joins. We visit nodes bottom-up so that pruning a node can in
+/// turn allow its ancestors to be pruned.
+fn prune_unused_columns(q: &mut MirQuery) -> Vec<MirNodeRef> {
+    for n in query_nodes(q).iter().rev() {
+        if !is_rewritable(n) || n.borrow().children.len() != 1 || feeds_leaf(n) {
+            continue;
+        }
+        let child = n.borrow().children[0].clone();
+        let needed = match required_parent_columns(&child) {
+            Some(needed) => needed,
+            None => continue,
+        };
+
+        let mut node = n.borrow_mut();
+        let mut keep: Vec<bool> = node.columns.iter().map(|c| needed.contains(c)).collect();
+        let MirNode {
+            ref mut columns,
+            ref mut inner,
+            ..
+        } = *node;
+        match *inner {
+            MirNodeType::Join {
+                ref on_left,
+                ref on_right,
+                ref mut project,
+            }
+            | MirNodeType::LeftJoin {
+                ref on_left,
+                ref on_right,
+                ref mut project,
+            } => {
+                if project.len() != columns.len() {
+                    continue;
+                }
+                // the join keys are needed to compute the join itself
+                for (k, c) in keep.iter_mut().zip(project.iter()) {
+                    *k = *k || on_left.contains(c) || on_right.contains(c);
+                }
+                retain_positions(project, &keep);
+            }
+            MirNodeType::Project { ref mut emit, .. } => {
+                // only emitted columns may go; arithmetic and literals are kept
+                for k in keep.iter_mut().skip(emit.len()) {
+                    *k = true;
+                }
+                if !keep.iter().any(|k| *k) {
+                    continue;
+                }
+                retain_positions(emit, &keep);
+            }
+            _ => continue,
+        }
+        retain_positions(columns, &keep);
+    }
+    vec![]
+}
+
+/// Keeps the elements of `v` whose position is marked in `keep`.
+fn retain_positions<T>(v: &mut Vec<T>, keep: &[bool]) {
+    let mut i = 0;
+    v.retain(|_| {
+        i += 1;
+        keep[i - 1]
+    });
+}
+
+/// Removes projections that do not change their input, and merges chains of projections that only
+/// select or permute columns into a single projection.
+fn remove_extraneous_projections(q: &mut MirQuery) -> Vec<MirNodeRef> {
+    for n in query_nodes(q) {
+        if !is_rewritable(&n) || n.borrow().ancestors.len() != 1 {
+            continue;
+        }
+        let parent = n.borrow().ancestors[0].clone();
+        if !feeds_leaf(&n) && is_identity_projection(&n, &parent) {
+            bypass_node(&n, &parent);
+        } else if is_rewritable(&parent) {
+            merge_projections(&parent, &n);
+        }
+    }
+    vec![]
+}
+
+/// Whether `n` is a projection that emits exactly `parent`'s columns, in the same order and under
+/// the same names.
+fn is_identity_projection(n: &MirNodeRef, parent: &MirNodeRef) -> bool {
+    let n = n.borrow();
+    let parent = parent.borrow();
+    match n.inner {
+        MirNodeType::Project {
+            ref emit,
+            ref arithmetic,
+            ref literals,
+        } if arithmetic.is_empty() && literals.is_empty() => {
+            emit.len() == parent.columns.len()
+                && emit.iter().zip(parent.columns.iter()).all(|(e, p)| e == p)
+                && n.columns
+                    .iter()
+                    .zip(parent.columns.iter())
+                    .all(|(c, p)| c.name == p.name && c.table == p.table)
+        }
+        _ => false,
+    }
+}
+
+/// Unlinks `n` from the graph, connecting its children straight to its only ancestor `parent`.
+fn bypass_node(n: &MirNodeRef, parent: &MirNodeRef) {
+    let children = n.borrow().children.clone();
+    // a child that already reads from `parent` would end up with it as an ancestor twice
+    if children
+        .iter()
+        .any(|c| c.borrow().ancestors().iter().any(|a| same_node(a, parent)))
+    {
+        return;
+    }
+
+    for c in &children {
+        replace_in(&mut c.borrow_mut().ancestors, n, parent);
+    }
+    {
+        let mut p = parent.borrow_mut();
+        p.children.retain(|c| !same_node(c, n));
+        p.children.extend(children);
+    }
+    let mut n = n.borrow_mut();
+    n.ancestors.clear();
+    n.children.clear();
+}
+
+/// Folds the column-only projection `upper` into its sole child projection `lower`.
+fn merge_projections(upper: &MirNodeRef, lower: &MirNodeRef) {
+    if upper.borrow().ancestors.len() != 1 || upper.borrow().children.len() != 1 {
+        return;
+    }
+    let new_emit = {
+        let up = upper.borrow();
+        let low = lower.borrow();
+        let up_emit = match up.inner {
+            MirNodeType::Project {
+                ref emit,
+                ref arithmetic,
+                ref literals,
+            } if arithmetic.is_empty() && literals.is_empty() => emit,
+            _ => return,
+        };
+        let low_emit = match low.inner {
+            // arithmetic refers to the parent's columns by name, which may not survive the merge
+            MirNodeType::Project {
+                ref emit,
+                ref arithmetic,
+                ..
+            } if arithmetic.is_empty() => emit,
+            _ => return,
+        };
+        let mut new_emit = Vec::with_capacity(low_emit.len());
+        for e in low_emit {
+            match up.columns.iter().position(|c| c == e) {
+                Some(i) => new_emit.push(up_emit[i].clone()),
+                None => return,
+            }
+        }
+        new_emit
+    };
+
+    let grandparent = upper.borrow().ancestors[0].clone();
+    replace_in(&mut grandparent.borrow_mut().children, upper, lower);
+    {
+        let mut low = lower.borrow_mut();
+        low.ancestors = vec![grandparent];
+        if let MirNodeType::Project { ref mut emit, .. } = low.inner {
+            *emit = new_emit;
+        }
+    }
+    let mut up = upper.borrow_mut();
+    up.ancestors.clear();
+    up.children.clear();
 }
 
 fn find_and_merge_filter_aggregates(q: &mut MirQuery) -> Vec<MirNodeRef> {
@@ -350,8 +740,188 @@ fn to_conditions(chained_filters: &[MirNodeRef]) -> Vec<(usize, FilterCondition)
     merged_conditions
 }
 
-// currently unused
-#[allow(dead_code)]
-fn remove_extraneous_projections(_q: &mut MirQuery) {
-    unimplemented!()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visualize::GraphViz;
+    use dataflow::ops::filter::Operator;
+    use nom_sql::{self, ColumnSpecification, SqlType};
+
+    fn base(name: &str, cols: &[&str]) -> MirNodeRef {
+        let cspec = |n: &str| -> (ColumnSpecification, Option<usize>) {
+            (
+                ColumnSpecification::new(nom_sql::Column::from(n), SqlType::Text),
+                None,
+            )
+        };
+        MirNode::new(
+            name,
+            0,
+            cols.iter().map(|c| Column::from(*c)).collect(),
+            MirNodeType::Base {
+                column_specs: cols.iter().map(|c| cspec(c)).collect(),
+                keys: vec![Column::from(cols[0])],
+                adapted_over: None,
+            },
+            vec![],
+            vec![],
+        )
+    }
+
+    fn project(name: &str, cols: &[&str], parent: MirNodeRef) -> MirNodeRef {
+        let cols: Vec<_> = cols.iter().map(|c| Column::from(*c)).collect();
+        MirNode::new(
+            name,
+            0,
+            cols.clone(),
+            MirNodeType::Project {
+                emit: cols,
+                arithmetic: vec![],
+                literals: vec![],
+            },
+            vec![parent],
+            vec![],
+        )
+    }
+
+    fn leaf(parent: MirNodeRef) -> MirNodeRef {
+        let cols = parent.borrow().columns.clone();
+        MirNode::new(
+            "q",
+            0,
+            cols.clone(),
+            MirNodeType::Leaf {
+                node: parent.clone(),
+                keys: vec![cols[0].clone()],
+            },
+            vec![parent],
+            vec![],
+        )
+    }
+
+    // a(aa, ab) ⋈ b(ba, bb) on ab = bb, followed by σ[f] and π[emit]
+    fn join_query(left_join: bool, f: usize, emit: &[&str]) -> (MirQuery, MirNodeRef) {
+        let a = base("a", &["aa", "ab"]);
+        let b = base("b", &["ba", "bb"]);
+        let cols = vec![Column::from("aa"), Column::from("ab"), Column::from("ba")];
+        let (on_left, on_right) = (vec![Column::from("ab")], vec![Column::from("bb")]);
+        let inner = if left_join {
+            MirNodeType::LeftJoin {
+                on_left,
+                on_right,
+                project: cols.clone(),
+            }
+        } else {
+            MirNodeType::Join {
+                on_left,
+                on_right,
+                project: cols.clone(),
+            }
+        };
+        let j = MirNode::new(
+            "j",
+            0,
+            cols.clone(),
+            inner,
+            vec![a.clone(), b.clone()],
+            vec![],
+        );
+        let filter = MirNode::new(
+            "f",
+            0,
+            cols,
+            MirNodeType::Filter {
+                conditions: vec![(
+                    f,
+                    FilterCondition::Comparison(Operator::Equal, Value::Constant(5.into())),
+                )],
+            },
+            vec![j.clone()],
+            vec![],
+        );
+        let p = project("p", emit, filter);
+        let q = MirQuery {
+            name: "q".into(),
+            roots: vec![a, b],
+            leaf: leaf(p),
+        };
+        (q, j)
+    }
+
+    fn edge(from: &str, to: &str) -> String {
+        format!("\"{}_v0\" -> \"{}_v0\"", from, to)
+    }
+
+    #[test]
+    fn it_removes_extraneous_projections() {
+        let a = base("a", &["aa", "ab"]);
+        let p1 = project("p1", &["aa", "ab"], a.clone());
+        let p2 = project("p2", &["ab", "aa"], p1);
+        let p3 = project("p3", &["ab"], p2);
+        let mut q = MirQuery {
+            name: "q".into(),
+            roots: vec![a],
+            leaf: leaf(p3),
+        };
+
+        optimize(&mut q);
+        let g = q.to_graphviz().unwrap();
+        assert!(g.contains(&edge("a", "p3")), "{}", g);
+        assert!(g.contains(&edge("p3", "q")), "{}", g);
+        assert!(!g.contains("p1_v0"), "{}", g);
+        assert!(!g.contains("p2_v0"), "{}", g);
+        assert!(g.contains("π: ab |"), "{}", g);
+    }
+
+    #[test]
+    fn it_keeps_the_projection_that_names_the_query() {
+        let a = base("a", &["aa", "ab"]);
+        let p = project("p", &["aa", "ab"], a.clone());
+        let mut q = MirQuery {
+            name: "q".into(),
+            roots: vec![a],
+            leaf: leaf(p),
+        };
+
+        optimize(&mut q);
+        let g = q.to_graphviz().unwrap();
+        assert!(g.contains(&edge("a", "p")), "{}", g);
+        assert!(g.contains(&edge("p", "q")), "{}", g);
+    }
+
+    #[test]
+    fn it_pushes_filters_below_joins() {
+        // filter on b.ba, which sits at index 2 below the join and at index 0 in b
+        let (mut q, j) = join_query(false, 2, &["aa"]);
+
+        optimize(&mut q);
+        let g = q.to_graphviz().unwrap();
+        assert!(g.contains(&edge("a", "j")), "{}", g);
+        assert!(g.contains(&edge("b", "f")), "{}", g);
+        assert!(g.contains(&edge("f", "j")), "{}", g);
+        assert!(g.contains(&edge("j", "p")), "{}", g);
+        assert!(g.contains("σ: f0 = 5"), "{}", g);
+
+        // nothing below the join reads ba any more, so it is pruned; the join key stays
+        assert_eq!(
+            j.borrow().columns,
+            vec![Column::from("aa"), Column::from("ab")]
+        );
+    }
+
+    #[test]
+    fn it_only_pushes_filters_to_the_left_of_left_joins() {
+        let (mut q, _) = join_query(true, 2, &["aa", "ba"]);
+        optimize(&mut q);
+        let g = q.to_graphviz().unwrap();
+        assert!(g.contains(&edge("j", "f")), "{}", g);
+        assert!(g.contains("σ: f2 = 5"), "{}", g);
+
+        let (mut q, _) = join_query(true, 0, &["aa", "ba"]);
+        optimize(&mut q);
+        let g = q.to_graphviz().unwrap();
+        assert!(g.contains(&edge("a", "f")), "{}", g);
+        assert!(g.contains(&edge("f", "j")), "{}", g);
+        assert!(g.contains("σ: f0 = 5"), "{}", g);
+    }
 }
@@ -0,0 +1,676 @@
+//! Export the contents of base tables and views to files, and import rows from such files into
+//! base tables.
+//!
+//! Three file formats are supported:
+//!
+//!  - `csv`: a header line with the column names, followed by one line per row. Fields that
+//!    contain commas, quotes, or line breaks are quoted, and an empty unquoted field is `NULL`.
+//!  - `json`: one JSON object per line, mapping column names to values.
+//!  - `columnar`: the column names, followed by groups of rows that are stored column by column,
+//!    all encoded with bincode.
+//!
+//! Base tables are exported straight from their state, a batch of rows at a time, and views must
+//! be fully materialized to be exported.
+//!
+//! When importing, values are parsed according to the types of the base table's columns, and rows
+//! are written to the table in batches as they are read. Columns that are missing from the file
+//! are `NULL`.
+
+use chrono::NaiveDateTime;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{format_err, Error, ResultExt};
+use nom_sql::SqlType;
+use noria::{ControllerHandle, DataType, Table, ZookeeperAuthority};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::{iter, mem};
+
+/// The format of timestamps in CSV and JSON files.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// The number of rows in each group of a columnar file.
+const ROWS_PER_GROUP: usize = 1024;
+
+/// The number of rows of a base table that are read from it at a time when it is exported.
+const EXPORT_BATCH: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Csv,
+    Json,
+    Columnar,
+}
+
+impl Format {
+    fn from_args(args: &ArgMatches<'_>) -> Self {
+        match args.value_of("format").unwrap() {
+            "csv" => Format::Csv,
+            "json" => Format::Json,
+            "columnar" => Format::Columnar,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// How the values of a column are parsed when they are imported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Integer,
+    Real,
+    Timestamp,
+    Text,
+}
+
+impl Kind {
+    fn of(ty: &SqlType) -> Self {
+        match *ty {
+            SqlType::Bool
+            | SqlType::Tinyint(_)
+            | SqlType::UnsignedTinyint(_)
+            | SqlType::Int(_)
+            | SqlType::UnsignedInt(_)
+            | SqlType::Bigint(_)
+            | SqlType::UnsignedBigint(_) => Kind::Integer,
+            SqlType::Double | SqlType::Float | SqlType::Real => Kind::Real,
+            SqlType::Timestamp => Kind::Timestamp,
+            _ => Kind::Text,
+        }
+    }
+
+    fn parse(self, s: &str) -> Result<DataType, Error> {
+        Ok(match self {
+            Kind::Integer => s.trim().parse::<i64>()?.into(),
+            Kind::Real => s.trim().parse::<f64>()?.into(),
+            Kind::Timestamp => {
+                DataType::Timestamp(NaiveDateTime::parse_from_str(s.trim(), TIMESTAMP_FORMAT)?)
+            }
+            Kind::Text => s.into(),
+        })
+    }
+}
+
+/// The name and kind of each column of the given base table.
+fn columns_of(table: &Table) -> Vec<(String, Kind)> {
+    table
+        .columns()
+        .iter()
+        .map(|name| {
+            let kind = table
+                .schema()
+                .and_then(|schema| schema.fields.iter().find(|f| &f.column.name == name))
+                .map(|f| Kind::of(&f.sql_type))
+                .unwrap_or(Kind::Text);
+            (name.clone(), kind)
+        })
+        .collect()
+}
+
+/// The position of the column with the given name in `columns`.
+fn position(columns: &[(String, Kind)], name: &str) -> Result<usize, Error> {
+    columns
+        .iter()
+        .position(|(c, _)| c == name)
+        .ok_or_else(|| format_err!("table has no column named {}", name))
+}
+
+/// The textual form of a value in CSV and JSON files, or `None` if the value is `NULL`.
+fn to_text(v: &DataType) -> Option<Cow<'_, str>> {
+    match *v {
+        DataType::None => None,
+        DataType::Text(..) | DataType::TinyText(..) => Some(v.into()),
+        DataType::Timestamp(ts) => Some(ts.format(TIMESTAMP_FORMAT).to_string().into()),
+        _ => Some(v.to_string().into()),
+    }
+}
+
+fn to_json(v: &DataType) -> Value {
+    match *v {
+        DataType::None => Value::Null,
+        DataType::Int(n) => n.into(),
+        DataType::UnsignedInt(n) => n.into(),
+        DataType::BigInt(n) => n.into(),
+        DataType::UnsignedBigInt(n) => n.into(),
+        DataType::Real(..) => Into::<f64>::into(v).into(),
+        _ => Value::String(to_text(v).unwrap().into_owned()),
+    }
+}
+
+fn from_json(kind: Kind, v: &Value) -> Result<DataType, Error> {
+    match *v {
+        Value::Null => Ok(DataType::None),
+        Value::Bool(b) => kind.parse(if b { "1" } else { "0" }),
+        Value::Number(ref n) => kind.parse(&n.to_string()),
+        Value::String(ref s) => kind.parse(s),
+        _ => Err(format_err!("can't import JSON value {}", v)),
+    }
+}
+
+fn write_csv_record<'a, W, I>(out: &mut W, fields: I) -> io::Result<()>
+where
+    W: Write + ?Sized,
+    I: IntoIterator<Item = Option<Cow<'a, str>>>,
+{
+    for (i, field) in fields.into_iter().enumerate() {
+        if i != 0 {
+            out.write_all(b",")?;
+        }
+        match field {
+            None => {}
+            // empty strings are quoted so that they aren't read back as NULL
+            Some(s)
+                if s.is_empty()
+                    || s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') =>
+            {
+                write!(out, "\"{}\"", s.replace('"', "\"\""))?
+            }
+            Some(s) => out.write_all(s.as_bytes())?,
+        }
+    }
+    out.write_all(b"\n")
+}
+
+/// Read the next record from a CSV file, or `None` at the end of the file. `NULL` fields are
+/// returned as `None`.
+fn read_csv_record<R: BufRead>(input: &mut R) -> Result<Option<Vec<Option<String>>>, Error> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut in_quotes = false;
+    loop {
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if in_quotes {
+                if c != '"' {
+                    field.push(c);
+                } else if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+                continue;
+            }
+
+            match c {
+                '"' => {
+                    in_quotes = true;
+                    quoted = true;
+                }
+                ',' => {
+                    fields.push(csv_field(mem::replace(&mut field, String::new()), quoted));
+                    quoted = false;
+                }
+                '\r' | '\n' => {}
+                c => field.push(c),
+            }
+        }
+
+        if !in_quotes {
+            break;
+        }
+        // the quoted field continues on the next line
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Err(format_err!("unterminated quoted field at end of file"));
+        }
+    }
+    fields.push(csv_field(field, quoted));
+    Ok(Some(fields))
+}
+
+fn csv_field(field: String, quoted: bool) -> Option<String> {
+    if field.is_empty() && !quoted {
+        None
+    } else {
+        Some(field)
+    }
+}
+
+/// Writes rows to a file in one of the formats as they are exported.
+struct Exporter<'a, W: Write + ?Sized> {
+    format: Format,
+    out: &'a mut W,
+    columns: &'a [String],
+    /// The rows of the columnar group that hasn't been written yet.
+    group: Vec<Vec<DataType>>,
+}
+
+impl<'a, W: Write + ?Sized> Exporter<'a, W> {
+    fn new(format: Format, out: &'a mut W, columns: &'a [String]) -> Result<Self, Error> {
+        match format {
+            Format::Csv => {
+                write_csv_record(&mut *out, columns.iter().map(|c| Some(Cow::from(&**c))))?
+            }
+            Format::Json => {}
+            Format::Columnar => bincode::serialize_into(&mut *out, columns)?,
+        }
+        Ok(Exporter {
+            format,
+            out,
+            columns,
+            group: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, row: &[DataType]) -> Result<(), Error> {
+        // views without parameters have an extra key column
+        let row = &row[..self.columns.len()];
+        match self.format {
+            Format::Csv => write_csv_record(&mut *self.out, row.iter().map(to_text))?,
+            Format::Json => {
+                let object: serde_json::Map<_, _> = self
+                    .columns
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().map(to_json))
+                    .collect();
+                serde_json::to_writer(&mut *self.out, &object)?;
+                self.out.write_all(b"\n")?;
+            }
+            Format::Columnar => {
+                self.group.push(row.to_vec());
+                if self.group.len() == ROWS_PER_GROUP {
+                    self.write_group()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_group(&mut self) -> Result<(), Error> {
+        let group: Vec<Vec<DataType>> = (0..self.columns.len())
+            .map(|c| self.group.iter().map(|row| row[c].clone()).collect())
+            .collect();
+        bincode::serialize_into(&mut *self.out, &group)?;
+        self.group.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        if self.format == Format::Columnar {
+            if !self.group.is_empty() {
+                self.write_group()?;
+            }
+            // a group without any columns marks the end of the file
+            bincode::serialize_into(&mut *self.out, &Vec::<Vec<DataType>>::new())?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Read the rows of a file for a base table with the given columns.
+fn import_rows<'a, R: BufRead + 'a>(
+    format: Format,
+    mut input: R,
+    columns: &'a [(String, Kind)],
+) -> Result<Box<dyn Iterator<Item = Result<Vec<DataType>, Error>> + 'a>, Error> {
+    Ok(match format {
+        Format::Csv => {
+            let header = read_csv_record(&mut input)?.unwrap_or_default();
+            let positions = header
+                .iter()
+                .map(|name| position(columns, name.as_ref().map(|s| &**s).unwrap_or("")))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Box::new(iter::from_fn(move || {
+                let fields = match read_csv_record(&mut input) {
+                    Ok(Some(fields)) => fields,
+                    Ok(None) => return None,
+                    Err(e) => return Some(Err(e)),
+                };
+                if fields.len() != positions.len() {
+                    return Some(Err(format_err!(
+                        "expected {} fields, but found {}",
+                        positions.len(),
+                        fields.len()
+                    )));
+                }
+
+                let mut row = vec![DataType::None; columns.len()];
+                for (&i, field) in positions.iter().zip(fields) {
+                    if let Some(field) = field {
+                        match columns[i].1.parse(&field) {
+                            Ok(v) => row[i] = v,
+                            Err(e) => return Some(Err(e)),
+                        }
+                    }
+                }
+                Some(Ok(row))
+            }))
+        }
+        Format::Json => Box::new(
+            input
+                .lines()
+                .filter(|line| line.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true))
+                .map(move |line| {
+                    let object = match serde_json::from_str(&line?)? {
+                        Value::Object(object) => object,
+                        v => return Err(format_err!("expected a JSON object, but found {}", v)),
+                    };
+                    let mut row = vec![DataType::None; columns.len()];
+                    for (name, v) in &object {
+                        let i = position(columns, name)?;
+                        row[i] = from_json(columns[i].1, v)?;
+                    }
+                    Ok(row)
+                }),
+        ),
+        Format::Columnar => {
+            let header: Vec<String> = bincode::deserialize_from(&mut input)?;
+            let positions = header
+                .iter()
+                .map(|name| position(columns, name))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut rows = VecDeque::new();
+            Box::new(iter::from_fn(move || {
+                if rows.is_empty() {
+                    let group: Vec<Vec<DataType>> = match bincode::deserialize_from(&mut input) {
+                        Ok(group) => group,
+                        Err(e) => return Some(Err(e.into())),
+                    };
+                    if group.is_empty() {
+                        return None;
+                    }
+                    if group.len() != positions.len() {
+                        return Some(Err(format_err!(
+                            "expected {} columns, but found {}",
+                            positions.len(),
+                            group.len()
+                        )));
+                    }
+
+                    let nrows = group[0].len();
+                    rows.extend((0..nrows).map(|_| vec![DataType::None; columns.len()]));
+                    for (&i, column) in positions.iter().zip(group) {
+                        for (row, v) in rows.iter_mut().zip(column) {
+                            row[i] = v;
+                        }
+                    }
+                }
+                rows.pop_front().map(Ok)
+            }))
+        }
+    })
+}
+
+async fn export(
+    g: &mut ControllerHandle<ZookeeperAuthority>,
+    name: &str,
+    format: Format,
+    out: &mut dyn Write,
+) -> Result<usize, Error> {
+    let mut exported = 0;
+    match g.table(name).await {
+        Ok(table) => {
+            // base tables are read straight from their state, a batch of rows at a time
+            let columns = table.columns().to_vec();
+            let mut exporter = Exporter::new(format, out, &columns)?;
+            let export = rand::random();
+            let mut shard = 0;
+            let written: Result<(), Error> = async {
+                while let Some(rows) = g.export_rows(name, export, shard, EXPORT_BATCH).await? {
+                    if rows.is_empty() {
+                        shard += 1;
+                    }
+                    for row in rows {
+                        exporter.write(&row)?;
+                        exported += 1;
+                    }
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(e) = written {
+                // the shards would otherwise hold on to the rows that weren't read for a while
+                let _ = g.cancel_export(name, export).await;
+                return Err(e);
+            }
+            exporter.finish()?;
+        }
+        Err(_) => {
+            let mut view = g.view(name).await?;
+            let columns: Vec<_> = match view.schema() {
+                Some(schema) => schema.iter().map(|c| c.column.name.clone()).collect(),
+                None => view.columns().to_vec(),
+            };
+            let rows = view
+                .scan()
+                .await
+                .with_context(|_| format!("failed to read all rows of {}", name))?;
+            let mut exporter = Exporter::new(format, out, &columns)?;
+            for row in rows {
+                exporter.write(&row)?;
+                exported += 1;
+            }
+            exporter.finish()?;
+        }
+    }
+    Ok(exported)
+}
+
+async fn import(
+    g: &mut ControllerHandle<ZookeeperAuthority>,
+    name: &str,
+    format: Format,
+    input: Box<dyn BufRead>,
+    batch_size: usize,
+) -> Result<usize, Error> {
+    let mut table = g.table(name).await?;
+    let columns = columns_of(&table);
+
+    let mut imported = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    for (i, row) in import_rows(format, input, &columns)?.enumerate() {
+        batch.push(row.with_context(|_| format!("failed to read row {}", i + 1))?);
+        if batch.len() == batch_size {
+            imported += batch.len();
+            let batch = mem::replace(&mut batch, Vec::with_capacity(batch_size));
+            table.perform_all(batch).await?;
+        }
+    }
+    if !batch.is_empty() {
+        imported += batch.len();
+        table.perform_all(batch).await?;
+    }
+    Ok(imported)
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .short("f")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["csv", "json", "columnar"])
+        .default_value("csv")
+        .help("File format.")
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = App::new("noria-data")
+        .version("0.0.1")
+        .about("Exports the contents of Noria tables and views, and imports rows into tables.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("zookeeper")
+                .short("z")
+                .long("zookeeper")
+                .takes_value(true)
+                .default_value("127.0.0.1:2181")
+                .help("Zookeeper connection info."),
+        )
+        .arg(
+            Arg::with_name("deployment")
+                .long("deployment")
+                .short("d")
+                .required(true)
+                .takes_value(true)
+                .help("Noria deployment ID."),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write the contents of a base table or view to a file.")
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .help("Name of the base table or view."),
+                )
+                .arg(Arg::with_name("FILE").help("File to write to. Defaults to standard output."))
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Insert the rows in a file into a base table.")
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .help("Name of the base table."),
+                )
+                .arg(Arg::with_name("FILE").help("File to read from. Defaults to standard input."))
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("batch-size")
+                        .long("batch-size")
+                        .takes_value(true)
+                        .default_value("1024")
+                        .validator(|s| match s.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err(String::from("batch size must be a positive integer")),
+                        })
+                        .help("Number of rows to write to the table at a time."),
+                ),
+        )
+        .get_matches();
+
+    let zookeeper_addr = format!(
+        "{}/{}",
+        matches.value_of("zookeeper").unwrap(),
+        matches.value_of("deployment").unwrap()
+    );
+
+    let result = async {
+        let mut g = ControllerHandle::from_zk(&zookeeper_addr).await?;
+        g.ready().await?;
+        match matches.subcommand() {
+            ("export", Some(args)) => {
+                let name = args.value_of("NAME").unwrap();
+                let mut out: Box<dyn Write> = match args.value_of("FILE") {
+                    Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                    None => Box::new(BufWriter::new(io::stdout())),
+                };
+                let n = export(&mut g, name, Format::from_args(args), &mut out).await?;
+                eprintln!("exported {} rows from {}", n, name);
+            }
+            ("import", Some(args)) => {
+                let name = args.value_of("NAME").unwrap();
+                let input: Box<dyn BufRead> = match args.value_of("FILE") {
+                    Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                    None => Box::new(BufReader::new(io::stdin())),
+                };
+                let batch_size = args.value_of("batch-size").unwrap().parse().unwrap();
+                let n = import(&mut g, name, Format::from_args(args), input, batch_size).await?;
+                eprintln!("imported {} rows into {}", n, name);
+            }
+            _ => unreachable!(),
+        }
+        Ok::<_, Error>(())
+    };
+
+    if let Err(e) = result.await {
+        eprintln!("error: {}", e);
+        for cause in e.iter_causes() {
+            eprintln!("  caused by: {}", cause);
+        }
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns() -> Vec<(String, Kind)> {
+        vec![
+            ("id".to_string(), Kind::Integer),
+            ("name".to_string(), Kind::Text),
+            ("score".to_string(), Kind::Real),
+        ]
+    }
+
+    fn rows() -> Vec<Vec<DataType>> {
+        vec![
+            vec![1.into(), "plain".into(), 1.5.into()],
+            vec![2.into(), "with, comma".into(), DataType::None],
+            vec![
+                3.into(),
+                "with \"quotes\"\nand a newline".into(),
+                0.25.into(),
+            ],
+            vec![4.into(), "".into(), (-2.0).into()],
+        ]
+    }
+
+    fn round_trip(format: Format) -> Vec<Vec<DataType>> {
+        let names: Vec<_> = columns().into_iter().map(|(name, _)| name).collect();
+        let mut file = Vec::new();
+        let mut exporter = Exporter::new(format, &mut file, &names).unwrap();
+        for row in rows() {
+            exporter.write(&row).unwrap();
+        }
+        exporter.finish().unwrap();
+
+        let columns = columns();
+        import_rows(format, &file[..], &columns)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn it_round_trips_csv() {
+        assert_eq!(round_trip(Format::Csv), rows());
+    }
+
+    #[test]
+    fn it_round_trips_json() {
+        assert_eq!(round_trip(Format::Json), rows());
+    }
+
+    #[test]
+    fn it_round_trips_columnar() {
+        assert_eq!(round_trip(Format::Columnar), rows());
+    }
+
+    #[test]
+    fn it_reads_csv_with_missing_columns() {
+        let file = "name,id\n\"a\"\"b\",7\n,8\n";
+        let columns = columns();
+        let read = import_rows(Format::Csv, file.as_bytes(), &columns)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            read,
+            vec![
+                vec![7.into(), "a\"b".into(), DataType::None],
+                vec![8.into(), DataType::None, DataType::None],
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_unknown_columns() {
+        let columns = columns();
+        assert!(import_rows(Format::Csv, "id,color\n1,red\n".as_bytes(), &columns).is_err());
+    }
+}
@@ -3,7 +3,7 @@ extern crate noria;
 extern crate serde_json;
 extern crate zookeeper;
 
-use noria::consensus::{CONTROLLER_KEY, STATE_KEY};
+use noria::consensus::{CONTROLLER_KEY, METADATA_KEY, STATE_KEY};
 use serde_json::Value;
 use std::process;
 use std::time::Duration;
@@ -114,5 +114,21 @@ fn main() {
             Ok(_) => println!("State cleaned."),
             Err(e) => println!("Failed to clean: {:?}", e),
         }
+
+        match zk.delete(METADATA_KEY, None) {
+            // any version
+            Ok(_) => println!("Metadata cleaned."),
+            Err(ZkError::NoNode) => {}
+            Err(e) => println!("Failed to clean: {:?}", e),
+        }
+
+        // the metadata is kept in parts, under keys named after it
+        let prefix = format!("{}_", METADATA_KEY.trim_start_matches('/'));
+        let parts = zk.get_children("/", false).unwrap_or_default();
+        for part in parts.into_iter().filter(|p| p.starts_with(&prefix)) {
+            if let Err(e) = zk.delete(&format!("/{}", part), None) {
+                println!("Failed to clean metadata part {}: {:?}", part, e);
+            }
+        }
     }
 }
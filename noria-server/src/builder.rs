@@ -2,10 +2,12 @@ use crate::handle::Handle;
 use crate::Config;
 use crate::FrontierStrategy;
 use crate::ReuseConfigType;
-use dataflow::PersistenceParameters;
+use crate::{PlacementConstraint, PlacementPolicy};
+use dataflow::{EvictionPolicy, PersistenceParameters, RocksDbOptions};
 use noria::consensus::{Authority, LocalAuthority};
 use std::future::Future;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time;
 
@@ -16,6 +18,8 @@ pub struct Builder {
     memory_check_frequency: Option<time::Duration>,
     listen_addr: IpAddr,
     log: slog::Logger,
+    restore_from: Option<PathBuf>,
+    labels: Vec<String>,
 }
 impl Default for Builder {
     fn default() -> Self {
@@ -25,6 +29,8 @@ impl Default for Builder {
             log: slog::Logger::root(slog::Discard, o!()),
             memory_limit: None,
             memory_check_frequency: None,
+            restore_from: None,
+            labels: Vec::new(),
         }
     }
 }
@@ -48,6 +54,14 @@ impl Builder {
         self.config.persistence = p;
     }
 
+    /// Set how RocksDB is tuned for base tables whose options the recipe doesn't give, and for
+    /// partial state that is kept on disk.
+    ///
+    /// Note that `set_persistence` replaces these options.
+    pub fn set_rocksdb_options(&mut self, options: RocksDbOptions) {
+        self.config.persistence.rocksdb = options;
+    }
+
     /// Disable partial materialization for all subsequent migrations
     pub fn disable_partial(&mut self) {
         self.config.partial_enabled = false;
@@ -58,6 +72,22 @@ impl Builder {
         self.config.frontier_strategy = f;
     }
 
+    /// Set how the controller chooses the workers that new domains run on.
+    pub fn set_placement_policy(&mut self, policy: PlacementPolicy) {
+        self.config.placement_policy = policy;
+    }
+
+    /// Require the domains of the nodes that `constraint` names to run on workers with its label.
+    /// Migrations that add such a domain fail if no healthy worker has the label.
+    pub fn add_placement_constraint(&mut self, constraint: PlacementConstraint) {
+        self.config.placement_constraints.push(constraint);
+    }
+
+    /// Give this worker a label that placement constraints can require.
+    pub fn add_label(&mut self, label: &str) {
+        self.labels.push(label.to_owned());
+    }
+
     /// Set sharding policy for all subsequent migrations; `None` disables
     pub fn set_sharding(&mut self, shards: Option<usize>) {
         self.config.sharding = shards;
@@ -78,6 +108,21 @@ impl Builder {
         self.memory_check_frequency = Some(check_freq);
     }
 
+    /// Set how keys are chosen for eviction from partially materialized state once the memory
+    /// limit is exceeded.
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.config.domain_config.eviction_policy = policy;
+    }
+
+    /// Set the eviction policy for the node (or view) with the given name, overriding the one set
+    /// with `set_eviction_policy`.
+    pub fn set_node_eviction_policy(&mut self, node: &str, policy: EvictionPolicy) {
+        self.config
+            .domain_config
+            .node_eviction_policies
+            .insert(node.to_owned(), policy);
+    }
+
     /// Set the IP address that the worker should use for listening.
     pub fn set_listen_addr(&mut self, listen_addr: IpAddr) {
         self.listen_addr = listen_addr;
@@ -93,6 +138,22 @@ impl Builder {
         self.config.reuse = reuse_type;
     }
 
+    /// Set how long a query installed through `ControllerHandle::query` may go unused before it
+    /// is removed again; `None` keeps such queries around forever.
+    pub fn set_adhoc_query_idle_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.config.adhoc_query_idle_timeout = timeout;
+    }
+
+    /// Start a new deployment from the backup in `dir`, which was taken with
+    /// `ControllerHandle::backup`.
+    ///
+    /// The deployment must not have any state yet, and must use the same sharding as the backed up
+    /// one did. The state of base tables is copied to this worker's machine, so this worker should
+    /// be the first, and only, member of the deployment's quorum.
+    pub fn set_restore_from(&mut self, dir: PathBuf) {
+        self.restore_from = Some(dir);
+    }
+
     /// Set the number of pool threads to use (default is #cores)
     pub fn set_threads(&mut self, threads: usize) {
         self.config.threads = Some(threads);
@@ -114,10 +175,14 @@ impl Builder {
             memory_limit,
             memory_check_frequency,
             ref log,
+            ref restore_from,
+            ref labels,
         } = *self;
 
         let config = config.clone();
         let log = log.clone();
+        let restore_from = restore_from.clone();
+        let labels = labels.clone();
 
         crate::startup::start_instance(
             authority,
@@ -125,7 +190,9 @@ impl Builder {
             config,
             memory_limit,
             memory_check_frequency,
+            labels,
             log,
+            restore_from,
         )
     }
 
@@ -0,0 +1,108 @@
+//! Backups of a running deployment, and restoring a new deployment from one.
+//!
+//! A backup is a directory that holds a manifest with everything the controller needs to rebuild
+//! the data-flow, along with a RocksDB checkpoint of every shard of every base table. Each worker
+//! writes the checkpoints of the domains it runs, so with more than one worker, the directory
+//! must be on storage that all of them share.
+
+use super::ControllerState;
+use crate::Config;
+use dataflow::DurabilityMode;
+use noria::consensus::{Authority, STATE_KEY};
+use noria::DataType;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the file in a backup directory that holds its `Manifest`.
+pub(crate) const MANIFEST: &str = "manifest.json";
+
+/// Everything about a backed up deployment, apart from the contents of its base tables.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub(super) recipe_version: usize,
+    pub(super) recipes: Vec<String>,
+    pub(super) security_config: Option<String>,
+    pub(super) universes: Vec<HashMap<String, DataType>>,
+    pub(super) memory_budgets: HashMap<String, usize>,
+    #[serde(default)]
+    pub(super) read_replicas: HashMap<String, usize>,
+    pub(super) sharding: Option<usize>,
+    /// The number of shards of each base table, by name.
+    pub(super) bases: HashMap<String, usize>,
+}
+
+impl Manifest {
+    /// Make `state`, the state of a new deployment, that of the backed up one.
+    pub(super) fn restore_into(&self, state: &mut ControllerState) {
+        state.recipe_version = self.recipe_version;
+        state.recipes = self.recipes.clone();
+        state.security_config = self.security_config.clone();
+        state.universes = self.universes.clone();
+        state.memory_budgets = self.memory_budgets.clone();
+        state.read_replicas = self.read_replicas.clone();
+    }
+}
+
+/// The name of the checkpoint of the given shard of a base table in a backup directory. This is
+/// the name that domains give the checkpoints they write.
+fn checkpoint_name(base: &str, shard: usize) -> String {
+    format!("{}-{}.db", base, shard)
+}
+
+/// Prepare to start a new deployment from the backup in `dir`.
+///
+/// This checks that the deployment can be restored from the backup, and copies the checkpoints
+/// of its base tables to where its domains will open them. The returned manifest should become
+/// the deployment's state once it has elected a controller.
+pub(crate) fn prepare_restore<A: Authority>(
+    dir: &Path,
+    config: &Config,
+    authority: &A,
+) -> Result<Manifest, failure::Error> {
+    let manifest: Manifest = serde_json::from_slice(&fs::read(dir.join(MANIFEST))?)?;
+
+    if authority.try_read(STATE_KEY)?.is_some() {
+        return Err(format_err!(
+            "deployment already has state, so it can't be restored from a backup"
+        ));
+    }
+    if config.persistence.mode != DurabilityMode::Permanent {
+        return Err(format_err!(
+            "restoring a backup requires permanent durability"
+        ));
+    }
+    if config.sharding != manifest.sharding {
+        return Err(format_err!(
+            "backup was taken with sharding {:?}, but deployment uses {:?}",
+            manifest.sharding,
+            config.sharding
+        ));
+    }
+
+    for (base, &shards) in &manifest.bases {
+        for shard in 0..shards {
+            // this is where the domain will look for the base table's state
+            let to = PathBuf::from(format!(
+                "{}-{}-{}.db",
+                config.persistence.log_prefix, base, shard
+            ));
+            if to.exists() {
+                return Err(format_err!("{:?} already exists", to));
+            }
+            copy_dir(&dir.join(checkpoint_name(base, shard)), &to)?;
+        }
+    }
+
+    Ok(manifest)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        fs::copy(entry.path(), to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
@@ -4,10 +4,90 @@ use noria::channel::tcp;
 use slog::Logger;
 use std::collections::HashMap;
 use std::io;
+use std::net::SocketAddr;
 
 pub(super) struct DomainShardHandle {
     pub(super) worker: WorkerIdentifier,
     pub(super) tx: Box<dyn noria::channel::Sender<Item = Box<Packet>> + Send>,
+    /// The packets that set up the shard's state, replay paths and connections to other domains,
+    /// in the order they were sent, so that the shard can be started anew if its worker fails.
+    pub(super) setup: Vec<Packet>,
+    /// The workers that run the shards that this one took the place of when it moved, and where
+    /// those shards are, should they still forward to it.
+    pub(super) forwarders: Vec<(WorkerIdentifier, SocketAddr)>,
+}
+
+impl DomainShardHandle {
+    pub(super) fn new(
+        worker: WorkerIdentifier,
+        tx: Box<dyn noria::channel::Sender<Item = Box<Packet>> + Send>,
+    ) -> Self {
+        DomainShardHandle {
+            worker,
+            tx,
+            setup: Vec::new(),
+            forwarders: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, p: Box<Packet>) -> Result<(), tcp::SendError> {
+        self.record(&p);
+        self.tx.send(p)
+    }
+
+    /// Record `p` among the packets that set up the shard if it is one, in place of the earlier
+    /// packets that it supersedes.
+    fn record(&mut self, p: &Packet) {
+        match *p {
+            Packet::UpdateMemoryBudget { node, .. } => {
+                // only the latest budget of a node counts
+                self.setup.retain(|q| match *q {
+                    Packet::UpdateMemoryBudget { node: n, .. } => n != node,
+                    _ => true,
+                });
+            }
+            Packet::UpdateSharder {
+                node,
+                new_txs: (child, _),
+            } => {
+                // the sharder sends to the shards of the child that it was told about last
+                self.setup.retain(|q| match *q {
+                    Packet::UpdateSharder {
+                        node: n,
+                        new_txs: (c, _),
+                    } => n != node || c != child,
+                    _ => true,
+                });
+            }
+            Packet::UpdateEgress {
+                node,
+                new_tx: Some((child, _, to)),
+                new_tag: None,
+            } => {
+                // an egress that already sends to the shard only reconnects to it
+                let known = self.setup.iter().any(|q| match *q {
+                    Packet::UpdateEgress {
+                        node: n,
+                        new_tx: Some((c, _, t)),
+                        ..
+                    } => n == node && c == child && t == to,
+                    _ => false,
+                });
+                if known {
+                    return;
+                }
+            }
+            Packet::PrepareState { .. }
+            | Packet::SetupReplayPath { .. }
+            | Packet::StartReplay { .. }
+            | Packet::Ready { .. }
+            | Packet::UpdateEgress { .. }
+            | Packet::AddBaseColumn { .. }
+            | Packet::DropBaseColumn { .. } => {}
+            _ => return,
+        }
+        self.setup.push(p.clone());
+    }
 }
 
 /// A `DomainHandle` is a handle that allows communicating with all of the shards of a given
@@ -42,7 +122,7 @@ impl DomainHandle {
     ) -> Result<(), tcp::SendError> {
         for shard in self.shards.iter_mut() {
             if workers[&shard.worker].healthy {
-                shard.tx.send(p.clone())?;
+                shard.send(p.clone())?;
             } else {
                 error!(
                     self.log,
@@ -61,7 +141,7 @@ impl DomainHandle {
         workers: &HashMap<WorkerIdentifier, Worker>,
     ) -> Result<(), tcp::SendError> {
         if workers[&self.shards[i].worker].healthy {
-            self.shards[i].tx.send(p)?;
+            self.shards[i].send(p)?;
         } else {
             error!(
                 self.log,
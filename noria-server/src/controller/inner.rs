@@ -1,29 +1,59 @@
+use crate::controller::backup::{self, Manifest};
 use crate::controller::domain_handle::{DomainHandle, DomainShardHandle};
+use crate::controller::metadata::{JournalEntry, Metadata, RecipeChange, ShardMetadata};
 use crate::controller::migrate::materialization::Materializations;
-use crate::controller::recipe::Schema;
+use crate::controller::migrate::Allocated;
+use crate::controller::placement::{Candidate, Placer};
+use crate::controller::recipe::{self, Schema};
 use crate::controller::schema;
-use crate::controller::{ControllerState, Migration, Recipe};
+use crate::controller::sql::{MirSnapshot, QueryReuse, TableStatistics};
+use crate::controller::{ControllerState, Migration, Recipe, Snapshot};
 use crate::controller::{Worker, WorkerIdentifier};
 use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
+use crate::ReuseConfigType;
+use dataflow::payload::ControlReplyPacket;
 use dataflow::prelude::*;
-use dataflow::{node, payload::ControlReplyPacket, prelude::Packet, DomainBuilder, DomainConfig};
+use dataflow::{node, prelude::Packet, DomainBuilder, DomainConfig};
 use futures_util::stream::StreamExt;
 use hyper::{self, Method, StatusCode};
 use mio::net::TcpListener;
-use nom_sql::ColumnSpecification;
+use nom_sql::{ColumnSpecification, SqlQuery};
 use noria::builders::*;
 use noria::channel::tcp::{SendError, TcpSender};
+use noria::channel::DomainConnectionBuilder;
 use noria::consensus::{Authority, Epoch, STATE_KEY};
+use noria::debug::explain::{Explanation, NodeExplanation, RecipePlan};
 use noria::debug::stats::{DomainStats, GraphStats, NodeStats};
+use noria::debug::status::{ClusterStatus, ReaderStatus, ShardStatus, WorkerStatus};
 use noria::ActivationResult;
 use petgraph::visit::Bfs;
 use slog::Logger;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::mem;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{cell, io, time};
+use std::{cell, io, iter, time};
+
+/// How long `cluster_status` waits for a domain's statistics before it reports them as unknown.
+const STATISTICS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long `pause_dataflow` waits for every domain shard to take its part of a snapshot before
+/// it lets writes through again and gives up.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long `recover` waits for each step of bringing back a lost domain shard, such as a full
+/// replay to it, before it gives up and rebuilds the affected queries instead.
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often heartbeats refresh the base table statistics that recipe changes plan joins with.
+const TABLE_STATISTICS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A domain shard that was booted to take the place of a handed off one: the worker it runs on,
+/// its address, and a connection to it.
+type NewShard = (WorkerIdentifier, SocketAddr, TcpSender<Box<Packet>>);
 
 /// `Controller` is the core component of the alternate Soup implementation.
 ///
@@ -42,10 +72,35 @@ pub(super) struct ControllerInner {
     /// Parameters for persistence code.
     pub(super) persistence: PersistenceParameters,
     pub(super) materializations: Materializations,
+    /// Chooses the workers that new domains run on.
+    placer: Placer,
 
     /// Current recipe
     recipe: Recipe,
 
+    /// Queries installed through `ControllerHandle::query`, and when they were last requested.
+    adhoc_queries: HashMap<String, Instant>,
+    adhoc_query_idle_timeout: Option<Duration>,
+
+    /// Memory budgets set through `ControllerHandle::set_memory_budget`, by query name. These
+    /// take precedence over budgets given in the recipe.
+    memory_budgets: HashMap<String, usize>,
+    /// The number of readers set through `ControllerHandle::set_read_replicas`, by query name, of
+    /// views that are read from more than one.
+    read_replicas: HashMap<String, usize>,
+    /// The memory budget that each node's domain was last told about.
+    node_budgets: HashMap<NodeIndex, usize>,
+
+    /// The latest complete snapshot of fully materialized state.
+    snapshot: Option<Snapshot>,
+    /// When the last snapshot was started.
+    last_snapshot: Instant,
+
+    /// The security configuration set through `set_security_config`.
+    security_config: Option<String>,
+    /// The contexts of all universes created through `create_universe`, in order.
+    universes: Vec<HashMap<String, DataType>>,
+
     pub(super) domains: HashMap<DomainIndex, DomainHandle>,
     pub(in crate::controller) domain_nodes: HashMap<DomainIndex, Vec<NodeIndex>>,
     pub(super) channel_coordinator: Arc<ChannelCoordinator>,
@@ -61,6 +116,25 @@ pub(super) struct ControllerInner {
     pub(super) epoch: Epoch,
 
     pending_recovery: Option<(Vec<String>, usize)>,
+    /// The base table statistics that each persisted recipe was planned with.
+    recipe_statistics: Vec<TableStatistics>,
+    /// The latest base table statistics, and when they were gathered. Heartbeats keep them fresh,
+    /// so that applying a recipe doesn't have to wait for every domain to report.
+    table_statistics: Option<(Instant, TableStatistics)>,
+    /// What the previous controller knew about the data-flow it left running, and when this
+    /// controller took over from it, until the running domains have been adopted.
+    pending_adoption: Option<(Metadata, Instant)>,
+    /// The domain shards that each worker that registered while recovery or adoption was pending
+    /// reported to be running.
+    reported: HashMap<WorkerIdentifier, Vec<DomainDescriptor>>,
+
+    /// Every change made to the recipe since this controller started, so that one that takes over
+    /// can make them again.
+    journal: Vec<JournalEntry>,
+    /// Whether anything that `Metadata` holds has changed since it was last persisted.
+    metadata_changed: bool,
+    /// How many versions of the metadata have been written.
+    metadata_generation: u64,
 
     quorum: usize,
     heartbeat_every: Duration,
@@ -72,19 +146,33 @@ pub(super) struct ControllerInner {
     pub(in crate::controller) replies: DomainReplies,
 }
 
-pub(in crate::controller) struct DomainReplies(
-    tokio::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
-);
+pub(in crate::controller) struct DomainReplies {
+    rx: tokio::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
+    /// How many replies to statistics requests that we stopped waiting for are yet to arrive.
+    late_statistics: usize,
+    /// The last time the data-flow was paused. Replies to earlier pauses, which we stopped
+    /// waiting for, are dropped.
+    pause: u64,
+}
 
 impl DomainReplies {
     async fn read_n_domain_replies(&mut self, n: usize) -> Vec<ControlReplyPacket> {
-        let crps: Vec<_> = (&mut self.0).take(n).collect().await;
-
-        if crps.len() != n {
-            unreachable!(
-                "got unexpected EOF from domain reply channel after {} replies",
-                crps.len()
-            );
+        let mut crps = Vec::with_capacity(n);
+        while crps.len() < n {
+            match self.rx.next().await {
+                Some(ControlReplyPacket::Statistics(..)) if self.late_statistics > 0 => {
+                    // a late reply to a statistics request that timed out
+                    self.late_statistics -= 1;
+                }
+                Some(ControlReplyPacket::SnapshotTaken(pause, ..)) if pause != self.pause => {
+                    // a late reply to a pause that timed out
+                }
+                Some(crp) => crps.push(crp),
+                None => unreachable!(
+                    "got unexpected EOF from domain reply channel after {} replies",
+                    crps.len()
+                ),
+            }
         }
 
         crps
@@ -112,6 +200,179 @@ impl DomainReplies {
         }
         stats
     }
+
+    /// Wait for every shard of the given domain to reply with its statistics, or return `None`
+    /// if they haven't all done so within `timeout`.
+    async fn wait_for_statistics_within(
+        &mut self,
+        d: &DomainHandle,
+        timeout: Duration,
+    ) -> Option<Vec<(DomainStats, HashMap<NodeIndex, NodeStats>)>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut stats = Vec::with_capacity(d.shards());
+        while stats.len() < d.shards() {
+            let r = match tokio::time::timeout_at(deadline, self.read_n_domain_replies(1)).await {
+                Ok(mut r) => r.pop(),
+                Err(_) => {
+                    // skip the replies that are still to come, rather than mistake them for
+                    // replies to whatever we wait on next
+                    self.late_statistics += d.shards() - stats.len();
+                    return None;
+                }
+            };
+            match r {
+                Some(ControlReplyPacket::Statistics(d, s)) => stats.push((d, s)),
+                r => unreachable!("got unexpected non-stats control reply: {:?}", r),
+            }
+        }
+        Some(stats)
+    }
+
+    /// Start a new pause of the data-flow, and return the tag that replies to it will carry.
+    fn next_pause(&mut self) -> u64 {
+        self.pause += 1;
+        self.pause
+    }
+
+    /// Wait for `nshards` domain shards to reply to the current pause, or fail if they haven't
+    /// all done so within `timeout`.
+    async fn wait_for_snapshots_within(
+        &mut self,
+        nshards: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(usize, Vec<(NodeIndex, u64)>)>, String> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut taken = Vec::with_capacity(nshards);
+        let mut failed = None;
+        for n in 0..nshards {
+            let r = match tokio::time::timeout_at(deadline, self.read_n_domain_replies(1)).await {
+                Ok(mut r) => r.pop(),
+                Err(_) => {
+                    return Err(format!(
+                        "only {} of {} domain shards took their snapshot in time",
+                        n, nshards
+                    ));
+                }
+            };
+            match r {
+                Some(ControlReplyPacket::SnapshotTaken(_, shard, Ok(seqs))) => {
+                    taken.push((shard, seqs))
+                }
+                Some(ControlReplyPacket::SnapshotTaken(_, _, Err(e))) => {
+                    failed = failed.or(Some(e))
+                }
+                r => unreachable!("got unexpected non-snapshot control reply: {:?}", r),
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(taken),
+        }
+    }
+
+    /// Wait for every shard of the given domain to reply to `Packet::LoadSnapshot`, and return
+    /// whether all of them had their state in the snapshot.
+    pub(in crate::controller) async fn wait_for_snapshot_loads(
+        &mut self,
+        d: &DomainHandle,
+    ) -> bool {
+        let mut loaded = true;
+        for r in self.read_n_domain_replies(d.shards()).await {
+            match r {
+                ControlReplyPacket::SnapshotLoaded(l) => loaded = loaded && l,
+                r => unreachable!("got unexpected non-snapshot control reply: {:?}", r),
+            }
+        }
+        loaded
+    }
+
+    /// Wait for the domain shard that was sent `Packet::ExportBase` to reply with the rows.
+    async fn wait_for_base_rows(&mut self) -> Vec<Vec<DataType>> {
+        match self.read_n_domain_replies(1).await.pop() {
+            Some(ControlReplyPacket::BaseRows(rows)) => rows,
+            r => unreachable!("got unexpected non-export control reply: {:?}", r),
+        }
+    }
+
+    /// Wait for a domain shard to reply how a step of handing it off went.
+    async fn wait_for_hand_off(&mut self) -> Result<(), String> {
+        match self.read_n_domain_replies(1).await.pop() {
+            Some(ControlReplyPacket::HandOff(r)) => r,
+            r => Err(format!(
+                "got unexpected non-hand-off control reply: {:?}",
+                r
+            )),
+        }
+    }
+
+    /// Wait for `n` replies to steps of handing off domain shards, and return the first error
+    /// that any of them ran into.
+    async fn wait_for_hand_offs(&mut self, n: usize) -> Result<(), String> {
+        let mut handed_off = Ok(());
+        for r in self.read_n_domain_replies(n).await {
+            match r {
+                ControlReplyPacket::HandOff(h) => handed_off = handed_off.and(h),
+                r => {
+                    let e = format!("got unexpected non-hand-off control reply: {:?}", r);
+                    handed_off = handed_off.and(Err(e));
+                }
+            }
+        }
+        handed_off
+    }
+
+    /// Wait for a single domain shard to acknowledge a packet.
+    async fn wait_for_ack(&mut self) -> Result<(), String> {
+        match self.read_n_domain_replies(1).await.pop() {
+            Some(ControlReplyPacket::Ack(_)) => Ok(()),
+            r => Err(format!("got unexpected non-ack control reply: {:?}", r)),
+        }
+    }
+
+    /// Like `wait_for_ack`, but fails if the shard hasn't acknowledged the packet within
+    /// `timeout`.
+    async fn wait_for_ack_within(&mut self, timeout: Duration) -> Result<(), String> {
+        tokio::time::timeout(timeout, self.wait_for_ack())
+            .await
+            .map_err(|_| "timed out waiting for a domain shard to acknowledge".to_owned())?
+    }
+
+    /// Wait for a domain shard that was sent to a worker to reply with where it runs.
+    async fn wait_for_boot(&mut self) -> Result<SocketAddr, String> {
+        match self.read_n_domain_replies(1).await.pop() {
+            Some(ControlReplyPacket::Booted(_, addr)) => Ok(addr),
+            r => Err(format!("got unexpected non-boot control reply: {:?}", r)),
+        }
+    }
+
+    /// Like `wait_for_boot`, but fails if the shard hasn't booted within `timeout`.
+    async fn wait_for_boot_within(&mut self, timeout: Duration) -> Result<SocketAddr, String> {
+        tokio::time::timeout(timeout, self.wait_for_boot())
+            .await
+            .map_err(|_| "timed out waiting for a domain shard to boot".to_owned())?
+    }
+
+    /// Drop the replies that arrive until none has for `grace`, such as those of domain shards
+    /// that were given up on, rather than mistake them for replies to whatever we wait on next.
+    async fn discard_replies(&mut self, grace: Duration) {
+        while tokio::time::timeout(grace, self.read_n_domain_replies(1))
+            .await
+            .is_ok()
+        {}
+    }
+
+    /// Wait for `nshards` domain shards to reply to `Packet::Backup`, and return the first error
+    /// that any of them ran into.
+    async fn wait_for_backups(&mut self, nshards: usize) -> Result<(), String> {
+        let mut taken = Ok(());
+        for r in self.read_n_domain_replies(nshards).await {
+            match r {
+                ControlReplyPacket::BackupTaken(t) => taken = taken.and(t),
+                r => unreachable!("got unexpected non-backup control reply: {:?}", r),
+            }
+        }
+        taken
+    }
 }
 
 pub(super) fn graphviz(
@@ -169,6 +430,37 @@ pub(super) fn graphviz(
     s
 }
 
+/// Sum up the number of rows each node holds across all of its shards.
+fn node_rows(stats: &GraphStats) -> HashMap<NodeIndex, u64> {
+    // shards hold disjoint parts of a node's state, so their row counts add up
+    let mut rows = HashMap::new();
+    for (_, nodes) in stats.domains.values() {
+        for (&ni, s) in nodes {
+            *rows.entry(ni).or_insert(0) += s.rows;
+        }
+    }
+    rows
+}
+
+/// The reuse strategy, the way an existing query was reused, if one was, and the existing nodes
+/// that a query was planned onto, as `Explanation` gives them.
+fn explain_reuse(reuse: Option<&QueryReuse>) -> (String, Option<String>, Vec<NodeIndex>) {
+    let reuse = match reuse {
+        Some(reuse) => reuse,
+        None => return ("none".to_owned(), None, Vec::new()),
+    };
+    let strategy = match reuse.strategy {
+        ReuseConfigType::Finkelstein => "finkelstein",
+        ReuseConfigType::Relaxed => "relaxed",
+        ReuseConfigType::Full => "full",
+        ReuseConfigType::NoReuse => "none",
+    };
+    let mut nodes = reuse.nodes.clone();
+    nodes.sort();
+    nodes.dedup();
+    (strategy.to_owned(), reuse.how.map(str::to_owned), nodes)
+}
+
 impl ControllerInner {
     pub(in crate::controller) fn topo_order(&self, new: &HashSet<NodeIndex>) -> Vec<NodeIndex> {
         let mut topo_list = Vec::with_capacity(new.len());
@@ -213,13 +505,23 @@ impl ControllerInner {
             (&Method::POST, "/get_statistics") => {
                 return Ok(Ok(json::to_string(&self.get_statistics()).unwrap()));
             }
+            (&Method::GET, "/cluster_status") | (&Method::POST, "/cluster_status") => {
+                return Ok(Ok(json::to_string(&self.cluster_status()).unwrap()));
+            }
             _ => {}
         }
 
-        if self.pending_recovery.is_some() || self.workers.len() < self.quorum {
+        if self.pending_recovery.is_some()
+            || self.pending_adoption.is_some()
+            || self.workers.len() < self.quorum
+        {
             return Err(StatusCode::SERVICE_UNAVAILABLE);
         }
 
+        let dry_run = query
+            .as_ref()
+            .map_or(false, |q| q.split('&').any(|v| v == "dry_run=true"));
+
         match (method, path.as_ref()) {
             (Method::GET, "/flush_partial") => {
                 Ok(Ok(json::to_string(&self.flush_partial()).unwrap()))
@@ -266,13 +568,39 @@ impl ControllerInner {
                 .map(|args| Ok(json::to_string(&self.table_builder(args)).unwrap())),
             (Method::POST, "/view_builder") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
-                .map(|args| Ok(json::to_string(&self.view_builder(args)).unwrap())),
+                .map(|args| {
+                    if let Some(used) = self.adhoc_queries.get_mut(args) {
+                        *used = Instant::now();
+                    }
+                    Ok(json::to_string(&self.view_builder(args)).unwrap())
+                }),
+            (Method::POST, "/query_builder") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args: String| {
+                    self.query_builder(authority, &args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/explain") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args: String| self.explain(&args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/extend_recipe") if dry_run => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.plan_recipe_extension(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
             (Method::POST, "/extend_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
                     self.extend_recipe(authority, args)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/install_recipe") if dry_run => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.plan_recipe_installation(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
             (Method::POST, "/install_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
@@ -282,13 +610,67 @@ impl ControllerInner {
             (Method::POST, "/set_security_config") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
-                    self.set_security_config(args)
+                    self.set_security_config(authority, args)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
             (Method::POST, "/create_universe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
-                    self.create_universe(args)
+                    self.create_universe(authority, args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/set_memory_budget") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(name, budget): (String, Option<usize>)| {
+                    self.set_memory_budget(authority, name, budget)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/set_read_replicas") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(name, replicas): (String, usize)| {
+                    self.set_read_replicas(authority, name, replicas)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/snapshot") => Ok(self
+                .take_snapshot(authority)
+                .map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/backup") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|dir| {
+                    self.backup(authority, dir)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/export_rows") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(table, export, shard, count): (String, _, _, _)| {
+                    self.export_rows(&table, export, shard, count)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/cancel_export") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(table, export): (String, _)| {
+                    self.cancel_export(&table, export)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/compact") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|table| self.compact(table).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/move_domain") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(di, shard, worker)| {
+                    self.move_domain(di, shard, worker)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/drain_worker") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|worker| {
+                    self.drain_worker(worker)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/reshard") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(table, shards)| {
+                    self.reshard(table, shards)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
             (Method::POST, "/remove_node") => json::from_slice(&body)
@@ -301,17 +683,25 @@ impl ControllerInner {
         }
     }
 
-    pub(super) fn handle_register(&mut self, msg: CoordinationMessage) -> Result<(), io::Error> {
-        let (remote, read_listen_addr) = if let CoordinationPayload::Register {
-            addr: remote,
-            read_listen_addr,
-            ..
-        } = msg.payload
-        {
-            (remote, read_listen_addr)
-        } else {
-            unreachable!();
-        };
+    pub(super) fn handle_register<A: Authority + 'static>(
+        &mut self,
+        msg: CoordinationMessage,
+        authority: &Arc<A>,
+    ) -> Result<(), io::Error> {
+        let (remote, read_listen_addr, cpus, labels, running) =
+            if let CoordinationPayload::Register {
+                addr: remote,
+                read_listen_addr,
+                cpus,
+                labels,
+                domains,
+                ..
+            } = msg.payload
+            {
+                (remote, read_listen_addr, cpus, labels, domains)
+            } else {
+                unreachable!();
+            };
 
         info!(
             self.log,
@@ -319,24 +709,31 @@ impl ControllerInner {
         );
 
         let sender = TcpSender::connect(&remote)?;
-        let ws = Worker::new(sender);
+        let ws = Worker::new(remote, sender, cpus, labels);
         self.workers.insert(msg.source, ws);
         self.read_addrs.insert(msg.source, read_listen_addr);
 
+        if self.pending_recovery.is_some() || self.pending_adoption.is_some() {
+            // what the worker runs is dealt with once it is known what to keep
+            self.reported.insert(msg.source, running);
+        } else {
+            self.quit_stale_domains(&running);
+            self.announce_domains(msg.source);
+        }
+
         if self.workers.len() >= self.quorum {
-            if let Some((recipes, recipe_version)) = self.pending_recovery.take() {
-                assert_eq!(self.workers.len(), self.quorum);
-                assert_eq!(self.recipe.version(), 0);
-                assert!(recipe_version + 1 >= recipes.len());
-
-                info!(self.log, "Restoring graph configuration");
-                self.recipe = Recipe::with_version(
-                    recipe_version + 1 - recipes.len(),
-                    Some(self.log.clone()),
-                );
-                for r in recipes {
-                    self.apply_recipe(self.recipe.clone().extend(&r).unwrap())
-                        .unwrap();
+            let addrs: Vec<_> = self.workers.values().map(|w| w.addr).collect();
+            match self.pending_adoption {
+                Some((ref metadata, _)) if metadata.covered_by(&addrs) => {
+                    self.finish_adoption(authority)
+                }
+                // the workers that run the rest of it may still come back
+                Some(_) => {}
+                None => {
+                    if let Some((recipes, recipe_version)) = self.pending_recovery.take() {
+                        assert_eq!(self.workers.len(), self.quorum);
+                        self.rebuild(recipes, recipe_version);
+                    }
                 }
             }
         }
@@ -344,123 +741,946 @@ impl ControllerInner {
         Ok(())
     }
 
-    fn check_worker_liveness(&mut self) {
-        let mut any_failed = false;
+    /// Build the data-flow anew from the persisted recipes and universes.
+    fn rebuild(&mut self, recipes: Vec<String>, recipe_version: usize) {
+        assert_eq!(self.recipe.version(), 0);
+        assert!(recipe_version + 1 >= recipes.len());
 
-        // check if there are any newly failed workers
-        if self.last_checked_workers.elapsed() > self.healthcheck_every {
-            for (_addr, ws) in self.workers.iter() {
-                if ws.healthy && ws.last_heartbeat.elapsed() > self.heartbeat_every * 4 {
-                    any_failed = true;
+        // whatever the workers still run is left over from an earlier controller
+        let reported = mem::replace(&mut self.reported, HashMap::new());
+        for running in reported.values() {
+            self.quit_stale_domains(running);
+        }
+
+        info!(self.log, "Restoring graph configuration");
+        let version = recipe_version + 1 - recipes.len();
+        self.recipe = Recipe::with_version(version, Some(self.log.clone()));
+        self.record_change(JournalEntry::new(RecipeChange::Reset(version)));
+        if let Some(config) = self.security_config.clone() {
+            self.recipe.set_security_config(&config);
+            self.record_change(JournalEntry::new(RecipeChange::SecurityConfig(config)));
+        }
+        if let Some(ref snapshot) = self.snapshot {
+            info!(self.log, "Restoring materialized state from snapshot"; "id" => snapshot.id);
+        }
+        self.materializations.restore_from(self.snapshot.clone());
+        for (i, r) in recipes.into_iter().enumerate() {
+            let new = self.recipe.clone().extend(&r).unwrap();
+            // joins are ordered by the statistics the recipe was first planned with, so that the
+            // graph comes out the same as before and restored state fits it
+            match self.recipe_statistics.get(i).cloned() {
+                Some(statistics) => {
+                    self.apply_recipe_with(new, RecipeChange::Extend(r), statistics)
                 }
+                None => self.apply_recipe(new, RecipeChange::Extend(r)),
             }
-            self.last_checked_workers = Instant::now();
+            .unwrap();
         }
+        self.materializations.restore_from(None);
+        self.reapply_base_logs();
 
-        // if we have newly failed workers, iterate again to find all workers that have missed >= 3
-        // heartbeats. This is necessary so that we correctly handle correlated failures of
-        // workers.
-        if any_failed {
-            let mut failed = Vec::new();
-            for (addr, ws) in self.workers.iter_mut() {
-                if ws.healthy && ws.last_heartbeat.elapsed() > self.heartbeat_every * 3 {
-                    error!(self.log, "worker at {:?} has failed!", addr);
-                    ws.healthy = false;
-                    failed.push(addr.clone());
+        for context in self.universes.clone() {
+            self.add_user_universe(context).unwrap();
+        }
+    }
+
+    /// Adopt the data-flow that the previous controller left running, or build it anew from the
+    /// persisted recipes if none of it is running anymore or it can't be adopted.
+    fn finish_adoption<A: Authority + 'static>(&mut self, authority: &Arc<A>) {
+        let (metadata, _) = self.pending_adoption.take().unwrap();
+        let recovery = self.pending_recovery.take();
+        self.metadata_changed = true;
+
+        let running = metadata.domains.iter().any(|(&di, shards)| {
+            shards
+                .iter()
+                .enumerate()
+                .any(|(i, s)| self.running_shard(di, i, s).is_some())
+        });
+        if running {
+            let blank = (self.ingredients.clone(), self.source, self.recipe.clone());
+            match self.adopt(metadata) {
+                Ok(()) => return,
+                Err(e) => {
+                    error!(self.log, "failed to adopt running data-flow; building it anew";
+                           "error" => e);
+                    self.abandon_adoption(blank);
+                    // so that a controller that takes over doesn't try to adopt it either
+                    self.forget_metadata(authority);
+                }
+            }
+        } else {
+            warn!(
+                self.log,
+                "none of the previous controller's data-flow is running anymore"
+            );
+        }
+
+        match recovery {
+            Some((recipes, recipe_version)) => self.rebuild(recipes, recipe_version),
+            None => {
+                let reported = mem::replace(&mut self.reported, HashMap::new());
+                for running in reported.values() {
+                    self.quit_stale_domains(running);
                 }
             }
-            self.handle_failed_workers(failed);
         }
     }
 
-    fn handle_failed_workers(&mut self, failed: Vec<WorkerIdentifier>) {
-        // first, translate from the affected workers to affected data-flow nodes
-        let mut affected_nodes = Vec::new();
-        for wi in failed {
-            info!(self.log, "handling failure of worker {:?}", wi);
-            affected_nodes.extend(self.get_failed_nodes(&wi));
+    /// Shut down the domain shards that an adoption that failed took over or started, and go back
+    /// to the blank graph and recipe that it started from.
+    fn abandon_adoption(&mut self, (ingredients, source, recipe): (Graph, NodeIndex, Recipe)) {
+        for d in self.domains.values_mut() {
+            for shard in &mut d.shards {
+                // the shards that were never started anew are not connected to
+                let _ = shard.tx.send(Box::new(Packet::Quit));
+            }
         }
+        // what the workers still run that isn't in the graph anymore is stale from here on
+        self.channel_coordinator = Arc::new(ChannelCoordinator::new());
+
+        self.ingredients = ingredients;
+        self.source = source;
+        self.ndomains = 0;
+        self.remap.clear();
+        self.materializations.adopt(Default::default());
+        self.domains.clear();
+        self.domain_nodes.clear();
+        self.node_budgets.clear();
+        self.recipe = recipe;
+        self.journal.clear();
+        self.adhoc_queries.clear();
+    }
 
-        // then, figure out which queries are affected (and thus must be removed and added again in
-        // a migration)
-        let affected_queries = self.recipe.queries_for_nodes(affected_nodes);
-        let (recovery, mut original) = self.recipe.make_recovery(affected_queries);
+    /// Take over the data-flow that `metadata` describes from the controller that started it.
+    ///
+    /// The domain shards that are still running keep running undisturbed. Those that are not,
+    /// because their worker has not come back, are started anew like those of a failed worker.
+    fn adopt(&mut self, metadata: Metadata) -> Result<(), String> {
+        info!(self.log, "adopting running data-flow";
+              "epoch" => ?metadata.epoch,
+              "domains" => metadata.domains.len());
+
+        let mut domains = HashMap::new();
+        let mut lost = Vec::new();
+        for (di, shards) in metadata.domains {
+            let mut handles = Vec::with_capacity(shards.len());
+            for (i, s) in shards.into_iter().enumerate() {
+                let mut handle = match self.running_shard(di, i, &s) {
+                    Some(worker) => {
+                        self.channel_coordinator.insert_remote((di, i), s.addr);
+                        let tx = self
+                            .channel_coordinator
+                            .builder_for(&(di, i))
+                            .unwrap()
+                            .build_sync()
+                            .map_err(|e| format!("failed to connect to running domain: {:?}", e))?;
+                        DomainShardHandle::new(worker, tx)
+                    }
+                    None => {
+                        // nothing is sent to the shard before `recover` starts it anew
+                        lost.push((di, i));
+                        let (tx, _) = tokio::sync::mpsc::unbounded_channel();
+                        DomainShardHandle::new(s.worker, Box::new(tx))
+                    }
+                };
+                handle.setup = s.setup;
+                handles.push(handle);
+            }
+            domains.insert(
+                di,
+                DomainHandle {
+                    idx: di,
+                    shards: handles,
+                    log: self.log.clone(),
+                },
+            );
+        }
 
-        // activate recipe
-        self.apply_recipe(recovery.clone())
-            .expect("failed to apply recovery recipe");
+        self.ingredients = metadata.ingredients;
+        self.source = metadata.source;
+        self.ndomains = metadata.ndomains;
+        self.remap = metadata.remap;
+        self.materializations.adopt(metadata.materializations);
+        self.domains = domains;
+        self.domain_nodes = metadata.domain_nodes;
+        self.node_budgets = metadata.node_budgets;
+
+        // whatever else the workers run is left over from an earlier controller
+        let reported = mem::replace(&mut self.reported, HashMap::new());
+        for running in reported.values() {
+            self.quit_stale_domains(running);
+        }
+        // workers that joined after the shards were started don't know where they are
+        let workers: Vec<_> = self.workers.keys().cloned().collect();
+        for worker in workers {
+            self.announce_domains(worker);
+        }
 
-        // we must do this *after* the migration, since the migration itself modifies the recipe in
-        // `recovery`, and we currently need to clone it here.
-        let tmp = self.recipe.clone();
-        original.set_prior(tmp.clone());
-        // somewhat awkward, but we must replace the stale `SqlIncorporator` state in `original`
-        original.set_sql_inc(tmp.sql_inc().clone());
+        self.replay_journal(metadata.journal)?;
+        let now = Instant::now();
+        self.adhoc_queries = metadata
+            .adhoc_queries
+            .into_iter()
+            .map(|name| (name, now))
+            .collect();
 
-        // back to original recipe, which should add the query again
-        self.apply_recipe(original)
-            .expect("failed to activate original recipe");
+        if !lost.is_empty() {
+            lost.sort_by_key(|&(di, shard)| (di.index(), shard));
+            warn!(self.log, "recovering domain shards that are no longer running";
+                  "shards" => lost.len());
+            self.recover(&lost)?;
+        }
+        Ok(())
     }
 
-    pub(super) fn handle_heartbeat(&mut self, msg: CoordinationMessage) -> Result<(), io::Error> {
-        match self.workers.get_mut(&msg.source) {
-            None => crit!(
-                self.log,
-                "got heartbeat for unknown worker {:?}",
-                msg.source
-            ),
-            Some(ref mut ws) => {
-                ws.last_heartbeat = Instant::now();
-            }
+    /// The worker that still runs the given shard of a domain that the previous controller
+    /// started, if any.
+    fn running_shard(
+        &self,
+        di: DomainIndex,
+        shard: usize,
+        s: &ShardMetadata,
+    ) -> Option<WorkerIdentifier> {
+        let (&worker, _) = self.workers.iter().find(|&(_, w)| w.addr == s.worker)?;
+        let running = self
+            .reported
+            .get(&worker)?
+            .iter()
+            .any(|dd| dd.domain() == di && dd.shard() == shard && dd.addr() == s.addr);
+        if running {
+            Some(worker)
+        } else {
+            None
         }
+    }
 
-        self.check_worker_liveness();
+    /// Make the changes in `journal` to the recipe again, against the nodes that they added to the
+    /// graph the first time around, to rebuild the SQL layer's view of the running data-flow.
+    fn replay_journal(&mut self, journal: Vec<JournalEntry>) -> Result<(), String> {
+        for entry in journal {
+            self.replay_change(&entry)?;
+            self.journal.push(entry);
+        }
         Ok(())
     }
 
-    /// Construct `ControllerInner` with a specified listening interface
-    pub(super) fn new(
-        log: slog::Logger,
-        state: ControllerState,
-        drx: tokio::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
-    ) -> Self {
-        let mut g = petgraph::Graph::new();
-        let source = g.add_node(node::Node::new(
-            "source",
-            &["because-type-inference"],
-            node::special::Source,
-        ));
-
-        let mut materializations = Materializations::new(&log);
-        if !state.config.partial_enabled {
-            materializations.disable_partial()
+    /// Make the change in `entry` to the recipe again, as `replay_journal` does.
+    fn replay_change(&mut self, entry: &JournalEntry) -> Result<(), String> {
+        match entry.change {
+            RecipeChange::Reset(version) => {
+                self.recipe = Recipe::with_version(version, Some(self.log.clone()));
+            }
+            RecipeChange::SecurityConfig(ref config) => self.recipe.set_security_config(config),
+            RecipeChange::Universe(ref context, ref groups) => {
+                let mut r = self.recipe.clone();
+                self.adopt_migration(context.clone(), entry.allocated.clone(), |mig| {
+                    r.next();
+                    r.create_universe(mig, groups.clone())
+                })
+                .and_then(|r| r)
+                .map_err(|e| format!("failed to create universe: {}", e))?;
+                self.recipe = r;
+            }
+            ref change => {
+                let mut new = match *change {
+                    RecipeChange::Extend(ref txt) => {
+                        self.recipe.clone().extend(txt).map_err(|(_, e)| e)?
+                    }
+                    RecipeChange::Install(ref txt) => {
+                        let r = Recipe::from_str(txt, Some(self.log.clone()))?;
+                        self.recipe.clone().replace(r)?
+                    }
+                    RecipeChange::Remove(ref names) => self.recipe.remove_queries(names),
+                    RecipeChange::Restore(ref names) => self.recipe.restore_queries(names),
+                    _ => unreachable!(),
+                };
+                new.set_table_statistics(entry.statistics.clone());
+                self.adopt_migration(HashMap::new(), entry.allocated.clone(), |mig| {
+                    new.activate(mig)
+                })
+                .and_then(|r| r)
+                .map_err(|e| format!("failed to activate recipe: {}", e))?;
+                self.recipe = new;
+            }
         }
-        materializations.set_frontier_strategy(state.config.frontier_strategy);
+        Ok(())
+    }
 
-        let cc = Arc::new(ChannelCoordinator::new());
-        assert_ne!(state.config.quorum, 0);
+    /// Shut down the given domain shards that a worker runs, unless this controller started or
+    /// adopted them. Those are left over from an earlier controller.
+    fn quit_stale_domains(&mut self, running: &[DomainDescriptor]) {
+        for dd in running {
+            if self
+                .channel_coordinator
+                .get_addr(&(dd.domain(), dd.shard()))
+                .map_or(false, |addr| addr == dd.addr())
+            {
+                continue;
+            }
 
-        let pending_recovery = if !state.recipes.is_empty() {
-            Some((state.recipes, state.recipe_version))
-        } else {
-            None
-        };
+            info!(self.log, "shutting down stale domain shard";
+                  "domain" => dd.domain().index(),
+                  "shard" => dd.shard(),
+                  "addr" => ?dd.addr());
+            let quit = DomainConnectionBuilder::for_domain(dd.addr())
+                .build_sync()
+                .map_err(SendError::from)
+                .and_then(|mut tx| tx.send(Box::new(Packet::Quit)));
+            if let Err(e) = quit {
+                warn!(self.log, "failed to shut down stale domain shard: {:?}", e);
+            }
+        }
+    }
 
-        let mut recipe = Recipe::blank(Some(log.clone()));
-        recipe.enable_reuse(state.config.reuse);
+    /// Tell the given worker where every domain shard runs, so that the domains it runs can reach
+    /// them.
+    fn announce_domains(&mut self, worker: WorkerIdentifier) {
+        let cc = &self.channel_coordinator;
+        let announce: Vec<_> = self
+            .domains
+            .iter()
+            .flat_map(|(&di, d)| {
+                (0..d.shards()).filter_map(move |i| cc.get_addr(&(di, i)).map(|a| (di, i, a)))
+            })
+            .map(|(di, shard, addr)| DomainDescriptor::new(di, shard, addr))
+            .collect();
 
-        ControllerInner {
+        let w = self.workers.get_mut(&worker).unwrap();
+        for dd in announce {
+            let sent = w.sender.send(CoordinationMessage {
+                epoch: self.epoch,
+                source: w.sender.local_addr().unwrap(),
+                payload: CoordinationPayload::DomainBooted(dd),
+            });
+            if let Err(e) = sent {
+                warn!(self.log, "failed to tell worker about domain: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    /// Record a change that was made to the recipe, so that a controller that takes over can make
+    /// it again.
+    fn record_change(&mut self, entry: JournalEntry) {
+        self.journal.push(entry);
+        self.metadata_changed = true;
+    }
+
+    /// Replace the journal with a single installation of the recipe as it is now, which hands out
+    /// those of the nodes that the journal's changes handed out that are still in the graph.
+    ///
+    /// Building the recipe from scratch only hands out the same nodes as making every change to it
+    /// did if no query was planned differently along the way, so the installation is made against
+    /// the graph first, and the journal is only replaced if that gives the recipe as it is now.
+    /// Universes and security configurations are not replayed this way, so a journal with any of
+    /// them is left as it is.
+    fn compact_journal(&mut self) {
+        let compactable = self.journal.iter().all(|entry| match entry.change {
+            RecipeChange::Universe(..) | RecipeChange::SecurityConfig(_) => false,
+            _ => true,
+        });
+        if self.journal.len() <= 2 || !compactable || self.recipe.version() == 0 {
+            return;
+        }
+        let text = match self.recipe.to_text() {
+            Some(text) => text,
+            None => {
+                debug!(
+                    self.log,
+                    "not compacting journal of recipe that can't be written out"
+                );
+                return;
+            }
+        };
+
+        let graph = &self.ingredients;
+        let nodes: Vec<_> = self
+            .journal
+            .iter()
+            .flat_map(|entry| entry.allocated.nodes())
+            .filter(|&ni| graph.node_weight(ni).map_or(false, |n| !n.is_dropped()))
+            .collect();
+        let compacted = vec![
+            JournalEntry::new(RecipeChange::Reset(self.recipe.version() - 1)),
+            JournalEntry {
+                change: RecipeChange::Install(text),
+                statistics: self.last_planned_statistics(),
+                allocated: Allocated::of_nodes(nodes),
+            },
+        ];
+
+        let recipe = mem::replace(&mut self.recipe, Recipe::blank(None));
+        let result = compacted
+            .iter()
+            .try_for_each(|entry| self.replay_change(entry));
+        let replayed = mem::replace(&mut self.recipe, recipe);
+        match result {
+            Ok(()) if self.recipe.same_as(&replayed) => {
+                debug!(self.log, "compacted journal"; "changes" => self.journal.len());
+                self.journal = compacted;
+            }
+            Ok(()) => debug!(
+                self.log,
+                "not compacting journal that doesn't rebuild the recipe"
+            ),
+            Err(e) => debug!(self.log, "not compacting journal"; "error" => e),
+        }
+    }
+
+    /// The base table statistics that the last change to the recipe was planned with.
+    fn last_planned_statistics(&self) -> TableStatistics {
+        self.journal
+            .last()
+            .map(|entry| entry.statistics.clone())
+            .unwrap_or_default()
+    }
+
+    /// What a controller that takes over would need to adopt the data-flow as it is now.
+    fn metadata(&self) -> Metadata {
+        let domains = self
+            .domains
+            .iter()
+            .map(|(&di, d)| {
+                let shards = d
+                    .shards
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| ShardMetadata {
+                        worker: self.workers.get(&s.worker).map_or(s.worker, |w| w.addr),
+                        addr: self.channel_coordinator.get_addr(&(di, i)).unwrap(),
+                        setup: s.setup.clone(),
+                    })
+                    .collect();
+                (di, shards)
+            })
+            .collect();
+
+        Metadata {
+            epoch: self.epoch,
+            ingredients: self.ingredients.clone(),
+            source: self.source,
+            ndomains: self.ndomains,
+            remap: self.remap.clone(),
+            materializations: self.materializations.plans(),
+            domains,
+            domain_nodes: self.domain_nodes.clone(),
+            node_budgets: self.node_budgets.clone(),
+            journal: self.journal.clone(),
+            adhoc_queries: self.adhoc_queries.keys().cloned().collect(),
+        }
+    }
+
+    /// Write the metadata that a standby controller needs to take over to the authority, if it
+    /// has changed since it was last written.
+    pub(super) fn persist_metadata<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+    ) -> Result<(), String> {
+        // until then, the metadata that is there still describes what is running
+        if !self.metadata_changed
+            || self.pending_recovery.is_some()
+            || self.pending_adoption.is_some()
+        {
+            return Ok(());
+        }
+
+        self.metadata().write(authority, self.metadata_generation)?;
+        self.metadata_generation += 1;
+        self.metadata_changed = false;
+        Ok(())
+    }
+
+    /// Remove the metadata from the authority, so that the next controller builds the data-flow
+    /// anew rather than trying to adopt it.
+    pub(super) fn forget_metadata<A: Authority + 'static>(&mut self, authority: &Arc<A>) {
+        if let Err(e) = Metadata::forget(authority, self.epoch) {
+            warn!(self.log, "failed to remove controller metadata: {}", e);
+        }
+    }
+
+    /// Forget about a worker that left the deployment. Any domain shards that it still ran are
+    /// recovered as if it had failed.
+    pub(super) fn handle_deregister(&mut self, msg: CoordinationMessage) {
+        let worker = msg.source;
+        let runs_shards = |ctrl: &Self| {
+            ctrl.domains
+                .values()
+                .any(|d| d.shards.iter().any(|s| s.worker == worker))
+        };
+        match self.workers.get_mut(&worker) {
+            Some(w) => w.healthy = false,
+            None => return,
+        }
+
+        if runs_shards(self) {
+            warn!(self.log, "worker left without being drained"; "worker" => ?worker);
+            self.handle_failed_workers(vec![worker]);
+            if runs_shards(self) {
+                // keep it around as a failed worker, so that its shards can be recovered later
+                return;
+            }
+        }
+
+        info!(self.log, "worker left the deployment"; "worker" => ?worker);
+        self.workers.remove(&worker);
+        self.read_addrs.remove(&worker);
+        self.reported.remove(&worker);
+        self.metadata_changed = true;
+    }
+
+    fn check_worker_liveness(&mut self) {
+        let mut any_failed = false;
+
+        // check if there are any newly failed workers
+        if self.last_checked_workers.elapsed() > self.healthcheck_every {
+            for (_addr, ws) in self.workers.iter() {
+                if ws.healthy && ws.last_heartbeat.elapsed() > self.heartbeat_every * 4 {
+                    any_failed = true;
+                }
+            }
+            self.last_checked_workers = Instant::now();
+        }
+
+        // if we have newly failed workers, iterate again to find all workers that have missed >= 3
+        // heartbeats. This is necessary so that we correctly handle correlated failures of
+        // workers.
+        if any_failed {
+            let mut failed = Vec::new();
+            for (addr, ws) in self.workers.iter_mut() {
+                if ws.healthy && ws.last_heartbeat.elapsed() > self.heartbeat_every * 3 {
+                    error!(self.log, "worker at {:?} has failed!", addr);
+                    ws.healthy = false;
+                    failed.push(addr.clone());
+                }
+            }
+            self.handle_failed_workers(failed);
+        }
+    }
+
+    fn handle_failed_workers(&mut self, failed: Vec<WorkerIdentifier>) {
+        for wi in &failed {
+            info!(self.log, "handling failure of worker {:?}", wi);
+        }
+
+        // the domain shards that went away with the failed workers, upstream ones first
+        let mut lost: Vec<_> = self
+            .domains
+            .iter()
+            .flat_map(|(&di, d)| {
+                d.shards
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, s)| failed.contains(&s.worker))
+                    .map(move |(shard, _)| (di, shard))
+            })
+            .collect();
+        if lost.is_empty() {
+            return;
+        }
+        lost.sort_by_key(|&(di, shard)| (di.index(), shard));
+        if !self.workers.values().any(|w| w.healthy) {
+            crit!(
+                self.log,
+                "no healthy workers left to recover lost domain shards on"
+            );
+            return;
+        }
+
+        let start = Instant::now();
+        match self.recover(&lost) {
+            Ok(()) => warn!(self.log, "recovered lost domain shards";
+                            "shards" => lost.len(),
+                            "ms" => start.elapsed().as_millis()),
+            Err(e) => {
+                error!(self.log, "failed to recover lost domain shards: {}", e);
+                match self.rebuild_queries(&lost) {
+                    Ok(()) => warn!(self.log, "rebuilt queries of lost domain shards";
+                                    "shards" => lost.len(),
+                                    "ms" => start.elapsed().as_millis()),
+                    Err(e) => crit!(self.log, "failed to rebuild queries: {}", e),
+                }
+            }
+        }
+    }
+
+    /// Remove every query that reads from the given domain shards, and add them again, so that
+    /// their nodes are built from scratch on the healthy workers. This is what is left to do when
+    /// the shards' state can't be brought back as it was.
+    fn rebuild_queries(&mut self, lost: &[(DomainIndex, usize)]) -> Result<(), String> {
+        // every node of the lost shards, and everything downstream of them
+        let mut nodes: Vec<_> = lost
+            .iter()
+            .flat_map(|(di, _)| self.domain_nodes[di].iter().cloned())
+            .collect();
+        let mut affected = HashSet::new();
+        while let Some(ni) = nodes.pop() {
+            if affected.insert(ni) {
+                nodes.extend(
+                    self.ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing),
+                );
+            }
+        }
+        let queries = self
+            .recipe
+            .queries_for_nodes(affected.into_iter().collect());
+        for q in &queries {
+            warn!(self.log, "query {} affected by failure", q);
+        }
+
+        let removed = self.recipe.remove_queries(&queries);
+        self.apply_recipe(removed, RecipeChange::Remove(queries.clone()))?;
+        let restored = self.recipe.restore_queries(&queries);
+        self.apply_recipe(restored, RecipeChange::Restore(queries))?;
+        Ok(())
+    }
+
+    /// Start the given domain shards, which went away with their workers, anew on the workers that
+    /// are left, and bring back their state: base tables from disk if they are kept there, other
+    /// fully materialized state by replay from upstream, and partially materialized state as it
+    /// is asked for. The other shards keep running throughout.
+    ///
+    /// If that fails, the shards that were started anew are shut down again, so that the affected
+    /// queries can be rebuilt without them.
+    fn recover(&mut self, lost: &[(DomainIndex, usize)]) -> Result<(), String> {
+        self.metadata_changed = true;
+
+        let mut started = Vec::with_capacity(lost.len());
+        let recovered = self.recover_shards(lost, &mut started);
+        if recovered.is_err() {
+            for (di, shard) in started {
+                warn!(self.log, "shutting down partially recovered domain shard";
+                      "domain" => di.index(),
+                      "shard" => shard);
+                // don't unwrap, because the shard may already have terminated
+                drop(
+                    self.domains.get_mut(&di).unwrap().shards[shard]
+                        .tx
+                        .send(Box::new(Packet::Quit)),
+                );
+            }
+            futures_executor::block_on(self.replies.discard_replies(Duration::from_secs(1)));
+        }
+        recovered
+    }
+
+    /// The steps of `recover`. Every shard that is started anew is added to `started`.
+    fn recover_shards(
+        &mut self,
+        lost: &[(DomainIndex, usize)],
+        started: &mut Vec<(DomainIndex, usize)>,
+    ) -> Result<(), String> {
+        // all the shards are started before any is set up, since they may replay to each other
+        let mut restarted = Vec::with_capacity(lost.len());
+        for &(di, shard) in lost {
+            let (addr, setup) = self.restart_shard(di, shard)?;
+            started.push((di, shard));
+            restarted.push((di, shard, addr, setup));
+        }
+
+        // every other shard should connect to the new ones
+        for &(di, shard, addr, _) in &restarted {
+            for (&odi, d) in &mut self.domains {
+                for (i, s) in d.shards.iter_mut().enumerate() {
+                    if (odi, i) == (di, shard) {
+                        continue;
+                    }
+                    s.tx.send(Box::new(Packet::Reconnect {
+                        to: (di, shard),
+                        addr,
+                    }))
+                    .map_err(|e| format!("failed to reconnect to recovered domain: {:?}", e))?;
+                }
+            }
+        }
+
+        // set the new shards up like the lost ones were, except that nodes whose state is filled
+        // in by replay are only marked ready once it has been
+        let mut pending = Vec::with_capacity(restarted.len());
+        for (di, shard, _, setup) in restarted {
+            let kept = self.setup_for_new_shard(di, setup);
+            let graph = &self.ingredients;
+            let dropped: HashSet<_> = self.domain_nodes[&di]
+                .iter()
+                .filter(|&&ni| graph[ni].is_dropped())
+                .map(|&ni| graph[ni].local_addr())
+                .collect();
+            let replays: Vec<_> = kept
+                .iter()
+                .filter_map(|p| match *p {
+                    Packet::SetupReplayPath {
+                        tag,
+                        ref path,
+                        notify_done: true,
+                        ..
+                    } if !dropped.contains(&path.last().unwrap().node) => {
+                        Some((path.last().unwrap().node, tag))
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            let mut ready = Vec::new();
+            for p in &kept {
+                let acks = match *p {
+                    Packet::StartReplay { .. } => continue,
+                    Packet::Ready { node, .. } if replays.iter().any(|&(n, _)| n == node) => {
+                        ready.push(p.clone());
+                        continue;
+                    }
+                    Packet::SetupReplayPath { .. }
+                    | Packet::Ready { .. }
+                    | Packet::AddBaseColumn { .. }
+                    | Packet::DropBaseColumn { .. } => true,
+                    _ => false,
+                };
+                let domain = self.domains.get_mut(&di).unwrap();
+                domain
+                    .send_to_healthy_shard(shard, Box::new(p.clone()), &self.workers)
+                    .map_err(|e| format!("failed to set up recovered domain: {:?}", e))?;
+                if acks {
+                    futures_executor::block_on(self.replies.wait_for_ack_within(RECOVERY_TIMEOUT))?;
+                }
+            }
+            pending.push((di, shard, kept, replays, ready));
+        }
+
+        // fill in the fully materialized state, upstream shards first so that they have theirs
+        // by the time they replay to downstream ones
+        for (di, shard, kept, replays, ready) in pending {
+            for (_, tag) in replays {
+                let (sdi, from) = match self.replay_source(tag) {
+                    Some(source) => source,
+                    // the path was set up, but another one was replayed along instead
+                    None => continue,
+                };
+                let p = Box::new(Packet::StartReplay { tag, from });
+                let domain = self.domains.get_mut(&sdi).unwrap();
+                let sent = if sdi == di {
+                    domain.send_to_healthy_shard(shard, p, &self.workers)
+                } else {
+                    // the other shards of this shard's domain ignore what isn't meant for them
+                    domain.send_to_healthy(p, &self.workers)
+                };
+                sent.map_err(|e| format!("failed to replay to recovered domain: {:?}", e))?;
+                futures_executor::block_on(self.replies.wait_for_ack_within(RECOVERY_TIMEOUT))?;
+            }
+            for p in ready {
+                let domain = self.domains.get_mut(&di).unwrap();
+                domain
+                    .send_to_healthy_shard(shard, Box::new(p), &self.workers)
+                    .map_err(|e| format!("failed to ready recovered domain: {:?}", e))?;
+                futures_executor::block_on(self.replies.wait_for_ack_within(RECOVERY_TIMEOUT))?;
+            }
+            self.domains.get_mut(&di).unwrap().shards[shard].setup = kept;
+        }
+        Ok(())
+    }
+
+    /// Start shard `shard` of domain `di` anew on one of the healthy workers, with its nodes as
+    /// they are in the graph. Returns the address of the new shard, and the packets that set up
+    /// the one that was lost.
+    fn restart_shard(
+        &mut self,
+        di: DomainIndex,
+        shard: usize,
+    ) -> Result<(SocketAddr, Vec<Packet>), String> {
+        let log = self.log.clone();
+        let nodes: Vec<_> = self.domain_nodes[&di]
+            .iter()
+            .map(|&ni| (ni, false))
+            .collect();
+        let worker = self.choose_workers(di, 1, &nodes)?[0];
+
+        let graph = &self.ingredients;
+        let builder = DomainBuilder {
+            index: di,
+            shard: graph[nodes[0].0].sharded_by().shards().map(|_| shard),
+            nshards: self.domains[&di].shards(),
+            config: self.domain_config.clone(),
+            nodes: nodes
+                .iter()
+                .map(|&(ni, _)| graph[ni].retake().finalize(graph))
+                .map(|n| (n.local_addr(), cell::RefCell::new(n)))
+                .collect(),
+            persistence_parameters: self.persistence.clone(),
+        };
+        let w = self.workers.get_mut(&worker).unwrap();
+        info!(
+            log,
+            "sending recovered domain {}.{} to worker {:?}",
+            di.index(),
+            shard,
+            w.sender.peer_addr()
+        );
+        let src = w.sender.local_addr().unwrap();
+        w.sender
+            .send(CoordinationMessage {
+                epoch: self.epoch,
+                source: src,
+                payload: CoordinationPayload::AssignDomain(builder),
+            })
+            .map_err(|e| format!("failed to send domain to worker: {:?}", e))?;
+
+        let addr = futures_executor::block_on(self.replies.wait_for_boot_within(RECOVERY_TIMEOUT))?;
+        self.channel_coordinator.insert_remote((di, shard), addr);
+        let tx = self
+            .channel_coordinator
+            .builder_for(&(di, shard))
+            .unwrap()
+            .build_sync()
+            .map_err(|e| format!("failed to connect to recovered domain: {:?}", e))?;
+        for endpoint in self.workers.values_mut().filter(|w| w.healthy) {
+            endpoint
+                .sender
+                .send(CoordinationMessage {
+                    epoch: self.epoch,
+                    source: endpoint.sender.local_addr().unwrap(),
+                    payload: CoordinationPayload::DomainBooted(DomainDescriptor::new(
+                        di, shard, addr,
+                    )),
+                })
+                .unwrap();
+        }
+
+        let lost = mem::replace(
+            &mut self.domains.get_mut(&di).unwrap().shards[shard],
+            DomainShardHandle::new(worker, tx),
+        );
+        Ok((addr, lost.setup))
+    }
+
+    /// The domain and node that the full replay along the path tagged `tag` starts from, if any
+    /// replay was started along it.
+    fn replay_source(&self, tag: Tag) -> Option<(DomainIndex, LocalNodeIndex)> {
+        self.domains.iter().find_map(|(&di, d)| {
+            d.shards
+                .iter()
+                .flat_map(|s| &s.setup)
+                .find_map(|p| match *p {
+                    Packet::StartReplay { tag: t, from } if t == tag => Some((di, from)),
+                    _ => None,
+                })
+        })
+    }
+
+    pub(super) fn handle_heartbeat<A: Authority + 'static>(
+        &mut self,
+        msg: CoordinationMessage,
+        authority: &Arc<A>,
+    ) -> Result<(), io::Error> {
+        match self.workers.get_mut(&msg.source) {
+            None => crit!(
+                self.log,
+                "got heartbeat for unknown worker {:?}",
+                msg.source
+            ),
+            Some(ref mut ws) => {
+                if let CoordinationPayload::Heartbeat { state_size, reads } = msg.payload {
+                    ws.heartbeat(state_size, reads);
+                    let read: Vec<_> = ws
+                        .read_rates
+                        .iter()
+                        .filter(|&(_, &rate)| rate > 0.0)
+                        .map(|(&(reader, _), _)| reader)
+                        .collect();
+                    self.mark_adhoc_queries_read(read);
+                } else {
+                    ws.last_heartbeat = Instant::now();
+                }
+            }
+        }
+
+        self.check_worker_liveness();
+        if let Some((_, since)) = self.pending_adoption {
+            // the workers that haven't come back by now have failed along with the previous
+            // controller, so the shards they ran are started anew
+            if self.workers.len() >= self.quorum && since.elapsed() > self.heartbeat_every * 4 {
+                self.finish_adoption(authority);
+            }
+        }
+        if self.pending_recovery.is_none() && self.pending_adoption.is_none() {
+            self.remove_idle_adhoc_queries(authority, Instant::now());
+
+            let stale = match self.table_statistics {
+                Some((gathered, _)) => gathered.elapsed() >= TABLE_STATISTICS_INTERVAL,
+                None => true,
+            };
+            if stale {
+                self.refresh_table_statistics();
+            }
+
+            if let Some(every) = self.persistence.snapshot_interval {
+                if self.persistence.mode == DurabilityMode::Permanent
+                    && self.last_snapshot.elapsed() >= every
+                {
+                    if let Err(e) = self.take_snapshot(authority) {
+                        warn!(self.log, "periodic snapshot failed: {}", e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Construct `ControllerInner` with a specified listening interface
+    pub(super) fn new(
+        log: slog::Logger,
+        state: ControllerState,
+        metadata: Option<Metadata>,
+        drx: tokio::sync::mpsc::UnboundedReceiver<ControlReplyPacket>,
+    ) -> Self {
+        let mut g = petgraph::Graph::new();
+        let source = g.add_node(node::Node::new(
+            "source",
+            &["because-type-inference"],
+            node::special::Source,
+        ));
+
+        let mut materializations = Materializations::new(&log);
+        if !state.config.partial_enabled {
+            materializations.disable_partial()
+        }
+        materializations.set_frontier_strategy(state.config.frontier_strategy);
+
+        let cc = Arc::new(ChannelCoordinator::new());
+        assert_ne!(state.config.quorum, 0);
+
+        let pending_recovery = if !state.recipes.is_empty() {
+            Some((state.recipes, state.recipe_version))
+        } else {
+            None
+        };
+
+        let mut recipe = Recipe::blank(Some(log.clone()));
+        recipe.enable_reuse(state.config.reuse);
+
+        ControllerInner {
             ingredients: g,
             source,
             ndomains: 0,
 
             materializations,
+            placer: Placer::new(
+                state.config.placement_policy,
+                state.config.placement_constraints,
+            ),
             sharding: state.config.sharding,
             domain_config: state.config.domain_config,
             persistence: state.config.persistence,
             heartbeat_every: state.config.heartbeat_every,
             healthcheck_every: state.config.healthcheck_every,
             recipe,
+            adhoc_queries: HashMap::default(),
+            adhoc_query_idle_timeout: state.config.adhoc_query_idle_timeout,
+            memory_budgets: state.memory_budgets,
+            read_replicas: state.read_replicas,
+            node_budgets: HashMap::default(),
+            snapshot: state.snapshot,
+            last_snapshot: Instant::now(),
+            security_config: state.security_config,
+            universes: state.universes,
             quorum: state.config.quorum,
             log,
 
@@ -476,9 +1696,20 @@ impl ControllerInner {
             workers: HashMap::default(),
 
             pending_recovery,
+            recipe_statistics: state.recipe_statistics,
+            table_statistics: None,
+            pending_adoption: metadata.map(|m| (m, Instant::now())),
+            reported: HashMap::default(),
+            journal: Vec::new(),
+            metadata_changed: false,
+            metadata_generation: 0,
             last_checked_workers: Instant::now(),
 
-            replies: DomainReplies(drx),
+            replies: DomainReplies {
+                rx: drx,
+                late_statistics: 0,
+                pause: 0,
+            },
         }
     }
 
@@ -498,13 +1729,15 @@ impl ControllerInner {
 
     /// Controls the persistence mode, and parameters related to persistence.
     ///
-    /// Three modes are available:
+    /// Four modes are available:
     ///
     ///  1. `DurabilityMode::Permanent`: all writes to base nodes should be written to disk.
     ///  2. `DurabilityMode::DeleteOnExit`: all writes are written to disk, but the log is
     ///     deleted once the `Controller` is dropped. Useful for tests.
     ///  3. `DurabilityMode::MemoryOnly`: no writes to disk, store all writes in memory.
     ///     Useful for baseline numbers.
+    ///  4. `DurabilityMode::Logged`: base nodes are kept in memory, but all writes to them are
+    ///     also appended to a write-ahead log on disk.
     ///
     /// `queue_capacity` indicates the number of packets that should be buffered until
     /// flushing, and `flush_timeout` indicates the length of time to wait before flushing
@@ -517,28 +1750,143 @@ impl ControllerInner {
         self.persistence = params;
     }
 
-    pub(in crate::controller) fn place_domain(
-        &mut self,
+    /// The labels that the new domain `idx`, which holds `nodes`, requires of the workers it runs
+    /// on, and the workers that it could run on.
+    fn placement_candidates(
+        &self,
         idx: DomainIndex,
-        num_shards: Option<usize>,
-        log: &Logger,
-        nodes: Vec<(NodeIndex, bool)>,
-    ) -> DomainHandle {
-        // TODO: can we just redirect all domain traffic through the worker's connection?
-        let mut assignments = Vec::new();
-        let mut nodes = Some(
-            nodes
-                .into_iter()
-                .map(|(ni, _)| {
-                    let node = self.ingredients.node_weight_mut(ni).unwrap().take();
-                    node.finalize(&self.ingredients)
-                })
-                .map(|nd| (nd.local_addr(), cell::RefCell::new(nd)))
-                .collect(),
-        );
+        nodes: &[(NodeIndex, bool)],
+    ) -> (Vec<String>, Vec<Candidate>) {
+        let labels = self
+            .placer
+            .required_labels(nodes.iter().map(|&(ni, _)| &self.ingredients[ni]));
+
+        // the domains that the new domain exchanges updates with
+        let mut neighbours = HashSet::new();
+        for &(ni, _) in nodes {
+            for nn in self.ingredients.neighbors_undirected(ni) {
+                let n = &self.ingredients[nn];
+                if n.has_domain() && n.domain() != idx {
+                    neighbours.insert(n.domain());
+                }
+            }
+        }
+
+        // replicas of a reader should run on workers that the reader and its other replicas don't
+        let replicated = nodes.iter().find_map(|&(ni, _)| {
+            self.ingredients[ni]
+                .with_reader(|r| r.replica_of())
+                .ok()
+                .flatten()
+        });
+        let mut avoid = HashSet::new();
+        if let Some(reader) = replicated {
+            let mut readers = self.read_replicas_of(reader);
+            readers.push(reader);
+            for r in readers {
+                if let Some(d) = self.domains.get(&self.ingredients[r].domain()) {
+                    avoid.extend(d.shards.iter().map(|s| s.worker));
+                }
+            }
+        }
+        if self
+            .workers
+            .iter()
+            .all(|(addr, w)| !w.healthy || w.draining || avoid.contains(addr))
+        {
+            // there aren't enough workers to go around
+            avoid.clear();
+        }
+
+        let mut candidates: HashMap<_, _> = self
+            .workers
+            .iter()
+            .filter(|&(addr, w)| w.healthy && !w.draining && !avoid.contains(addr))
+            .map(|(&addr, w)| {
+                let c = Candidate {
+                    addr,
+                    cpus: w.cpus,
+                    labels: w.labels.clone(),
+                    state_size: w.state_size,
+                    domains: 0,
+                    neighbours: 0,
+                };
+                (addr, c)
+            })
+            .collect();
+        for (di, d) in &self.domains {
+            for shard in &d.shards {
+                if let Some(c) = candidates.get_mut(&shard.worker) {
+                    c.domains += 1;
+                    if neighbours.contains(di) {
+                        c.neighbours += 1;
+                    }
+                }
+            }
+        }
+
+        (labels, candidates.into_iter().map(|(_, c)| c).collect())
+    }
+
+    /// Choose the worker that each shard of the new domain `idx`, which holds `nodes`, runs on.
+    fn choose_workers(
+        &mut self,
+        idx: DomainIndex,
+        shards: usize,
+        nodes: &[(NodeIndex, bool)],
+    ) -> Result<Vec<WorkerIdentifier>, String> {
+        let (labels, candidates) = self.placement_candidates(idx, nodes);
+        self.placer.place(&labels, candidates, shards)
+    }
+
+    /// Check that every domain that a migration adds, with the nodes in `new`, can be placed on
+    /// a worker that has the labels that placement constraints require of it.
+    pub(in crate::controller) fn check_placement(
+        &self,
+        new: &HashSet<NodeIndex>,
+    ) -> Result<(), String> {
+        let mut domains: HashMap<DomainIndex, Vec<(NodeIndex, bool)>> = HashMap::new();
+        for &ni in new {
+            let n = &self.ingredients[ni];
+            if ni == self.source || n.is_dropped() || self.domains.contains_key(&n.domain()) {
+                continue;
+            }
+            domains.entry(n.domain()).or_default().push((ni, true));
+        }
+        for (di, nodes) in domains {
+            let (labels, candidates) = self.placement_candidates(di, &nodes);
+            if !Placer::can_place(&labels, &candidates) {
+                return Err(format!(
+                    "no healthy worker has the labels {:?} that placement constraints require \
+                     of domain {}",
+                    labels,
+                    di.index()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub(in crate::controller) fn place_domain(
+        &mut self,
+        idx: DomainIndex,
+        num_shards: Option<usize>,
+        log: &Logger,
+        nodes: Vec<(NodeIndex, bool)>,
+    ) -> Result<DomainHandle, String> {
+        let assignments = self.choose_workers(idx, num_shards.unwrap_or(1), &nodes)?;
 
-        // TODO(malte): simple round-robin placement for the moment
-        let mut wi = self.workers.iter_mut();
+        // TODO: can we just redirect all domain traffic through the worker's connection?
+        let mut nodes = Some(
+            nodes
+                .into_iter()
+                .map(|(ni, _)| {
+                    let node = self.ingredients.node_weight_mut(ni).unwrap().take();
+                    node.finalize(&self.ingredients)
+                })
+                .map(|nd| (nd.local_addr(), cell::RefCell::new(nd)))
+                .collect(),
+        );
 
         // Send `AssignDomain` to each shard of the given domain
         for i in 0..num_shards.unwrap_or(1) {
@@ -557,15 +1905,7 @@ impl ControllerInner {
                 persistence_parameters: self.persistence.clone(),
             };
 
-            let (identifier, w) = loop {
-                if let Some((i, w)) = wi.next() {
-                    if w.healthy {
-                        break (*i, w);
-                    }
-                } else {
-                    wi = self.workers.iter_mut();
-                }
-            };
+            let w = self.workers.get_mut(&assignments[i]).unwrap();
 
             // send domain to worker
             info!(
@@ -583,8 +1923,6 @@ impl ControllerInner {
                     payload: CoordinationPayload::AssignDomain(domain),
                 })
                 .unwrap();
-
-            assignments.push(identifier);
         }
 
         // Wait for all the domains to acknowledge.
@@ -643,15 +1981,15 @@ impl ControllerInner {
             .enumerate()
             .map(|(i, worker)| {
                 let tx = txs.remove(&i).unwrap();
-                DomainShardHandle { worker, tx }
+                DomainShardHandle::new(worker, tx)
             })
             .collect();
 
-        DomainHandle {
+        Ok(DomainHandle {
             idx,
             shards,
             log: log.clone(),
-        }
+        })
     }
 
     /// Set the `Logger` to use for internal log messages.
@@ -665,45 +2003,169 @@ impl ControllerInner {
 
     /// Adds a new user universe.
     /// User universes automatically enforce security policies.
-    fn add_universe<F, T>(&mut self, context: HashMap<String, DataType>, f: F) -> T
+    ///
+    /// Also returns the nodes and base columns that the migration handed out.
+    fn add_universe<F, T>(
+        &mut self,
+        context: HashMap<String, DataType>,
+        f: F,
+    ) -> Result<(T, Allocated), String>
     where
-        F: FnOnce(&mut Migration) -> T,
+        F: FnOnce(&mut Migration) -> Result<T, String>,
     {
         info!(self.log, "starting migration: new soup universe");
+        self.migrate_in(context, f)
+    }
+
+    /// Perform a new query schema migration.
+    ///
+    /// If the migration turns out to be invalid, none of it is applied, and an error is returned.
+    // crate viz for tests
+    pub(crate) fn migrate<F, T>(&mut self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut Migration) -> T,
+    {
+        self.migrate_allocating(|mig| Ok(f(mig))).map(|(r, _)| r)
+    }
+
+    /// Perform a new query schema migration, and also return the nodes and base columns that it
+    /// handed out, which `adopt_migration` needs to perform it again.
+    ///
+    /// If `f` fails, or the migration turns out to be invalid, none of it is applied, and an error
+    /// is returned.
+    fn migrate_allocating<F, T>(&mut self, f: F) -> Result<(T, Allocated), String>
+    where
+        F: FnOnce(&mut Migration) -> Result<T, String>,
+    {
+        info!(self.log, "starting migration");
+        self.migrate_in(Default::default(), f)
+    }
+
+    fn migrate_in<F, T>(
+        &mut self,
+        context: HashMap<String, DataType>,
+        f: F,
+    ) -> Result<(T, Allocated), String>
+    where
+        F: FnOnce(&mut Migration) -> Result<T, String>,
+    {
         let miglog = self.log.new(o!());
         let mut m = Migration {
             mainline: self,
             added: Default::default(),
             columns: Default::default(),
+            unaltered: Default::default(),
             readers: Default::default(),
             context,
             start: time::Instant::now(),
             log: miglog,
+            allocated: Default::default(),
+            adopting: false,
+            diverged: None,
         };
-        let r = f(&mut m);
-        m.commit();
-        r
+        let r = match f(&mut m) {
+            Ok(r) => r,
+            Err(e) => {
+                m.abandon();
+                return Err(e);
+            }
+        };
+        let allocated = mem::replace(&mut m.allocated, Default::default());
+        m.commit()?;
+        self.metadata_changed = true;
+        Ok((r, allocated))
     }
 
-    /// Perform a new query schema migration.
-    // crate viz for tests
-    pub(crate) fn migrate<F, T>(&mut self, f: F) -> T
+    /// Perform a migration that the controller which started the running data-flow performed
+    /// again, handing out the nodes and base columns that it handed out then. Nothing is added
+    /// to the graph, and nothing is sent to any domain; this only brings whatever drives the
+    /// migration up to date with the graph.
+    ///
+    /// Fails if the migration doesn't ask for the nodes and base columns it was handed out.
+    fn adopt_migration<F, T>(
+        &mut self,
+        context: HashMap<String, DataType>,
+        allocated: Allocated,
+        f: F,
+    ) -> Result<T, String>
     where
         F: FnOnce(&mut Migration) -> T,
     {
-        info!(self.log, "starting migration");
         let miglog = self.log.new(o!());
         let mut m = Migration {
             mainline: self,
             added: Default::default(),
             columns: Default::default(),
+            unaltered: Default::default(),
             readers: Default::default(),
-            context: Default::default(),
+            context,
             start: time::Instant::now(),
             log: miglog,
+            allocated,
+            adopting: true,
+            diverged: None,
         };
         let r = f(&mut m);
-        m.commit();
+        if let Some(why) = m.diverged {
+            return Err(why);
+        }
+        if !m.allocated.is_empty() {
+            return Err("adopted migration added fewer nodes than it did originally".to_owned());
+        }
+        Ok(r)
+    }
+
+    /// Plan a new query schema migration without applying it to any domain.
+    ///
+    /// If `f` succeeds, and the migration is valid, this returns its result along with all the
+    /// nodes the migration would add. The graph is left in the state the migration would put it
+    /// in, so this should only be called within `dry_run`.
+    fn plan_migration<F, T>(&mut self, f: F) -> Result<(T, HashSet<NodeIndex>), String>
+    where
+        F: FnOnce(&mut Migration) -> Result<T, String>,
+    {
+        info!(self.log, "starting migration dry run");
+        let miglog = self.log.new(o!());
+        let mut m = Migration {
+            mainline: self,
+            added: Default::default(),
+            columns: Default::default(),
+            unaltered: Default::default(),
+            readers: Default::default(),
+            context: Default::default(),
+            start: time::Instant::now(),
+            log: miglog,
+            allocated: Default::default(),
+            adopting: false,
+            diverged: None,
+        };
+
+        let r = f(&mut m)?;
+        Ok((r, m.plan()?))
+    }
+
+    /// Run `f`, and then undo all changes it made to the graph, its domain assignment, and its
+    /// materializations. If given, `mir` is restored as well.
+    ///
+    /// `f` must not send anything to any domain, since that could not be undone.
+    fn dry_run<F, T>(&mut self, mir: Option<MirSnapshot>, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let ingredients = self.ingredients.clone();
+        let ndomains = self.ndomains;
+        let remap = self.remap.clone();
+        let materializations = self.materializations.clone();
+
+        let r = f(self);
+
+        self.ingredients = ingredients;
+        self.ndomains = ndomains;
+        self.remap = remap;
+        self.materializations = materializations;
+        if let Some(mir) = mir {
+            mir.restore();
+        }
         r
     }
 
@@ -756,7 +2218,7 @@ impl ControllerInner {
         let mut bfs = Bfs::new(&self.ingredients, node);
         while let Some(child) = bfs.next(&self.ingredients) {
             if self.ingredients[child]
-                .with_reader(|r| r.is_for() == node)
+                .with_reader(|r| r.is_for() == node && r.replica_of().is_none())
                 .unwrap_or(false)
                 && self.ingredients[child].name() == name
             {
@@ -766,9 +2228,8 @@ impl ControllerInner {
         None
     }
 
-    /// Obtain a `ViewBuilder` that can be sent to a client and then used to query a given
-    /// (already maintained) reader node called `name`.
-    fn view_builder(&self, name: &str) -> Option<ViewBuilder> {
+    /// Find the reader node for the query or view called `name`.
+    fn reader_for(&self, name: &str) -> Option<NodeIndex> {
         // first try to resolve the node via the recipe, which handles aliasing between identical
         // queries.
         let node = match self.recipe.node_addr_for(name) {
@@ -780,12 +2241,20 @@ impl ControllerInner {
             }
         };
 
-        self.find_view_for(node, name).map(|r| {
-            let domain = self.ingredients[r].domain();
+        self.find_view_for(node, name)
+    }
+
+    /// Obtain a `ViewBuilder` that can be sent to a client and then used to query a given
+    /// (already maintained) reader node called `name`.
+    fn view_builder(&self, name: &str) -> Option<ViewBuilder> {
+        self.reader_for(name).map(|r| {
             let columns = self.ingredients[r].fields().to_vec();
             let schema = self.view_schema(r);
-            let shards = (0..self.domains[&domain].shards())
-                .map(|i| self.read_addrs[&self.domains[&domain].assignment(i)])
+            let shards = self.read_shards(r);
+            let replicas = self
+                .read_replicas_of(r)
+                .into_iter()
+                .map(|replica| (replica, self.read_shards(replica)))
                 .collect();
 
             ViewBuilder {
@@ -793,10 +2262,312 @@ impl ControllerInner {
                 columns,
                 schema,
                 shards,
+                replicas,
+            }
+        })
+    }
+
+    /// The addresses that the shards of the given reader are read from.
+    fn read_shards(&self, reader: NodeIndex) -> Vec<SocketAddr> {
+        let domain = &self.domains[&self.ingredients[reader].domain()];
+        (0..domain.shards())
+            .map(|i| self.read_addrs[&domain.assignment(i)])
+            .collect()
+    }
+
+    /// Obtain a `ViewBuilder` for an ad-hoc `SELECT` query.
+    ///
+    /// If the recipe already contains an identical query, its view is reused. Otherwise, the
+    /// query is installed under a name derived from its hash. Queries installed this way are not
+    /// persisted to the authority, and are removed again once they have been neither requested
+    /// (by this method or by name through `view_builder`) nor read from for
+    /// `adhoc_query_idle_timeout`.
+    fn query_builder<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        sql: &str,
+    ) -> Result<ViewBuilder, String> {
+        let q = nom_sql::parse_query(sql).map_err(|e| format!("failed to parse query: {}", e))?;
+        match q {
+            SqlQuery::Select(_) | SqlQuery::CompoundSelect(_) => {}
+            _ => return Err("only SELECT queries can be installed ad-hoc".to_owned()),
+        }
+
+        let name = match self.recipe.name_for_query(&q) {
+            Some(name) => name.to_owned(),
+            None => {
+                let name = format!("adhoc_{:x}", recipe::hash_query(&q));
+                let add_txt = format!("QUERY {}: {};", name, sql.trim().trim_end_matches(';'));
+
+                let old = mem::replace(&mut self.recipe, Recipe::blank(None));
+                match old.extend(&add_txt) {
+                    Ok(new) => {
+                        self.apply_recipe(new, RecipeChange::Extend(add_txt))?;
+                        self.persist_recipe_version(authority)?;
+                    }
+                    Err((old, e)) => {
+                        crit!(self.log, "failed to install ad-hoc query: {:?}", e);
+                        self.recipe = old;
+                        return Err("failed to install ad-hoc query".to_owned());
+                    }
+                }
+
+                info!(self.log, "installed ad-hoc query"; "name" => &name);
+                self.adhoc_queries.insert(name.clone(), Instant::now());
+                name
+            }
+        };
+
+        if let Some(used) = self.adhoc_queries.get_mut(&name) {
+            *used = Instant::now();
+        }
+
+        self.view_builder(&name)
+            .ok_or_else(|| format!("no view exists for query {}", name))
+    }
+
+    /// Keep the ad-hoc queries whose views the given readers serve from being removed as idle.
+    fn mark_adhoc_queries_read(&mut self, readers: Vec<NodeIndex>) {
+        if self.adhoc_queries.is_empty() {
+            return;
+        }
+        for reader in readers {
+            // the reader may have been removed since the worker's last heartbeat
+            let mut n = match self.ingredients.node_weight(reader) {
+                Some(n) => n,
+                None => continue,
+            };
+            // replicas of a reader serve the same view as the reader
+            if let Some(of) = n.with_reader(|r| r.replica_of()).ok().flatten() {
+                n = &self.ingredients[of];
             }
+            if let Some(used) = self.adhoc_queries.get_mut(n.name()) {
+                *used = Instant::now();
+            }
+        }
+    }
+
+    /// Persist the version of the recipe after a change that is not itself persisted, so that a
+    /// controller that rebuilds the recipe later numbers it the same way.
+    fn persist_recipe_version<A: Authority + 'static>(
+        &self,
+        authority: &Arc<A>,
+    ) -> Result<(), String> {
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.recipe_version = self.recipe.version();
+                    Ok(state)
+                }
+            })
+            .is_err()
+        {
+            return Err("Failed to persist recipe version".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Remove ad-hoc queries that have been neither requested nor read from for longer than the
+    /// idle timeout.
+    pub(super) fn remove_idle_adhoc_queries<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        now: Instant,
+    ) {
+        let timeout = match self.adhoc_query_idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let idle: Vec<String> = self
+            .adhoc_queries
+            .iter()
+            .filter(|&(_, &used)| now.saturating_duration_since(used) > timeout)
+            .map(|(name, _)| name.clone())
+            .collect();
+        if idle.is_empty() {
+            return;
+        }
+
+        for name in &idle {
+            self.adhoc_queries.remove(name);
+        }
+
+        // a query that has since also been added to the recipe under another name is no longer
+        // ad-hoc, and must stay around.
+        let idle: Vec<String> = idle
+            .into_iter()
+            .filter(|name| !self.recipe.is_aliased(name))
+            .collect();
+        if idle.is_empty() {
+            return;
+        }
+
+        info!(self.log, "removing {} idle ad-hoc queries", idle.len());
+        let new = self.recipe.remove_queries(&idle);
+        if let Err(e) = self.apply_recipe(new, RecipeChange::Remove(idle)) {
+            crit!(self.log, "failed to remove idle ad-hoc queries: {}", e);
+            return;
+        }
+        if let Err(e) = self.persist_recipe_version(authority) {
+            crit!(self.log, "{}", e);
+        }
+    }
+
+    /// Explain how the given `SELECT` query is executed, or would be executed if it were added to
+    /// the recipe.
+    ///
+    /// A query that is not yet installed is planned against a copy of the recipe within
+    /// `dry_run`, which tells the existing nodes it would reuse apart from the ones it would add
+    /// without changing the running data-flow.
+    fn explain(&mut self, sql: &str) -> Result<Explanation, String> {
+        let sql = match sql.trim().get(..7) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("explain") => sql.trim()[7..].trim(),
+            _ => sql.trim(),
+        };
+        let q = nom_sql::parse_query(sql).map_err(|e| format!("failed to parse query: {}", e))?;
+        match q {
+            SqlQuery::Select(_) | SqlQuery::CompoundSelect(_) => {}
+            _ => return Err("only SELECT queries can be explained".to_owned()),
+        }
+
+        let stats = self.graph_statistics();
+        let rows = node_rows(&stats);
+
+        if let Some(name) = self.recipe.name_for_query(&q).map(str::to_owned) {
+            let leaf = self.recipe.node_addr_for(&name)?;
+            let nodes = self.query_nodes(leaf, &name);
+            let (reuse_strategy, reuse, reused_nodes) =
+                explain_reuse(self.recipe.query_reuse(&name));
+            return Ok(Explanation {
+                mir: self.recipe.mir_graphviz(&name),
+                reuse_strategy,
+                reuse,
+                reused_nodes,
+                nodes: self.explain_nodes(nodes, &HashSet::new(), &rows),
+                name,
+                installed: true,
+            });
+        }
+
+        let name = format!("explain_{:x}", recipe::hash_query(&q));
+        let add_txt = format!("QUERY {}: {};", name, sql.trim_end_matches(';'));
+        let mut candidate = self.recipe.clone().extend(&add_txt).map_err(|(_, e)| e)?;
+        candidate.set_table_statistics(self.base_table_statistics(&stats));
+
+        let mir = candidate.mir_snapshot();
+        self.dry_run(mir, |inner| {
+            let (ra, new) = inner.plan_migration(|mig| candidate.activate(mig))?;
+            let leaf = match ra.new_nodes.get(&name) {
+                Some(&ni) => ni,
+                None => candidate.node_addr_for(&name)?,
+            };
+            let nodes = inner.query_nodes(leaf, &name);
+            let (reuse_strategy, reuse, reused_nodes) = explain_reuse(candidate.query_reuse(&name));
+            Ok(Explanation {
+                mir: candidate.mir_graphviz(&name),
+                reuse_strategy,
+                reuse,
+                reused_nodes,
+                nodes: inner.explain_nodes(nodes, &new, &rows),
+                name,
+                installed: false,
+            })
         })
     }
 
+    /// Returns all nodes that the reader of query `name` on `leaf` reads from, in topological
+    /// order.
+    fn query_nodes(&self, leaf: NodeIndex, name: &str) -> Vec<NodeIndex> {
+        let mut nodes = HashSet::new();
+        let mut stack = vec![self.find_view_for(leaf, name).unwrap_or(leaf)];
+        while let Some(ni) = stack.pop() {
+            if ni != self.source && nodes.insert(ni) {
+                stack.extend(
+                    self.ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Incoming),
+                );
+            }
+        }
+        // nodes are always added after their ancestors, so this is a topological order
+        let mut nodes: Vec<_> = nodes.into_iter().collect();
+        nodes.sort();
+        nodes
+    }
+
+    /// Describe the given nodes, which must be in topological order.
+    ///
+    /// Nodes in `new` are marked as new. The output size of existing fully materialized nodes is
+    /// taken from `rows`; for all other nodes, it is estimated from the sizes of their ancestors.
+    fn explain_nodes(
+        &self,
+        nodes: Vec<NodeIndex>,
+        new: &HashSet<NodeIndex>,
+        rows: &HashMap<NodeIndex, u64>,
+    ) -> Vec<NodeExplanation> {
+        let known_rows = |ni: NodeIndex| {
+            let n = &self.ingredients[ni];
+            let known = match self.materializations.get_status(ni, n) {
+                MaterializationStatus::Full => true,
+                _ => n.is_base(),
+            };
+            if known && !new.contains(&ni) {
+                rows.get(&ni).cloned()
+            } else {
+                None
+            }
+        };
+
+        let mut estimates = HashMap::new();
+        nodes
+            .into_iter()
+            .map(|ni| {
+                let n = &self.ingredients[ni];
+                let estimated_rows = known_rows(ni).or_else(|| {
+                    self.ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                        .filter(|&p| p != self.source)
+                        .map(|p| estimates.get(&p).cloned().unwrap_or_else(|| known_rows(p)))
+                        .collect::<Option<Vec<u64>>>()
+                        .filter(|parents| !parents.is_empty())
+                        .map(|parents| {
+                            if n.is_internal() && n.is_join() {
+                                // assume every row finds at most one match on the other side
+                                parents.into_iter().max().unwrap()
+                            } else {
+                                parents.into_iter().sum()
+                            }
+                        })
+                });
+                estimates.insert(ni, estimated_rows);
+
+                let description = if n.is_internal() {
+                    n.description(true)
+                } else if n.is_base() {
+                    "Base table".to_owned()
+                } else if n.is_reader() {
+                    "Leaf view".to_owned()
+                } else {
+                    format!("{:?}", n)
+                };
+
+                NodeExplanation {
+                    node: ni,
+                    name: n.name().to_owned(),
+                    description,
+                    new: new.contains(&ni),
+                    domain: n.domain().index(),
+                    sharding: format!("{:?}", n.sharded_by()),
+                    materialization: self.materializations.get_status(ni, n),
+                    indices: self.materializations.indices(ni),
+                    estimated_rows,
+                }
+            })
+            .collect()
+    }
+
     fn view_schema(&self, view_ni: NodeIndex) -> Option<Vec<ColumnSpecification>> {
         let n = &self.ingredients[view_ni];
         let schema: Vec<_> = (0..n.fields().len())
@@ -874,210 +2645,1965 @@ impl ControllerInner {
         })
     }
 
-    /// Get statistics about the time spent processing different parts of the graph.
-    fn get_statistics(&mut self) -> GraphStats {
-        trace!(self.log, "asked to get statistics");
-        let log = &self.log;
-        let workers = &self.workers;
-        let replies = &mut self.replies;
-        // TODO: request stats from domains in parallel.
-        let domains = self
-            .domains
-            .iter_mut()
-            .flat_map(|(&di, s)| {
-                trace!(log, "requesting stats from domain"; "di" => di.index());
-                s.send_to_healthy(Box::new(Packet::GetStatistics), workers)
-                    .unwrap();
-                futures_executor::block_on(replies.wait_for_statistics(&s))
-                    .into_iter()
-                    .enumerate()
-                    .map(move |(i, s)| ((di, i), s))
-            })
-            .collect();
+    /// Get statistics about the time spent processing different parts of the graph.
+    fn get_statistics(&mut self) -> GraphStats {
+        trace!(self.log, "asked to get statistics");
+        let log = &self.log;
+        let workers = &self.workers;
+        let replies = &mut self.replies;
+        // TODO: request stats from domains in parallel.
+        let domains = self
+            .domains
+            .iter_mut()
+            .flat_map(|(&di, s)| {
+                trace!(log, "requesting stats from domain"; "di" => di.index());
+                s.send_to_healthy(Box::new(Packet::GetStatistics), workers)
+                    .unwrap();
+                futures_executor::block_on(replies.wait_for_statistics(&s))
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(i, s)| ((di, i), s))
+            })
+            .collect();
+
+        GraphStats { domains }
+    }
+
+    /// Get statistics from all domains, if there are any.
+    fn graph_statistics(&mut self) -> GraphStats {
+        if self.domains.is_empty() {
+            GraphStats {
+                domains: HashMap::default(),
+            }
+        } else {
+            self.get_statistics()
+        }
+    }
+
+    /// Get statistics from every domain whose shards all run on healthy workers, and that replies
+    /// within `timeout`. The others are left out.
+    fn statistics_within(&mut self, timeout: Duration) -> GraphStats {
+        let mut domains = HashMap::new();
+        let log = &self.log;
+        let workers = &self.workers;
+        let replies = &mut self.replies;
+        for (&di, d) in self.domains.iter_mut() {
+            // a shard's worker may also have been removed since it failed
+            if (0..d.shards()).any(|shard| {
+                workers
+                    .get(&d.assignment(shard))
+                    .map(|w| !w.healthy)
+                    .unwrap_or(true)
+            }) {
+                continue;
+            }
+            if d.send_to_healthy(Box::new(Packet::GetStatistics), workers)
+                .is_err()
+            {
+                continue;
+            }
+            let stats =
+                match futures_executor::block_on(replies.wait_for_statistics_within(&d, timeout)) {
+                    Some(stats) => stats,
+                    None => {
+                        warn!(log, "timed out waiting for domain statistics";
+                          "domain" => di.index());
+                        continue;
+                    }
+                };
+            for (shard, stats) in stats.into_iter().enumerate() {
+                domains.insert((di, shard), stats);
+            }
+        }
+        GraphStats { domains }
+    }
+
+    /// Gather the base table statistics that recipe changes plan their joins with anew.
+    fn refresh_table_statistics(&mut self) {
+        let stats = self.statistics_within(STATISTICS_TIMEOUT);
+        let tables = self.base_table_statistics(&stats);
+        self.table_statistics = Some((Instant::now(), tables));
+    }
+
+    /// The latest base table statistics, which are only gathered here if they never have been.
+    fn table_statistics(&mut self) -> TableStatistics {
+        if self.table_statistics.is_none() {
+            self.refresh_table_statistics();
+        }
+        self.table_statistics.as_ref().unwrap().1.clone()
+    }
+
+    /// Extract row counts and join key cardinalities for all base tables from `stats`.
+    fn base_table_statistics(&self, stats: &GraphStats) -> TableStatistics {
+        let mut tables = TableStatistics::default();
+        // the distinct values of each column, and the rows, in each shard of each table
+        let mut distinct: HashMap<(NodeIndex, usize), Vec<(usize, usize)>> = HashMap::new();
+        for (_, nodes) in stats.domains.values() {
+            for (&ni, stats) in nodes {
+                let node = &self.ingredients[ni];
+                if !node.is_base() {
+                    continue;
+                }
+                // shards hold disjoint parts of the table, so their row counts add up
+                let table = tables.entry(node.name().to_owned()).or_default();
+                table.rows += stats.rows as usize;
+                for &(ref key, count) in &stats.key_counts {
+                    if key.len() == 1 {
+                        distinct
+                            .entry((ni, key[0]))
+                            .or_default()
+                            .push((count as usize, stats.rows as usize));
+                    }
+                }
+            }
+        }
+
+        for ((ni, col), shards) in distinct {
+            let node = &self.ingredients[ni];
+            let by_col = match node.sharded_by() {
+                Sharding::ByColumn(c, _) => c == col,
+                Sharding::Random(_) => false,
+                Sharding::None | Sharding::ForcedNone => true,
+            };
+            let unique = shards.iter().all(|&(count, rows)| count == rows);
+            let counts = shards.iter().map(|&(count, _)| count);
+            let count = if by_col || unique {
+                // every value lives in a single shard, so the counts add up
+                counts.sum()
+            } else {
+                // a value can turn up in every shard, so all we know is that there are at least as
+                // many as any one shard has
+                counts.max().unwrap_or(0)
+            };
+            tables
+                .get_mut(node.name())
+                .unwrap()
+                .distinct
+                .insert(node.fields()[col].clone(), count);
+        }
+        tables
+    }
+
+    fn get_instances(&self) -> Vec<(WorkerIdentifier, bool, Duration)> {
+        self.workers
+            .iter()
+            .map(|(&id, ref status)| (id, status.healthy, status.last_heartbeat.elapsed()))
+            .collect()
+    }
+
+    /// The membership and health of the deployment.
+    ///
+    /// Only domains whose shards all run on healthy workers are asked for their statistics, and
+    /// none are while the controller is recovering, so that the answer isn't held up by shards
+    /// that can't give one.
+    fn cluster_status(&mut self) -> ClusterStatus {
+        let mut shard_stats = HashMap::new();
+        if self.pending_recovery.is_none() && self.pending_adoption.is_none() {
+            let stats = self.statistics_within(STATISTICS_TIMEOUT);
+            for (shard, (domain_stats, node_stats)) in stats.domains {
+                let mem_size: u64 = node_stats.values().map(|n| n.mem_size).sum();
+                shard_stats.insert(shard, (mem_size, domain_stats.replay_backlog));
+            }
+        }
+
+        let domains = &self.domains;
+        let ingredients = &self.ingredients;
+        let cc = &self.channel_coordinator;
+        let mut workers: Vec<_> = self
+            .workers
+            .iter()
+            .map(|(&id, w)| {
+                let mut shards: Vec<_> = domains
+                    .iter()
+                    .flat_map(|(&di, d)| {
+                        (0..d.shards())
+                            .filter(move |&shard| d.assignment(shard) == id)
+                            .map(move |shard| (di, shard))
+                    })
+                    .map(|(di, shard)| {
+                        let stats = shard_stats.get(&(di, shard));
+                        ShardStatus {
+                            domain: di.index(),
+                            shard,
+                            addr: cc.get_addr(&(di, shard)),
+                            mem_size: stats.map(|&(mem_size, _)| mem_size),
+                            replay_backlog: stats.map(|&(_, backlog)| backlog),
+                        }
+                    })
+                    .collect();
+                shards.sort_by_key(|s| (s.domain, s.shard));
+
+                let mut readers: Vec<_> = w
+                    .reads
+                    .iter()
+                    .map(|(&(node, shard), &requests)| ReaderStatus {
+                        node,
+                        // the reader may have been removed since the worker's last heartbeat
+                        name: ingredients
+                            .node_weight(node)
+                            .map(|n| n.name().to_owned())
+                            .unwrap_or_default(),
+                        shard,
+                        requests: requests as u64,
+                        requests_per_sec: w.read_rates.get(&(node, shard)).cloned().unwrap_or(0.0),
+                    })
+                    .collect();
+                readers.sort_by_key(|r| (r.node, r.shard));
+
+                WorkerStatus {
+                    id,
+                    addr: w.addr,
+                    healthy: w.healthy,
+                    draining: w.draining,
+                    heartbeat_age: w.last_heartbeat.elapsed(),
+                    cpus: w.cpus,
+                    labels: w.labels.clone(),
+                    state_size: w.state_size as u64,
+                    shards,
+                    readers,
+                }
+            })
+            .collect();
+        workers.sort_by_key(|w| w.id);
+
+        ClusterStatus { workers }
+    }
+
+    fn flush_partial(&mut self) -> u64 {
+        // get statistics for current domain sizes
+        // and evict all state from partial nodes
+        let workers = &self.workers;
+        let replies = &mut self.replies;
+        let to_evict: Vec<_> = self
+            .domains
+            .iter_mut()
+            .map(|(di, s)| {
+                s.send_to_healthy(Box::new(Packet::GetStatistics), workers)
+                    .unwrap();
+                let to_evict: Vec<(NodeIndex, u64)> =
+                    futures_executor::block_on(replies.wait_for_statistics(&s))
+                        .into_iter()
+                        .flat_map(move |(_, node_stats)| {
+                            node_stats
+                                .into_iter()
+                                .filter_map(|(ni, ns)| match ns.materialized {
+                                    MaterializationStatus::Partial { .. } => {
+                                        Some((ni, ns.mem_size))
+                                    }
+                                    _ => None,
+                                })
+                        })
+                        .collect();
+                (*di, to_evict)
+            })
+            .collect();
+
+        let mut total_evicted = 0;
+        for (di, nodes) in to_evict {
+            for (ni, bytes) in nodes {
+                let na = self.ingredients[ni].local_addr();
+                self.domains
+                    .get_mut(&di)
+                    .unwrap()
+                    .send_to_healthy(
+                        Box::new(Packet::Evict {
+                            node: Some(na),
+                            num_bytes: bytes as usize,
+                        }),
+                        workers,
+                    )
+                    .expect("failed to send domain flush message");
+                total_evicted += bytes;
+            }
+        }
+
+        warn!(
+            self.log,
+            "flushed {} bytes of partial domain state", total_evicted
+        );
+
+        total_evicted
+    }
+
+    /// Create a universe with the given context, and remember it so that recovery creates it
+    /// again.
+    pub(super) fn create_universe<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        context: HashMap<String, DataType>,
+    ) -> Result<(), String> {
+        self.add_user_universe(context.clone())?;
+
+        self.universes.push(context);
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.universes = self.universes.clone();
+                    Ok(state)
+                }
+            })
+            .is_err()
+        {
+            return Err("Failed to persist universe".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Add the nodes that enforce the security policies for the universe with the given context.
+    fn add_user_universe(&mut self, context: HashMap<String, DataType>) -> Result<(), String> {
+        let log = self.log.clone();
+        let mut r = self.recipe.clone();
+        let groups = self.recipe.security_groups();
+
+        let mut universe_groups = HashMap::new();
+
+        let uid = context
+            .get("id")
+            .expect("Universe context must have id")
+            .clone();
+        let uid = &[uid];
+        if context.get("group").is_none() {
+            let x = Arc::new(Mutex::new(HashMap::new()));
+            for g in groups {
+                // TODO: this should use external APIs through noria::ControllerHandle
+                // TODO: can this move to the client entirely?
+                let rgb: Option<ViewBuilder> = self.view_builder(&g);
+                // TODO: using block_on here _only_ works because View::lookup just waits on a
+                // channel, which doesn't use anything except the pure executor
+                let mut view = rgb.map(|rgb| rgb.build(x.clone()).unwrap()).unwrap();
+                let my_groups: Vec<DataType> = futures_executor::block_on(view.lookup(uid, true))
+                    .unwrap()
+                    .iter()
+                    .map(|v| v[1].clone())
+                    .collect();
+                universe_groups.insert(g, my_groups);
+            }
+        }
+
+        let groups = universe_groups.clone();
+        let mir = r.mir_snapshot();
+        let created = self.add_universe(context.clone(), |mut mig| {
+            r.next();
+            match r.create_universe(&mut mig, universe_groups) {
+                Ok(ar) => {
+                    info!(log, "{} expressions added", ar.expressions_added);
+                    info!(log, "{} expressions removed", ar.expressions_removed);
+                    Ok(())
+                }
+                Err(e) => {
+                    crit!(log, "failed to create universe: {:?}", e);
+                    Err("failed to create universe".to_owned())
+                }
+            }
+        });
+        let ((), allocated) = match created {
+            Ok(created) => created,
+            Err(e) => {
+                if let Some(mir) = mir {
+                    mir.restore();
+                }
+                return Err(e);
+            }
+        };
+
+        self.recipe = r;
+        self.record_change(JournalEntry {
+            change: RecipeChange::Universe(context, groups),
+            statistics: Default::default(),
+            allocated,
+        });
+        Ok(())
+    }
+
+    fn set_security_config<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        p: String,
+    ) -> Result<(), String> {
+        self.recipe.set_security_config(&p);
+        self.record_change(JournalEntry::new(RecipeChange::SecurityConfig(p.clone())));
+
+        self.security_config = Some(p);
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.security_config = self.security_config.clone();
+                    Ok(state)
+                }
+            })
+            .is_err()
+        {
+            return Err("Failed to persist security config".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Plan extending the recipe with `add_txt`, without applying or persisting it.
+    fn plan_recipe_extension(&mut self, add_txt: String) -> Result<RecipePlan, String> {
+        let new = self.recipe.clone().extend(&add_txt).map_err(|(_, e)| e)?;
+        self.plan_recipe(new)
+    }
+
+    /// Plan replacing the recipe with `r_txt`, without applying or persisting it.
+    fn plan_recipe_installation(&mut self, r_txt: String) -> Result<RecipePlan, String> {
+        let r = Recipe::from_str(&r_txt, Some(self.log.clone()))?;
+        let new = self.recipe.clone().replace(r)?;
+        self.plan_recipe(new)
+    }
+
+    /// Plan the migration that applying `new` would perform against a copy of the graph, and
+    /// report the nodes it would add.
+    fn plan_recipe(&mut self, mut new: Recipe) -> Result<RecipePlan, String> {
+        let stats = self.graph_statistics();
+        let rows = node_rows(&stats);
+        new.set_table_statistics(self.base_table_statistics(&stats));
+
+        let mir = new.mir_snapshot();
+        self.dry_run(mir, |inner| {
+            let (activation, added) = inner.plan_migration(|mig| {
+                new.activate(mig)
+                    .map_err(|e| format!("failed to activate recipe: {}", e))
+            })?;
+
+            let mut nodes: Vec<_> = added
+                .iter()
+                .cloned()
+                .filter(|&ni| ni != inner.source && !inner.ingredients[ni].is_dropped())
+                .collect();
+            nodes.sort();
+            Ok(RecipePlan {
+                activation,
+                nodes: inner.explain_nodes(nodes, &added, &rows),
+            })
+        })
+    }
+
+    /// Apply `new`, which `change` made to the current recipe, planning its joins with the latest
+    /// base table statistics.
+    fn apply_recipe(
+        &mut self,
+        new: Recipe,
+        change: RecipeChange,
+    ) -> Result<ActivationResult, String> {
+        let statistics = self.table_statistics();
+        self.apply_recipe_with(new, change, statistics)
+    }
+
+    /// Apply `new`, planning its joins with the given base table statistics.
+    fn apply_recipe_with(
+        &mut self,
+        mut new: Recipe,
+        change: RecipeChange,
+        statistics: TableStatistics,
+    ) -> Result<ActivationResult, String> {
+        new.set_table_statistics(statistics.clone());
+
+        // the migration is checked in full before any domain hears of it. if the recipe fails to
+        // activate, or its migration is invalid, the migration is abandoned, and the recipe and
+        // its MIR graph are put back the way they were.
+        let unactivated = new.clone();
+        let mir = new.mir_snapshot();
+        let r = self.migrate_allocating(|mig| {
+            new.activate(mig)
+                .map_err(|e| format!("failed to activate recipe: {}", e))
+        });
+
+        let (ra, allocated) = match r {
+            Ok(r) => r,
+            Err(e) => {
+                crit!(self.log, "failed to apply recipe: {}", e);
+                if let Some(mir) = mir {
+                    mir.restore();
+                }
+                self.recipe = unactivated.revert();
+                return Err(e);
+            }
+        };
+
+        self.record_change(JournalEntry {
+            change,
+            statistics,
+            allocated,
+        });
+
+        let (removed_bases, removed_other): (Vec<_>, Vec<_>) = ra
+            .removed_leaves
+            .iter()
+            .cloned()
+            .partition(|ni| self.ingredients[*ni].is_base());
+
+        // first remove query nodes in reverse topological order
+        let mut topo_removals = Vec::with_capacity(removed_other.len());
+        let mut topo = petgraph::visit::Topo::new(&self.ingredients);
+        while let Some(node) = topo.next(&self.ingredients) {
+            if removed_other.contains(&node) {
+                topo_removals.push(node);
+            }
+        }
+        topo_removals.reverse();
+
+        for leaf in topo_removals {
+            self.remove_leaf(leaf)?;
+        }
+
+        // now remove bases
+        for base in removed_bases {
+            // TODO(malte): support removing bases that still have children?
+            let children: Vec<NodeIndex> = self
+                .ingredients
+                .neighbors_directed(base, petgraph::EdgeDirection::Outgoing)
+                .collect();
+            // TODO(malte): what about domain crossings? can ingress/egress nodes be left
+            // behind?
+            assert_eq!(children.len(), 0);
+            debug!(
+                self.log,
+                "Removing base \"{}\"",
+                self.ingredients[base].name();
+                "node" => base.index(),
+            );
+            // now drop the (orphaned) base
+            self.remove_nodes(vec![base].as_slice()).unwrap();
+        }
+
+        self.recipe = new;
+        self.compact_journal();
+        self.update_read_replicas();
+        if let Err(e) = self.update_memory_budgets() {
+            // the recipe has been applied regardless, and the budgets are sent again with the
+            // next update
+            warn!(
+                self.log,
+                "recipe applied without updating memory budgets: {}", e
+            );
+        }
+
+        Ok(ra)
+    }
+
+    /// Set the memory budget, in bytes, for the state of the query or view called `name`,
+    /// overriding any budget the recipe gives it. `None` removes the override.
+    fn set_memory_budget<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        name: String,
+        budget: Option<usize>,
+    ) -> Result<(), String> {
+        if self.reader_for(&name).is_none() {
+            return Err(format!("no view named \"{}\"", name));
+        }
+
+        match budget {
+            Some(budget) => self.memory_budgets.insert(name, budget),
+            None => self.memory_budgets.remove(&name),
+        };
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.memory_budgets = self.memory_budgets.clone();
+                    Ok(state)
+                }
+            })
+            .is_err()
+        {
+            return Err("Failed to persist memory budget".to_owned());
+        }
+
+        self.update_memory_budgets()
+    }
+
+    /// Serve reads of the query or view called `name` from `replicas` readers, placed on
+    /// different workers where possible, rather than from one.
+    fn set_read_replicas<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        name: String,
+        replicas: usize,
+    ) -> Result<(), String> {
+        if replicas == 0 {
+            return Err("a view needs at least one reader".to_owned());
+        }
+        if self.reader_for(&name).is_none() {
+            return Err(format!("no view named \"{}\"", name));
+        }
+
+        if replicas == 1 {
+            self.read_replicas.remove(&name);
+        } else {
+            self.read_replicas.insert(name, replicas);
+        }
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.read_replicas = self.read_replicas.clone();
+                    Ok(state)
+                }
+            })
+            .is_err()
+        {
+            return Err("Failed to persist read replicas".to_owned());
+        }
+
+        self.update_read_replicas();
+        self.update_memory_budgets()
+    }
+
+    /// The replicas of the given reader, in the order they were added.
+    fn read_replicas_of(&self, reader: NodeIndex) -> Vec<NodeIndex> {
+        self.ingredients
+            .node_indices()
+            .filter(|&ni| !self.ingredients[ni].is_dropped())
+            .filter(|&ni| {
+                self.ingredients[ni]
+                    .with_reader(|r| r.replica_of() == Some(reader))
+                    .unwrap_or(false)
+            })
+            .collect()
+    }
+
+    /// Add or remove replicas of readers so that every view has as many readers as it was asked
+    /// to have through `set_read_replicas`.
+    fn update_read_replicas(&mut self) {
+        let readers: Vec<_> = self
+            .ingredients
+            .node_indices()
+            .filter(|&ni| !self.ingredients[ni].is_dropped())
+            .filter(|&ni| {
+                self.ingredients[ni]
+                    .with_reader(|r| r.replica_of().is_none())
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        let mut add = Vec::new();
+        let mut remove = Vec::new();
+        for r in readers {
+            let want = self
+                .read_replicas
+                .get(self.ingredients[r].name())
+                .map_or(0, |&replicas| replicas - 1);
+            let have = self.read_replicas_of(r);
+            if have.len() < want {
+                add.extend(iter::repeat(r).take(want - have.len()));
+            } else {
+                remove.extend_from_slice(&have[want..]);
+            }
+        }
+
+        for replica in remove {
+            info!(self.log, "removing read replica"; "node" => replica.index());
+            if let Err(e) = self.remove_read_replica(replica) {
+                crit!(self.log, "failed to remove read replica: {}", e);
+            }
+        }
+        if !add.is_empty() {
+            let added = self.migrate(move |mig| {
+                for r in add {
+                    mig.add_read_replica(r);
+                }
+            });
+            if let Err(e) = added {
+                crit!(self.log, "failed to add read replicas: {}", e);
+            }
+        }
+    }
+
+    /// Remove a replica of a reader, along with the nodes that only fed it.
+    fn remove_read_replica(&mut self, replica: NodeIndex) -> Result<(), String> {
+        self.remove_with_ancestors(replica, replica, Vec::new())
+    }
+
+    /// Take a consistent snapshot of all fully materialized state, and make it the one that
+    /// recovery starts from. Returns the id of the snapshot.
+    ///
+    /// Writes to base tables are paused until every domain has taken its part of the snapshot.
+    fn take_snapshot<A: Authority + 'static>(&mut self, authority: &Arc<A>) -> Result<u64, String> {
+        if self.persistence.mode != DurabilityMode::Permanent
+            || self.persistence.snapshot_interval.is_none()
+        {
+            return Err(
+                "snapshots require permanent durability and a snapshot interval".to_owned(),
+            );
+        }
+
+        self.last_snapshot = Instant::now();
+        let id = self.snapshot.as_ref().map(|s| s.id + 1).unwrap_or(0);
+
+        let mut base_seqs = HashMap::new();
+        for (shard, seqs) in self.pause_dataflow(id, true)? {
+            for (ni, seq) in seqs {
+                let base = &self.ingredients[ni];
+                let shards = self.domains[&base.domain()].shards();
+                base_seqs
+                    .entry(base.name().to_owned())
+                    .or_insert_with(|| vec![0; shards])[shard] = seq;
+            }
+        }
+        let snapshot = Snapshot { id, base_seqs };
+
+        let persisted =
+            authority.read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.snapshot = Some(snapshot.clone());
+                    Ok(state)
+                }
+            });
+        let complete = match persisted {
+            Ok(Ok(_)) => true,
+            _ => false,
+        };
+
+        self.resume_dataflow(id, complete)?;
+        if !complete {
+            return Err("Failed to persist snapshot".to_owned());
+        }
+
+        info!(self.log, "took snapshot of materialized state"; "id" => id);
+        self.snapshot = Some(snapshot);
+        Ok(id)
+    }
+
+    /// Hold back writes to base tables, and wait until every update that was already accepted
+    /// has made its way through the data-flow, using snapshot `id`.
+    ///
+    /// If `persist` is set, every domain shard also writes its fully materialized state to disk,
+    /// and the sequence numbers of the last change to each base table included are returned.
+    ///
+    /// If the data-flow can't be paused, writes are let through again before the error is
+    /// returned.
+    fn pause_dataflow(
+        &mut self,
+        id: u64,
+        persist: bool,
+    ) -> Result<Vec<(usize, Vec<(NodeIndex, u64)>)>, String> {
+        let pause = self.replies.next_pause();
+        let mut nshards = 0;
+        for (di, markers) in self.snapshot_markers() {
+            let domain = self.domains.get_mut(&di).unwrap();
+            let started = domain.send_to_healthy(
+                Box::new(Packet::TakeSnapshot {
+                    id,
+                    markers,
+                    persist,
+                    pause,
+                }),
+                &self.workers,
+            );
+            if let Err(e) = started {
+                self.resume_dataflow(id, false)?;
+                return Err(format!("failed to start snapshot: {:?}", e));
+            }
+            nshards += domain.shards();
+        }
+
+        let taken = futures_executor::block_on(
+            self.replies
+                .wait_for_snapshots_within(nshards, SNAPSHOT_TIMEOUT),
+        );
+        if taken.is_err() {
+            self.resume_dataflow(id, false)?;
+        }
+        taken
+    }
+
+    /// The number of snapshot markers that each shard of every domain waits for: one from every
+    /// upstream domain shard that sends it updates, along every edge into the domain.
+    fn snapshot_markers(&self) -> HashMap<DomainIndex, usize> {
+        let graph = &self.ingredients;
+        let mut markers: HashMap<_, _> = self.domains.keys().map(|&di| (di, 0)).collect();
+        for (&di, nodes) in &self.domain_nodes {
+            let shards = self.domains[&di].shards();
+            let n: usize = nodes
+                .iter()
+                .filter(|&&ni| graph[ni].is_ingress() && !graph[ni].is_dropped())
+                .flat_map(|&ni| graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming))
+                .map(|sender| {
+                    let senders = self.domains[&graph[sender].domain()].shards();
+                    if graph[sender].is_sharder() || shards == 1 {
+                        // a sharder sends to every shard of its child domain, as does every shard
+                        // of a domain that merges into an unsharded one
+                        senders
+                    } else {
+                        // without a shuffle, a shard only hears from the same shard of its parent
+                        1
+                    }
+                })
+                .sum();
+            markers.insert(di, n);
+        }
+        markers
+    }
+
+    /// Let writes to base tables through again after `pause_dataflow`.
+    fn resume_dataflow(&mut self, id: u64, complete: bool) -> Result<(), String> {
+        for domain in self.domains.values_mut() {
+            domain
+                .send_to_healthy(
+                    Box::new(Packet::FinishSnapshot { id, complete }),
+                    &self.workers,
+                )
+                .map_err(|e| format!("failed to finish snapshot: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Move shard `shard` of domain `di`, along with its state, to `worker`.
+    ///
+    /// The data-flow is paused while the shard moves. The old shard forwards everything that is
+    /// still sent to it to the new one, so clients and domains that are connected to it keep
+    /// working; the domains that feed the shard are told to connect to it anew.
+    fn move_domain(
+        &mut self,
+        di: DomainIndex,
+        shard: usize,
+        worker: WorkerIdentifier,
+    ) -> Result<(), String> {
+        match self.domains.get(&di) {
+            Some(d) if shard < d.shards() => {
+                if d.assignment(shard) == worker {
+                    return Ok(());
+                }
+            }
+            _ => return Err(format!("no shard {} of domain {}", shard, di.index())),
+        }
+        match self.workers.get(&worker) {
+            Some(w) if w.healthy && !w.draining => {}
+            _ => return Err(format!("no healthy worker at {:?}", worker)),
+        }
+
+        // nothing may be on its way to the shard while it is handed off
+        let id = self.snapshot.as_ref().map(|s| s.id + 1).unwrap_or(0);
+        self.pause_dataflow(id, false)?;
+
+        let moved = self.hand_off(di, shard, worker);
+        let resumed = self.resume_dataflow(id, false);
+        moved?;
+        resumed?;
+
+        info!(self.log, "moved domain shard";
+              "domain" => di.index(),
+              "shard" => shard,
+              "worker" => ?worker);
+        Ok(())
+    }
+
+    /// Move every domain shard that `worker` runs to other workers, and then tell it to leave the
+    /// deployment. No new shards are placed on the worker once this is called.
+    ///
+    /// The data-flow is paused while the shards move, as it is for `move_domain`.
+    fn drain_worker(&mut self, worker: WorkerIdentifier) -> Result<(), String> {
+        match self.workers.get(&worker) {
+            Some(w) if w.healthy => {}
+            _ => return Err(format!("no healthy worker at {:?}", worker)),
+        }
+        if !self
+            .workers
+            .iter()
+            .any(|(&addr, w)| addr != worker && w.healthy && !w.draining)
+        {
+            return Err("no other healthy worker to move domain shards to".to_owned());
+        }
+        self.workers.get_mut(&worker).unwrap().draining = true;
+
+        let moved = self.move_shards_off(worker);
+        if moved.is_err() {
+            // the worker still runs whatever didn't move, so it may take new shards again
+            if let Some(w) = self.workers.get_mut(&worker) {
+                w.draining = false;
+            }
+        }
+        let moved = moved?;
+        self.stop_forwarders(worker);
+
+        let w = self.workers.get_mut(&worker).unwrap();
+        let src = w.sender.local_addr().unwrap();
+        w.sender
+            .send(CoordinationMessage {
+                epoch: self.epoch,
+                source: src,
+                payload: CoordinationPayload::Drained,
+            })
+            .map_err(|e| format!("failed to tell worker it was drained: {:?}", e))?;
+
+        info!(self.log, "drained worker";
+              "worker" => ?worker,
+              "shards" => moved);
+        Ok(())
+    }
+
+    /// Move every domain shard that `worker` runs to other workers, and return how many there
+    /// were.
+    fn move_shards_off(&mut self, worker: WorkerIdentifier) -> Result<usize, String> {
+        let mut shards: Vec<_> = self
+            .domains
+            .iter()
+            .flat_map(|(&di, d)| {
+                (0..d.shards())
+                    .filter(move |&shard| d.assignment(shard) == worker)
+                    .map(move |shard| (di, shard))
+            })
+            .collect();
+        shards.sort_by_key(|&(di, shard)| (di.index(), shard));
+
+        if !shards.is_empty() {
+            let id = self.snapshot.as_ref().map(|s| s.id + 1).unwrap_or(0);
+            self.pause_dataflow(id, false)?;
+
+            let mut moved = Ok(());
+            for &(di, shard) in &shards {
+                let nodes: Vec<_> = self.domain_nodes[&di]
+                    .iter()
+                    .map(|&ni| (ni, false))
+                    .collect();
+                let to = match self.choose_workers(di, 1, &nodes) {
+                    Ok(workers) => workers[0],
+                    Err(e) => {
+                        moved = Err(e);
+                        break;
+                    }
+                };
+                info!(self.log, "moving domain shard off draining worker";
+                      "domain" => di.index(),
+                      "shard" => shard,
+                      "worker" => ?to);
+                moved = self.hand_off(di, shard, to);
+                if moved.is_err() {
+                    break;
+                }
+            }
+            let resumed = self.resume_dataflow(id, false);
+            moved?;
+            resumed?;
+        }
+        Ok(shards.len())
+    }
+
+    /// Shut down the handed off domain shards on `worker` that forward to the shards that took
+    /// their place, so that nothing is left running on it. Writes they still wait on the new
+    /// shards to confirm fail.
+    fn stop_forwarders(&mut self, worker: WorkerIdentifier) {
+        let log = &self.log;
+        for d in self.domains.values_mut() {
+            for s in &mut d.shards {
+                s.forwarders.retain(|&(w, addr)| {
+                    if w != worker {
+                        return true;
+                    }
+                    let quit = DomainConnectionBuilder::for_domain(addr)
+                        .build_sync()
+                        .map_err(SendError::from)
+                        .and_then(|mut tx| tx.send(Box::new(Packet::Quit)));
+                    if let Err(e) = quit {
+                        // it stopped on its own already
+                        debug!(log, "failed to shut down forwarding domain shard: {:?}", e);
+                    }
+                    false
+                });
+            }
+        }
+    }
+
+    /// Start shard `shard` of domain `di` on `worker` with the nodes and state of the running
+    /// shard, and send everything meant for the old shard to the new one from now on.
+    ///
+    /// The old shard stays the one that holds the state until the new one has all of it. If the
+    /// new shard can't be started or filled, it is stopped again, and the old one carries on as
+    /// before.
+    fn hand_off(
+        &mut self,
+        di: DomainIndex,
+        shard: usize,
+        worker: WorkerIdentifier,
+    ) -> Result<(), String> {
+        self.metadata_changed = true;
+        let nshards = self.domains[&di].shards();
+        let addrs = (0..nshards)
+            .map(|i| self.channel_coordinator.get_addr(&(di, i)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("no address for every shard of domain {}", di.index()))?;
+        let old_addr = addrs[shard];
+        let old_worker = self.domains[&di].assignment(shard);
+        let readers_to = match self.read_addrs.get(&worker) {
+            Some(&addr) => vec![addr],
+            None => return Err(format!("no healthy worker at {:?}", worker)),
+        };
+
+        // the new shard opens the base tables' storage where the old one kept it if it runs on
+        // the same machine, and is sent the tables' rows otherwise
+        let share_storage = old_worker.ip() == worker.ip()
+            && match self.persistence.mode {
+                DurabilityMode::Permanent | DurabilityMode::Logged => true,
+                _ => false,
+            };
+        let domain = self.domains.get_mut(&di).unwrap();
+        domain
+            .send_to_healthy_shard(
+                shard,
+                Box::new(Packet::HandOff { share_storage }),
+                &self.workers,
+            )
+            .map_err(|e| format!("failed to hand off domain shard: {:?}", e))?;
+        futures_executor::block_on(self.replies.wait_for_hand_off())?;
+
+        let index = self.ingredients[self.domain_nodes[&di][0]]
+            .sharded_by()
+            .shards()
+            .map(|_| shard);
+        let (addr, mut tx) = match self.boot_shard(di, index, nshards, None, worker) {
+            Ok(booted) => booted,
+            Err(e) => {
+                self.cancel_hand_offs(di, &[shard], Vec::new())?;
+                return Err(e);
+            }
+        };
+
+        let setup = self.domains[&di].shards[shard].setup.clone();
+        let setup = self.setup_for_new_shard(di, setup);
+        let mut writes_to = addrs;
+        writes_to[shard] = addr;
+        let taken_over = self
+            .set_up_shard(&mut tx, &setup)
+            .and_then(|_| self.transfer_state(di, &[shard], vec![addr], &mut [&mut tx]))
+            .and_then(|_| {
+                let forward = Packet::Forward {
+                    to: vec![addr],
+                    readers_to: readers_to.clone(),
+                    writes_to: writes_to.clone(),
+                    from: old_addr,
+                };
+                let domain = self.domains.get_mut(&di).unwrap();
+                domain
+                    .send_to_healthy_shard(shard, Box::new(forward), &self.workers)
+                    .map_err(|e| format!("failed to redirect old domain shard: {:?}", e))?;
+                futures_executor::block_on(self.replies.wait_for_hand_off())
+            });
+        if let Err(e) = taken_over {
+            warn!(self.log, "cancelling hand-off of domain shard";
+                  "domain" => di.index(),
+                  "shard" => shard,
+                  "error" => &e);
+            self.cancel_hand_offs(di, &[shard], vec![tx])?;
+            return Err(e);
+        }
+
+        // from here on, the new shard is the one that holds the state
+        self.channel_coordinator.insert_remote((di, shard), addr);
+        self.announce_shard(di, shard, addr)?;
+        let mut forwarders = mem::replace(
+            &mut self.domains.get_mut(&di).unwrap().shards[shard],
+            DomainShardHandle::new(worker, Box::new(tx)),
+        )
+        .forwarders;
+        forwarders.push((old_worker, old_addr));
+        self.reforward(&mut forwarders, vec![addr], readers_to, writes_to);
+        let handle = &mut self.domains.get_mut(&di).unwrap().shards[shard];
+        handle.setup = setup;
+        handle.forwarders = forwarders;
+
+        // the domains that feed the shard should send to it directly
+        let nodes = &self.domain_nodes[&di];
+        let graph = &self.ingredients;
+        let mut updates = Vec::new();
+        for &ni in nodes {
+            let n = &graph[ni];
+            if !n.is_ingress() || n.is_dropped() {
+                continue;
+            }
+            for sender in graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming) {
+                let s = &graph[sender];
+                if s.is_egress() {
+                    let to_shard = if nshards != 1 && !s.sharded_by().is_none() {
+                        // only the same shard of the parent domain sends to the shard
+                        Some(shard)
+                    } else {
+                        None
+                    };
+                    let p = Packet::UpdateEgress {
+                        node: s.local_addr(),
+                        new_tx: Some((ni, n.local_addr(), (di, shard))),
+                        new_tag: None,
+                    };
+                    updates.push((s.domain(), to_shard, p));
+                } else if s.is_sharder() {
+                    let p = Packet::UpdateSharder {
+                        node: s.local_addr(),
+                        new_txs: (n.local_addr(), (0..nshards).map(|i| (di, i)).collect()),
+                    };
+                    updates.push((s.domain(), None, p));
+                }
+            }
+        }
+        for (sdi, to_shard, p) in updates {
+            let domain = self.domains.get_mut(&sdi).unwrap();
+            let sent = match to_shard {
+                Some(i) => domain.send_to_healthy_shard(i, Box::new(p), &self.workers),
+                None => domain.send_to_healthy(Box::new(p), &self.workers),
+            };
+            sent.map_err(|e| format!("failed to reroute to moved domain shard: {:?}", e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Start shard `shard` of domain `di`, which is to have `nshards` shards, on `worker` with
+    /// the domain's nodes as they are in the graph, split across `reshard` shards if given. No one
+    /// is told about the new shard; its address and a connection to it are returned.
+    fn boot_shard(
+        &mut self,
+        di: DomainIndex,
+        shard: Option<usize>,
+        nshards: usize,
+        reshard: Option<usize>,
+        worker: WorkerIdentifier,
+    ) -> Result<(SocketAddr, TcpSender<Box<Packet>>), String> {
+        let graph = &self.ingredients;
+        let builder = DomainBuilder {
+            index: di,
+            shard,
+            nshards,
+            config: self.domain_config.clone(),
+            nodes: self.domain_nodes[&di]
+                .iter()
+                .map(|&ni| {
+                    let mut n = graph[ni].retake().finalize(graph);
+                    if let Some(shards) = reshard {
+                        n.reshard(shards);
+                    }
+                    (n.local_addr(), cell::RefCell::new(n))
+                })
+                .collect(),
+            persistence_parameters: self.persistence.clone(),
+        };
+        let w = self
+            .workers
+            .get_mut(&worker)
+            .ok_or_else(|| format!("no worker at {:?}", worker))?;
+        info!(
+            self.log,
+            "sending domain {}.{} to worker {:?}",
+            di.index(),
+            shard.unwrap_or(0),
+            w.sender.peer_addr()
+        );
+        let src = w.sender.local_addr().unwrap();
+        w.sender
+            .send(CoordinationMessage {
+                epoch: self.epoch,
+                source: src,
+                payload: CoordinationPayload::AssignDomain(builder),
+            })
+            .map_err(|e| format!("failed to send domain to worker: {:?}", e))?;
+
+        let addr = futures_executor::block_on(self.replies.wait_for_boot())?;
+        let tx = DomainConnectionBuilder::for_domain(addr)
+            .build_sync()
+            .map_err(|e| format!("failed to connect to new domain shard: {:?}", e))?;
+        Ok((addr, tx))
+    }
+
+    /// The packets among `setup`, which set up a shard of domain `di`, that a shard that is
+    /// started with the domain's nodes as they are in the graph should be sent.
+    fn setup_for_new_shard(&self, di: DomainIndex, setup: Vec<Packet>) -> Vec<Packet> {
+        let graph = &self.ingredients;
+        let nodes = &self.domain_nodes[&di];
+        let dropped: HashSet<_> = nodes
+            .iter()
+            .filter(|&&ni| graph[ni].is_dropped())
+            .map(|&ni| graph[ni].local_addr())
+            .collect();
+        let bases: HashSet<_> = nodes
+            .iter()
+            .filter(|&&ni| graph[ni].is_base())
+            .map(|&ni| graph[ni].local_addr())
+            .collect();
+        setup
+            .into_iter()
+            .filter(|p| match *p {
+                Packet::PrepareState { node, .. }
+                | Packet::Ready { node, .. }
+                | Packet::UpdateEgress { node, .. }
+                | Packet::UpdateSharder { node, .. }
+                | Packet::UpdateMemoryBudget { node, .. } => !dropped.contains(&node),
+                Packet::AddBaseColumn { node, .. } | Packet::DropBaseColumn { node, .. } => {
+                    // the base nodes in the graph already have their columns changed
+                    !dropped.contains(&node) && !bases.contains(&node)
+                }
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Set up a new domain shard that takes the place of a handed off one with the packets that
+    /// set up the old shard, except for replays, since its state comes from the old shard.
+    fn set_up_shard(
+        &mut self,
+        tx: &mut TcpSender<Box<Packet>>,
+        setup: &[Packet],
+    ) -> Result<(), String> {
+        for p in setup {
+            let acks = match *p {
+                Packet::StartReplay { .. } => continue,
+                Packet::SetupReplayPath { .. }
+                | Packet::Ready { .. }
+                | Packet::AddBaseColumn { .. }
+                | Packet::DropBaseColumn { .. } => true,
+                _ => false,
+            };
+            tx.send(Box::new(p.clone()))
+                .map_err(|e| format!("failed to set up new domain shard: {:?}", e))?;
+            if acks {
+                futures_executor::block_on(self.replies.wait_for_ack())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Have the given handed off shards of domain `di` send their state to the new shards at
+    /// `to`, and wait for the new shards to have all of it.
+    fn transfer_state(
+        &mut self,
+        di: DomainIndex,
+        shards: &[usize],
+        to: Vec<SocketAddr>,
+        txs: &mut [&mut TcpSender<Box<Packet>>],
+    ) -> Result<(), String> {
+        let domain = self.domains.get_mut(&di).unwrap();
+        for &shard in shards {
+            domain
+                .send_to_healthy_shard(
+                    shard,
+                    Box::new(Packet::StreamState { to: to.clone() }),
+                    &self.workers,
+                )
+                .map_err(|e| format!("failed to have domain shard send its state: {:?}", e))?;
+        }
+        // every old shard replies, whether or not it got all of its state out
+        futures_executor::block_on(self.replies.wait_for_hand_offs(shards.len()))?;
+
+        for tx in txs.iter_mut() {
+            tx.send(Box::new(Packet::AwaitTakeOver {
+                shards: shards.len(),
+            }))
+            .map_err(|e| format!("failed to fill new domain shard: {:?}", e))?;
+        }
+        futures_executor::block_on(self.replies.wait_for_hand_offs(txs.len()))
+    }
+
+    /// Stop the new shards that were to take the place of the given handed off shards of domain
+    /// `di`, and have the old shards carry on as before.
+    fn cancel_hand_offs(
+        &mut self,
+        di: DomainIndex,
+        shards: &[usize],
+        new: Vec<TcpSender<Box<Packet>>>,
+    ) -> Result<(), String> {
+        for mut tx in new {
+            // the shard may have failed already
+            let _ = tx.send(Box::new(Packet::Quit));
+        }
+        let domain = self.domains.get_mut(&di).unwrap();
+        for &shard in shards {
+            domain
+                .send_to_healthy_shard(shard, Box::new(Packet::CancelHandOff), &self.workers)
+                .map_err(|e| format!("failed to cancel hand-off of domain shard: {:?}", e))?;
+        }
+        futures_executor::block_on(self.replies.wait_for_hand_offs(shards.len()))?;
+
+        // the workers the new shards ran on took note of them
+        for &shard in shards {
+            if let Some(addr) = self.channel_coordinator.get_addr(&(di, shard)) {
+                self.announce_shard(di, shard, addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell every healthy worker that shard `shard` of domain `di` runs at `addr`.
+    fn announce_shard(
+        &mut self,
+        di: DomainIndex,
+        shard: usize,
+        addr: SocketAddr,
+    ) -> Result<(), String> {
+        for endpoint in self.workers.values_mut().filter(|w| w.healthy) {
+            endpoint
+                .sender
+                .send(CoordinationMessage {
+                    epoch: self.epoch,
+                    source: endpoint.sender.local_addr().unwrap(),
+                    payload: CoordinationPayload::DomainBooted(DomainDescriptor::new(
+                        di, shard, addr,
+                    )),
+                })
+                .map_err(|e| format!("failed to announce domain shard: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Have the handed off domain shards at `forwarders`, which may still forward to a shard that
+    /// has now been handed off in turn, forward to the shards at `to` instead. Forwarders that
+    /// can't be reached have stopped, and are forgotten.
+    fn reforward(
+        &self,
+        forwarders: &mut Vec<(WorkerIdentifier, SocketAddr)>,
+        to: Vec<SocketAddr>,
+        readers_to: Vec<SocketAddr>,
+        writes_to: Vec<SocketAddr>,
+    ) {
+        forwarders.retain(|&(_, addr)| {
+            let p = Packet::Reforward {
+                to: to.clone(),
+                readers_to: readers_to.clone(),
+                writes_to: writes_to.clone(),
+            };
+            DomainConnectionBuilder::for_domain(addr)
+                .build_sync()
+                .map_err(SendError::from)
+                .and_then(|mut tx| tx.send(Box::new(p)))
+                .is_ok()
+        });
+    }
+
+    /// Reshard the named base table, and everything that has to be resharded along with it,
+    /// across `shards` shards.
+    fn reshard(&mut self, table: String, shards: usize) -> Result<(), String> {
+        match self.persistence.mode {
+            DurabilityMode::MemoryOnly | DurabilityMode::DeleteOnExit => {}
+            _ => {
+                // recovery builds the tables from the recipe with the configured shard count, and
+                // each shard opens its RocksDB database or write-ahead log by shard index, so it
+                // would find the rows that belong to another shard of the resharded table
+                return Err(format!(
+                    "tables that are kept across restarts ({:?}) can't be resharded",
+                    self.persistence.mode
+                ));
+            }
+        }
+        if shards < 2 {
+            return Err("tables can only be resharded across two or more shards".to_owned());
+        }
+        let base = match self.inputs().get(&table) {
+            Some(&ni) => ni,
+            None => return Err(format!("no base table named \"{}\"", table)),
+        };
+        let di = self.ingredients[base].domain();
+        match self.domains[&di].shards() {
+            1 => return Err(format!("table \"{}\" isn't sharded", table)),
+            n if n == shards => return Ok(()),
+            _ => {}
+        }
+
+        let domains = self.resharded_domains(di);
+
+        // nothing may be on its way to the domains while they are handed off
+        let id = self.snapshot.as_ref().map(|s| s.id + 1).unwrap_or(0);
+        self.pause_dataflow(id, false)?;
+
+        let resharded = self.reshard_domains(&domains, shards);
+        let resumed = self.resume_dataflow(id, false);
+        resharded?;
+        resumed?;
+
+        info!(self.log, "resharded table";
+              "table" => table,
+              "shards" => shards,
+              "domains" => domains.len());
+        Ok(())
+    }
+
+    /// The domains that have to be resharded along with domain `di`: all domains downstream of
+    /// it, and the sharded domains that send to any of those without a shuffle, since they must
+    /// keep the same shards as the domains they send to.
+    fn resharded_domains(&self, di: DomainIndex) -> Vec<DomainIndex> {
+        let graph = &self.ingredients;
+        let mut domains = HashSet::new();
+        domains.insert(di);
+        let mut stack = vec![di];
+        while let Some(di) = stack.pop() {
+            for &ni in &self.domain_nodes[&di] {
+                let n = &graph[ni];
+                if n.is_dropped() {
+                    continue;
+                }
+                for child in graph.neighbors_directed(ni, petgraph::EdgeDirection::Outgoing) {
+                    let cdi = graph[child].domain();
+                    if domains.insert(cdi) {
+                        stack.push(cdi);
+                    }
+                }
+                if !n.is_ingress() {
+                    continue;
+                }
+                for sender in graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming) {
+                    let s = &graph[sender];
+                    if s.is_egress()
+                        && self.domains[&s.domain()].shards() > 1
+                        && domains.insert(s.domain())
+                    {
+                        stack.push(s.domain());
+                    }
+                }
+            }
+        }
 
-        GraphStats { domains }
+        let mut domains: Vec<_> = domains.into_iter().collect();
+        domains.sort_by_key(|di| di.index());
+        domains
     }
 
-    fn get_instances(&self) -> Vec<(WorkerIdentifier, bool, Duration)> {
-        self.workers
+    /// Hand off every shard of the given domains, and start the sharded ones anew across `shards`
+    /// shards and the others on a single shard again, with their state split up among the new
+    /// shards.
+    ///
+    /// If anything goes wrong before the new shards have all of the state, the new shards are
+    /// stopped and the old ones carry on. Once the old shards start forwarding to the new ones,
+    /// the new shards are the ones that hold the state, and there is no going back.
+    fn reshard_domains(&mut self, domains: &[DomainIndex], shards: usize) -> Result<(), String> {
+        self.metadata_changed = true;
+        let sharded: HashSet<_> = domains
             .iter()
-            .map(|(&id, ref status)| (id, status.healthy, status.last_heartbeat.elapsed()))
-            .collect()
-    }
+            .cloned()
+            .filter(|di| self.domains[di].shards() > 1)
+            .collect();
 
-    fn flush_partial(&mut self) -> u64 {
-        // get statistics for current domain sizes
-        // and evict all state from partial nodes
-        let workers = &self.workers;
-        let replies = &mut self.replies;
-        let to_evict: Vec<_> = self
-            .domains
-            .iter_mut()
-            .map(|(di, s)| {
-                s.send_to_healthy(Box::new(Packet::GetStatistics), workers)
-                    .unwrap();
-                let to_evict: Vec<(NodeIndex, u64)> =
-                    futures_executor::block_on(replies.wait_for_statistics(&s))
-                        .into_iter()
-                        .flat_map(move |(_, node_stats)| {
-                            node_stats
-                                .into_iter()
-                                .filter_map(|(ni, ns)| match ns.materialized {
-                                    MaterializationStatus::Partial { .. } => {
-                                        Some((ni, ns.mem_size))
-                                    }
-                                    _ => None,
-                                })
-                        })
-                        .collect();
-                (*di, to_evict)
+        let mut handed_off = Vec::new();
+        let mut started = Vec::new();
+        let mut setups = Vec::new();
+        let prepared = self.prepare_reshard(
+            domains,
+            shards,
+            &sharded,
+            &mut handed_off,
+            &mut started,
+            &mut setups,
+        );
+        if let Err(e) = prepared {
+            warn!(self.log, "cancelling resharding of domains";
+                  "domains" => domains.len(),
+                  "error" => &e);
+            self.cancel_reshard(&handed_off, started);
+            return Err(e);
+        }
+
+        // where each old shard is to redirect to is worked out before any of them are, so that
+        // nothing stands in the way once the first one is
+        let redirects = started
+            .iter()
+            .map(|(di, new)| {
+                let to: Vec<_> = new.iter().map(|&(_, addr, _)| addr).collect();
+                let readers_to = new
+                    .iter()
+                    .map(|&(worker, _, _)| self.read_addrs.get(&worker).cloned())
+                    .collect::<Option<Vec<_>>>();
+                let from = (0..self.domains[di].shards())
+                    .map(|shard| self.channel_coordinator.get_addr(&(*di, shard)))
+                    .collect::<Option<Vec<_>>>();
+                match (readers_to, from) {
+                    (Some(readers_to), Some(from)) => Ok((to, readers_to, from)),
+                    _ => Err(format!(
+                        "no address for every shard of domain {}",
+                        di.index()
+                    )),
+                }
             })
-            .collect();
+            .collect::<Result<Vec<_>, _>>();
+        let redirects = match redirects {
+            Ok(redirects) => redirects,
+            Err(e) => {
+                self.cancel_reshard(&handed_off, started);
+                return Err(e);
+            }
+        };
 
-        let mut total_evicted = 0;
-        for (di, nodes) in to_evict {
-            for (ni, bytes) in nodes {
-                let na = self.ingredients[ni].local_addr();
-                self.domains
-                    .get_mut(&di)
-                    .unwrap()
-                    .send_to_healthy(
-                        Box::new(Packet::Evict {
-                            node: Some(na),
-                            num_bytes: bytes as usize,
-                        }),
-                        workers,
-                    )
-                    .expect("failed to send domain flush message");
-                total_evicted += bytes;
+        // the old shards pass on what they held back, and anything that still comes their way
+        let mut forwarded = false;
+        for (i, (to, readers_to, from)) in redirects.iter().enumerate() {
+            let di = started[i].0;
+            for (shard, &from) in from.iter().enumerate() {
+                let p = Packet::Forward {
+                    to: to.clone(),
+                    readers_to: readers_to.clone(),
+                    writes_to: to.clone(),
+                    from,
+                };
+                let domain = self.domains.get_mut(&di).unwrap();
+                let redirected = domain
+                    .send_to_healthy_shard(shard, Box::new(p), &self.workers)
+                    .map_err(|e| format!("failed to redirect old domain shard: {:?}", e))
+                    .and_then(|_| futures_executor::block_on(self.replies.wait_for_hand_off()));
+                match redirected {
+                    Ok(()) => forwarded = true,
+                    Err(e) if !forwarded => {
+                        self.cancel_reshard(&handed_off, started);
+                        return Err(e);
+                    }
+                    Err(e) => return Err(e),
+                }
             }
         }
 
-        warn!(
-            self.log,
-            "flushed {} bytes of partial domain state", total_evicted
-        );
+        let mut announced = Ok(());
+        for (((di, new), setups), (to, readers_to, from)) in
+            started.into_iter().zip(setups).zip(redirects)
+        {
+            let mut forwarders = Vec::new();
+            for (shard, from) in from.into_iter().enumerate() {
+                let old = &mut self.domains.get_mut(&di).unwrap().shards[shard];
+                forwarders.extend(mem::replace(&mut old.forwarders, Vec::new()));
+                forwarders.push((old.worker, from));
+            }
+            self.reforward(&mut forwarders, to.clone(), readers_to, to);
+
+            let mut handles = Vec::with_capacity(new.len());
+            for ((shard, (worker, addr, tx)), setup) in new.into_iter().enumerate().zip(setups) {
+                self.channel_coordinator.insert_remote((di, shard), addr);
+                announced = announced.and(self.announce_shard(di, shard, addr));
+                let mut handle = DomainShardHandle::new(worker, Box::new(tx));
+                handle.setup = setup;
+                handles.push(handle);
+            }
+            // forwarders are only kept track of for the first shard, since all new shards share
+            // them
+            handles[0].forwarders = forwarders;
+            self.domains.get_mut(&di).unwrap().shards = handles;
+        }
 
-        total_evicted
+        for di in domains {
+            for &ni in &self.domain_nodes[di] {
+                self.ingredients[ni].reshard(shards);
+            }
+        }
+
+        // the domains that feed the resharded ones from outside should send to the new shards
+        let graph = &self.ingredients;
+        let mut updates = Vec::new();
+        for &di in domains {
+            let shards = self.domains[&di].shards();
+            for &ni in &self.domain_nodes[&di] {
+                let n = &graph[ni];
+                if !n.is_ingress() || n.is_dropped() {
+                    continue;
+                }
+                for sender in graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming) {
+                    let s = &graph[sender];
+                    if domains.contains(&s.domain()) {
+                        // already sends to the new shards
+                        continue;
+                    }
+                    if s.is_egress() {
+                        // only unsharded domains send to the resharded ones without a shuffle
+                        let p = Packet::UpdateEgress {
+                            node: s.local_addr(),
+                            new_tx: Some((ni, n.local_addr(), (di, 0))),
+                            new_tag: None,
+                        };
+                        updates.push((s.domain(), p));
+                    } else if s.is_sharder() {
+                        let p = Packet::UpdateSharder {
+                            node: s.local_addr(),
+                            new_txs: (n.local_addr(), (0..shards).map(|i| (di, i)).collect()),
+                        };
+                        updates.push((s.domain(), p));
+                    }
+                }
+            }
+        }
+        for (sdi, p) in updates {
+            let domain = self.domains.get_mut(&sdi).unwrap();
+            domain
+                .send_to_healthy(Box::new(p), &self.workers)
+                .map_err(|e| format!("failed to reroute to resharded domain: {:?}", e))?;
+        }
+
+        announced
     }
 
-    pub(super) fn create_universe(
+    /// Hand off every shard of the given domains, and boot, set up and fill the shards that are to
+    /// take their place. The domains that were handed off and the shards that were booted are
+    /// recorded as they are, so that they can be cancelled and stopped if this fails part of the
+    /// way.
+    fn prepare_reshard(
         &mut self,
-        context: HashMap<String, DataType>,
+        domains: &[DomainIndex],
+        shards: usize,
+        sharded: &HashSet<DomainIndex>,
+        handed_off: &mut Vec<DomainIndex>,
+        started: &mut Vec<(DomainIndex, Vec<NewShard>)>,
+        setups: &mut Vec<Vec<Vec<Packet>>>,
     ) -> Result<(), String> {
-        let log = self.log.clone();
-        let mut r = self.recipe.clone();
-        let groups = self.recipe.security_groups();
+        // all the domains are handed off before any are started anew, so that no old shard sends
+        // anything to a new one
+        for &di in domains {
+            let domain = self.domains.get_mut(&di).unwrap();
+            let n = domain.shards();
+            domain
+                .send_to_healthy(
+                    Box::new(Packet::HandOff {
+                        share_storage: false,
+                    }),
+                    &self.workers,
+                )
+                .map_err(|e| format!("failed to hand off domain: {:?}", e))?;
+            // some shards may have been handed off even if others weren't
+            handed_off.push(di);
+            futures_executor::block_on(self.replies.wait_for_hand_offs(n))?;
+        }
 
-        let mut universe_groups = HashMap::new();
+        for &di in domains {
+            let nshards = if sharded.contains(&di) { shards } else { 1 };
+            let nodes: Vec<_> = self.domain_nodes[&di]
+                .iter()
+                .map(|&ni| (ni, false))
+                .collect();
+            let workers = self.choose_workers(di, nshards, &nodes)?;
+            started.push((di, Vec::with_capacity(nshards)));
+            for (shard, worker) in workers.into_iter().enumerate() {
+                let index = if nshards > 1 { Some(shard) } else { None };
+                let (addr, tx) = self.boot_shard(di, index, nshards, Some(shards), worker)?;
+                started.last_mut().unwrap().1.push((worker, addr, tx));
+            }
+        }
 
-        let uid = context
-            .get("id")
-            .expect("Universe context must have id")
-            .clone();
-        let uid = &[uid];
-        if context.get("group").is_none() {
-            let x = Arc::new(Mutex::new(HashMap::new()));
-            for g in groups {
-                // TODO: this should use external APIs through noria::ControllerHandle
-                // TODO: can this move to the client entirely?
-                let rgb: Option<ViewBuilder> = self.view_builder(&g);
-                // TODO: using block_on here _only_ works because View::lookup just waits on a
-                // channel, which doesn't use anything except the pure executor
-                let mut view = rgb.map(|rgb| rgb.build(x.clone()).unwrap()).unwrap();
-                let my_groups: Vec<DataType> = futures_executor::block_on(view.lookup(uid, true))
-                    .unwrap()
+        // set up the new shards' state and replay paths like the old ones' were, and fill in
+        // their share of the fully materialized state
+        for (di, new) in started.iter_mut() {
+            let di = *di;
+            let setup = self.domains[&di].shards[0].setup.clone();
+            let setup = self.setup_for_new_shard(di, setup);
+            let mut resharded = Vec::with_capacity(new.len());
+            for (shard, (_, _, tx)) in new.iter_mut().enumerate() {
+                let setup: Vec<_> = setup
                     .iter()
-                    .map(|v| v[1].clone())
+                    .map(|p| p.resharded(shard, shards, sharded))
                     .collect();
-                universe_groups.insert(g, my_groups);
+                self.set_up_shard(tx, &setup)?;
+                resharded.push(setup);
             }
+            setups.push(resharded);
+        }
+        for (di, new) in started.iter_mut() {
+            let di = *di;
+            let old: Vec<_> = (0..self.domains[&di].shards()).collect();
+            let to = new.iter().map(|&(_, addr, _)| addr).collect();
+            let mut txs: Vec<_> = new.iter_mut().map(|(_, _, tx)| tx).collect();
+            self.transfer_state(di, &old, to, &mut txs)?;
         }
+        Ok(())
+    }
 
-        self.add_universe(context.clone(), |mut mig| {
-            r.next();
-            match r.create_universe(&mut mig, universe_groups) {
-                Ok(ar) => {
-                    info!(log, "{} expressions added", ar.expressions_added);
-                    info!(log, "{} expressions removed", ar.expressions_removed);
-                    Ok(())
-                }
-                Err(e) => {
-                    crit!(log, "failed to create universe: {:?}", e);
-                    Err("failed to create universe".to_owned())
-                }
+    /// Stop the shards that were started to take the place of the handed off domains, and have
+    /// the old shards of those domains carry on as before.
+    fn cancel_reshard(
+        &mut self,
+        handed_off: &[DomainIndex],
+        mut started: Vec<(DomainIndex, Vec<NewShard>)>,
+    ) {
+        for &di in handed_off {
+            let new = match started.iter().position(|&(sdi, _)| sdi == di) {
+                Some(i) => started.swap_remove(i).1,
+                None => Vec::new(),
+            };
+            let txs = new.into_iter().map(|(_, _, tx)| tx).collect();
+            let shards: Vec<_> = (0..self.domains[&di].shards()).collect();
+            // a shard that was never handed off says so, and carries on regardless
+            if let Err(e) = self.cancel_hand_offs(di, &shards, txs) {
+                warn!(self.log, "failed to cancel hand-off of domain";
+                      "domain" => di.index(),
+                      "error" => e);
             }
-            .unwrap();
-        });
+        }
+    }
 
-        self.recipe = r;
+    /// Compact the on-disk state of the named base table, or of all base tables. The domains
+    /// compact on their own time, so this doesn't wait for them and keeps the controller free.
+    fn compact(&mut self, table: Option<String>) -> Result<(), String> {
+        let targets: Vec<_> = match table {
+            Some(name) => {
+                let base = match self.inputs().get(&name) {
+                    Some(&ni) => &self.ingredients[ni],
+                    None => return Err(format!("no base table named \"{}\"", name)),
+                };
+                vec![(base.domain(), Some(base.local_addr()))]
+            }
+            None => self.domains.keys().map(|&di| (di, None)).collect(),
+        };
+
+        for (di, node) in targets {
+            let domain = self.domains.get_mut(&di).unwrap();
+            domain
+                .send_to_healthy(Box::new(Packet::Compact { node }), &self.workers)
+                .map_err(|e| format!("failed to start compaction: {:?}", e))?;
+        }
         Ok(())
     }
 
-    fn set_security_config(&mut self, p: String) -> Result<(), String> {
-        self.recipe.set_security_config(&p);
+    /// Read the next `count` rows of shard `shard` of the given base table that export `export`
+    /// hasn't read yet, straight from the table's state. An empty result means that the export
+    /// has read every row of the shard, and `None` that the table has no such shard.
+    fn export_rows(
+        &mut self,
+        table: &str,
+        export: u64,
+        shard: usize,
+        count: usize,
+    ) -> Result<Option<Vec<Vec<DataType>>>, String> {
+        let base = match self.inputs().get(table) {
+            Some(&ni) => &self.ingredients[ni],
+            None => return Err(format!("no base table named \"{}\"", table)),
+        };
+        let node = base.local_addr();
+        let domain = self.domains.get_mut(&base.domain()).unwrap();
+        if shard >= domain.shards() {
+            return Ok(None);
+        }
+
+        domain
+            .send_to_healthy_shard(
+                shard,
+                Box::new(Packet::ExportBase {
+                    node,
+                    export,
+                    count,
+                }),
+                &self.workers,
+            )
+            .map_err(|e| format!("failed to read base table: {:?}", e))?;
+        Ok(Some(futures_executor::block_on(
+            self.replies.wait_for_base_rows(),
+        )))
+    }
+
+    /// Have every shard of the given base table drop the rows that export `export` hasn't read
+    /// yet, since it stopped before reading them all.
+    fn cancel_export(&mut self, table: &str, export: u64) -> Result<(), String> {
+        let base = match self.inputs().get(table) {
+            Some(&ni) => &self.ingredients[ni],
+            None => return Err(format!("no base table named \"{}\"", table)),
+        };
+        let node = base.local_addr();
+        self.domains
+            .get_mut(&base.domain())
+            .unwrap()
+            .send_to_healthy(
+                Box::new(Packet::CancelExport { node, export }),
+                &self.workers,
+            )
+            .map_err(|e| format!("failed to cancel export: {:?}", e))
+    }
+
+    /// Write a backup of the deployment to `dir`, from which `Builder::set_restore_from` can
+    /// start an identical deployment.
+    ///
+    /// The backup holds a consistent checkpoint of every base table: writes to all base tables are
+    /// held back, and every write that was already accepted has made its way through the
+    /// data-flow, before any domain writes its checkpoints. Writes resume once all domains have.
+    fn backup<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        dir: PathBuf,
+    ) -> Result<(), String> {
+        match self.persistence.mode {
+            DurabilityMode::Permanent | DurabilityMode::DeleteOnExit => {}
+            _ => return Err("backups require base tables to be kept in RocksDB".to_owned()),
+        }
+        if dir.join(backup::MANIFEST).exists() {
+            return Err(format!("{:?} already holds a backup", dir));
+        }
+        fs::create_dir_all(&dir).map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+
+        // the recipes are taken from where recovery would take them from
+        let state: ControllerState = match authority.try_read(STATE_KEY) {
+            Ok(Some(state)) => serde_json::from_slice(&state).unwrap(),
+            _ => return Err("failed to read controller state".to_owned()),
+        };
+
+        let id = self.snapshot.as_ref().map(|s| s.id + 1).unwrap_or(0);
+        self.pause_dataflow(id, false)?;
+
+        let mut nshards = 0;
+        let mut taken = Ok(());
+        for domain in self.domains.values_mut() {
+            let sent = domain
+                .send_to_healthy(Box::new(Packet::Backup { dir: dir.clone() }), &self.workers);
+            if let Err(e) = sent {
+                taken = Err(format!("failed to start backup: {:?}", e));
+                break;
+            }
+            nshards += domain.shards();
+        }
+        taken = taken.and(futures_executor::block_on(
+            self.replies.wait_for_backups(nshards),
+        ));
+        self.resume_dataflow(id, false)?;
+        taken?;
+
+        let manifest = Manifest {
+            recipe_version: state.recipe_version,
+            recipes: state.recipes,
+            security_config: self.security_config.clone(),
+            universes: self.universes.clone(),
+            memory_budgets: self.memory_budgets.clone(),
+            read_replicas: self.read_replicas.clone(),
+            sharding: self.sharding,
+            bases: self
+                .inputs()
+                .into_iter()
+                .map(|(name, ni)| (name, self.domains[&self.ingredients[ni].domain()].shards()))
+                .collect(),
+        };
+        // the manifest is written last, so that a backup that has one is complete
+        fs::write(
+            dir.join(backup::MANIFEST),
+            serde_json::to_vec_pretty(&manifest).unwrap(),
+        )
+        .map_err(|e| format!("failed to write backup manifest: {}", e))?;
+
+        info!(self.log, "backed up deployment"; "dir" => ?dir);
         Ok(())
     }
 
-    fn apply_recipe(&mut self, mut new: Recipe) -> Result<ActivationResult, String> {
-        let r = self.migrate(|mig| {
-            new.activate(mig)
-                .map_err(|e| format!("failed to activate recipe: {}", e))
-        });
+    /// Send the changes made to base tables since the snapshot that recovery started from, which
+    /// were undone during recovery, through the data-flow again.
+    fn reapply_base_logs(&mut self) {
+        let snapshot = match self.snapshot {
+            Some(ref snapshot) => snapshot,
+            None => return,
+        };
 
-        match r {
-            Ok(ref ra) => {
-                let (removed_bases, removed_other): (Vec<_>, Vec<_>) = ra
-                    .removed_leaves
-                    .iter()
-                    .cloned()
-                    .partition(|ni| self.ingredients[*ni].is_base());
-
-                // first remove query nodes in reverse topological order
-                let mut topo_removals = Vec::with_capacity(removed_other.len());
-                let mut topo = petgraph::visit::Topo::new(&self.ingredients);
-                while let Some(node) = topo.next(&self.ingredients) {
-                    if removed_other.contains(&node) {
-                        topo_removals.push(node);
-                    }
-                }
-                topo_removals.reverse();
+        let bases: Vec<_> = self
+            .inputs()
+            .into_iter()
+            .filter(|(name, _)| snapshot.base_seqs.contains_key(name))
+            .map(|(_, ni)| ni)
+            .collect();
+        for ni in bases {
+            let base = &self.ingredients[ni];
+            let domain = self.domains.get_mut(&base.domain()).unwrap();
+            domain
+                .send_to_healthy(
+                    Box::new(Packet::ReapplyBaseLog {
+                        node: base.local_addr(),
+                    }),
+                    &self.workers,
+                )
+                .unwrap();
+            futures_executor::block_on(self.replies.wait_for_acks(domain));
+        }
+    }
 
-                for leaf in topo_removals {
-                    self.remove_leaf(leaf)?;
+    /// Tell the domains holding the partial state of queries with a memory budget about it, and
+    /// lift the budgets of nodes that no longer have one.
+    ///
+    /// A query's budget covers all of its partially materialized state: that of its reader, of
+    /// the reader's replicas, and of the partially materialized nodes they read from. Each of those
+    /// nodes may hold an equal share of it. Nodes that several queries with a budget share get the
+    /// smallest of their shares.
+    fn update_memory_budgets(&mut self) -> Result<(), String> {
+        let mut budgets = self.recipe.memory_budgets().clone();
+        budgets.extend(self.memory_budgets.iter().map(|(n, &b)| (n.clone(), b)));
+
+        let mut node_budgets: HashMap<NodeIndex, usize> = HashMap::new();
+        for (name, budget) in budgets {
+            let r = match self.reader_for(&name) {
+                Some(r) => r,
+                None => {
+                    warn!(self.log, "no view to apply memory budget to"; "name" => name);
+                    continue;
                 }
+            };
 
-                // now remove bases
-                for base in removed_bases {
-                    // TODO(malte): support removing bases that still have children?
-                    let children: Vec<NodeIndex> = self
-                        .ingredients
-                        .neighbors_directed(base, petgraph::EdgeDirection::Outgoing)
-                        .collect();
-                    // TODO(malte): what about domain crossings? can ingress/egress nodes be left
-                    // behind?
-                    assert_eq!(children.len(), 0);
-                    debug!(
-                        self.log,
-                        "Removing base \"{}\"",
-                        self.ingredients[base].name();
-                        "node" => base.index(),
-                    );
-                    // now drop the (orphaned) base
-                    self.remove_nodes(vec![base].as_slice()).unwrap();
-                }
+            let mut nodes = HashSet::new();
+            for reader in iter::once(r).chain(self.read_replicas_of(r)) {
+                nodes.extend(self.query_nodes(reader, &name).into_iter().filter(|&ni| {
+                    match self.materializations.get_status(ni, &self.ingredients[ni]) {
+                        MaterializationStatus::Partial { .. } => true,
+                        _ => false,
+                    }
+                }));
+            }
+            if nodes.is_empty() {
+                warn!(self.log, "no partial state to apply memory budget to"; "name" => name);
+                continue;
+            }
 
-                self.recipe = new;
+            let share = budget / nodes.len();
+            for ni in nodes {
+                let b = node_budgets.entry(ni).or_insert(share);
+                *b = (*b).min(share);
             }
-            Err(ref e) => {
-                crit!(self.log, "failed to apply recipe: {}", e);
-                // TODO(malte): a little yucky, since we don't really need the blank recipe
-                let recipe = mem::replace(&mut self.recipe, Recipe::blank(None));
-                self.recipe = recipe.revert();
+        }
+
+        let mut updates: Vec<_> = node_budgets
+            .iter()
+            .filter(|&(ni, budget)| self.node_budgets.get(ni) != Some(budget))
+            .map(|(&ni, &budget)| (ni, Some(budget)))
+            .collect();
+        updates.extend(
+            self.node_budgets
+                .keys()
+                .filter(|&&ni| {
+                    !node_budgets.contains_key(&ni) && !self.ingredients[ni].is_dropped()
+                })
+                .map(|&ni| (ni, None)),
+        );
+        self.node_budgets
+            .retain(|ni, _| node_budgets.contains_key(ni));
+
+        // a node whose domain could not be told keeps the budget it had, so that the next update
+        // tries again
+        let mut failed = None;
+        for (ni, budget) in updates {
+            let domain = self.ingredients[ni].domain();
+            let node = self.ingredients[ni].local_addr();
+            let dh = self.domains.get_mut(&domain).unwrap();
+            // every shard holds part of the node's state, so each gets part of the budget
+            let shards = dh.shards();
+            let shard_budget = budget.map(|b| (b + shards - 1) / shards);
+            info!(self.log, "updating memory budget";
+                  "node" => ni.index(),
+                  "budget" => ?shard_budget);
+            match dh.send_to_healthy(
+                Box::new(Packet::UpdateMemoryBudget {
+                    node,
+                    budget: shard_budget,
+                }),
+                &self.workers,
+            ) {
+                Ok(()) => {
+                    self.metadata_changed = true;
+                    match budget {
+                        Some(budget) => self.node_budgets.insert(ni, budget),
+                        None => self.node_budgets.remove(&ni),
+                    };
+                }
+                Err(e) => {
+                    crit!(self.log, "failed to send memory budget to domain";
+                          "node" => ni.index(),
+                          "error" => ?e);
+                    failed = Some(format!(
+                        "failed to send memory budget of node {} to domain {}: {:?}",
+                        ni.index(),
+                        domain.index(),
+                        e
+                    ));
+                }
             }
         }
 
-        r
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
     fn extend_recipe<A: Authority + 'static>(
@@ -1089,7 +4615,10 @@ impl ControllerInner {
         let new = mem::replace(&mut self.recipe, Recipe::blank(None));
         match new.extend(&add_txt) {
             Ok(new) => {
-                let activation_result = self.apply_recipe(new);
+                let activation_result =
+                    self.apply_recipe(new, RecipeChange::Extend(add_txt.clone()))?;
+                let statistics = self.last_planned_statistics();
+                self.recipe_statistics.push(statistics);
                 if authority
                     .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
                         None => unreachable!(),
@@ -1097,6 +4626,7 @@ impl ControllerInner {
                         Some(mut state) => {
                             state.recipe_version = self.recipe.version();
                             state.recipes.push(add_txt.clone());
+                            state.recipe_statistics = self.recipe_statistics.clone();
                             Ok(state)
                         }
                     })
@@ -1105,7 +4635,7 @@ impl ControllerInner {
                     return Err("Failed to persist recipe extension".to_owned());
                 }
 
-                activation_result
+                Ok(activation_result)
             }
             Err((old, e)) => {
                 // need to restore the old recipe
@@ -1125,7 +4655,9 @@ impl ControllerInner {
             Ok(r) => {
                 let old = mem::replace(&mut self.recipe, Recipe::blank(None));
                 let new = old.replace(r).unwrap();
-                let activation_result = self.apply_recipe(new);
+                let activation_result =
+                    self.apply_recipe(new, RecipeChange::Install(r_txt.clone()))?;
+                self.recipe_statistics = vec![self.last_planned_statistics()];
                 if authority
                     .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
                         None => unreachable!(),
@@ -1133,6 +4665,7 @@ impl ControllerInner {
                         Some(mut state) => {
                             state.recipe_version = self.recipe.version();
                             state.recipes = vec![r_txt.clone()];
+                            state.recipe_statistics = self.recipe_statistics.clone();
                             Ok(state)
                         }
                     })
@@ -1140,7 +4673,7 @@ impl ControllerInner {
                 {
                     return Err("Failed to persist recipe installation".to_owned());
                 }
-                activation_result
+                Ok(activation_result)
             }
             Err(e) => {
                 crit!(self.log, "failed to parse recipe: {:?}", e);
@@ -1158,6 +4691,17 @@ impl ControllerInner {
         let start = leaf;
         assert!(!self.ingredients[leaf].is_source());
 
+        // replicas of the leaf's reader hang off the leaf too, through domains of their own
+        if let Some(reader) = self
+            .ingredients
+            .neighbors_directed(leaf, petgraph::EdgeDirection::Outgoing)
+            .find(|&ni| self.ingredients[ni].is_reader())
+        {
+            for replica in self.read_replicas_of(reader) {
+                self.remove_read_replica(replica)?;
+            }
+        }
+
         info!(
             self.log,
             "Computing removals for removing node {}",
@@ -1217,7 +4761,18 @@ impl ControllerInner {
             0
         );
 
-        let mut nodes = vec![leaf];
+        self.remove_with_ancestors(leaf, start, removals)
+    }
+
+    /// Remove `node`, which has no children, along with every ancestor that is left without
+    /// children and isn't the source, a base, or the leaf of a query other than `start`.
+    fn remove_with_ancestors(
+        &mut self,
+        node: NodeIndex,
+        start: NodeIndex,
+        mut removals: Vec<NodeIndex>,
+    ) -> Result<(), String> {
+        let mut nodes = vec![node];
         while let Some(node) = nodes.pop() {
             let mut parents = self
                 .ingredients
@@ -1247,6 +4802,8 @@ impl ControllerInner {
     }
 
     fn remove_nodes(&mut self, removals: &[NodeIndex]) -> Result<(), String> {
+        self.metadata_changed = true;
+
         // Remove node from controller local state
         let mut domain_removals: HashMap<DomainIndex, Vec<LocalNodeIndex>> = HashMap::default();
         for ni in removals {
@@ -1293,26 +4850,6 @@ impl ControllerInner {
         Ok(())
     }
 
-    fn get_failed_nodes(&self, lost_worker: &WorkerIdentifier) -> Vec<NodeIndex> {
-        // Find nodes directly impacted by worker failure.
-        let mut nodes: Vec<NodeIndex> = self.nodes_on_worker(Some(lost_worker));
-
-        // Add any other downstream nodes.
-        let mut failed_nodes = Vec::new();
-        while let Some(node) = nodes.pop() {
-            failed_nodes.push(node);
-            for child in self
-                .ingredients
-                .neighbors_directed(node, petgraph::EdgeDirection::Outgoing)
-            {
-                if !nodes.contains(&child) {
-                    nodes.push(child);
-                }
-            }
-        }
-        failed_nodes
-    }
-
     /// List data-flow nodes, on a specific worker if `worker` specified.
     fn nodes_on_worker(&self, worker: Option<&WorkerIdentifier>) -> Vec<NodeIndex> {
         // NOTE(malte): this traverses all graph vertices in order to find those assigned to a
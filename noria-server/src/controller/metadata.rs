@@ -0,0 +1,230 @@
+//! What a controller knows about the data-flow it runs, which it keeps in the authority so that a
+//! controller that takes over from it can adopt the running domains instead of rebuilding them.
+//!
+//! The graph, the domains and their materializations are kept as they are. The SQL layer's state
+//! can't be kept that way, so it is rebuilt by making every change to the recipe again, with each
+//! migration handing out the nodes that it handed out the first time around rather than adding new
+//! ones. Where it can, the controller compacts those changes into a single installation of the
+//! recipe as it is, so that they don't pile up.
+//!
+//! The graph and the journal of a large deployment take up more than ZooKeeper keeps under a
+//! single node, so the serialized metadata is split into parts that are each kept under their own
+//! key. The key the metadata is known by only names the parts that make up the latest version.
+
+use crate::controller::migrate::materialization::Plans;
+use crate::controller::migrate::Allocated;
+use crate::controller::sql::TableStatistics;
+use dataflow::prelude::*;
+use noria::consensus::{Authority, Epoch, METADATA_KEY};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// How many bytes of the serialized metadata are kept under one key. ZooKeeper keeps at most a
+/// megabyte under a node, and a part may take up to twice its length once it is escaped.
+const PART_LEN: usize = 256 * 1024;
+
+/// What is kept under `METADATA_KEY`: which parts make up the metadata that was written last.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    epoch: Epoch,
+    generation: u64,
+    parts: usize,
+}
+
+/// A piece of the serialized metadata.
+#[derive(Serialize, Deserialize)]
+struct Part {
+    epoch: Epoch,
+    generation: u64,
+    data: String,
+}
+
+/// The key that part `i` of the metadata of the given generation is kept under. Consecutive
+/// generations use different keys, so that the parts of the latest complete version are left alone
+/// while the next one is written.
+fn part_key(generation: u64, i: usize) -> String {
+    format!("{}_{}_{}", METADATA_KEY, generation % 2, i)
+}
+
+/// A change to the recipe, as it was asked for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(super) enum RecipeChange {
+    /// Start over from a blank recipe with the given version, as recovery does.
+    Reset(usize),
+    /// Extend the recipe with the given text.
+    Extend(String),
+    /// Replace the recipe with the given text.
+    Install(String),
+    /// Remove the named queries.
+    Remove(Vec<String>),
+    /// Add the named queries, which the change before removed, back as they were.
+    Restore(Vec<String>),
+    /// Set the given security configuration.
+    SecurityConfig(String),
+    /// Create the universe with the given context, whose user is in the given groups.
+    Universe(HashMap<String, DataType>, HashMap<String, Vec<DataType>>),
+}
+
+/// A change to the recipe that was made, along with what is needed to make it again the same way.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(super) struct JournalEntry {
+    pub(super) change: RecipeChange,
+    /// The base table statistics that the change was planned with.
+    pub(super) statistics: TableStatistics,
+    /// The nodes and base columns that the change's migration handed out.
+    pub(super) allocated: Allocated,
+}
+
+impl JournalEntry {
+    pub(super) fn new(change: RecipeChange) -> Self {
+        JournalEntry {
+            change,
+            statistics: TableStatistics::default(),
+            allocated: Allocated::default(),
+        }
+    }
+}
+
+/// A running domain shard.
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct ShardMetadata {
+    /// The address that the shard's worker listens on for the controller.
+    pub(super) worker: SocketAddr,
+    /// The address that the shard listens on.
+    pub(super) addr: SocketAddr,
+    /// The packets that set the shard up, in the order they were sent.
+    pub(super) setup: Vec<Packet>,
+}
+
+/// Everything a controller needs to take over a running data-flow.
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct Metadata {
+    /// The epoch of the controller that wrote the metadata.
+    pub(super) epoch: Epoch,
+
+    pub(super) ingredients: Graph,
+    pub(super) source: NodeIndex,
+    pub(super) ndomains: usize,
+    pub(super) remap: HashMap<DomainIndex, HashMap<NodeIndex, IndexPair>>,
+    pub(super) materializations: Plans,
+
+    pub(super) domains: HashMap<DomainIndex, Vec<ShardMetadata>>,
+    pub(super) domain_nodes: HashMap<DomainIndex, Vec<NodeIndex>>,
+    pub(super) node_budgets: HashMap<NodeIndex, usize>,
+
+    /// The changes made to the recipe since the controller started, in order, or fewer changes
+    /// that make the recipe the same.
+    pub(super) journal: Vec<JournalEntry>,
+    /// The queries that were installed ad-hoc, and are removed once they go unused.
+    pub(super) adhoc_queries: Vec<String>,
+}
+
+impl Metadata {
+    /// Whether the workers at `workers` run all the domain shards that the metadata describes.
+    pub(super) fn covered_by(&self, workers: &[SocketAddr]) -> bool {
+        self.domains
+            .values()
+            .flatten()
+            .all(|s| workers.contains(&s.worker))
+    }
+
+    /// Write the metadata to the authority as its `generation`th version, unless a controller with
+    /// a later epoch has written its own. The parts are written before the manifest that names
+    /// them, so a controller that takes over reads either all of this version or the one before.
+    pub(super) fn write<A: Authority + 'static>(
+        &self,
+        authority: &Arc<A>,
+        generation: u64,
+    ) -> Result<(), String> {
+        let epoch = self.epoch;
+        let json = serde_json::to_string(self)
+            .map_err(|e| format!("failed to serialize controller metadata: {:?}", e))?;
+        let mut parts = Vec::new();
+        let mut rest = &json[..];
+        while !rest.is_empty() {
+            let mut end = rest.len().min(PART_LEN);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            parts.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+
+        let newer = || "a newer controller has written metadata".to_owned();
+        for (i, data) in parts.iter().enumerate() {
+            authority
+                .read_modify_write(&part_key(generation, i), |old: Option<Part>| match old {
+                    Some(ref old) if old.epoch > epoch => Err(()),
+                    _ => Ok(Part {
+                        epoch,
+                        generation,
+                        data: (*data).to_owned(),
+                    }),
+                })
+                .map_err(|e| format!("failed to write controller metadata: {:?}", e))?
+                .map_err(|_| newer())?;
+        }
+        authority
+            .read_modify_write(METADATA_KEY, |old: Option<Option<Manifest>>| match old {
+                Some(Some(ref old)) if old.epoch > epoch => Err(()),
+                _ => Ok(Some(Manifest {
+                    epoch,
+                    generation,
+                    parts: parts.len(),
+                })),
+            })
+            .map_err(|e| format!("failed to write controller metadata: {:?}", e))?
+            .map_err(|_| newer())?;
+        Ok(())
+    }
+
+    /// Read the metadata that was last written to the authority, if there is any.
+    pub(super) fn read<A: Authority + 'static>(authority: &Arc<A>) -> Result<Option<Self>, String> {
+        let read = |key: &str| {
+            authority
+                .try_read(key)
+                .map_err(|e| format!("failed to read {}: {:?}", key, e))
+        };
+        let manifest: Manifest = match read(METADATA_KEY)? {
+            Some(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(Some(manifest)) => manifest,
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(format!("unreadable metadata manifest: {:?}", e)),
+            },
+            None => return Ok(None),
+        };
+
+        let mut json = String::new();
+        for i in 0..manifest.parts {
+            let key = part_key(manifest.generation, i);
+            let part: Part = match read(&key)? {
+                Some(bytes) => serde_json::from_slice(&bytes)
+                    .map_err(|e| format!("unreadable metadata part {}: {:?}", key, e))?,
+                None => return Err(format!("metadata part {} is missing", key)),
+            };
+            if part.epoch != manifest.epoch || part.generation != manifest.generation {
+                return Err(format!("metadata part {} belongs to another version", key));
+            }
+            json.push_str(&part.data);
+        }
+        serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| format!("unreadable controller metadata: {:?}", e))
+    }
+
+    /// Remove the metadata from the authority, unless a controller with a later epoch than `epoch`
+    /// has written its own.
+    pub(super) fn forget<A: Authority + 'static>(
+        authority: &Arc<A>,
+        epoch: Epoch,
+    ) -> Result<(), String> {
+        authority
+            .read_modify_write(METADATA_KEY, |old: Option<Option<Manifest>>| match old {
+                Some(Some(ref old)) if old.epoch > epoch => Err(()),
+                _ => Ok(None),
+            })
+            .map_err(|e| format!("failed to remove controller metadata: {:?}", e))?
+            .map_err(|_| "a newer controller has written metadata".to_owned())
+    }
+}
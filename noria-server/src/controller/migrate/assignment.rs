@@ -11,6 +11,7 @@ pub fn assign(log: &Logger, graph: &mut Graph, topo_list: &[NodeIndex], ndomains
     //
     //  - the child of a Sharder is always in a different domain from the sharder
     //  - shard merge nodes are never in the same domain as their sharded ancestors
+    //  - replicas of a reader are never in the same domain as anything else
 
     let mut next_domain = || {
         *ndomains += 1;
@@ -92,6 +93,12 @@ pub fn assign(log: &Logger, graph: &mut Graph, topo_list: &[NodeIndex], ndomains
                 return next_domain();
             }
 
+            if n.with_reader(|r| r.replica_of().is_some()).unwrap_or(false) {
+                // replicas of a reader are meant to run on other workers than the reader, so they
+                // each need a domain of their own
+                return next_domain();
+            }
+
             let any_parents = move |prime: &dyn Fn(&Node) -> bool,
                                     check: &dyn Fn(&Node) -> bool| {
                 let mut stack: Vec<_> = graph
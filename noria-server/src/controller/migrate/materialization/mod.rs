@@ -6,11 +6,8 @@
 //! module).
 
 use crate::controller::domain_handle::DomainHandle;
-use crate::controller::{
-    inner::{graphviz, DomainReplies},
-    keys,
-};
-use crate::controller::{Worker, WorkerIdentifier};
+use crate::controller::{inner::DomainReplies, keys};
+use crate::controller::{Snapshot, Worker, WorkerIdentifier};
 use dataflow::prelude::*;
 use petgraph;
 use petgraph::graph::NodeIndex;
@@ -46,6 +43,15 @@ impl Default for FrontierStrategy {
     }
 }
 
+/// The materializations of the running data-flow, which a controller that takes over from another
+/// needs in order to plan further migrations the way that one would have.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(in crate::controller) struct Plans {
+    have: HashMap<NodeIndex, Indices>,
+    partial: HashSet<NodeIndex>,
+    next_tag: usize,
+}
+
 pub(in crate::controller) struct Materializations {
     log: Logger,
 
@@ -56,9 +62,40 @@ pub(in crate::controller) struct Materializations {
     partial_enabled: bool,
     frontier_strategy: FrontierStrategy,
 
+    /// The indices and partial materializations that planning added since the last `commit`, so
+    /// that `abandon` can take them back.
+    planned: Vec<(NodeIndex, Vec<usize>)>,
+    planned_partial: Vec<NodeIndex>,
+
+    /// The snapshot that new base tables are rewound to, and that new fully materialized nodes
+    /// are loaded from where possible, while recovering.
+    restore: Option<Snapshot>,
+
     tag_generator: AtomicUsize,
 }
 
+impl Clone for Materializations {
+    fn clone(&self) -> Self {
+        Materializations {
+            log: self.log.clone(),
+
+            have: self.have.clone(),
+            added: self.added.clone(),
+
+            partial: self.partial.clone(),
+            partial_enabled: self.partial_enabled,
+            frontier_strategy: self.frontier_strategy.clone(),
+
+            planned: self.planned.clone(),
+            planned_partial: self.planned_partial.clone(),
+
+            restore: self.restore.clone(),
+
+            tag_generator: AtomicUsize::new(self.tag_generator.load(Ordering::SeqCst)),
+        }
+    }
+}
+
 impl Materializations {
     /// Create a new set of materializations.
     pub(in crate::controller) fn new(logger: &Logger) -> Self {
@@ -72,6 +109,11 @@ impl Materializations {
             partial_enabled: true,
             frontier_strategy: FrontierStrategy::None,
 
+            planned: Vec::new(),
+            planned_partial: Vec::new(),
+
+            restore: None,
+
             tag_generator: AtomicUsize::default(),
         }
     }
@@ -90,6 +132,32 @@ impl Materializations {
     pub(in crate::controller) fn set_frontier_strategy(&mut self, f: FrontierStrategy) {
         self.frontier_strategy = f;
     }
+
+    /// Restore new materializations from the given snapshot rather than rebuilding them from
+    /// scratch, or stop doing so if `None`.
+    pub(in crate::controller) fn restore_from(&mut self, snapshot: Option<Snapshot>) {
+        self.restore = snapshot;
+    }
+
+    /// The materializations of the running data-flow. May only be called between migrations.
+    pub(in crate::controller) fn plans(&self) -> Plans {
+        assert!(self.added.is_empty());
+        Plans {
+            have: self.have.clone(),
+            partial: self.partial.clone(),
+            next_tag: self.tag_generator.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Take over the materializations of a running data-flow that another controller planned.
+    pub(in crate::controller) fn adopt(&mut self, plans: Plans) {
+        self.have = plans.have;
+        self.added.clear();
+        self.partial = plans.partial;
+        self.planned.clear();
+        self.planned_partial.clear();
+        self.tag_generator = AtomicUsize::new(plans.next_tag);
+    }
 }
 
 impl Materializations {
@@ -100,7 +168,7 @@ impl Materializations {
     /// Extend the current set of materializations with any additional materializations needed to
     /// satisfy indexing obligations in the given set of (new) nodes.
     #[allow(clippy::cognitive_complexity)]
-    fn extend(&mut self, graph: &Graph, new: &HashSet<NodeIndex>) {
+    fn extend(&mut self, graph: &Graph, new: &HashSet<NodeIndex>) -> Result<(), String> {
         // this code used to be a mess, and will likely be a mess this time around too.
         // but, let's try to start out in a principled way...
         //
@@ -263,7 +331,7 @@ impl Materializations {
                        "for" => mi.index(),
                        "to" => parent.index());
                 mi = parent;
-                indices = map_indices(m, mi, &indices).unwrap();
+                indices = map_indices(m, mi, &indices)?;
                 m = &graph[mi];
             }
 
@@ -275,6 +343,8 @@ impl Materializations {
                 );
 
                 if self.have.entry(mi).or_default().insert(columns.clone()) {
+                    self.planned.push((mi, columns.clone()));
+
                     // also add a replay obligation to enable partial
                     replay_obligations
                         .entry(mi)
@@ -410,7 +480,9 @@ impl Materializations {
 
             if able {
                 // we can do partial if we add all those indices!
-                self.partial.insert(ni);
+                if self.partial.insert(ni) {
+                    self.planned_partial.push(ni);
+                }
                 warn!(self.log, "using partial materialization for {}", ni.index());
                 for (mi, indices) in add {
                     let m = replay_obligations.entry(mi).or_default();
@@ -418,11 +490,11 @@ impl Materializations {
                         m.insert(index);
                     }
                 }
-            } else {
-                assert!(
-                    !graph[ni].purge,
-                    "full materialization placed beyond materialization frontier"
-                );
+            } else if graph[ni].purge {
+                return Err(format!(
+                    "full materialization of node {} placed beyond materialization frontier",
+                    ni.index()
+                ));
             }
 
             // no matter what happens, we're going to have to fulfill our replay obligations.
@@ -431,6 +503,7 @@ impl Materializations {
                     let new_index = m.insert(index.clone());
 
                     if new_index {
+                        self.planned.push((ni, index.clone()));
                         info!(self.log,
                           "adding index to view to enable partial";
                           "on" => ni.index(),
@@ -450,6 +523,7 @@ impl Materializations {
             }
         }
         assert!(replay_obligations.is_empty());
+        Ok(())
     }
 
     /// Retrieves the materialization status of a given node, or None
@@ -473,20 +547,29 @@ impl Materializations {
         }
     }
 
-    /// Commit to all materialization decisions since the last time `commit` was called.
+    /// Returns the indices that the given node's state is keyed by, including any planned ones.
+    pub(in crate::controller) fn indices(&self, ni: NodeIndex) -> Vec<Vec<usize>> {
+        let mut indices: Vec<_> = self
+            .have
+            .get(&ni)
+            .map(|idxs| idxs.iter().cloned().collect())
+            .unwrap_or_default();
+        indices.sort();
+        indices
+    }
+
+    /// Decide on the materializations needed by the given new nodes, and check that those
+    /// decisions are consistent with each other and with existing materializations.
     ///
-    /// This includes setting up replay paths, adding new indices to existing materializations, and
-    /// populating new materializations.
+    /// Nothing is sent to any domain; `commit` does that. If the decisions are inconsistent, an
+    /// error is returned, and `abandon` should be called to take them back.
     #[allow(clippy::cognitive_complexity)]
-    pub(super) fn commit(
+    pub(super) fn plan(
         &mut self,
         graph: &mut Graph,
         new: &HashSet<NodeIndex>,
-        domains: &mut HashMap<DomainIndex, DomainHandle>,
-        workers: &HashMap<WorkerIdentifier, Worker>,
-        replies: &mut DomainReplies,
-    ) {
-        self.extend(graph, new);
+    ) -> Result<(), String> {
+        self.extend(graph, new)?;
 
         // check that we don't have fully materialized nodes downstream of partially materialized
         // nodes.
@@ -513,12 +596,58 @@ impl Materializations {
                 }
 
                 if let Some(pi) = any_partial(self, graph, ni) {
-                    println!("{}", graphviz(graph, true, &self));
                     crit!(self.log, "partial materializations above full materialization";
                               "full" => ni.index(),
                               "partial" => pi.index());
-                    unimplemented!();
+                    return Err(format!(
+                        "partial materialization of node {} above full materialization of node {}",
+                        pi.index(),
+                        ni.index()
+                    ));
+                }
+            }
+        }
+
+        // check that we don't make an existing non-materialized node partial if any of its
+        // children are materialized, as we might stop forwarding updates to them, which would
+        // make them very sad.
+        //
+        // the exception to this is for new children, or old children that are now becoming
+        // materialized; those are necessarily empty, and so we won't be violating key
+        // monotonicity.
+        for (&node, index_on) in &self.added {
+            if new.contains(&node) || !self.partial.contains(&node) || self.have[&node] != *index_on
+            {
+                continue;
+            }
+
+            let mut stack: Vec<_> = graph
+                .neighbors_directed(node, petgraph::EdgeDirection::Outgoing)
+                .collect();
+            while let Some(child) = stack.pop() {
+                if new.contains(&child) {
+                    // NOTE: no need to check its children either
+                    continue;
                 }
+
+                if self.added.get(&child).map(|i| i.len()).unwrap_or(0)
+                    != self.have.get(&child).map(|i| i.len()).unwrap_or(0)
+                {
+                    // node was previously materialized!
+                    crit!(
+                        self.log,
+                        "attempting to make old non-materialized node with children partial";
+                        "node" => node.index(),
+                        "child" => child.index(),
+                    );
+                    return Err(format!(
+                        "cannot make existing node {} partial, since its child {} is materialized",
+                        node.index(),
+                        child.index()
+                    ));
+                }
+
+                stack.extend(graph.neighbors_directed(child, petgraph::EdgeDirection::Outgoing));
             }
         }
 
@@ -595,7 +724,6 @@ impl Materializations {
                                                 .find(|c| !index.contains(&c))
                                         });
                                     if let Some(not_shared) = unshared {
-                                        println!("{}", graphviz(graph, true, &self));
                                         crit!(self.log, "partially overlapping partial indices";
                                                   "parent" => pni.index(),
                                                   "pcols" => ?index,
@@ -603,7 +731,14 @@ impl Materializations {
                                                   "cols" => ?columns,
                                                   "conflict" => not_shared,
                                         );
-                                        unimplemented!();
+                                        return Err(format!(
+                                            "partial index of node {} on {:?} partially overlaps \
+                                             partial index of its ancestor {} on {:?}",
+                                            ni.index(),
+                                            columns,
+                                            pni.index(),
+                                            index
+                                        ));
                                     }
                                 }
                             } else if self.have.contains_key(&ni) {
@@ -671,7 +806,13 @@ impl Materializations {
                                       "alias" => c,
                                       "shard" => col,
                             );
-                            unimplemented!();
+                            return Err(format!(
+                                "cannot merge shards of node {}, since it is sharded by column \
+                                 {}, which column {} aliases",
+                                parent.index(),
+                                col,
+                                c
+                            ));
                         }
                     }
                 }
@@ -696,10 +837,13 @@ impl Materializations {
                               "node" => ni.index());
                         continue;
                     }
-                    assert!(
-                        self.partial.contains(&pi),
-                        "attempting to place full materialization beyond materialization frontier"
-                    );
+                    if !self.partial.contains(&pi) {
+                        return Err(format!(
+                            "full materialization of node {} placed beyond materialization \
+                             frontier",
+                            pi.index()
+                        ));
+                    }
                     graph.node_weight_mut(pi).unwrap().purge = true;
                 }
             }
@@ -716,12 +860,10 @@ impl Materializations {
         }
         while let Some(ni) = non_purge.pop() {
             if graph[ni].purge {
-                println!("{}", graphviz(graph, true, &self));
-                assert!(
-                    !graph[ni].purge,
+                return Err(format!(
                     "found purge node {} above non-purge node",
                     ni.index()
-                );
+                ));
             }
             if self.have.contains_key(&ni) {
                 // already shceduled to be checked
@@ -732,8 +874,40 @@ impl Materializations {
                 non_purge.push(pi);
             }
         }
-        drop(non_purge);
+        Ok(())
+    }
 
+    /// Take back all materialization decisions since the last time `commit` was called, for a
+    /// migration that will not be committed.
+    pub(super) fn abandon(&mut self) {
+        for (ni, index) in self.planned.drain(..) {
+            if let Some(indices) = self.have.get_mut(&ni) {
+                indices.remove(&index);
+                if indices.is_empty() {
+                    self.have.remove(&ni);
+                }
+            }
+        }
+        for ni in self.planned_partial.drain(..) {
+            self.partial.remove(&ni);
+        }
+        self.added.clear();
+    }
+
+    /// Commit to all materialization decisions since the last time `commit` was called, which
+    /// `plan` must already have made.
+    ///
+    /// This includes setting up replay paths, adding new indices to existing materializations, and
+    /// populating new materializations.
+    #[allow(clippy::cognitive_complexity)]
+    pub(super) fn commit(
+        &mut self,
+        graph: &mut Graph,
+        new: &HashSet<NodeIndex>,
+        domains: &mut HashMap<DomainIndex, DomainHandle>,
+        workers: &HashMap<WorkerIdentifier, Worker>,
+        replies: &mut DomainReplies,
+    ) {
         let mut reindex = Vec::with_capacity(new.len());
         let mut make = Vec::with_capacity(new.len());
         let mut topo = petgraph::visit::Topo::new(&*graph);
@@ -756,44 +930,9 @@ impl Materializations {
         for node in reindex {
             let mut index_on = self.added.remove(&node).unwrap();
 
-            // are they trying to make a non-materialized node materialized?
+            // are they trying to make a non-materialized node materialized? `plan` has checked
+            // that doing so does not violate key monotonicity.
             if self.have[&node] == index_on {
-                if self.partial.contains(&node) {
-                    // we can't make this node partial if any of its children are materialized, as
-                    // we might stop forwarding updates to them, which would make them very sad.
-                    //
-                    // the exception to this is for new children, or old children that are now
-                    // becoming materialized; those are necessarily empty, and so we won't be
-                    // violating key monotonicity.
-                    let mut stack: Vec<_> = graph
-                        .neighbors_directed(node, petgraph::EdgeDirection::Outgoing)
-                        .collect();
-                    while let Some(child) = stack.pop() {
-                        if new.contains(&child) {
-                            // NOTE: no need to check its children either
-                            continue;
-                        }
-
-                        if self.added.get(&child).map(|i| i.len()).unwrap_or(0)
-                            != self.have.get(&child).map(|i| i.len()).unwrap_or(0)
-                        {
-                            // node was previously materialized!
-                            println!("{}", graphviz(graph, true, &self));
-                            crit!(
-                                self.log,
-                                "attempting to make old non-materialized node with children partial";
-                                "node" => node.index(),
-                                "child" => child.index(),
-                            );
-                            unimplemented!();
-                        }
-
-                        stack.extend(
-                            graph.neighbors_directed(child, petgraph::EdgeDirection::Outgoing),
-                        );
-                    }
-                }
-
                 warn!(self.log, "materializing existing non-materialized node";
                       "node" => node.index(),
                       "cols" => ?index_on);
@@ -861,6 +1000,30 @@ impl Materializations {
             futures_executor::block_on(replies.wait_for_acks(&domain));
             trace!(self.log, "node ready"; "node" => ni.index());
 
+            if let Some(seqs) = self
+                .restore
+                .as_ref()
+                .filter(|_| n.is_base())
+                .and_then(|snapshot| snapshot.base_seqs.get(n.name()))
+            {
+                // undo the writes the snapshot doesn't include. they are sent through the
+                // data-flow again once recovery has finished.
+                info!(self.log, "rewinding base table to snapshot"; "node" => ni.index());
+                for (shard, &seq) in seqs.iter().enumerate() {
+                    domain
+                        .send_to_healthy_shard(
+                            shard,
+                            Box::new(Packet::RestoreBase {
+                                node: n.local_addr(),
+                                seq,
+                            }),
+                            workers,
+                        )
+                        .unwrap();
+                }
+                futures_executor::block_on(replies.wait_for_acks(&domain));
+            }
+
             if reconstructed {
                 info!(self.log, "reconstruction completed";
                 "ms" => start.elapsed().as_millis(),
@@ -870,6 +1033,8 @@ impl Materializations {
         }
 
         self.added.clear();
+        self.planned.clear();
+        self.planned_partial.clear();
     }
 
     /// Perform all operations necessary to bring any materializations for the given node up, and
@@ -963,6 +1128,10 @@ impl Materializations {
             plan.finalize()
         };
 
+        if !pending.is_empty() && self.load_snapshot(ni, graph, domains, workers, replies) {
+            return;
+        }
+
         if !pending.is_empty() {
             trace!(self.log, "all domains ready for replay");
 
@@ -995,4 +1164,50 @@ impl Materializations {
             futures_executor::block_on(replies.wait_for_acks(&domains[&target]));
         }
     }
+
+    /// Fill the (empty) state of the given fully materialized node from the snapshot being
+    /// restored, if it has the state of every shard of the node. Returns whether it did.
+    ///
+    /// Readers aren't included in snapshots, and are instead rebuilt through replay from their
+    /// closest materialized ancestor.
+    fn load_snapshot(
+        &self,
+        ni: NodeIndex,
+        graph: &Graph,
+        domains: &mut HashMap<DomainIndex, DomainHandle>,
+        workers: &HashMap<WorkerIdentifier, Worker>,
+        replies: &mut DomainReplies,
+    ) -> bool {
+        let id = match self.restore {
+            Some(ref snapshot) => snapshot.id,
+            None => return false,
+        };
+        let n = &graph[ni];
+        if n.is_reader() || self.partial.contains(&ni) {
+            return false;
+        }
+
+        let domain = domains.get_mut(&n.domain()).unwrap();
+        for &probe in &[true, false] {
+            domain
+                .send_to_healthy(
+                    Box::new(Packet::LoadSnapshot {
+                        node: n.local_addr(),
+                        id,
+                        probe,
+                    }),
+                    workers,
+                )
+                .unwrap();
+            if !futures_executor::block_on(replies.wait_for_snapshot_loads(&domain)) {
+                // only probes can fail, so the state is still empty
+                assert!(probe);
+                info!(self.log, "snapshot lacks state, replaying"; "node" => ni.index());
+                return false;
+            }
+        }
+
+        info!(self.log, "loaded state from snapshot"; "node" => ni.index());
+        true
+    }
 }
@@ -21,10 +21,11 @@
 //! Beware, Here be dragons™
 
 use crate::controller::ControllerInner;
+use dataflow::node::special::Retention;
 use dataflow::prelude::*;
 use dataflow::{node, prelude::Packet};
-use std::collections::{HashMap, HashSet};
-use std::time::Instant;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
 use petgraph;
 use slog;
@@ -41,6 +42,33 @@ pub(super) enum ColumnChange {
     Drop(usize),
 }
 
+/// The nodes and base columns that a migration handed out to the code that drove it, in the order
+/// it handed them out.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(super) struct Allocated {
+    nodes: VecDeque<NodeIndex>,
+    columns: VecDeque<usize>,
+}
+
+impl Allocated {
+    pub(super) fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.columns.is_empty()
+    }
+
+    /// Hand out the given nodes, and no base columns.
+    pub(super) fn of_nodes<I: IntoIterator<Item = NodeIndex>>(nodes: I) -> Self {
+        Allocated {
+            nodes: nodes.into_iter().collect(),
+            columns: VecDeque::new(),
+        }
+    }
+
+    /// The nodes handed out, in order.
+    pub(super) fn nodes<'a>(&'a self) -> impl Iterator<Item = NodeIndex> + 'a {
+        self.nodes.iter().cloned()
+    }
+}
+
 /// A `Migration` encapsulates a number of changes to the Soup data flow graph.
 ///
 /// Only one `Migration` can be in effect at any point in time. No changes are made to the running
@@ -50,6 +78,8 @@ pub struct Migration<'a> {
     pub(super) mainline: &'a mut ControllerInner,
     pub(super) added: HashSet<NodeIndex>,
     pub(super) columns: Vec<(NodeIndex, ColumnChange)>,
+    /// The base nodes whose columns the migration changed, as they were before it changed them.
+    pub(super) unaltered: HashMap<NodeIndex, Node>,
     pub(super) readers: HashMap<NodeIndex, NodeIndex>,
 
     pub(super) start: Instant,
@@ -57,6 +87,15 @@ pub struct Migration<'a> {
 
     /// Additional migration information provided by the client
     pub(super) context: HashMap<String, DataType>,
+
+    /// The nodes and base columns handed out so far. When adopting, these are instead the ones
+    /// that the migration handed out when it was first performed, which are handed out again
+    /// without changing the graph.
+    pub(super) allocated: Allocated,
+    pub(super) adopting: bool,
+    /// How the migration being adopted asked for other nodes or columns than it handed out when
+    /// it was first performed, if it did.
+    pub(super) diverged: Option<String>,
 }
 
 impl<'a> Migration<'a> {
@@ -73,6 +112,11 @@ impl<'a> Migration<'a> {
         FS: IntoIterator<Item = S2>,
         I: Into<NodeOperator>,
     {
+        if self.adopting {
+            let fields: Vec<_> = fields.into_iter().map(|f| f.to_string()).collect();
+            return self.adopted_node(Some((&name.to_string(), &fields)));
+        }
+
         let mut i = node::Node::new(name.to_string(), fields, i.into());
         i.on_connected(&self.mainline.ingredients);
         let parents = i.ancestors();
@@ -93,6 +137,7 @@ impl<'a> Migration<'a> {
             self.mainline.ingredients.add_edge(parent, ni, ());
         }
         // and tell the caller its id
+        self.allocated.nodes.push_back(ni);
         ni
     }
 
@@ -111,6 +156,11 @@ impl<'a> Migration<'a> {
         S2: ToString,
         FS: IntoIterator<Item = S2>,
     {
+        if self.adopting {
+            let fields: Vec<_> = fields.into_iter().map(|f| f.to_string()).collect();
+            return self.adopted_node(Some((&name.to_string(), &fields)));
+        }
+
         // add to the graph
         let ni = self
             .mainline
@@ -128,9 +178,109 @@ impl<'a> Migration<'a> {
             .ingredients
             .add_edge(self.mainline.source, ni, ());
         // and tell the caller its id
+        self.allocated.nodes.push_back(ni);
+        ni
+    }
+
+    /// The next node that the migration being adopted handed out, which should have the given
+    /// name and fields if given.
+    ///
+    /// Nothing looks at the graph while a migration is adopted, so if it asks for more nodes than
+    /// it handed out, or for different ones, that is noted, and a node that isn't in the graph is
+    /// handed out instead.
+    fn adopted_node(&mut self, expected: Option<(&str, &[String])>) -> NodeIndex {
+        let ni = match self.allocated.nodes.pop_front() {
+            Some(ni) => ni,
+            None => {
+                self.diverge(|| "adopted migration added more nodes than it did originally".into());
+                return NodeIndex::end();
+            }
+        };
+        if let Some((name, fields)) = expected {
+            let why = match self.mainline.ingredients.node_weight(ni) {
+                Some(n) if n.name() == name && n.fields() == fields => None,
+                Some(n) => Some(format!(
+                    "adopted migration added node {} where it originally added {}",
+                    name,
+                    n.name()
+                )),
+                None => Some(format!("adopted migration added missing node {}", name)),
+            };
+            if let Some(why) = why {
+                self.diverge(|| why);
+            }
+        }
         ni
     }
 
+    /// Note how the migration being adopted differs from how it was first performed, unless it
+    /// already did.
+    fn diverge<F: FnOnce() -> String>(&mut self, why: F) {
+        if self.diverged.is_none() {
+            self.diverged = Some(why());
+        }
+    }
+
+    /// Tune the RocksDB instance of the given new base by applying the given options on top of
+    /// the deployment's.
+    ///
+    /// Bases that already existed keep the options they were created with.
+    pub(super) fn set_rocksdb_options(
+        &mut self,
+        base: NodeIndex,
+        options: &[(String, String)],
+    ) -> Result<(), String> {
+        if self.adopting {
+            return Ok(());
+        }
+
+        let mut rocksdb = self.mainline.persistence.rocksdb.clone();
+        for (option, value) in options {
+            rocksdb.set(option, value)?;
+        }
+
+        if !self.added.contains(&base) {
+            warn!(self.log, "not changing RocksDB options of existing base"; "node" => base.index());
+            return Ok(());
+        }
+        self.mainline.ingredients[base]
+            .get_base_mut()
+            .expect("tried to set RocksDB options of non-base node")
+            .set_rocksdb_options(rocksdb);
+        Ok(())
+    }
+
+    /// Expire the rows of the given new base once the given time has passed since the time in
+    /// the given column.
+    ///
+    /// Bases that already existed keep the retention they were created with.
+    pub(super) fn set_retention(
+        &mut self,
+        base: NodeIndex,
+        column: &str,
+        ttl: Duration,
+    ) -> Result<(), String> {
+        if self.adopting {
+            return Ok(());
+        }
+
+        let column = self.mainline.ingredients[base]
+            .fields()
+            .iter()
+            .position(|f| f == column)
+            .ok_or_else(|| format!("no column \"{}\" to expire rows by", column))?;
+
+        if !self.added.contains(&base) {
+            warn!(self.log, "not changing retention of existing base"; "node" => base.index());
+            return Ok(());
+        }
+        self.mainline.ingredients[base]
+            .get_base_mut()
+            .expect("tried to set retention of non-base node")
+            .set_retention(Retention { column, ttl });
+        Ok(())
+    }
+
     /// Mark the given node as being beyond the materialization frontier.
     ///
     /// When a node is marked as such, it will quickly evict state after it is no longer
@@ -185,12 +335,25 @@ impl<'a> Migration<'a> {
         field: S,
         default: DataType,
     ) -> usize {
+        if self.adopting {
+            return match self.allocated.columns.pop_front() {
+                Some(column) => column,
+                None => {
+                    self.diverge(|| {
+                        "adopted migration added more columns than it did originally".into()
+                    });
+                    0
+                }
+            };
+        }
+
         // not allowed to add columns to new nodes
         assert!(!self.added.contains(&node));
 
         let field = field.to_string();
         let base = &mut self.mainline.ingredients[node];
         assert!(base.is_base());
+        self.unaltered.entry(node).or_insert_with(|| base.clone());
 
         // we need to tell the base about its new column and its default, so that old writes that
         // do not have it get the additional value added to them.
@@ -204,17 +367,23 @@ impl<'a> Migration<'a> {
         // also eventually propagate to domain clone
         self.columns.push((node, ColumnChange::Add(field, default)));
 
+        self.allocated.columns.push_back(col_i1);
         col_i1
     }
 
     /// Drop a column from a base node.
     // crate viz for tests
     pub fn drop_column(&mut self, node: NodeIndex, column: usize) {
+        if self.adopting {
+            return;
+        }
+
         // not allowed to drop columns from new nodes
         assert!(!self.added.contains(&node));
 
         let base = &mut self.mainline.ingredients[node];
         assert!(base.is_base());
+        self.unaltered.entry(node).or_insert_with(|| base.clone());
 
         // we need to tell the base about the dropped column, so that old writes that contain that
         // column will have it filled in with default values (this is done in Mutator).
@@ -254,6 +423,10 @@ impl<'a> Migration<'a> {
     ///
     /// To query into the maintained state, use `ControllerInner::get_getter`.
     pub fn maintain_anonymous(&mut self, n: NodeIndex, key: &[usize]) -> NodeIndex {
+        if self.adopting {
+            return self.adopted_node(None);
+        }
+
         self.ensure_reader_for(n, None);
         let ri = self.readers[&n];
 
@@ -261,6 +434,7 @@ impl<'a> Migration<'a> {
             .with_reader_mut(|r| r.set_key(key))
             .unwrap();
 
+        self.allocated.nodes.push_back(ri);
         ri
     }
 
@@ -268,6 +442,10 @@ impl<'a> Migration<'a> {
     ///
     /// To query into the maintained state, use `ControllerInner::get_getter`.
     pub fn maintain(&mut self, name: String, n: NodeIndex, key: &[usize]) {
+        if self.adopting {
+            return;
+        }
+
         self.ensure_reader_for(n, Some(name));
 
         let ri = self.readers[&n];
@@ -277,92 +455,83 @@ impl<'a> Migration<'a> {
             .unwrap();
     }
 
+    /// Add another reader that serves the same reads as the given reader, in a domain of its own.
+    pub(super) fn add_read_replica(&mut self, reader: NodeIndex) -> NodeIndex {
+        let (n, key) = self.mainline.ingredients[reader]
+            .with_reader(|r| (r.is_for(), r.key().map(Vec::from)))
+            .unwrap();
+        let name = self.mainline.ingredients[reader].name().to_owned();
+
+        let mut r = node::special::Reader::replica(n, reader);
+        if let Some(key) = key {
+            r.set_key(&key);
+        }
+        let mut r = self.mainline.ingredients[n].named_mirror(r, name);
+        r.purge = self.mainline.ingredients[reader].purge;
+        let r = self.mainline.ingredients.add_node(r);
+        self.mainline.ingredients.add_edge(n, r, ());
+        self.added.insert(r);
+        r
+    }
+
+    /// Plan the changes introduced by this `Migration` the way `commit` would, by sharding the new
+    /// nodes, assigning them to domains, routing between domains, and deciding on their
+    /// materializations. Unlike `commit`, this never touches any running domain.
+    ///
+    /// Returns all nodes the migration would add, including the ones added for sharding and
+    /// routing, or an error if the migration is invalid.
+    pub(super) fn plan(self) -> Result<HashSet<NodeIndex>, String> {
+        info!(self.log, "planning migration"; "#nodes" => self.added.len());
+
+        let log = self.log;
+        let mainline = self.mainline;
+        let mut new = self.added;
+
+        prepare(&log, mainline, &mut new)?;
+        mainline
+            .materializations
+            .plan(&mut mainline.ingredients, &new)?;
+        Ok(new)
+    }
+
+    /// Undo the changes introduced by this `Migration`, none of which have reached any domain.
+    pub(super) fn abandon(self) {
+        info!(self.log, "abandoning migration"; "#nodes" => self.added.len());
+
+        let ndomains = self.mainline.ndomains;
+        abandon(self.mainline, &self.added, self.unaltered, ndomains);
+    }
+
     /// Commit the changes introduced by this `Migration` to the master `Soup`.
     ///
     /// This will spin up an execution thread for each new thread domain, and hook those new
     /// domains into the larger Soup graph. The returned map contains entry points through which
     /// new updates should be sent to introduce them into the Soup.
+    ///
+    /// The whole migration is planned and checked before any domain is told about it. If it is
+    /// invalid, it is abandoned, and an error is returned.
     #[allow(clippy::cognitive_complexity)]
-    pub(super) fn commit(self) {
+    pub(super) fn commit(self) -> Result<(), String> {
         info!(self.log, "finalizing migration"; "#nodes" => self.added.len());
 
         let log = self.log;
         let start = self.start;
         let mut mainline = self.mainline;
         let mut new = self.added;
-        let mut topo = mainline.topo_order(&new);
-
-        // Shard the graph as desired
-        let mut swapped0 = if let Some(shards) = mainline.sharding {
-            let (t, swapped) =
-                sharding::shard(&log, &mut mainline.ingredients, &mut new, &topo, shards);
-            topo = t;
-
-            swapped
-        } else {
-            HashMap::default()
-        };
-
-        // Assign domains
-        assignment::assign(
-            &log,
-            &mut mainline.ingredients,
-            &topo,
-            &mut mainline.ndomains,
-        );
-
-        // Set up ingress and egress nodes
-        let swapped1 = routing::add(
-            &log,
-            &mut mainline.ingredients,
-            mainline.source,
-            &mut new,
-            &topo,
-        );
-        topo = mainline.topo_order(&new);
-
-        // Merge the swap lists
-        for ((dst, src), instead) in swapped1 {
-            use std::collections::hash_map::Entry;
-            match swapped0.entry((dst, src)) {
-                Entry::Occupied(mut instead0) => {
-                    if &instead != instead0.get() {
-                        // This can happen if sharding decides to add a Sharder *under* a node,
-                        // and routing decides to add an ingress/egress pair between that node
-                        // and the Sharder. It's perfectly okay, but we should prefer the
-                        // "bottommost" swap to take place (i.e., the node that is *now*
-                        // closest to the dst node). This *should* be the sharding node, unless
-                        // routing added an ingress *under* the Sharder. We resolve the
-                        // collision by looking at which translation currently has an adge from
-                        // `src`, and then picking the *other*, since that must then be node
-                        // below.
-                        if mainline.ingredients.find_edge(src, instead).is_some() {
-                            // src -> instead -> instead0 -> [children]
-                            // from [children]'s perspective, we should use instead0 for from, so
-                            // we can just ignore the `instead` swap.
-                        } else {
-                            // src -> instead0 -> instead -> [children]
-                            // from [children]'s perspective, we should use instead for src, so we
-                            // need to prefer the `instead` swap.
-                            *instead0.get_mut() = instead;
-                        }
-                    }
-                }
-                Entry::Vacant(hole) => {
-                    hole.insert(instead);
-                }
-            }
-
-            // we may also already have swapped the parents of some node *to* `src`. in
-            // swapped0. we want to change that mapping as well, since lookups in swapped
-            // aren't recursive.
-            for (_, instead0) in swapped0.iter_mut() {
-                if *instead0 == src {
-                    *instead0 = instead;
-                }
-            }
+        let ndomains = mainline.ndomains;
+        let planned = prepare(&log, mainline, &mut new)
+            .and_then(|()| {
+                mainline
+                    .materializations
+                    .plan(&mut mainline.ingredients, &new)
+            })
+            .and_then(|()| mainline.check_placement(&new));
+        if let Err(e) = planned {
+            crit!(log, "abandoning invalid migration: {}", e);
+            abandon(mainline, &new, self.unaltered, ndomains);
+            return Err(e);
         }
-        let swapped = swapped0;
+
         let mut sorted_new = new.iter().collect::<Vec<_>>();
         sorted_new.sort();
 
@@ -373,83 +542,6 @@ impl<'a> Migration<'a> {
             .map(|&&ni| mainline.ingredients[ni].domain())
             .collect();
 
-        let mut domain_new_nodes = sorted_new
-            .iter()
-            .filter(|&&&ni| ni != mainline.source)
-            .filter(|&&&ni| !mainline.ingredients[ni].is_dropped())
-            .map(|&&ni| (mainline.ingredients[ni].domain(), ni))
-            .fold(HashMap::new(), |mut dns, (d, ni)| {
-                dns.entry(d).or_insert_with(Vec::new).push(ni);
-                dns
-            });
-
-        // Assign local addresses to all new nodes, and initialize them
-        for (domain, nodes) in &mut domain_new_nodes {
-            // Number of pre-existing nodes
-            let mut nnodes = mainline.remap.get(domain).map(HashMap::len).unwrap_or(0);
-
-            if nodes.is_empty() {
-                // Nothing to do here
-                continue;
-            }
-
-            let log = log.new(o!("domain" => domain.index()));
-
-            // Give local addresses to every (new) node
-            for &ni in nodes.iter() {
-                debug!(log,
-                       "assigning local index";
-                       "type" => format!("{:?}", mainline.ingredients[ni]),
-                       "node" => ni.index(),
-                       "local" => nnodes
-                );
-
-                let mut ip: IndexPair = ni.into();
-                ip.set_local(unsafe { LocalNodeIndex::make(nnodes as u32) });
-                mainline.ingredients[ni].set_finalized_addr(ip);
-                mainline
-                    .remap
-                    .entry(*domain)
-                    .or_insert_with(HashMap::new)
-                    .insert(ni, ip);
-                nnodes += 1;
-            }
-
-            // Initialize each new node
-            for &ni in nodes.iter() {
-                if mainline.ingredients[ni].is_internal() {
-                    // Figure out all the remappings that have happened
-                    // NOTE: this has to be *per node*, since a shared parent may be remapped
-                    // differently to different children (due to sharding for example). we just
-                    // allocate it once though.
-                    let mut remap = mainline.remap[domain].clone();
-
-                    // Parents in other domains have been swapped for ingress nodes.
-                    // Those ingress nodes' indices are now local.
-                    for (&(dst, src), &instead) in &swapped {
-                        if dst != ni {
-                            // ignore mappings for other nodes
-                            continue;
-                        }
-
-                        let old = remap.insert(src, mainline.remap[domain][&instead]);
-                        assert_eq!(old, None);
-                    }
-
-                    trace!(log, "initializing new node"; "node" => ni.index());
-                    mainline
-                        .ingredients
-                        .node_weight_mut(ni)
-                        .unwrap()
-                        .on_commit(&remap);
-                }
-            }
-        }
-
-        if let Some(shards) = mainline.sharding {
-            sharding::validate(&log, &mainline.ingredients, &topo, shards)
-        };
-
         // at this point, we've hooked up the graph such that, for any given domain, the graph
         // looks like this:
         //
@@ -508,7 +600,7 @@ impl<'a> Migration<'a> {
                 mainline.ingredients[nodes[0].0].sharded_by().shards(),
                 &log,
                 nodes,
-            );
+            )?;
             mainline.domains.insert(domain, d);
         }
 
@@ -582,5 +674,208 @@ impl<'a> Migration<'a> {
         );
 
         warn!(log, "migration completed"; "ms" => start.elapsed().as_millis());
+        Ok(())
+    }
+}
+
+/// Take the `new` nodes back out of the graph, along with the domains, local addresses, and
+/// materializations that were planned for them, and restore the `unaltered` base nodes. None of
+/// this may have reached any domain yet.
+///
+/// The nodes cannot be removed from the graph without changing the indices of other nodes, so
+/// they are instead disconnected and marked as dropped.
+fn abandon(
+    mainline: &mut ControllerInner,
+    new: &HashSet<NodeIndex>,
+    unaltered: HashMap<NodeIndex, Node>,
+    ndomains: usize,
+) {
+    for &ni in new {
+        if ni == mainline.source {
+            continue;
+        }
+        while let Some(e) = mainline
+            .ingredients
+            .first_edge(ni, petgraph::EdgeDirection::Incoming)
+        {
+            mainline.ingredients.remove_edge(e);
+        }
+        while let Some(e) = mainline
+            .ingredients
+            .first_edge(ni, petgraph::EdgeDirection::Outgoing)
+        {
+            mainline.ingredients.remove_edge(e);
+        }
+        mainline.ingredients[ni].remove();
+    }
+    for (ni, base) in unaltered {
+        mainline.ingredients[ni] = base;
+    }
+
+    for remap in mainline.remap.values_mut() {
+        remap.retain(|ni, _| !new.contains(ni));
+    }
+    mainline
+        .remap
+        .retain(|di, remap| di.index() < ndomains || !remap.is_empty());
+    mainline.ndomains = ndomains;
+
+    mainline.materializations.abandon();
+}
+
+/// Hooks the `new` nodes into the graph: shards them, assigns them to domains, adds ingress and
+/// egress nodes between domains, and gives every new node its local address.
+///
+/// Returns an error if the sharding it arrives at is inconsistent.
+fn prepare(
+    log: &slog::Logger,
+    mainline: &mut ControllerInner,
+    new: &mut HashSet<NodeIndex>,
+) -> Result<(), String> {
+    let mut topo = mainline.topo_order(new);
+
+    // Shard the graph as desired
+    let mut swapped0 = if let Some(shards) = mainline.sharding {
+        let (t, swapped) = sharding::shard(log, &mut mainline.ingredients, new, &topo, shards)?;
+        topo = t;
+
+        swapped
+    } else {
+        HashMap::default()
+    };
+
+    // Assign domains
+    assignment::assign(
+        log,
+        &mut mainline.ingredients,
+        &topo,
+        &mut mainline.ndomains,
+    );
+
+    // Set up ingress and egress nodes
+    let swapped1 = routing::add(log, &mut mainline.ingredients, mainline.source, new, &topo);
+    topo = mainline.topo_order(new);
+
+    // Merge the swap lists
+    for ((dst, src), instead) in swapped1 {
+        use std::collections::hash_map::Entry;
+        match swapped0.entry((dst, src)) {
+            Entry::Occupied(mut instead0) => {
+                if &instead != instead0.get() {
+                    // This can happen if sharding decides to add a Sharder *under* a node,
+                    // and routing decides to add an ingress/egress pair between that node
+                    // and the Sharder. It's perfectly okay, but we should prefer the
+                    // "bottommost" swap to take place (i.e., the node that is *now*
+                    // closest to the dst node). This *should* be the sharding node, unless
+                    // routing added an ingress *under* the Sharder. We resolve the
+                    // collision by looking at which translation currently has an adge from
+                    // `src`, and then picking the *other*, since that must then be node
+                    // below.
+                    if mainline.ingredients.find_edge(src, instead).is_some() {
+                        // src -> instead -> instead0 -> [children]
+                        // from [children]'s perspective, we should use instead0 for from, so
+                        // we can just ignore the `instead` swap.
+                    } else {
+                        // src -> instead0 -> instead -> [children]
+                        // from [children]'s perspective, we should use instead for src, so we
+                        // need to prefer the `instead` swap.
+                        *instead0.get_mut() = instead;
+                    }
+                }
+            }
+            Entry::Vacant(hole) => {
+                hole.insert(instead);
+            }
+        }
+
+        // we may also already have swapped the parents of some node *to* `src`. in
+        // swapped0. we want to change that mapping as well, since lookups in swapped
+        // aren't recursive.
+        for (_, instead0) in swapped0.iter_mut() {
+            if *instead0 == src {
+                *instead0 = instead;
+            }
+        }
+    }
+    let swapped = swapped0;
+    let mut sorted_new = new.iter().collect::<Vec<_>>();
+    sorted_new.sort();
+
+    let mut domain_new_nodes = sorted_new
+        .iter()
+        .filter(|&&&ni| ni != mainline.source)
+        .filter(|&&&ni| !mainline.ingredients[ni].is_dropped())
+        .map(|&&ni| (mainline.ingredients[ni].domain(), ni))
+        .fold(HashMap::new(), |mut dns, (d, ni)| {
+            dns.entry(d).or_insert_with(Vec::new).push(ni);
+            dns
+        });
+
+    // Assign local addresses to all new nodes, and initialize them
+    for (domain, nodes) in &mut domain_new_nodes {
+        // Number of pre-existing nodes
+        let mut nnodes = mainline.remap.get(domain).map(HashMap::len).unwrap_or(0);
+
+        if nodes.is_empty() {
+            // Nothing to do here
+            continue;
+        }
+
+        let log = log.new(o!("domain" => domain.index()));
+
+        // Give local addresses to every (new) node
+        for &ni in nodes.iter() {
+            debug!(log,
+                   "assigning local index";
+                   "type" => format!("{:?}", mainline.ingredients[ni]),
+                   "node" => ni.index(),
+                   "local" => nnodes
+            );
+
+            let mut ip: IndexPair = ni.into();
+            ip.set_local(unsafe { LocalNodeIndex::make(nnodes as u32) });
+            mainline.ingredients[ni].set_finalized_addr(ip);
+            mainline
+                .remap
+                .entry(*domain)
+                .or_insert_with(HashMap::new)
+                .insert(ni, ip);
+            nnodes += 1;
+        }
+
+        // Initialize each new node
+        for &ni in nodes.iter() {
+            if mainline.ingredients[ni].is_internal() {
+                // Figure out all the remappings that have happened
+                // NOTE: this has to be *per node*, since a shared parent may be remapped
+                // differently to different children (due to sharding for example). we just
+                // allocate it once though.
+                let mut remap = mainline.remap[domain].clone();
+
+                // Parents in other domains have been swapped for ingress nodes.
+                // Those ingress nodes' indices are now local.
+                for (&(dst, src), &instead) in &swapped {
+                    if dst != ni {
+                        // ignore mappings for other nodes
+                        continue;
+                    }
+
+                    let old = remap.insert(src, mainline.remap[domain][&instead]);
+                    assert_eq!(old, None);
+                }
+
+                trace!(log, "initializing new node"; "node" => ni.index());
+                mainline
+                    .ingredients
+                    .node_weight_mut(ni)
+                    .unwrap()
+                    .on_commit(&remap);
+            }
+        }
+    }
+
+    if let Some(shards) = mainline.sharding {
+        sharding::validate(log, &mainline.ingredients, &topo, shards)?;
     }
+    Ok(())
 }
@@ -13,7 +13,7 @@ pub fn shard(
     new: &mut HashSet<NodeIndex>,
     topo_list: &[NodeIndex],
     sharding_factor: usize,
-) -> (Vec<NodeIndex>, HashMap<(NodeIndex, NodeIndex), NodeIndex>) {
+) -> Result<(Vec<NodeIndex>, HashMap<(NodeIndex, NodeIndex), NodeIndex>), String> {
     // we must keep track of changes we make to the parent of a node, since this remapping must be
     // communicated to the nodes so they know the true identifier of their parent in the graph.
     let mut swaps = HashMap::new();
@@ -618,9 +618,9 @@ pub fn shard(
         }
         topo_list.push(node);
     }
-    validate(log, graph, &topo_list, sharding_factor);
+    validate(log, graph, &topo_list, sharding_factor)?;
 
-    (topo_list, swaps)
+    Ok((topo_list, swaps))
 }
 
 /// Modify the graph such that the path between `src` and `dst` shuffles the input such that the
@@ -686,7 +686,13 @@ fn reshard(
     );
 }
 
-pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], sharding_factor: usize) {
+/// Check that every node in `topo_list` is sharded the way its ancestors' outputs are.
+pub fn validate(
+    log: &Logger,
+    graph: &Graph,
+    topo_list: &[NodeIndex],
+    sharding_factor: usize,
+) -> Result<(), String> {
     // ensure that each node matches the sharding of each of its ancestors, unless the ancestor is
     // a sharder or a shard merger
     for &node in topo_list {
@@ -746,24 +752,32 @@ pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], sharding_f
             let in_node = &graph[in_ni];
             if in_node.is_sharder() {
                 // ancestor is a sharder, so its output sharding must match ours
-                in_node.with_sharder(|s| {
-                    let in_sharding = remap(
-                        n,
-                        in_ni,
-                        Sharding::ByColumn(s.sharded_by(), sharding_factor),
+                let in_sharding = in_node
+                    .with_sharder(|s| {
+                        remap(
+                            n,
+                            in_ni,
+                            Sharding::ByColumn(s.sharded_by(), sharding_factor),
+                        )
+                    })
+                    .unwrap();
+                if in_sharding != n.sharded_by() {
+                    crit!(
+                        log,
+                        "invalid sharding: {} shards to {:?} != {}'s {:?}",
+                        in_ni.index(),
+                        in_sharding,
+                        node.index(),
+                        n.sharded_by(),
                     );
-                    if in_sharding != n.sharded_by() {
-                        crit!(
-                            log,
-                            "invalid sharding: {} shards to {:?} != {}'s {:?}",
-                            in_ni.index(),
-                            in_sharding,
-                            node.index(),
-                            n.sharded_by(),
-                        );
-                    }
-                    assert_eq!(in_sharding, n.sharded_by());
-                });
+                    return Err(format!(
+                        "invalid sharding: {} shards to {:?}, but {} is sharded by {:?}",
+                        in_ni.index(),
+                        in_sharding,
+                        node.index(),
+                        n.sharded_by(),
+                    ));
+                }
             } else {
                 // ancestor is an ordinary node, so it must have the same sharding
                 let in_sharding = remap(n, in_ni, in_node.sharded_by());
@@ -787,9 +801,16 @@ pub fn validate(log: &Logger, graph: &Graph, topo_list: &[NodeIndex], sharding_f
                         node.index(),
                         graph[node].sharded_by(),
                     );
+                    return Err(format!(
+                        "invalid sharding: {} is sharded by {:?}, but {} is sharded by {:?}",
+                        in_ni.index(),
+                        in_sharding,
+                        node.index(),
+                        out_sharding,
+                    ));
                 }
-                assert!(equal);
             }
         }
     }
+    Ok(())
 }
@@ -1,4 +1,5 @@
 use crate::controller::inner::ControllerInner;
+use crate::controller::metadata::Metadata;
 use crate::controller::migrate::Migration;
 use crate::controller::recipe::Recipe;
 use crate::coordination::CoordinationMessage;
@@ -7,6 +8,7 @@ use crate::startup::Event;
 use crate::Config;
 use async_bincode::AsyncBincodeReader;
 use dataflow::payload::ControlReplyPacket;
+use dataflow::prelude::NodeIndex;
 use futures_util::{
     future::FutureExt,
     future::TryFutureExt,
@@ -17,6 +19,8 @@ use hyper::{self, StatusCode};
 use noria::channel::TcpSender;
 use noria::consensus::{Authority, Epoch, STATE_KEY};
 use noria::ControllerDescriptor;
+use noria::DataType;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
@@ -24,11 +28,14 @@ use std::time;
 use stream_cancel::Valve;
 use tokio::sync::mpsc::UnboundedSender;
 
+pub(crate) mod backup;
 mod domain_handle;
 mod inner;
 mod keys;
+mod metadata;
 pub(crate) mod migrate; // crate viz for tests
 mod mir_to_flow;
+pub(crate) mod placement;
 pub(crate) mod recipe; // crate viz for tests
 mod schema;
 mod security;
@@ -41,22 +48,110 @@ pub(crate) struct ControllerState {
 
     recipe_version: usize,
     recipes: Vec<String>,
+    /// The base table statistics that each of `recipes` was planned with, so that recovery plans
+    /// them the same way.
+    #[serde(default)]
+    recipe_statistics: Vec<sql::TableStatistics>,
+    /// Memory budgets set through `ControllerHandle::set_memory_budget`, by query name.
+    #[serde(default)]
+    memory_budgets: HashMap<String, usize>,
+    /// The number of readers set through `ControllerHandle::set_read_replicas`, by query name.
+    #[serde(default)]
+    read_replicas: HashMap<String, usize>,
+    /// The latest complete snapshot of fully materialized state, which recovery starts from.
+    #[serde(default)]
+    snapshot: Option<Snapshot>,
+    /// The security configuration set through `set_security_config`.
+    #[serde(default)]
+    security_config: Option<String>,
+    /// The contexts of all universes created through `create_universe`, in order.
+    #[serde(default)]
+    universes: Vec<HashMap<String, DataType>>,
+}
+
+/// A consistent snapshot of all fully materialized state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) id: u64,
+    /// The sequence number of the last change to each shard of each base table, by name, that the
+    /// snapshot includes. Recovery undoes later changes before loading the snapshot, and then
+    /// sends them through the data-flow again.
+    pub(crate) base_seqs: HashMap<String, Vec<u64>>,
 }
 
 struct Worker {
     healthy: bool,
+    /// Whether the worker's domain shards are being moved elsewhere, so that it can leave. No new
+    /// shards are placed on a draining worker.
+    draining: bool,
+    /// The address the worker listens on for the controller. Unlike the `WorkerIdentifier`, this
+    /// stays the same when another controller takes over.
+    addr: SocketAddr,
     last_heartbeat: time::Instant,
     sender: TcpSender<CoordinationMessage>,
+    /// The number of cores on the worker's machine.
+    cpus: usize,
+    /// The labels that placement constraints can require of the worker.
+    labels: Vec<String>,
+    /// The size, in bytes, of the state of the worker's domains, as of its last heartbeat.
+    state_size: usize,
+    /// The number of read requests each of the worker's reader shards had served as of its last
+    /// heartbeat.
+    reads: HashMap<(NodeIndex, usize), usize>,
+    /// The read requests per second that each of the worker's reader shards served between its
+    /// last two heartbeats.
+    read_rates: HashMap<(NodeIndex, usize), f64>,
 }
 
 impl Worker {
-    fn new(sender: TcpSender<CoordinationMessage>) -> Self {
+    fn new(
+        addr: SocketAddr,
+        sender: TcpSender<CoordinationMessage>,
+        cpus: usize,
+        labels: Vec<String>,
+    ) -> Self {
         Worker {
             healthy: true,
+            draining: false,
+            addr,
             last_heartbeat: time::Instant::now(),
             sender,
+            cpus,
+            labels,
+            state_size: 0,
+            reads: HashMap::new(),
+            read_rates: HashMap::new(),
         }
     }
+
+    /// Take note of a heartbeat from the worker.
+    fn heartbeat(&mut self, state_size: usize, reads: HashMap<(NodeIndex, usize), usize>) {
+        let elapsed = self.last_heartbeat.elapsed().as_secs_f64();
+        self.last_heartbeat = time::Instant::now();
+        self.state_size = state_size;
+
+        let last = &self.reads;
+        self.read_rates = reads
+            .iter()
+            .map(|(target, &n)| {
+                let since = match last.get(target) {
+                    Some(&m) if m <= n => n - m,
+                    // the shard was started anew, and counted from zero again
+                    Some(_) => n,
+                    // the shard is new to us, so there is nothing to compare with until the next
+                    // heartbeat
+                    None => 0,
+                };
+                let rate = if elapsed > 0.0 {
+                    since as f64 / elapsed
+                } else {
+                    0.0
+                };
+                (*target, rate)
+            })
+            .collect();
+        self.reads = reads;
+    }
 }
 
 type WorkerIdentifier = SocketAddr;
@@ -71,6 +166,7 @@ pub(super) async fn main<A: Authority + 'static>(
     log: slog::Logger,
     authority: Arc<A>,
     tx: tokio::sync::mpsc::UnboundedSender<Event>,
+    restore: Option<backup::Manifest>,
 ) {
     let (dtx, drx) = tokio::sync::mpsc::unbounded_channel();
 
@@ -84,7 +180,7 @@ pub(super) async fn main<A: Authority + 'static>(
 
     // note that we do not start up the data-flow until we find a controller!
 
-    let campaign = instance_campaign(tx.clone(), authority.clone(), descriptor, config);
+    let campaign = instance_campaign(tx.clone(), authority.clone(), descriptor, config, restore);
 
     // state that this instance will take if it becomes the controller
     let mut campaign = Some(campaign);
@@ -95,25 +191,31 @@ pub(super) async fn main<A: Authority + 'static>(
         match e {
             Event::InternalMessage(msg) => match msg.payload {
                 CoordinationPayload::Deregister => {
-                    unimplemented!();
+                    if let Some(ref mut ctrl) = controller {
+                        tokio::task::block_in_place(|| ctrl.handle_deregister(msg));
+                    }
                 }
                 CoordinationPayload::CreateUniverse(universe) => {
                     if let Some(ref mut ctrl) = controller {
-                        tokio::task::block_in_place(|| ctrl.create_universe(universe).unwrap());
+                        tokio::task::block_in_place(|| {
+                            ctrl.create_universe(&authority, universe).unwrap()
+                        });
                     }
                 }
                 CoordinationPayload::Register { .. } => {
                     if let Some(ref mut ctrl) = controller {
                         tokio::task::block_in_place(|| {
-                            if let Err(e) = ctrl.handle_register(msg) {
+                            if let Err(e) = ctrl.handle_register(msg, &authority) {
                                 warn!(log, "worker registered and then immediately left: {:?}", e);
                             }
                         });
                     }
                 }
-                CoordinationPayload::Heartbeat => {
+                CoordinationPayload::Heartbeat { .. } => {
                     if let Some(ref mut ctrl) = controller {
-                        tokio::task::block_in_place(|| ctrl.handle_heartbeat(msg).unwrap());
+                        tokio::task::block_in_place(|| {
+                            ctrl.handle_heartbeat(msg, &authority).unwrap()
+                        });
                     }
                 }
                 _ => unreachable!(),
@@ -122,7 +224,17 @@ pub(super) async fn main<A: Authority + 'static>(
                 if let Some(ref mut ctrl) = controller {
                     let authority = &authority;
                     let reply = tokio::task::block_in_place(|| {
-                        ctrl.external_request(method, path, query, body, &authority)
+                        let reply = ctrl.external_request(method, path, query, body, &authority);
+                        // a controller that takes over must know about whatever the request
+                        // changed before the change is acknowledged
+                        match (reply, ctrl.persist_metadata(authority)) {
+                            (Ok(Ok(_)), Err(e)) => Ok(Err(format!(
+                                "the change was made, but a standby controller can't take it \
+                                 over: {}",
+                                e
+                            ))),
+                            (reply, _) => reply,
+                        }
                     });
 
                     if reply_tx.send(reply).is_err() {
@@ -136,8 +248,16 @@ pub(super) async fn main<A: Authority + 'static>(
                 if let Some(ref mut ctrl) = controller {
                     if !ctrl.workers.is_empty() {
                         tokio::task::block_in_place(|| {
-                            ctrl.migrate(move |m| f(m));
-                            done.send(()).unwrap();
+                            match ctrl
+                                .migrate(move |m| f(m))
+                                .and_then(|_| ctrl.persist_metadata(&authority))
+                            {
+                                Ok(()) => done.send(()).unwrap(),
+                                Err(e) => {
+                                    // dropping `done` tells the migration's caller that it failed
+                                    crit!(log, "manual migration failed: {}", e);
+                                }
+                            }
                         });
                     }
                 } else {
@@ -145,6 +265,37 @@ pub(super) async fn main<A: Authority + 'static>(
                 }
             }
             #[cfg(test)]
+            Event::StopController => {
+                if let Some(mut ctrl) = controller.take() {
+                    info!(
+                        log,
+                        "stopping controller, and leaving the data-flow running"
+                    );
+                    if let Err(e) =
+                        tokio::task::block_in_place(|| ctrl.persist_metadata(&authority))
+                    {
+                        warn!(log, "failed to persist controller metadata: {}", e);
+                    }
+                    // the domains keep running, for the controller that takes over to adopt
+                    ctrl.domains.clear();
+                    drop(ctrl);
+                    if let Err(e) = authority.surrender_leadership() {
+                        error!(log, "failed to surrender leadership");
+                        eprintln!("{:?}", e);
+                    }
+                    // this instance's worker joins whichever controller takes over
+                    watch_leadership(tx.clone(), authority.clone());
+                }
+            }
+            #[cfg(test)]
+            Event::RemoveIdleAdhocQueries(after, done) => {
+                if let Some(ref mut ctrl) = controller {
+                    let now = time::Instant::now() + after;
+                    tokio::task::block_in_place(|| ctrl.remove_idle_adhoc_queries(&authority, now));
+                }
+                done.send(()).unwrap();
+            }
+            #[cfg(test)]
             Event::IsReady(reply) => {
                 reply
                     .send(
@@ -159,17 +310,35 @@ pub(super) async fn main<A: Authority + 'static>(
                 let c = campaign.take().unwrap();
                 tokio::task::block_in_place(move || c.join().unwrap());
                 let drx = drx.take().unwrap();
-                controller = Some(ControllerInner::new(log.clone(), state, drx));
+
+                // what the previous controller knew about the data-flow it left running, if any
+                let metadata = match Metadata::read(&authority) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        warn!(log, "ignoring controller metadata: {}", e);
+                        None
+                    }
+                };
+                controller = Some(ControllerInner::new(log.clone(), state, metadata, drx));
             }
             Event::CampaignError(e) => {
                 panic!("{:?}", e);
             }
             e => unreachable!("{:?} is not a controller event", e),
         }
+
+        if let Some(ref mut ctrl) = controller {
+            if let Err(e) = tokio::task::block_in_place(|| ctrl.persist_metadata(&authority)) {
+                warn!(log, "failed to persist controller metadata: {}", e);
+            }
+        }
     }
 
     // shutting down
-    if controller.is_some() {
+    if let Some(ref mut ctrl) = controller {
+        // the domains are shut down along with the controller, so there is nothing left for
+        // another one to adopt
+        ctrl.forget_metadata(&authority);
         if let Err(e) = authority.surrender_leadership() {
             error!(log, "failed to surrender leadership");
             eprintln!("{:?}", e);
@@ -217,15 +386,11 @@ fn instance_campaign<A: Authority + 'static>(
     authority: Arc<A>,
     descriptor: ControllerDescriptor,
     config: Config,
+    restore: Option<backup::Manifest>,
 ) -> JoinHandle<()> {
     let descriptor_bytes = serde_json::to_vec(&descriptor).unwrap();
     let campaign_inner = move |event_tx: UnboundedSender<Event>| -> Result<(), failure::Error> {
-        let payload_to_event = |payload: Vec<u8>| -> Result<Event, failure::Error> {
-            let descriptor: ControllerDescriptor = serde_json::from_slice(&payload[..])?;
-            let state: ControllerState =
-                serde_json::from_slice(&authority.try_read(STATE_KEY).unwrap().unwrap())?;
-            Ok(Event::LeaderChange(state, descriptor))
-        };
+        let payload_to_event = |payload: Vec<u8>| leader_change(&authority, payload);
 
         loop {
             // WORKER STATE - watch for leadership changes
@@ -257,12 +422,24 @@ fn instance_campaign<A: Authority + 'static>(
             let state = authority.read_modify_write(
                 STATE_KEY,
                 |state: Option<ControllerState>| match state {
-                    None => Ok(ControllerState {
-                        config: config.clone(),
-                        epoch,
-                        recipe_version: 0,
-                        recipes: vec![],
-                    }),
+                    None => {
+                        let mut state = ControllerState {
+                            config: config.clone(),
+                            epoch,
+                            recipe_version: 0,
+                            recipes: vec![],
+                            recipe_statistics: vec![],
+                            memory_budgets: HashMap::new(),
+                            read_replicas: HashMap::new(),
+                            snapshot: None,
+                            security_config: None,
+                            universes: vec![],
+                        };
+                        if let Some(ref backup) = restore {
+                            backup.restore_into(&mut state);
+                        }
+                        Ok(state)
+                    }
                     Some(ref state) if state.epoch > epoch => Err(()),
                     Some(mut state) => {
                         state.epoch = epoch;
@@ -303,3 +480,51 @@ fn instance_campaign<A: Authority + 'static>(
         })
         .unwrap()
 }
+
+/// The event that tells this instance's worker about the leader whose descriptor is `payload`.
+fn leader_change<A: Authority + 'static>(
+    authority: &Arc<A>,
+    payload: Vec<u8>,
+) -> Result<Event, failure::Error> {
+    let descriptor: ControllerDescriptor = serde_json::from_slice(&payload[..])?;
+    let state: ControllerState =
+        serde_json::from_slice(&authority.try_read(STATE_KEY).unwrap().unwrap())?;
+    Ok(Event::LeaderChange(state, descriptor))
+}
+
+/// Tell this instance's worker about every leader from now on, without campaigning to become the
+/// leader, as an instance whose controller was stopped does. Stops once the instance is gone.
+#[cfg(test)]
+fn watch_leadership<A: Authority + 'static>(
+    event_tx: UnboundedSender<Event>,
+    authority: Arc<A>,
+) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("srv-zk".to_owned())
+        .spawn(move || {
+            let mut epoch = None;
+            loop {
+                let leader = match epoch {
+                    None => authority.try_get_leader(),
+                    Some(epoch) => authority.await_new_epoch(epoch),
+                };
+                match leader {
+                    Ok(Some((e, payload))) => {
+                        epoch = Some(e);
+                        let sent = leader_change(&authority, payload)
+                            .map(|event| event_tx.send(event).is_ok());
+                        if let Ok(false) = sent {
+                            break;
+                        }
+                    }
+                    // no controller has taken over yet
+                    Ok(None) => {
+                        epoch = None;
+                        thread::sleep(time::Duration::from_millis(50));
+                    }
+                    Err(_) => break,
+                }
+            }
+        })
+        .unwrap()
+}
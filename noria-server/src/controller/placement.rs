@@ -0,0 +1,296 @@
+//! Choosing the workers that the shards of a new domain run on.
+//!
+//! Which worker a shard goes to is decided by the deployment's `PlacementPolicy`, but only among
+//! the workers that have all the labels that `PlacementConstraint`s require of the domain. If no
+//! worker has them, the domain isn't placed at all.
+
+use super::WorkerIdentifier;
+use dataflow::prelude::*;
+use std::str::FromStr;
+
+/// How the controller chooses a worker for each shard of a new domain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PlacementPolicy {
+    /// Go through the healthy workers in turn.
+    RoundRobin,
+    /// Prefer workers with few domains for each of their cores and little state, and workers that
+    /// already run domains that the new domain communicates with (this is the default).
+    Balanced,
+}
+
+impl Default for PlacementPolicy {
+    fn default() -> Self {
+        PlacementPolicy::Balanced
+    }
+}
+
+impl FromStr for PlacementPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(PlacementPolicy::RoundRobin),
+            "balanced" => Ok(PlacementPolicy::Balanced),
+            _ => Err(format!("unknown placement policy \"{}\"", s)),
+        }
+    }
+}
+
+/// Requires the domains of some nodes to run on workers with a given label.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PlacementConstraint {
+    /// The name of the constrained nodes, such as that of a table or a view.
+    pub name: String,
+    /// Whether only readers with that name are constrained.
+    pub readers_only: bool,
+    /// The label that a worker must have to run a domain with any of the constrained nodes.
+    pub label: String,
+}
+
+impl PlacementConstraint {
+    fn applies_to(&self, n: &Node) -> bool {
+        n.name() == self.name && (!self.readers_only || n.is_reader())
+    }
+}
+
+impl FromStr for PlacementConstraint {
+    type Err = String;
+
+    /// Parses a constraint of the form `[readers:]<name>=<label>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let eq = s
+            .find('=')
+            .ok_or_else(|| format!("placement constraint \"{}\" has no label", s))?;
+        let (name, label) = (s[..eq].trim(), s[eq + 1..].trim());
+        let (name, readers_only) = if name.starts_with("readers:") {
+            (name["readers:".len()..].trim(), true)
+        } else {
+            (name, false)
+        };
+        if name.is_empty() || label.is_empty() {
+            return Err(format!("invalid placement constraint \"{}\"", s));
+        }
+        Ok(PlacementConstraint {
+            name: name.to_owned(),
+            readers_only,
+            label: label.to_owned(),
+        })
+    }
+}
+
+/// A healthy worker that a domain shard could be placed on.
+#[derive(Clone, Debug)]
+pub(super) struct Candidate {
+    pub(super) addr: WorkerIdentifier,
+    pub(super) cpus: usize,
+    pub(super) labels: Vec<String>,
+    /// The size, in bytes, of the state of the worker's domains.
+    pub(super) state_size: usize,
+    /// The number of domain shards that the worker runs.
+    pub(super) domains: usize,
+    /// The number of shards of domains that the new domain communicates with that the worker
+    /// runs.
+    pub(super) neighbours: usize,
+}
+
+impl Candidate {
+    /// The cost of placing one more shard here, given the deployment's total state size and
+    /// number of shards of neighbouring domains. Lower is better.
+    fn cost(&self, total_state_size: usize, total_neighbours: usize) -> f64 {
+        let load = (self.domains + 1) as f64 / self.cpus.max(1) as f64;
+        let memory = if total_state_size == 0 {
+            0.0
+        } else {
+            self.state_size as f64 / total_state_size as f64
+        };
+        let locality = if total_neighbours == 0 {
+            0.0
+        } else {
+            self.neighbours as f64 / total_neighbours as f64
+        };
+        load + memory - locality
+    }
+}
+
+pub(super) struct Placer {
+    policy: PlacementPolicy,
+    constraints: Vec<PlacementConstraint>,
+    /// The worker that round-robin placement goes to next.
+    next: usize,
+}
+
+impl Placer {
+    pub(super) fn new(policy: PlacementPolicy, constraints: Vec<PlacementConstraint>) -> Self {
+        Placer {
+            policy,
+            constraints,
+            next: 0,
+        }
+    }
+
+    /// The labels that the constraints require of the workers that run a domain with `nodes`.
+    pub(super) fn required_labels<'a, I>(&self, nodes: I) -> Vec<String>
+    where
+        I: IntoIterator<Item = &'a Node>,
+    {
+        let mut labels = Vec::new();
+        for n in nodes {
+            for c in &self.constraints {
+                if c.applies_to(n) && !labels.contains(&c.label) {
+                    labels.push(c.label.clone());
+                }
+            }
+        }
+        labels
+    }
+
+    /// Returns whether any of `candidates` has all the `labels` that a domain requires.
+    pub(super) fn can_place(labels: &[String], candidates: &[Candidate]) -> bool {
+        candidates
+            .iter()
+            .any(|c| labels.iter().all(|l| c.labels.contains(l)))
+    }
+
+    /// Choose a worker for each of the `shards` shards of a domain among the `candidates` that
+    /// have all the `labels` the domain requires.
+    pub(super) fn place(
+        &mut self,
+        labels: &[String],
+        candidates: Vec<Candidate>,
+        shards: usize,
+    ) -> Result<Vec<WorkerIdentifier>, String> {
+        if candidates.is_empty() {
+            return Err("no healthy workers to place domain on".to_owned());
+        }
+
+        let mut candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|c| labels.iter().all(|l| c.labels.contains(l)))
+            .collect();
+        if candidates.is_empty() {
+            return Err(format!(
+                "no healthy worker has the labels {:?} that placement constraints require",
+                labels
+            ));
+        }
+        // so that placement doesn't depend on the order workers are kept in
+        candidates.sort_by_key(|c| c.addr);
+
+        let total_state_size = candidates.iter().map(|c| c.state_size).sum();
+        let total_neighbours = candidates.iter().map(|c| c.neighbours).sum();
+        let workers = (0..shards)
+            .map(|_| {
+                let i = match self.policy {
+                    PlacementPolicy::RoundRobin => {
+                        let i = self.next % candidates.len();
+                        self.next += 1;
+                        i
+                    }
+                    PlacementPolicy::Balanced => {
+                        let costs: Vec<_> = candidates
+                            .iter()
+                            .map(|c| c.cost(total_state_size, total_neighbours))
+                            .collect();
+                        (0..candidates.len())
+                            .min_by(|&a, &b| costs[a].partial_cmp(&costs[b]).unwrap())
+                            .unwrap()
+                    }
+                };
+                candidates[i].domains += 1;
+                candidates[i].addr
+            })
+            .collect();
+        Ok(workers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(port: u16, labels: &[String]) -> Candidate {
+        Candidate {
+            addr: ([127, 0, 0, 1], port).into(),
+            cpus: 4,
+            labels: labels.to_vec(),
+            state_size: 0,
+            domains: 0,
+            neighbours: 0,
+        }
+    }
+
+    #[test]
+    fn it_parses_constraints() {
+        assert_eq!(
+            "readers:feed=edge".parse::<PlacementConstraint>(),
+            Ok(PlacementConstraint {
+                name: "feed".to_owned(),
+                readers_only: true,
+                label: "edge".to_owned(),
+            })
+        );
+        assert_eq!(
+            "Article = ssd".parse::<PlacementConstraint>(),
+            Ok(PlacementConstraint {
+                name: "Article".to_owned(),
+                readers_only: false,
+                label: "ssd".to_owned(),
+            })
+        );
+        assert!("feed".parse::<PlacementConstraint>().is_err());
+        assert!("readers:=edge".parse::<PlacementConstraint>().is_err());
+    }
+
+    #[test]
+    fn round_robin_goes_through_workers_in_turn() {
+        let mut p = Placer::new(PlacementPolicy::RoundRobin, Vec::new());
+        let cs = vec![candidate(2, &[]), candidate(1, &[])];
+        let a = p.place(&[], cs.clone(), 3).unwrap();
+        assert_eq!(a, vec![cs[1].addr, cs[0].addr, cs[1].addr]);
+        // and carries on where it left off
+        assert_eq!(p.place(&[], cs.clone(), 1).unwrap(), vec![cs[0].addr]);
+    }
+
+    #[test]
+    fn balanced_prefers_idle_cores_and_little_state() {
+        let mut p = Placer::new(PlacementPolicy::Balanced, Vec::new());
+        let mut cs = vec![candidate(1, &[]), candidate(2, &[])];
+        cs[0].domains = 4;
+        assert_eq!(p.place(&[], cs.clone(), 1).unwrap(), vec![cs[1].addr]);
+
+        // a worker with more cores can take more domains
+        cs[0].cpus = 16;
+        cs[1].domains = 2;
+        assert_eq!(p.place(&[], cs.clone(), 1).unwrap(), vec![cs[0].addr]);
+
+        // but not if it holds most of the state
+        cs[0].state_size = 1 << 30;
+        cs[1].state_size = 1 << 20;
+        assert_eq!(p.place(&[], cs.clone(), 1).unwrap(), vec![cs[1].addr]);
+    }
+
+    #[test]
+    fn balanced_spreads_shards_and_prefers_neighbours() {
+        let mut p = Placer::new(PlacementPolicy::Balanced, Vec::new());
+        let mut cs = vec![candidate(1, &[]), candidate(2, &[])];
+        let a = p.place(&[], cs.clone(), 2).unwrap();
+        assert_ne!(a[0], a[1]);
+
+        cs[1].neighbours = 2;
+        assert_eq!(p.place(&[], cs.clone(), 1).unwrap(), vec![cs[1].addr]);
+    }
+
+    #[test]
+    fn it_honours_labels() {
+        let edge = vec!["edge".to_owned()];
+        let mut p = Placer::new(PlacementPolicy::Balanced, Vec::new());
+        let mut cs = vec![candidate(1, &[]), candidate(2, &edge)];
+        cs[1].domains = 10;
+        assert_eq!(p.place(&edge, cs.clone(), 2).unwrap(), vec![cs[1].addr; 2]);
+
+        // without a suitable worker, the domain isn't placed
+        let ssd = vec!["ssd".to_owned()];
+        assert!(!Placer::can_place(&ssd, &cs));
+        assert!(p.place(&ssd, cs.clone(), 1).is_err());
+        assert!(Placer::can_place(&edge, &cs));
+    }
+}
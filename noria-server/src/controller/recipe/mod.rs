@@ -1,10 +1,11 @@
 use crate::controller::security::SecurityConfig;
-use crate::controller::sql::SqlIncorporator;
+use crate::controller::sql::{MirSnapshot, QueryReuse, SqlIncorporator, TableStatistics};
 use crate::controller::Migration;
 use crate::ReuseConfigType;
 use dataflow::ops::trigger::Trigger;
 use dataflow::ops::trigger::TriggerEvent;
 use dataflow::prelude::DataType;
+use dataflow::RocksDbOptions;
 use nom_sql::parser as sql_parser;
 use nom_sql::SqlQuery;
 use noria::ActivationResult;
@@ -12,8 +13,10 @@ use petgraph::graph::NodeIndex;
 
 use nom_sql::CreateTableStatement;
 use slog;
+use std::cmp;
 use std::collections::HashMap;
 use std::str;
+use std::time::Duration;
 use std::vec::Vec;
 
 type QueryID = u64;
@@ -28,6 +31,12 @@ pub(crate) struct Recipe {
     expression_order: Vec<QueryID>,
     /// Named read/write expression aliases, mapping to queries in `expressions`.
     aliases: HashMap<String, QueryID>,
+    /// Memory budgets, in bytes, for the state of named queries.
+    budgets: HashMap<String, usize>,
+    /// RocksDB options given for base tables, by table name.
+    rocksdb_options: HashMap<String, Vec<(String, String)>>,
+    /// The time-to-live of the rows of base tables, and the column it counts from, by table name.
+    ttls: HashMap<String, (String, Duration)>,
     /// Security configuration
     security_config: Option<SecurityConfig>,
 
@@ -50,6 +59,9 @@ impl PartialEq for Recipe {
         self.expressions == other.expressions
             && self.expression_order == other.expression_order
             && self.aliases == other.aliases
+            && self.budgets == other.budgets
+            && self.rocksdb_options == other.rocksdb_options
+            && self.ttls == other.ttls
             && self.version == other.version
             && self.prior == other.prior
     }
@@ -61,7 +73,7 @@ pub(super) enum Schema {
     View(Vec<String>),
 }
 
-fn hash_query(q: &SqlQuery) -> QueryID {
+pub(super) fn hash_query(q: &SqlQuery) -> QueryID {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
 
@@ -78,7 +90,53 @@ fn ident(input: &str) -> nom::IResult<&str, &str> {
     })
 }
 
-fn query_prefix(input: &str) -> nom::IResult<&str, (bool, Option<&str>)> {
+/// Parses a `/* <option>=<value>, ... */` annotation, returning the (unparsed) options.
+fn annotation(input: &str) -> nom::IResult<&str, &str> {
+    use nom::bytes::complete::{tag, take_until};
+    let (input, _) = tag("/*")(input)?;
+    let (input, options) = take_until("*/")(input)?;
+    let (input, _) = tag("*/")(input)?;
+    Ok((input, options.trim()))
+}
+
+/// Splits the options of an annotation into option names and values.
+fn annotation_options(options: &str) -> Result<Vec<(&str, &str)>, String> {
+    options
+        .split(',')
+        .map(|option| {
+            let eq = option
+                .find('=')
+                .ok_or_else(|| format!("option \"{}\" has no value", option.trim()))?;
+            Ok((option[..eq].trim(), option[eq + 1..].trim()))
+        })
+        .collect()
+}
+
+/// Parses a memory budget such as `512MB` or `2GB` into a number of bytes.
+///
+/// Units are powers of 1024, and a plain number is taken to be in bytes.
+fn parse_budget(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| size.len());
+    let (number, unit) = size.split_at(digits);
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" => 10,
+        "M" | "MB" => 20,
+        "G" | "GB" => 30,
+        "T" | "TB" => 40,
+        _ => return Err(format!("invalid memory budget \"{}\"", size)),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid memory budget \"{}\"", size))
+}
+
+fn query_prefix(input: &str) -> nom::IResult<&str, (bool, Option<&str>, Option<&str>)> {
     use nom::branch::alt;
     use nom::bytes::complete::tag_no_case;
     use nom::character::complete::{char, multispace0, space1};
@@ -91,35 +149,131 @@ fn query_prefix(input: &str) -> nom::IResult<&str, (bool, Option<&str>)> {
     let (input, _) = multispace0(input)?;
     let (input, name) = opt(terminated(ident, multispace0))(input)?;
     let (input, _) = multispace0(input)?;
+    let (input, options) = opt(terminated(annotation, multispace0))(input)?;
     let (input, _) = char(':')(input)?;
     let (input, _) = multispace0(input)?;
-    Ok((input, (public.is_some(), name)))
+    Ok((input, (public.is_some(), name, options)))
 }
 
-fn query_expr(input: &str) -> nom::IResult<&str, (bool, Option<&str>, SqlQuery)> {
-    use nom::character::complete::multispace0;
-    use nom::combinator::opt;
-    let (input, prefix) = opt(query_prefix)(input)?;
+fn sql_query(input: &str) -> nom::IResult<&str, SqlQuery> {
     // NOTE: some massaging since nom_sql operates on &[u8], not &str
-    let (input, expr) = match sql_parser::sql_query(input.as_bytes()) {
+    match sql_parser::sql_query(input.as_bytes()) {
         Ok((i, e)) => Ok((std::str::from_utf8(i).unwrap(), e)),
         Err(nom::Err::Incomplete(n)) => Err(nom::Err::Incomplete(n)),
         Err(nom::Err::Error((i, e))) => Err(nom::Err::Error((std::str::from_utf8(i).unwrap(), e))),
         Err(nom::Err::Failure((i, e))) => {
             Err(nom::Err::Error((std::str::from_utf8(i).unwrap(), e)))
         }
-    }?;
+    }
+}
+
+/// Parses a `TTL <number> <unit> ON <column>` clause, which makes the rows of a base table expire
+/// once the given amount of time has passed since the time in the given column.
+fn ttl_clause(input: &str) -> nom::IResult<&str, (&str, Duration)> {
+    use nom::branch::alt;
+    use nom::bytes::complete::tag_no_case;
+    use nom::character::complete::{digit1, multispace0, multispace1};
+    use nom::combinator::{map, map_res, opt};
+    let (input, _) = tag_no_case("ttl")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, n) = map_res(digit1, str::parse::<u64>)(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, unit) = alt((
+        map(tag_no_case("second"), |_| 1),
+        map(tag_no_case("minute"), |_| 60),
+        map(tag_no_case("hour"), |_| 60 * 60),
+        map(tag_no_case("day"), |_| 24 * 60 * 60),
+        map(tag_no_case("week"), |_| 7 * 24 * 60 * 60),
+    ))(input)?;
+    let (input, _) = opt(tag_no_case("s"))(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _) = tag_no_case("on")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, column) = ident(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, (column, Duration::from_secs(n.saturating_mul(unit)))))
+}
+
+/// Parses a `CREATE TABLE` statement with a TTL clause after its columns and table options.
+///
+/// nom-sql doesn't know about TTL clauses, so the clause is cut out of the statement before
+/// nom-sql parses the rest of it.
+fn create_table_with_ttl(input: &str) -> nom::IResult<&str, (SqlQuery, (&str, Duration))> {
+    let fail = || nom::Err::Error((input, nom::error::ErrorKind::Tag));
+    if !input
+        .get(..12)
+        .map_or(false, |s| s.eq_ignore_ascii_case("create table"))
+    {
+        return Err(fail());
+    }
+
+    let end = input.find(';').unwrap_or_else(|| input.len());
+    let statement = &input[..end];
+    let columns_end = statement.rfind(')').ok_or_else(fail)? + 1;
+    let clause = columns_end
+        + statement[columns_end..]
+            .to_ascii_lowercase()
+            .find("ttl")
+            .ok_or_else(fail)?;
+    let (rest, ttl) = ttl_clause(&statement[clause..])?;
+    if !rest.is_empty() {
+        return Err(fail());
+    }
+
+    let without_ttl = format!("{};", &statement[..clause]);
+    let expr = match sql_query(&without_ttl) {
+        Ok((rest, expr @ SqlQuery::CreateTable(_))) if rest.trim().is_empty() => expr,
+        _ => return Err(fail()),
+    };
+    Ok((&input[cmp::min(end + 1, input.len())..], (expr, ttl)))
+}
+
+#[allow(clippy::type_complexity)]
+fn query_expr(
+    input: &str,
+) -> nom::IResult<
+    &str,
+    (
+        bool,
+        Option<&str>,
+        Option<&str>,
+        SqlQuery,
+        Option<(&str, Duration)>,
+    ),
+> {
+    use nom::character::complete::multispace0;
+    use nom::combinator::opt;
+    let (input, prefix) = opt(query_prefix)(input)?;
+    let (input, (expr, ttl)) = match create_table_with_ttl(input) {
+        Ok((input, (expr, ttl))) => (input, (expr, Some(ttl))),
+        Err(_) => {
+            let (input, expr) = sql_query(input)?;
+            (input, (expr, None))
+        }
+    };
     let (input, _) = multispace0(input)?;
     Ok((
         input,
         match prefix {
-            None => (false, None, expr),
-            Some((public, name)) => (public, name, expr),
+            None => (false, None, None, expr, ttl),
+            Some((public, name, options)) => (public, name, options, expr, ttl),
         },
     ))
 }
 
-fn query_exprs(input: &str) -> nom::IResult<&str, Vec<(bool, Option<&str>, SqlQuery)>> {
+#[allow(clippy::type_complexity)]
+fn query_exprs(
+    input: &str,
+) -> nom::IResult<
+    &str,
+    Vec<(
+        bool,
+        Option<&str>,
+        Option<&str>,
+        SqlQuery,
+        Option<(&str, Duration)>,
+    )>,
+> {
     nom::multi::many1(query_expr)(input)
 }
 
@@ -146,6 +300,9 @@ impl Recipe {
             expressions: HashMap::default(),
             expression_order: Vec::default(),
             aliases: HashMap::default(),
+            budgets: HashMap::default(),
+            rocksdb_options: HashMap::default(),
+            ttls: HashMap::default(),
             version: 0,
             prior: None,
             inc: match log {
@@ -185,6 +342,13 @@ impl Recipe {
         self.inc.as_mut().unwrap().enable_reuse(reuse_type)
     }
 
+    /// Set the base table statistics used to order joins in queries that are added from now on.
+    pub(super) fn set_table_statistics(&mut self, stats: TableStatistics) {
+        if let Some(ref mut inc) = self.inc {
+            inc.set_table_statistics(stats);
+        }
+    }
+
     fn resolve_alias(&self, alias: &str) -> Option<&str> {
         self.aliases.get(alias).map(|ref qid| {
             let (ref internal_qn, _, _) = self.expressions[qid];
@@ -213,6 +377,44 @@ impl Recipe {
         }
     }
 
+    /// Returns the memory budgets, in bytes, given for named queries in the recipe.
+    pub(super) fn memory_budgets(&self) -> &HashMap<String, usize> {
+        &self.budgets
+    }
+
+    /// Returns the name of a query in the recipe that is identical to `q`, if one exists.
+    pub(super) fn name_for_query(&self, q: &SqlQuery) -> Option<&str> {
+        self.expressions
+            .get(&hash_query(q))
+            .and_then(|&(ref n, _, _)| n.as_ref().map(String::as_str))
+    }
+
+    /// Renders the MIR graph of the query with the given name in GraphViz format.
+    pub(super) fn mir_graphviz(&self, name: &str) -> Option<String> {
+        let name = self.resolve_alias(name).unwrap_or(name);
+        self.inc.as_ref().and_then(|inc| inc.mir_graphviz(name))
+    }
+
+    /// How the query with the given name reuses the nodes of other queries, if it is a `SELECT`
+    /// query.
+    pub(super) fn query_reuse(&self, name: &str) -> Option<&QueryReuse> {
+        let name = self.resolve_alias(name).unwrap_or(name);
+        self.inc.as_ref().and_then(|inc| inc.query_reuse(name))
+    }
+
+    /// Captures the current state of the recipe's MIR graph.
+    pub(super) fn mir_snapshot(&self) -> Option<MirSnapshot> {
+        self.inc.as_ref().map(SqlIncorporator::mir_snapshot)
+    }
+
+    /// Returns true if the query named `name` is also known under another name.
+    pub(super) fn is_aliased(&self, name: &str) -> bool {
+        match self.aliases.get(name) {
+            None => false,
+            Some(qid) => self.aliases.values().filter(|&q| q == qid).count() > 1,
+        }
+    }
+
     /// Get schema for a base table or view in the recipe.
     pub(super) fn schema_for(&self, name: &str) -> Option<Schema> {
         let inc = self.inc.as_ref().expect("Recipe not applied");
@@ -249,9 +451,13 @@ impl Recipe {
         let cleaned_recipe_text = lines.join("\n");
 
         // parse and compute differences to current recipe
-        let parsed_queries = Recipe::parse(&cleaned_recipe_text)?;
+        let (parsed_queries, budgets, rocksdb_options, ttls) = Recipe::parse(&cleaned_recipe_text)?;
 
-        Ok(Recipe::from_queries(parsed_queries, log))
+        let mut recipe = Recipe::from_queries(parsed_queries, log);
+        recipe.budgets = budgets;
+        recipe.rocksdb_options = rocksdb_options;
+        recipe.ttls = ttls;
+        Ok(recipe)
     }
 
     /// Creates a recipe from a set of pre-parsed `SqlQuery` structures.
@@ -304,6 +510,9 @@ impl Recipe {
             expressions,
             expression_order,
             aliases,
+            budgets: HashMap::default(),
+            rocksdb_options: HashMap::default(),
+            ttls: HashMap::default(),
             security_config: None,
             version: 0,
             prior: None,
@@ -453,6 +662,10 @@ impl Recipe {
         // returned to the caller (who may use them to obtain mutators and getters)
         for qid in added {
             let (n, q, is_leaf) = self.expressions[&qid].clone();
+            let table = match q {
+                SqlQuery::CreateTable(ref ctq) => Some(ctq.table.name.clone()),
+                _ => None,
+            };
 
             // add the query
             let qfp = self
@@ -461,6 +674,15 @@ impl Recipe {
                 .unwrap()
                 .add_parsed_query(q, n.clone(), is_leaf, mig)?;
 
+            if let Some(ref table) = table {
+                if let Some(options) = self.rocksdb_options.get(table) {
+                    mig.set_rocksdb_options(qfp.query_leaf, options)?;
+                }
+                if let Some((column, ttl)) = self.ttls.get(table) {
+                    mig.set_retention(qfp.query_leaf, column, *ttl)?;
+                }
+            }
+
             // If the user provided us with a query name, use that.
             // If not, use the name internally used by the QFP.
             let query_name = match n {
@@ -557,6 +779,9 @@ impl Recipe {
             expressions: self.expressions.clone(),
             expression_order: self.expression_order.clone(),
             aliases: self.aliases.clone(),
+            budgets: self.budgets.clone(),
+            rocksdb_options: self.rocksdb_options.clone(),
+            ttls: self.ttls.clone(),
             version: self.version + 1,
             inc: prior_inc,
             log: self.log.clone(),
@@ -580,28 +805,33 @@ impl Recipe {
             );
         }
         new.aliases.extend(add_rp.aliases);
+        new.budgets.extend(add_rp.budgets);
+        new.rocksdb_options.extend(add_rp.rocksdb_options);
+        new.ttls.extend(add_rp.ttls);
 
         // return new recipe as replacement for self
         Ok(new)
     }
 
-    /// Helper method to reparent a recipe. This is needed for the recovery logic to build
-    /// recovery and original recipe (see `make_recovery`).
-    pub(in crate::controller) fn set_prior(&mut self, new_prior: Recipe) {
-        self.prior = Some(Box::new(new_prior));
-    }
-
     /// Helper method to reparent a recipe. This is needed for some of t
     pub(super) fn sql_inc(&self) -> &SqlIncorporator {
         self.inc.as_ref().unwrap()
     }
 
-    /// Helper method to reparent a recipe. This is needed for some of t
-    pub(in crate::controller) fn set_sql_inc(&mut self, new_inc: SqlIncorporator) {
-        self.inc = Some(new_inc);
-    }
-
-    fn parse(recipe_text: &str) -> Result<Vec<(Option<String>, SqlQuery, bool)>, String> {
+    /// Parses the queries in `recipe_text`, along with the memory budgets given for them, and the
+    /// RocksDB options and time-to-live given for base tables.
+    #[allow(clippy::type_complexity)]
+    fn parse(
+        recipe_text: &str,
+    ) -> Result<
+        (
+            Vec<(Option<String>, SqlQuery, bool)>,
+            HashMap<String, usize>,
+            HashMap<String, Vec<(String, String)>>,
+            HashMap<String, (String, Duration)>,
+        ),
+        String,
+    > {
         let lines: Vec<&str> = recipe_text
             .lines()
             .filter(|l| !l.is_empty() && !l.starts_with('#'))
@@ -632,37 +862,80 @@ impl Recipe {
             i += 1;
         }
 
-        let parsed_queries = query_strings.iter().fold(
-            Vec::new(),
-            |mut acc: Vec<Result<(bool, Option<&str>, SqlQuery), String>>, q| {
-                match query_exprs(q) {
-                    Result::Err(e) => {
-                        // we got a parse error
-                        acc.push(Err(format!("Query \"{}\", parse error: {}", q, e)));
-                    }
-                    Result::Ok((remainder, parsed)) => {
-                        // should have consumed all input
-                        assert!(
-                            remainder.is_empty(),
-                            format!(
-                                "failed to parse the complete recipe; left with: {}",
-                                remainder
-                            )
-                        );
-                        acc.extend(parsed.into_iter().map(|p| Ok(p)).collect::<Vec<_>>());
+        let parsed_queries =
+            query_strings
+                .iter()
+                .fold(Vec::new(), |mut acc: Vec<Result<_, String>>, q| {
+                    match query_exprs(q) {
+                        Result::Err(e) => {
+                            // we got a parse error
+                            acc.push(Err(format!("Query \"{}\", parse error: {}", q, e)));
+                        }
+                        Result::Ok((remainder, parsed)) => {
+                            // should have consumed all input
+                            assert!(
+                                remainder.is_empty(),
+                                format!(
+                                    "failed to parse the complete recipe; left with: {}",
+                                    remainder
+                                )
+                            );
+                            acc.extend(parsed.into_iter().map(|p| Ok(p)).collect::<Vec<_>>());
+                        }
                     }
+                    acc
+                });
+
+        let mut queries = Vec::with_capacity(parsed_queries.len());
+        let mut budgets = HashMap::new();
+        let mut rocksdb_options = HashMap::new();
+        let mut ttls = HashMap::new();
+        for pr in parsed_queries {
+            let (public, name, options, q, ttl) = pr.unwrap();
+            let name = name.filter(|name| !name.is_empty());
+            let options = match options {
+                Some(options) => annotation_options(options)?,
+                None => Vec::new(),
+            };
+            for (option, value) in options {
+                if option.eq_ignore_ascii_case("budget") {
+                    match name {
+                        Some(name) => budgets.insert(name.to_owned(), parse_budget(value)?),
+                        None => {
+                            return Err(format!("memory budget given for unnamed query \"{}\"", q))
+                        }
+                    };
+                    continue;
                 }
-                acc
-            },
-        );
 
-        Ok(parsed_queries
-            .into_iter()
-            .map(|pr| {
-                let pr = pr.unwrap();
-                (pr.1.map(String::from), pr.2, pr.0)
-            })
-            .collect::<Vec<_>>())
+                // any other option tunes the RocksDB instance of a base table
+                let table = match q {
+                    SqlQuery::CreateTable(ref ctq) => ctq.table.name.clone(),
+                    _ => return Err(format!("RocksDB option given for non-table \"{}\"", q)),
+                };
+                RocksDbOptions::default().set(option, value)?;
+                rocksdb_options
+                    .entry(table)
+                    .or_insert_with(Vec::new)
+                    .push((option.to_owned(), value.to_owned()));
+            }
+
+            if let Some((column, ttl)) = ttl {
+                let ctq = match q {
+                    SqlQuery::CreateTable(ref ctq) => ctq,
+                    _ => unreachable!("TTL clause parsed for non-table"),
+                };
+                if !ctq.fields.iter().any(|f| f.column.name == column) {
+                    return Err(format!(
+                        "TTL given on column \"{}\", which table {} does not have",
+                        column, ctq.table.name
+                    ));
+                }
+                ttls.insert(ctq.table.name.clone(), (column.to_owned(), ttl));
+            }
+            queries.push((name.map(String::from), q, public));
+        }
+        Ok((queries, budgets, rocksdb_options, ttls))
     }
 
     /// Returns the predecessor from which this `Recipe` was migrated to.
@@ -680,9 +953,77 @@ impl Recipe {
         let qid = qid.unwrap();
 
         self.aliases.remove(qname);
+        self.budgets.remove(qname);
+        if let Some((_, SqlQuery::CreateTable(ref ctq), _)) = self.expressions.get(&qid) {
+            self.rocksdb_options.remove(&ctq.table.name);
+            self.ttls.remove(&ctq.table.name);
+        }
         self.expressions.remove(&qid).is_some() && self.expression_order.remove_item(&qid).is_some()
     }
 
+    /// Produces a successor of this recipe that no longer contains the named queries.
+    pub(super) fn remove_queries(&self, names: &[String]) -> Recipe {
+        let mut new = self.clone();
+        new.prior = Some(Box::new(self.clone()));
+        new.next();
+
+        for q in names {
+            if !new.remove_query(q) {
+                warn!(self.log, "Call to Recipe::remove_query() failed for {}", q);
+            }
+        }
+
+        new
+    }
+
+    /// Produces a successor of this recipe that has the named queries back as its predecessor had
+    /// them, along with their memory budgets. Together with `remove_queries`, this rebuilds the
+    /// queries from scratch.
+    pub(super) fn restore_queries(&self, names: &[String]) -> Recipe {
+        let mut new = self.clone();
+        new.prior = Some(Box::new(self.clone()));
+        new.next();
+
+        let prior = match self.prior {
+            Some(ref prior) => prior,
+            None => return new,
+        };
+        for q in names {
+            let qid = match prior.aliases.get(q) {
+                Some(&qid) if prior.expressions.contains_key(&qid) => qid,
+                _ => {
+                    warn!(self.log, "Query {} not found in prior recipe", q);
+                    continue;
+                }
+            };
+            new.aliases.insert(q.clone(), qid);
+            if !new.expressions.contains_key(&qid) {
+                new.expressions.insert(qid, prior.expressions[&qid].clone());
+                new.expression_order.push(qid);
+            }
+            if let Some(&budget) = prior.budgets.get(q) {
+                new.budgets.insert(q.clone(), budget);
+            }
+        }
+
+        new
+    }
+
+    pub(super) fn queries_for_nodes(&self, nodes: Vec<NodeIndex>) -> Vec<String> {
+        let mut queries: Vec<_> = nodes
+            .iter()
+            .flat_map(|ni| {
+                self.inc
+                    .as_ref()
+                    .expect("need SQL incorporator")
+                    .get_queries_for_node(*ni)
+            })
+            .collect();
+        queries.sort();
+        queries.dedup();
+        queries
+    }
+
     /// Replace this recipe with a new one, retaining queries that exist in both. Any queries only
     /// contained in `new` (but not in `self`) will be added; any contained in `self`, but not in
     /// `new` will be removed.
@@ -703,6 +1044,98 @@ impl Recipe {
         Ok(new)
     }
 
+    /// Write the recipe out as text that `from_str` parses back into the same queries, along with
+    /// their names, memory budgets, RocksDB options and time-to-live, or return `None` if some
+    /// query doesn't come out the same after it is written out.
+    pub(super) fn to_text(&self) -> Option<String> {
+        let mut text = String::new();
+        for qid in &self.expression_order {
+            let (ref name, ref q, public) = self.expressions[qid];
+
+            // the query's other names come first, since parsing keeps the name it sees last
+            let mut names: Vec<_> = self
+                .aliases
+                .iter()
+                .filter(|&(n, id)| id == qid && Some(n) != name.as_ref())
+                .map(|(n, _)| Some(n))
+                .collect();
+            names.sort();
+            names.push(name.as_ref());
+
+            let last = names.len() - 1;
+            for (i, n) in names.into_iter().enumerate() {
+                let mut options = Vec::new();
+                if let Some(budget) = n.and_then(|n| self.budgets.get(n)) {
+                    options.push(format!("budget={}", budget));
+                }
+                let mut statement = q.to_string();
+                let table = match *q {
+                    SqlQuery::CreateTable(ref ctq) if i == last => Some(&ctq.table.name),
+                    _ => None,
+                };
+                if let Some(table) = table {
+                    if let Some(tuned) = self.rocksdb_options.get(table) {
+                        for (option, value) in tuned {
+                            options.push(format!("{}={}", option, value));
+                        }
+                    }
+                    if let Some((column, ttl)) = self.ttls.get(table) {
+                        statement.push_str(&format!(
+                            " TTL {} seconds ON {}",
+                            ttl.as_secs(),
+                            column
+                        ));
+                    }
+                }
+                statement.push(';');
+
+                let mut prefix = String::new();
+                if public {
+                    prefix.push_str("QUERY ");
+                }
+                if let Some(n) = n {
+                    prefix.push_str(n);
+                }
+                if !options.is_empty() {
+                    prefix.push_str(&format!(" /* {} */", options.join(", ")));
+                }
+                if public || n.is_some() || !options.is_empty() {
+                    prefix.push_str(": ");
+                }
+                let line = format!("{}{}", prefix, statement);
+
+                // the parser cuts comments out before it sees the query
+                if line.contains('#') || line.contains('\n') {
+                    return None;
+                }
+                match query_expr(&line) {
+                    Ok((rest, (p, _, _, ref parsed, _)))
+                        if rest.is_empty() && p == public && parsed == q => {}
+                    _ => return None,
+                }
+                text.push_str(&line);
+                text.push('\n');
+            }
+        }
+        Some(text)
+    }
+
+    /// Whether `other` has the same queries as this recipe, along with their names and options,
+    /// has the same version, and finds every named query at the same node.
+    pub(super) fn same_as(&self, other: &Recipe) -> bool {
+        self.expressions == other.expressions
+            && self.expression_order == other.expression_order
+            && self.aliases == other.aliases
+            && self.budgets == other.budgets
+            && self.rocksdb_options == other.rocksdb_options
+            && self.ttls == other.ttls
+            && self.version == other.version
+            && self
+                .aliases
+                .keys()
+                .all(|n| self.node_addr_for(n).ok() == other.node_addr_for(n).ok())
+    }
+
     /// Increments the version of a recipe. Returns the new version number.
     pub(super) fn next(&mut self) -> usize {
         self.version += 1;
@@ -716,48 +1149,18 @@ impl Recipe {
     }
 
     /// Reverts to prior version of recipe
-    pub(super) fn revert(self) -> Recipe {
-        if let Some(prior) = self.prior {
+    pub(super) fn revert(mut self) -> Recipe {
+        if let Some(mut prior) = self.prior.take() {
+            // `extend` and `replace` moved these to us, so hand them back
+            prior.inc = self.inc.take();
+            if prior.security_config.is_none() {
+                prior.security_config = self.security_config.take();
+            }
             *prior
         } else {
             Recipe::blank(Some(self.log))
         }
     }
-
-    pub(super) fn queries_for_nodes(&self, nodes: Vec<NodeIndex>) -> Vec<String> {
-        nodes
-            .iter()
-            .flat_map(|ni| {
-                self.inc
-                    .as_ref()
-                    .expect("need SQL incorporator")
-                    .get_queries_for_node(*ni)
-            })
-            .collect()
-    }
-
-    pub(super) fn make_recovery(&self, mut affected_queries: Vec<String>) -> (Recipe, Recipe) {
-        affected_queries.sort();
-        affected_queries.dedup();
-
-        let mut recovery = self.clone();
-        recovery.prior = Some(Box::new(self.clone()));
-        recovery.next();
-
-        // remove from recipe
-        for q in affected_queries {
-            warn!(self.log, "query {} affected by failure", q);
-            if !recovery.remove_query(&q) {
-                warn!(self.log, "Call to Recipe::remove_query() failed for {}", q);
-            }
-        }
-
-        let mut original = self.clone();
-        original.next();
-        original.next();
-
-        (recovery, original)
-    }
 }
 
 #[cfg(test)]
@@ -802,6 +1205,22 @@ mod tests {
         assert_eq!(removed[0], q1_id);
     }
 
+    #[test]
+    fn it_finds_identical_queries() {
+        let r0 = Recipe::blank(None);
+
+        let r1_txt = "QUERY q_0: SELECT a FROM b;\nQUERY q_1: SELECT a FROM b;";
+        let r1_t = Recipe::from_str(r1_txt, None).unwrap();
+        let r1 = r0.replace(r1_t).unwrap();
+
+        let q = sql_parser::parse_query("SELECT a FROM b;").unwrap();
+        assert!(r1.name_for_query(&q).is_some());
+        assert!(r1.is_aliased("q_0"));
+
+        let q = sql_parser::parse_query("SELECT c FROM b;").unwrap();
+        assert_eq!(r1.name_for_query(&q), None);
+    }
+
     #[test]
     fn it_replaces() {
         let r0 = Recipe::blank(None);
@@ -828,6 +1247,21 @@ mod tests {
         assert_eq!(r2.prior, Some(Box::new(r1_copy)));
     }
 
+    #[test]
+    fn it_reverts() {
+        let r0 = Recipe::blank(None);
+        let r0_copy = r0.clone();
+
+        let r1 = r0.extend("SELECT a FROM b;").unwrap();
+        assert_eq!(r1.version, 1);
+        assert!(r1.inc.is_some());
+
+        // the incorporator moves back to the prior recipe
+        let r0 = r1.revert();
+        assert_eq!(r0, r0_copy);
+        assert!(r0.inc.is_some());
+    }
+
     #[test]
     #[should_panic(expected = "Query name exists but existing query is different")]
     fn it_avoids_spurious_aliasing() {
@@ -847,6 +1281,134 @@ mod tests {
         assert_eq!(r2.expressions.len(), 4);
     }
 
+    #[test]
+    fn it_parses_memory_budgets() {
+        let r0 = Recipe::blank(None);
+
+        let r1_txt = "QUERY feed /* budget=2GB */: SELECT a FROM b;\n\
+                      VIEW dashboard /*budget = 512 MB*/ : SELECT x FROM y;\n\
+                      QUERY q_2: SELECT a, c FROM b;";
+        let r1_t = Recipe::from_str(r1_txt, None).unwrap();
+        let r1 = r0.replace(r1_t).unwrap();
+        assert_eq!(r1.expressions.len(), 3);
+        assert_eq!(r1.memory_budgets().len(), 2);
+        assert_eq!(r1.memory_budgets()["feed"], 2 << 30);
+        assert_eq!(r1.memory_budgets()["dashboard"], 512 << 20);
+
+        // budgets carry over into extensions, and can be changed by them
+        let r2 = r1
+            .extend("QUERY feed /* budget=1GB */: SELECT a FROM b;")
+            .unwrap();
+        assert_eq!(r2.memory_budgets()["feed"], 1 << 30);
+        assert_eq!(r2.memory_budgets()["dashboard"], 512 << 20);
+
+        assert!(Recipe::from_str("QUERY q /* budget=lots */: SELECT a FROM b;", None).is_err());
+        assert!(Recipe::from_str("/* budget=1GB */: SELECT a FROM b;", None).is_err());
+    }
+
+    #[test]
+    fn it_parses_rocksdb_options() {
+        let r1_txt = "Article /* write_buffer_size=64MB, compression=none:lz4:zstd */: \
+                      CREATE TABLE Article (aid int, title varchar(255), PRIMARY KEY(aid));\n\
+                      /* bloom_bits_per_key = 16 */: CREATE TABLE Vote (aid int, uid int);\n\
+                      QUERY votes /* budget=1GB */: SELECT aid FROM Vote WHERE uid = ?;";
+        let r1 = Recipe::from_str(r1_txt, None).unwrap();
+        assert_eq!(r1.expressions.len(), 3);
+        assert_eq!(r1.memory_budgets()["votes"], 1 << 30);
+        assert_eq!(r1.rocksdb_options.len(), 2);
+        assert_eq!(
+            r1.rocksdb_options["Article"],
+            vec![
+                ("write_buffer_size".to_owned(), "64MB".to_owned()),
+                ("compression".to_owned(), "none:lz4:zstd".to_owned()),
+            ]
+        );
+        assert_eq!(
+            r1.rocksdb_options["Vote"],
+            vec![("bloom_bits_per_key".to_owned(), "16".to_owned())]
+        );
+
+        // options carry over into extensions
+        let r2 = r1.extend("QUERY q: SELECT uid FROM Vote;").unwrap();
+        assert_eq!(r2.rocksdb_options.len(), 2);
+
+        // options have to be known, have valid values, and apply to tables
+        assert!(Recipe::from_str("t /* cache=1GB */: CREATE TABLE t (a int);", None).is_err());
+        assert!(Recipe::from_str(
+            "t /* write_buffer_size=lots */: CREATE TABLE t (a int);",
+            None
+        )
+        .is_err());
+        assert!(
+            Recipe::from_str("t /* compression=gzip */: CREATE TABLE t (a int);", None).is_err()
+        );
+        assert!(Recipe::from_str("q /* bloom_bits_per_key=8 */: SELECT a FROM b;", None).is_err());
+    }
+
+    #[test]
+    fn it_parses_ttls() {
+        let r1_txt = "CREATE TABLE Event (id int, at timestamp) TTL 7 DAYS ON at;\n\
+                      Click /* budget=1MB */: CREATE TABLE Click (id int, ts bigint, \
+                      PRIMARY KEY(id)) ttl 90 second on ts;\n\
+                      QUERY clicks: SELECT id FROM Click;";
+        let r1 = Recipe::from_str(r1_txt, None).unwrap();
+        assert_eq!(r1.expressions.len(), 3);
+        assert_eq!(r1.memory_budgets()["Click"], 1 << 20);
+        assert_eq!(
+            r1.ttls["Event"],
+            ("at".to_owned(), Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert_eq!(r1.ttls["Click"], ("ts".to_owned(), Duration::from_secs(90)));
+
+        // the clause is not part of the table's definition
+        let r2 = Recipe::from_str("CREATE TABLE Event (id int, at timestamp);", None).unwrap();
+        assert!(r1.expressions.contains_key(&r2.expression_order[0]));
+
+        // time-to-live carries over into extensions, and has to be given on an existing column
+        let r3 = r1.extend("QUERY events: SELECT id FROM Event;").unwrap();
+        assert_eq!(r3.ttls.len(), 2);
+        assert!(Recipe::from_str("CREATE TABLE t (a int) TTL 1 DAY ON b;", None).is_err());
+    }
+
+    #[test]
+    fn it_writes_itself_out() {
+        let r1_txt = "Article /* write_buffer_size=64MB */: \
+                      CREATE TABLE Article (aid int, title varchar(255), PRIMARY KEY(aid));\n\
+                      CREATE TABLE Vote (aid int, uid int, at timestamp) TTL 1 DAY ON at;\n\
+                      QUERY votes /* budget=1GB */: SELECT aid FROM Vote WHERE uid = ?;\n\
+                      titles: SELECT title FROM Article;";
+        let r1 = Recipe::blank(None)
+            .replace(Recipe::from_str(r1_txt, None).unwrap())
+            .unwrap();
+        let r2 = r1
+            .extend("QUERY my_votes: SELECT aid FROM Vote WHERE uid = ?;")
+            .unwrap();
+        let r3 = r2.remove_queries(&["titles".to_owned()]);
+
+        let text = r3.to_text().unwrap();
+        let written = Recipe::with_version(r3.version - 1, None)
+            .replace(Recipe::from_str(&text, None).unwrap())
+            .unwrap();
+        assert!(r3.same_as(&written));
+        assert_eq!(written.memory_budgets()["votes"], 1 << 30);
+        assert!(written.is_aliased("votes"));
+        assert!(written.node_addr_for("titles").is_err());
+
+        // a recipe that differs in anything is not the same
+        assert!(!r2.same_as(&written));
+    }
+
+    #[test]
+    fn it_parses_budget_sizes() {
+        assert_eq!(parse_budget("1024"), Ok(1024));
+        assert_eq!(parse_budget("10B"), Ok(10));
+        assert_eq!(parse_budget("4kb"), Ok(4 << 10));
+        assert_eq!(parse_budget("3 M"), Ok(3 << 20));
+        assert!(parse_budget("").is_err());
+        assert!(parse_budget("GB").is_err());
+        assert!(parse_budget("1.5GB").is_err());
+    }
+
     #[test]
     fn it_handles_multiple_statements_per_line() {
         let r0 = Recipe::blank(None);
@@ -0,0 +1,217 @@
+use crate::controller::sql::query_graph::{JoinRef, QueryGraph, QueryGraphEdge};
+use nom_sql::{ConditionBase, ConditionExpression, ConditionTree};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+/// Size estimates for a base table, derived from the statistics its domains report.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(in crate::controller) struct TableStats {
+    /// Number of rows in the table.
+    pub(in crate::controller) rows: usize,
+    /// Number of distinct values in each column that we know the key count for.
+    pub(in crate::controller) distinct: HashMap<String, usize>,
+}
+
+/// Statistics for all base tables, keyed by table name.
+pub(in crate::controller) type TableStatistics = HashMap<String, TableStats>;
+
+/// A set of relations that have already been joined together, along with the estimated number of
+/// rows the join produces.
+struct Chain {
+    tables: HashSet<String>,
+    rows: usize,
+}
+
+/// Reorders the join predicates of `qg` to keep the intermediate results small, since every
+/// intermediate join result that a later join looks up into has to be materialized.
+///
+/// We greedily pick the join with the smallest estimated output at each step. Queries that involve
+/// left joins, or tables we have no statistics for, keep their syntactic join order.
+pub(super) fn order_joins(qg: &mut QueryGraph, stats: &TableStatistics) {
+    if qg.join_order.len() < 2 {
+        return;
+    }
+    let has_left_join = qg.edges.values().any(|e| match *e {
+        QueryGraphEdge::LeftJoin(_) => true,
+        _ => false,
+    });
+    let all_known = qg
+        .join_order
+        .iter()
+        .all(|jref| stats.contains_key(&jref.src) && stats.contains_key(&jref.dst));
+    if has_left_join || !all_known {
+        return;
+    }
+
+    let mut chains: Vec<Chain> = Vec::new();
+    let mut remaining = qg.join_order.clone();
+    let mut new_order = Vec::with_capacity(remaining.len());
+
+    while !remaining.is_empty() {
+        // the first candidate wins ties, so equally good plans keep their syntactic order
+        let (best, rows) = remaining
+            .iter()
+            .enumerate()
+            .map(|(i, jref)| (i, estimate_join(qg, jref, &chains, stats)))
+            .min_by_key(|&(_, rows)| rows)
+            .unwrap();
+
+        // all predicates between the same pair of relations are turned into the same join
+        let jref = remaining.remove(best);
+        let (same, rest): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|j| j.src == jref.src && j.dst == jref.dst);
+        remaining = rest;
+
+        let mut tables = HashSet::new();
+        for t in &[&jref.src, &jref.dst] {
+            match chains.iter().position(|c| c.tables.contains(*t)) {
+                Some(i) => tables.extend(chains.swap_remove(i).tables),
+                None => {
+                    tables.insert((*t).clone());
+                }
+            }
+        }
+        chains.push(Chain { tables, rows });
+
+        new_order.push(jref);
+        new_order.extend(same);
+    }
+
+    qg.join_order = new_order;
+}
+
+/// Estimates the number of rows produced by the join that `jref` introduces, given the joins that
+/// have already been placed in `chains`.
+///
+/// For an equi-join of `l` and `r`, we assume the smaller set of join key values is contained in
+/// the larger one, which gives `|l| * |r| / max(distinct(l.key), distinct(r.key))`. Where we do not
+/// know how many distinct values a join column has, we assume it is a key of its table.
+fn estimate_join(
+    qg: &QueryGraph,
+    jref: &JoinRef,
+    chains: &[Chain],
+    stats: &TableStatistics,
+) -> usize {
+    let chain_rows = |table: &str| {
+        chains
+            .iter()
+            .find(|c| c.tables.contains(table))
+            .map(|c| c.rows)
+            .unwrap_or(stats[table].rows)
+    };
+    let in_same_chain = chains
+        .iter()
+        .any(|c| c.tables.contains(&jref.src) && c.tables.contains(&jref.dst));
+    if in_same_chain {
+        // this predicate only filters an existing join
+        return chain_rows(&jref.src);
+    }
+
+    let jp = match qg.edges[&(jref.src.clone(), jref.dst.clone())] {
+        QueryGraphEdge::Join(ref jps) | QueryGraphEdge::LeftJoin(ref jps) => &jps[jref.index],
+        QueryGraphEdge::GroupBy(_) => unreachable!(),
+    };
+    let (src_col, dst_col) = join_columns(jp, &jref.src);
+    let distinct = |table: &str, col: Option<&str>| {
+        let ts = &stats[table];
+        col.and_then(|c| ts.distinct.get(c))
+            .cloned()
+            .unwrap_or(ts.rows)
+    };
+    let d = cmp::max(distinct(&jref.src, src_col), distinct(&jref.dst, dst_col));
+
+    let rows =
+        (chain_rows(&jref.src) as u128 * chain_rows(&jref.dst) as u128) / cmp::max(d, 1) as u128;
+    cmp::min(rows, usize::max_value() as u128) as usize
+}
+
+/// Returns the names of the columns that `jp` joins on, ordered as (`src` column, other column).
+fn join_columns<'a>(jp: &'a ConditionTree, src: &str) -> (Option<&'a str>, Option<&'a str>) {
+    let field = |ce: &'a ConditionExpression| match *ce {
+        ConditionExpression::Base(ConditionBase::Field(ref f)) => Some(f),
+        _ => None,
+    };
+    match (field(&*jp.left), field(&*jp.right)) {
+        (Some(l), Some(r)) if r.table.as_ref().map(String::as_str) == Some(src) => {
+            (Some(r.name.as_str()), Some(l.name.as_str()))
+        }
+        (l, r) => (l.map(|c| c.name.as_str()), r.map(|c| c.name.as_str())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::sql::query_graph::to_query_graph;
+    use crate::controller::sql::query_signature::Signature;
+    use nom_sql::{parser, SqlQuery};
+
+    fn qg(sql: &str) -> QueryGraph {
+        match parser::parse_query(sql).unwrap() {
+            SqlQuery::Select(ref st) => to_query_graph(st).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn table(rows: usize, distinct: &[(&str, usize)]) -> TableStats {
+        TableStats {
+            rows,
+            distinct: distinct.iter().map(|&(c, d)| (c.to_owned(), d)).collect(),
+        }
+    }
+
+    fn tables(qg: &QueryGraph) -> Vec<(String, String)> {
+        qg.join_order
+            .iter()
+            .map(|j| (j.src.clone(), j.dst.clone()))
+            .collect()
+    }
+
+    const SQL: &str = "SELECT a.x FROM a, b, c WHERE a.id = b.a_id AND b.id = c.b_id AND a.x = ?;";
+
+    #[test]
+    fn it_joins_small_tables_first() {
+        let mut stats = TableStatistics::default();
+        stats.insert("a".into(), table(10, &[("id", 10)]));
+        stats.insert(
+            "b".into(),
+            table(1_000_000, &[("id", 1_000_000), ("a_id", 1_000_000)]),
+        );
+        stats.insert("c".into(), table(1_000_000, &[("b_id", 10)]));
+
+        let mut q = qg(SQL);
+        // without statistics, we'd join b and c first
+        assert_eq!(tables(&q)[0], ("b".into(), "c".into()));
+        order_joins(&mut q, &stats);
+        // but a ⋈ b is tiny, whereas b ⋈ c has a million rows that we'd have to materialize
+        assert_eq!(
+            tables(&q),
+            vec![("a".into(), "b".into()), ("b".into(), "c".into())]
+        );
+    }
+
+    #[test]
+    fn it_does_not_change_the_query_signature() {
+        let mut stats = TableStatistics::default();
+        stats.insert("a".into(), table(10, &[("id", 10)]));
+        stats.insert("b".into(), table(1_000_000, &[("a_id", 1_000_000)]));
+        stats.insert("c".into(), table(1_000_000, &[("b_id", 10)]));
+
+        let syntactic = qg(SQL);
+        let mut q = qg(SQL);
+        order_joins(&mut q, &stats);
+        assert_ne!(tables(&q), tables(&syntactic));
+        // so that the same query is still reused however its joins end up being ordered
+        assert_eq!(q.signature().hash, syntactic.signature().hash);
+        assert_eq!(q.exact_hash(), syntactic.exact_hash());
+    }
+
+    #[test]
+    fn it_keeps_syntactic_order_without_statistics() {
+        let mut q = qg(SQL);
+        let syntactic = tables(&q);
+        order_joins(&mut q, &TableStatistics::default());
+        assert_eq!(tables(&q), syntactic);
+    }
+}
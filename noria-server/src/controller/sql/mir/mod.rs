@@ -1,6 +1,6 @@
 use mir::node::{GroupedNodeType, MirNode, MirNodeType};
 use mir::query::MirQuery;
-use mir::{Column, MirNodeRef};
+use mir::{Column, FlowNode, MirNodeRef};
 use noria::DataType;
 use petgraph::graph::NodeIndex;
 // TODO(malte): remove if possible
@@ -107,6 +107,36 @@ pub(super) struct SqlToMirConverter {
     universe: Universe,
 }
 
+/// The mutable parts of a MIR node, as captured by a `MirSnapshot`.
+#[derive(Clone, Debug)]
+struct MirNodeLinks {
+    columns: Vec<Column>,
+    ancestors: Vec<MirNodeRef>,
+    children: Vec<MirNodeRef>,
+    flow_node: Option<FlowNode>,
+}
+
+/// The state of all MIR nodes a `SqlToMirConverter` knows about at some point in time.
+///
+/// MIR nodes are shared between clones of a converter, so cloning the converter is not enough to
+/// undo a migration that was only planned; the snapshot also restores the columns and edges that
+/// new queries added to existing nodes.
+#[derive(Debug)]
+pub(in crate::controller) struct MirSnapshot(Vec<(MirNodeRef, MirNodeLinks)>);
+
+impl MirSnapshot {
+    /// Restore all captured nodes to the state they were in when the snapshot was taken.
+    pub(in crate::controller) fn restore(self) {
+        for (node, links) in self.0 {
+            let mut n = node.borrow_mut();
+            n.columns = links.columns;
+            n.ancestors = links.ancestors;
+            n.children = links.children;
+            n.flow_node = links.flow_node;
+        }
+    }
+}
+
 impl Default for SqlToMirConverter {
     fn default() -> Self {
         SqlToMirConverter {
@@ -154,6 +184,25 @@ impl SqlToMirConverter {
             })
     }
 
+    /// Capture the current state of all MIR nodes known to this converter.
+    pub(super) fn snapshot(&self) -> MirSnapshot {
+        MirSnapshot(
+            self.nodes
+                .values()
+                .map(|node| {
+                    let n = node.borrow();
+                    let links = MirNodeLinks {
+                        columns: n.columns.clone(),
+                        ancestors: n.ancestors.clone(),
+                        children: n.children.clone(),
+                        flow_node: n.flow_node.clone(),
+                    };
+                    (node.clone(), links)
+                })
+                .collect(),
+        )
+    }
+
     pub fn add_nodes(&mut self, nodes: Vec<MirNodeRef>) {
         for node in nodes {
             let node_id = (String::from(node.borrow().name()), self.schema_version);
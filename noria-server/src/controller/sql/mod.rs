@@ -1,3 +1,4 @@
+mod join_planner;
 mod mir;
 mod passes;
 mod query_graph;
@@ -6,6 +7,8 @@ mod query_utils;
 mod reuse;
 pub(super) mod security;
 
+pub(in crate::controller) use self::join_planner::TableStatistics;
+pub(in crate::controller) use self::mir::MirSnapshot;
 use self::mir::SqlToMirConverter;
 use self::query_graph::{to_query_graph, QueryGraph};
 use self::query_signature::Signature;
@@ -15,6 +18,7 @@ use crate::controller::Migration;
 use crate::ReuseConfigType;
 use ::mir::query::{MirQuery, QueryFlowParts};
 use ::mir::reuse as mir_reuse;
+use ::mir::visualize::GraphViz;
 use ::mir::Column;
 use ::mir::MirNodeRef;
 use dataflow::prelude::DataType;
@@ -39,6 +43,17 @@ enum QueryGraphReuse {
     None,
 }
 
+/// How a query was planned to reuse the nodes of the queries that were already in the graph.
+#[derive(Clone, Debug)]
+pub(super) struct QueryReuse {
+    /// The reuse strategy in effect when the query was planned.
+    pub(super) strategy: ReuseConfigType,
+    /// How the query reuses an existing query, if it found one to reuse.
+    pub(super) how: Option<&'static str>,
+    /// The existing data-flow nodes that the query's own nodes were planned onto.
+    pub(super) nodes: Vec<NodeIndex>,
+}
+
 /// Long-lived struct that holds information about the SQL queries that have been incorporated into
 /// the Soup graph `grap`.
 /// The incorporator shares the lifetime of the flow graph it is associated with.
@@ -61,6 +76,11 @@ pub(crate) struct SqlIncorporator {
     schema_version: usize,
 
     reuse_type: ReuseConfigType,
+    /// How each query reuses existing nodes, as of when it was planned.
+    query_reuse: HashMap<String, QueryReuse>,
+
+    /// Base table sizes, used to pick join orders.
+    table_statistics: TableStatistics,
 
     /// Active universes mapped to the group they belong to.
     /// If an user universe, mapped to None.
@@ -86,6 +106,8 @@ impl Default for SqlIncorporator {
             schema_version: 0,
 
             reuse_type: ReuseConfigType::Finkelstein,
+            query_reuse: HashMap::default(),
+            table_statistics: TableStatistics::default(),
             universes: HashMap::default(),
         }
     }
@@ -114,6 +136,11 @@ impl SqlIncorporator {
         self.reuse_type = reuse_type;
     }
 
+    /// Update the base table statistics that future migrations use to order joins.
+    pub(super) fn set_table_statistics(&mut self, stats: TableStatistics) {
+        self.table_statistics = stats;
+    }
+
     /// Incorporates a single query into via the flow graph migration in `mig`. The `query`
     /// argument is a string that holds a parameterized SQL query, and the `name` argument supplies
     /// an optional name for the query. If no `name` is specified, the table name is used in the
@@ -174,6 +201,24 @@ impl SqlIncorporator {
         }
     }
 
+    /// Renders the MIR graph of the query with the given name in GraphViz format.
+    pub(super) fn mir_graphviz(&self, name: &str) -> Option<String> {
+        self.mir_queries
+            .values()
+            .find(|mq| mq.name == name)
+            .and_then(|mq| mq.to_graphviz().ok())
+    }
+
+    /// How the query with the given name reuses existing nodes, if it is a `SELECT` query.
+    pub(super) fn query_reuse(&self, name: &str) -> Option<&QueryReuse> {
+        self.query_reuse.get(name)
+    }
+
+    /// Captures the current state of all MIR nodes, so that a planned migration can be undone.
+    pub(super) fn mir_snapshot(&self) -> MirSnapshot {
+        self.mir_converter.snapshot()
+    }
+
     pub(super) fn is_leaf_address(&self, ni: NodeIndex) -> bool {
         self.leaf_addresses.values().any(|nn| *nn == ni)
     }
@@ -199,6 +244,8 @@ impl SqlIncorporator {
             Err(e) => panic!(e),
         };
 
+        join_planner::order_joins(&mut qg, &self.table_statistics);
+
         trace!(self.log, "QG for \"{}\": {:#?}", query_name, qg);
 
         // if reuse is disabled, we're done
@@ -470,6 +517,7 @@ impl SqlIncorporator {
         let qfp = mir_query_to_flow_parts(&mut combined_mir_query, &mut mig, None);
 
         self.register_query(query_name, None, &combined_mir_query, mig.universe());
+        self.record_reuse(query_name, None, &qfp);
 
         Ok(qfp)
     }
@@ -484,7 +532,13 @@ impl SqlIncorporator {
         mig: &mut Migration,
     ) -> Result<(QueryFlowParts, Option<MirQuery>), String> {
         let (qg, reuse) = self.consider_query_graph(&query_name, mig.universe(), sq);
-        Ok(match reuse {
+        let how = match reuse {
+            QueryGraphReuse::ExactMatch(..) => Some("identical to an existing query"),
+            QueryGraphReuse::ExtendExisting(..) => Some("extends existing queries"),
+            QueryGraphReuse::ReaderOntoExisting(..) => Some("reads from an existing query"),
+            QueryGraphReuse::None => None,
+        };
+        let planned = match reuse {
             QueryGraphReuse::ExactMatch(mn) => {
                 let flow_node = mn.borrow().flow_node.as_ref().unwrap().address();
                 let qfp = QueryFlowParts {
@@ -508,7 +562,19 @@ impl SqlIncorporator {
                 let (qfp, mir) = self.add_query_via_mir(&query_name, sq, qg, is_leaf, mig)?;
                 (qfp, Some(mir))
             }
-        })
+        };
+        self.record_reuse(query_name, how, &planned.0);
+        Ok(planned)
+    }
+
+    /// Remember which existing nodes the query named `query_name` was planned onto, and how.
+    fn record_reuse(&mut self, query_name: &str, how: Option<&'static str>, qfp: &QueryFlowParts) {
+        let reuse = QueryReuse {
+            strategy: self.reuse_type.clone(),
+            how,
+            nodes: qfp.reused_nodes.clone(),
+        };
+        self.query_reuse.insert(query_name.to_owned(), reuse);
     }
 
     fn add_query_via_mir(
@@ -519,7 +585,6 @@ impl SqlIncorporator {
         is_leaf: bool,
         mut mig: &mut Migration,
     ) -> Result<(QueryFlowParts, MirQuery), String> {
-        use ::mir::visualize::GraphViz;
         let universe = mig.universe();
         // no QG-level reuse possible, so we'll build a new query.
         // first, compute the MIR representation of the SQL query
@@ -571,6 +636,7 @@ impl SqlIncorporator {
             .leaf_addresses
             .remove(query_name)
             .expect("tried to remove unknown query");
+        self.query_reuse.remove(query_name);
 
         let qg_hash = self
             .named_queries
@@ -680,7 +746,6 @@ impl SqlIncorporator {
         mut mig: &mut Migration,
     ) -> Result<QueryFlowParts, String> {
         use ::mir::reuse::merge_mir_for_queries;
-        use ::mir::visualize::GraphViz;
         let universe = mig.universe();
 
         // no QG-level reuse possible, so we'll build a new query.
@@ -26,15 +26,28 @@ pub enum CoordinationPayload {
         read_listen_addr: SocketAddr,
         /// Which log files are stored locally on the worker.
         log_files: Vec<String>,
+        /// The number of cores on the worker's machine.
+        cpus: usize,
+        /// The labels that placement constraints can require of the worker.
+        labels: Vec<String>,
+        /// The domain shards that the worker still runs, started by an earlier controller.
+        domains: Vec<DomainDescriptor>,
     },
     /// Worker going offline.
     Deregister,
     /// Worker is still alive.
-    Heartbeat,
+    Heartbeat {
+        /// The size, in bytes, of the state of the worker's domains.
+        state_size: usize,
+        /// The number of read requests that each reader shard on the worker has served so far.
+        reads: HashMap<(NodeIndex, usize), usize>,
+    },
     /// Assign a new domain for a worker to run.
     AssignDomain(DomainBuilder),
     /// Remove a running domain from a worker.
     RemoveDomain,
+    /// The worker's domain shards have all moved to other workers, so it should leave.
+    Drained,
     /// Domain connectivity gossip.
     DomainBooted(DomainDescriptor),
     /// Create a new security universe.
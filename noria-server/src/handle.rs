@@ -62,6 +62,30 @@ impl<A: Authority + 'static> Handle<A> {
         }
     }
 
+    /// Stop this instance's controller, as if it had failed, but leave its worker and the domains
+    /// it runs going, so that another instance's controller takes over and adopts them.
+    #[cfg(test)]
+    pub(super) fn stop_controller(&mut self) {
+        self.event_tx
+            .as_mut()
+            .unwrap()
+            .send(Event::StopController)
+            .unwrap();
+    }
+
+    /// Remove the ad-hoc queries that will have gone unused for longer than the idle timeout once
+    /// `after` has passed, rather than wait for the controller to do so on a worker heartbeat.
+    #[cfg(test)]
+    pub(super) async fn remove_idle_adhoc_queries(&mut self, after: std::time::Duration) {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.event_tx
+            .as_mut()
+            .unwrap()
+            .send(Event::RemoveIdleAdhocQueries(after, tx))
+            .unwrap();
+        rx.await.unwrap();
+    }
+
     #[doc(hidden)]
     pub async fn migrate<F, T>(&mut self, f: F) -> T
     where
@@ -82,7 +106,7 @@ impl<A: Authority + 'static> Handle<A> {
             .send(Event::ManualMigration { f: b, done: fin_tx })
             .unwrap();
 
-        fin_rx.await.unwrap();
+        fin_rx.await.expect("migration failed");
         ret_rx.await.unwrap()
     }
 
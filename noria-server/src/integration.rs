@@ -8,8 +8,10 @@ use dataflow::ops::join::JoinSource::*;
 use dataflow::ops::join::{Join, JoinSource, JoinType};
 use dataflow::ops::project::Project;
 use dataflow::ops::union::Union;
-use dataflow::{DurabilityMode, PersistenceParameters};
+use dataflow::{DurabilityMode, PartialStorage, PersistenceParameters};
 use noria::consensus::LocalAuthority;
+use noria::debug::status::ClusterStatus;
+use noria::internal::{DomainIndex, MaterializationStatus};
 use noria::DataType;
 
 use std::collections::HashMap;
@@ -574,6 +576,881 @@ async fn it_works_with_sql_recipe() {
     assert_eq!(result[0][0], 2.into());
 }
 
+#[tokio::test(threaded_scheduler)]
+async fn it_expires_rows_past_ttl() {
+    let mut g = start_simple("it_expires_rows_past_ttl").await;
+    let sql = "
+        CREATE TABLE Click (id int, page varchar(255), at bigint, PRIMARY KEY(id)) \
+            TTL 10 SECONDS ON at;
+        CREATE TABLE Visit (page varchar(255), at timestamp) TTL 10 SECONDS ON at;
+        QUERY ClickCount: SELECT COUNT(*) FROM Click WHERE page = ?;
+        QUERY VisitCount: SELECT COUNT(*) FROM Visit WHERE page = ?;
+    ";
+    g.install_recipe(sql).await.unwrap();
+
+    let mut clicks = g.table("Click").await.unwrap();
+    let mut visits = g.table("Visit").await.unwrap();
+    let now = chrono::Local::now();
+    let hour_ago = now - chrono::Duration::hours(1);
+    for (id, at) in vec![hour_ago, hour_ago, now].into_iter().enumerate() {
+        clicks
+            .insert(vec![id.into(), "index".into(), at.timestamp().into()])
+            .await
+            .unwrap();
+        visits
+            .insert(vec!["index".into(), DataType::Timestamp(at.naive_local())])
+            .await
+            .unwrap();
+    }
+
+    // rows are checked for expiry every second
+    tokio::time::delay_for(Duration::from_secs(2)).await;
+    sleep().await;
+
+    let mut click_count = g.view("ClickCount").await.unwrap();
+    assert_eq!(
+        click_count.lookup(&["index".into()], true).await.unwrap(),
+        vec![vec![1.into()]]
+    );
+    let mut visit_count = g.view("VisitCount").await.unwrap();
+    assert_eq!(
+        visit_count.lookup(&["index".into()], true).await.unwrap(),
+        vec![vec![1.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_reshards_tables() {
+    let mut g = start_simple("it_reshards_tables").await;
+    let sql = "
+        CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+        QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;
+        QUERY BrandCount: SELECT COUNT(*) FROM Car WHERE brand = ?;
+    ";
+    g.install_recipe(sql).await.unwrap();
+
+    let mut cars = g.table("Car").await.unwrap();
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    let mut brand_count = g.view("BrandCount").await.unwrap();
+    for id in 0..8 {
+        let brand = if id % 2 == 0 { "Volvo" } else { "Saab" };
+        cars.insert(vec![id.into(), brand.into()]).await.unwrap();
+    }
+    sleep().await;
+
+    g.reshard("Car", 3).await.unwrap();
+    assert!(g.reshard("Truck", 3).await.is_err());
+    assert!(g.reshard("Car", 1).await.is_err());
+
+    // tables and views that were made before pick up the new shards
+    for id in 8..12 {
+        cars.insert(vec![id.into(), "Volvo".into()]).await.unwrap();
+    }
+    sleep().await;
+
+    let mut ids = by_brand.lookup(&["Volvo".into()], true).await.unwrap();
+    ids.sort();
+    let expected: Vec<Vec<DataType>> = vec![0, 2, 4, 6, 8, 9, 10, 11]
+        .into_iter()
+        .map(|id| vec![id.into()])
+        .collect();
+    assert_eq!(ids, expected);
+    assert_eq!(
+        brand_count.lookup(&["Saab".into()], true).await.unwrap(),
+        vec![vec![4.into()]]
+    );
+
+    // and so do new ones
+    let mut brand_count = g.view("BrandCount").await.unwrap();
+    assert_eq!(
+        brand_count.lookup(&["Volvo".into()], true).await.unwrap(),
+        vec![vec![8.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_keeps_permanent_tables_sharded_as_configured() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("it_keeps_permanent_tables_sharded_as_configured");
+    let mut g = Builder::default();
+    g.set_sharding(DEFAULT_SHARDING);
+    g.set_persistence(PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    ));
+    let mut g = g.start_local().await.unwrap().0;
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    )
+    .await
+    .unwrap();
+    let mut cars = g.table("Car").await.unwrap();
+    cars.insert(vec![1.into(), "Volvo".into()]).await.unwrap();
+
+    // a restart would open the RocksDB databases by shard index, across the configured shards
+    assert!(g.reshard("Car", 3).await.is_err());
+
+    // and the table carries on with the shards it had
+    cars.insert(vec![2.into(), "Volvo".into()]).await.unwrap();
+    sleep().await;
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    let mut ids = by_brand.lookup(&["Volvo".into()], true).await.unwrap();
+    ids.sort();
+    assert_eq!(ids, vec![vec![1.into()], vec![2.into()]]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_only_drains_known_workers() {
+    let mut g = start_simple("it_only_drains_known_workers").await;
+    g.install_recipe("CREATE TABLE Car (id int, PRIMARY KEY(id));")
+        .await
+        .unwrap();
+
+    assert!(g
+        .drain_worker("127.0.0.1:1".parse().unwrap())
+        .await
+        .is_err());
+
+    // nothing was drained
+    let mut cars = g.table("Car").await.unwrap();
+    cars.insert(vec![1.into()]).await.unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_reports_cluster_status() {
+    let mut g = start_simple("it_reports_cluster_status").await;
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    )
+    .await
+    .unwrap();
+
+    let mut cars = g.table("Car").await.unwrap();
+    cars.insert(vec![1.into(), "Volvo".into()]).await.unwrap();
+    sleep().await;
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    for _ in 0..3 {
+        by_brand.lookup(&["Volvo".into()], true).await.unwrap();
+    }
+
+    // read counts arrive with the worker's next heartbeat
+    tokio::time::delay_for(Duration::from_secs(2)).await;
+    let status = g.cluster_status().await.unwrap();
+    assert_eq!(status.workers.len(), 1);
+    let worker = &status.workers[0];
+    assert!(worker.healthy);
+    assert!(!worker.draining);
+
+    // the only worker runs every domain, and all of them answered
+    assert!(!worker.shards.is_empty());
+    assert!(worker
+        .shards
+        .iter()
+        .all(|s| s.mem_size.is_some() && s.replay_backlog == Some(0)));
+
+    let reader = worker
+        .readers
+        .iter()
+        .find(|r| r.name == "CarsByBrand")
+        .unwrap();
+    assert_eq!(reader.requests, 3);
+}
+
+// Starts two instances that share an authority. Neither runs any migration until both have
+// joined.
+async fn start_pair(
+    params: PersistenceParameters,
+) -> (Handle<LocalAuthority>, Handle<LocalAuthority>) {
+    let authority = Arc::new(LocalAuthority::new());
+    let mut builder = Builder::default();
+    builder.set_sharding(DEFAULT_SHARDING);
+    builder.set_quorum(2);
+    builder.set_persistence(params);
+    let (a, _) = builder.start(authority.clone()).await.unwrap();
+    let (mut b, _) = builder.start(authority).await.unwrap();
+    loop {
+        if let Ok(status) = b.cluster_status().await {
+            if status.workers.len() == 2 {
+                break;
+            }
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    (a, b)
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_moves_domains_under_write_load() {
+    let (_a, mut g) = start_pair(get_persistence_params("it_moves_domains_under_write_load")).await;
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    )
+    .await
+    .unwrap();
+
+    // writes keep coming while the domains move; every one that is acknowledged must be visible
+    // afterwards
+    let mut cars = g.table("Car").await.unwrap();
+    let writer = tokio::spawn(async move {
+        let mut acked = Vec::new();
+        for id in 0..500 {
+            if cars.insert(vec![id.into(), "Volvo".into()]).await.is_err() {
+                break;
+            }
+            acked.push(id);
+        }
+        acked
+    });
+    tokio::time::delay_for(Duration::from_millis(20)).await;
+
+    let status = g.cluster_status().await.unwrap();
+    let from = status
+        .workers
+        .iter()
+        .max_by_key(|w| w.shards.len())
+        .unwrap();
+    let to = status.workers.iter().find(|w| w.id != from.id).unwrap().id;
+    for s in &from.shards {
+        g.move_domain(DomainIndex::from(s.domain), s.shard, to)
+            .await
+            .unwrap();
+    }
+    let acked = writer.await.unwrap();
+    assert!(!acked.is_empty());
+    sleep().await;
+
+    // everything now runs on the other worker
+    let status = g.cluster_status().await.unwrap();
+    assert!(status
+        .workers
+        .iter()
+        .find(|w| w.id == from.id)
+        .unwrap()
+        .shards
+        .is_empty());
+
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    let ids = by_brand.lookup(&["Volvo".into()], true).await.unwrap();
+    for id in acked {
+        assert!(
+            ids.contains(&vec![id.into()]),
+            "acknowledged write {} was lost",
+            id
+        );
+    }
+
+    // and new handles write and read through the moved domains
+    let mut cars = g.table("Car").await.unwrap();
+    cars.insert(vec![1000.into(), "Saab".into()]).await.unwrap();
+    sleep().await;
+    assert_eq!(
+        by_brand.lookup(&["Saab".into()], true).await.unwrap(),
+        vec![vec![1000.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_moves_domains_back_and_forth() {
+    // with storage on disk, the shards share it when they move within the machine, and have
+    // their rows sent otherwise
+    for mode in vec![DurabilityMode::DeleteOnExit, DurabilityMode::Logged] {
+        let dir = tempfile::tempdir().unwrap();
+        let mut params = PersistenceParameters::default();
+        params.mode = mode.clone();
+        params.log_prefix = format!("it_moves_domains_back_and_forth_{:?}", mode);
+        params.log_dir = Some(dir.path().to_owned());
+        let (_a, mut g) = start_pair(params).await;
+        g.install_recipe(
+            "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+             QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+        )
+        .await
+        .unwrap();
+        let mut cars = g.table("Car").await.unwrap();
+        for id in 0..100 {
+            cars.insert(vec![id.into(), "Volvo".into()]).await.unwrap();
+        }
+
+        let status = g.cluster_status().await.unwrap();
+        let from = status
+            .workers
+            .iter()
+            .max_by_key(|w| w.shards.len())
+            .unwrap();
+        let to = status.workers.iter().find(|w| w.id != from.id).unwrap().id;
+        let shards: Vec<_> = from
+            .shards
+            .iter()
+            .map(|s| (DomainIndex::from(s.domain), s.shard))
+            .collect();
+        let from = from.id;
+        for &(di, shard) in &shards {
+            g.move_domain(di, shard, to).await.unwrap();
+        }
+
+        // written while the shards are away
+        let mut cars = g.table("Car").await.unwrap();
+        for id in 100..200 {
+            cars.insert(vec![id.into(), "Volvo".into()]).await.unwrap();
+        }
+        sleep().await;
+
+        for &(di, shard) in &shards {
+            g.move_domain(di, shard, from).await.unwrap();
+        }
+        sleep().await;
+
+        let mut by_brand = g.view("CarsByBrand").await.unwrap();
+        let mut ids = by_brand.lookup(&["Volvo".into()], true).await.unwrap();
+        ids.sort();
+        let expected: Vec<Vec<DataType>> = (0..200).map(|id| vec![id.into()]).collect();
+        assert_eq!(
+            ids, expected,
+            "rows were lost moving back in {:?} mode",
+            mode
+        );
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_drains_workers_under_write_load() {
+    let (_a, mut g) =
+        start_pair(get_persistence_params("it_drains_workers_under_write_load")).await;
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    )
+    .await
+    .unwrap();
+
+    let mut cars = g.table("Car").await.unwrap();
+    let writer = tokio::spawn(async move {
+        let mut acked = Vec::new();
+        for id in 0..500 {
+            if cars.insert(vec![id.into(), "Volvo".into()]).await.is_err() {
+                break;
+            }
+            acked.push(id);
+        }
+        acked
+    });
+    tokio::time::delay_for(Duration::from_millis(20)).await;
+
+    let status = g.cluster_status().await.unwrap();
+    let drained = status
+        .workers
+        .iter()
+        .max_by_key(|w| w.shards.len())
+        .unwrap()
+        .id;
+    g.drain_worker(drained).await.unwrap();
+    let acked = writer.await.unwrap();
+    assert!(!acked.is_empty());
+    sleep().await;
+
+    // nothing runs on the drained worker anymore
+    let status = g.cluster_status().await.unwrap();
+    assert!(status
+        .workers
+        .iter()
+        .filter(|w| w.id == drained)
+        .all(|w| w.shards.is_empty()));
+
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    let ids = by_brand.lookup(&["Volvo".into()], true).await.unwrap();
+    for id in acked {
+        assert!(
+            ids.contains(&vec![id.into()]),
+            "acknowledged write {} was lost",
+            id
+        );
+    }
+
+    let mut cars = g.table("Car").await.unwrap();
+    cars.insert(vec![1000.into(), "Saab".into()]).await.unwrap();
+    sleep().await;
+    assert_eq!(
+        by_brand.lookup(&["Saab".into()], true).await.unwrap(),
+        vec![vec![1000.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_recovers_from_failed_workers() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut params = PersistenceParameters::default();
+    params.mode = DurabilityMode::Logged;
+    params.log_prefix = String::from("it_recovers_from_failed_workers");
+    params.log_dir = Some(dir.path().to_owned());
+    let (mut g, b) = start_pair(params).await;
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    )
+    .await
+    .unwrap();
+    let mut cars = g.table("Car").await.unwrap();
+    for id in 0..10 {
+        cars.insert(vec![id.into(), "Volvo".into()]).await.unwrap();
+    }
+    sleep().await;
+
+    // the second instance's worker goes away along with the domain shards it ran, which are
+    // started anew on the first once its heartbeats are missed
+    drop(b);
+    let expected: Vec<Vec<DataType>> = (0..10).map(|id| vec![id.into()]).collect();
+    let mut recovered = false;
+    for _ in 0..60 {
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        let status = match g.cluster_status().await {
+            Ok(status) => status,
+            Err(_) => continue,
+        };
+        if status
+            .workers
+            .iter()
+            .any(|w| !w.healthy && !w.shards.is_empty())
+        {
+            continue;
+        }
+        let mut by_brand = match g.view("CarsByBrand").await {
+            Ok(view) => view,
+            Err(_) => continue,
+        };
+        // a view that still points at the failed worker doesn't answer
+        let lookup = by_brand.lookup(&["Volvo".into()], true);
+        if let Ok(Ok(mut ids)) = tokio::time::timeout(Duration::from_secs(1), lookup).await {
+            ids.sort();
+            if ids == expected {
+                recovered = true;
+                break;
+            }
+        }
+    }
+    assert!(recovered, "reads never saw every row again");
+
+    // and the recovered domains take writes again
+    let mut cars = g.table("Car").await.unwrap();
+    cars.insert(vec![10.into(), "Saab".into()]).await.unwrap();
+    sleep().await;
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    assert_eq!(
+        by_brand.lookup(&["Saab".into()], true).await.unwrap(),
+        vec![vec![10.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_fails_over_to_standby_controllers() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut params = PersistenceParameters::default();
+    params.mode = DurabilityMode::Logged;
+    params.log_prefix = String::from("it_fails_over_to_standby_controllers");
+    params.log_dir = Some(dir.path().to_owned());
+    let (mut a, mut g) = start_pair(params).await;
+    a.install_recipe("CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+    let mut cars = a.table("Car").await.unwrap();
+    for id in 0..10 {
+        cars.insert(vec![id.into(), "Volvo".into()]).await.unwrap();
+    }
+    a.extend_recipe("QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;")
+        .await
+        .unwrap();
+    // a query that comes and goes leaves the journal of changes to adopt compacted
+    a.extend_recipe("QUERY BrandById: SELECT brand FROM Car WHERE id = ?;")
+        .await
+        .unwrap();
+    a.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));\n\
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    )
+    .await
+    .unwrap();
+
+    // where every domain shard runs, which is still where it runs if the domains are adopted
+    // rather than built anew
+    let shards = |status: ClusterStatus| -> Vec<_> {
+        let mut shards: Vec<_> = status
+            .workers
+            .into_iter()
+            .flat_map(|w| w.shards)
+            .map(|s| (s.domain, s.shard, s.addr))
+            .collect();
+        shards.sort();
+        shards
+    };
+    let before = shards(a.cluster_status().await.unwrap());
+    assert!(!before.is_empty());
+    assert!(before.iter().all(|&(_, _, addr)| addr.is_some()));
+
+    // the first instance runs the controller, which goes away right after it acknowledged the
+    // query, while the first instance's worker keeps running its domains
+    a.stop_controller();
+    let expected: Vec<Vec<DataType>> = (0..10).map(|id| vec![id.into()]).collect();
+    let mut recovered = false;
+    for _ in 0..60 {
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        let mut by_brand = match g.view("CarsByBrand").await {
+            Ok(view) => view,
+            Err(_) => continue,
+        };
+        let lookup = by_brand.lookup(&["Volvo".into()], true);
+        if let Ok(Ok(mut ids)) = tokio::time::timeout(Duration::from_secs(1), lookup).await {
+            ids.sort();
+            if ids == expected {
+                recovered = true;
+                break;
+            }
+        }
+    }
+    assert!(recovered, "the standby controller never served every row");
+    let mut adopted = false;
+    for _ in 0..20 {
+        let status = g.cluster_status().await.unwrap();
+        if status.workers.len() == 2 {
+            assert_eq!(shards(status), before);
+            adopted = true;
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+    assert!(
+        adopted,
+        "the first instance's worker never joined the standby controller"
+    );
+
+    let mut cars = g.table("Car").await.unwrap();
+    cars.insert(vec![10.into(), "Saab".into()]).await.unwrap();
+    sleep().await;
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    assert_eq!(
+        by_brand.lookup(&["Saab".into()], true).await.unwrap(),
+        vec![vec![10.into()]]
+    );
+
+    // the adopted recipe can be changed further
+    assert!(g.view("BrandById").await.is_err());
+    g.extend_recipe("QUERY BrandById: SELECT brand FROM Car WHERE id = ?;")
+        .await
+        .unwrap();
+    let mut by_id = g.view("BrandById").await.unwrap();
+    assert_eq!(
+        by_id.lookup(&[10.into()], true).await.unwrap(),
+        vec![vec!["Saab".into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_serves_reads_from_replicas() {
+    let mut g = start_simple("it_serves_reads_from_replicas").await;
+    let table = "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));";
+    let sql = format!(
+        "{}\nQUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+        table
+    );
+    g.install_recipe(&sql).await.unwrap();
+
+    let mut cars = g.table("Car").await.unwrap();
+    for id in 0..4 {
+        cars.insert(vec![id.into(), "Volvo".into()]).await.unwrap();
+    }
+    sleep().await;
+
+    g.set_read_replicas("CarsByBrand", 3).await.unwrap();
+    assert!(g.set_read_replicas("CarsByBrand", 0).await.is_err());
+    assert!(g.set_read_replicas("TrucksByBrand", 2).await.is_err());
+    // the replicas serve the same view
+    assert_eq!(g.outputs().await.unwrap().len(), 1);
+
+    // every reader has the rows written before and after the replicas were added
+    cars.insert(vec![4.into(), "Volvo".into()]).await.unwrap();
+    sleep().await;
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    let expected: Vec<Vec<DataType>> = (0..5).map(|id| vec![id.into()]).collect();
+    for _ in 0..6 {
+        let mut ids = by_brand.lookup(&["Volvo".into()], true).await.unwrap();
+        ids.sort();
+        assert_eq!(ids, expected);
+    }
+    assert_eq!(by_brand.len().await.unwrap(), 5);
+
+    // going back to a single reader leaves the view readable
+    g.set_read_replicas("CarsByBrand", 1).await.unwrap();
+    let mut by_brand = g.view("CarsByBrand").await.unwrap();
+    let mut ids = by_brand.lookup(&["Volvo".into()], true).await.unwrap();
+    ids.sort();
+    assert_eq!(ids, expected);
+
+    // and a query with replicas can be removed
+    g.set_read_replicas("CarsByBrand", 2).await.unwrap();
+    g.install_recipe(table).await.unwrap();
+    assert_eq!(g.outputs().await.unwrap().len(), 0);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_installs_adhoc_queries() {
+    let mut g = start_simple("it_installs_adhoc_queries").await;
+    g.install_recipe("CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+    assert_eq!(g.outputs().await.unwrap().len(), 0);
+
+    let mut mutator = g.table("Car").await.unwrap();
+    mutator
+        .insert(vec![1.into(), "Volvo".into()])
+        .await
+        .unwrap();
+    sleep().await;
+
+    let mut getter = g
+        .query("SELECT id, brand FROM Car WHERE id = ?")
+        .await
+        .unwrap();
+    assert_eq!(g.outputs().await.unwrap().len(), 1);
+    assert_eq!(
+        getter.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), "Volvo".into()]]
+    );
+
+    // asking again should reuse the existing view
+    let _ = g
+        .query("SELECT id, brand FROM Car WHERE id = ?;")
+        .await
+        .unwrap();
+    assert_eq!(g.outputs().await.unwrap().len(), 1);
+
+    // so should asking for a query that is already named in the recipe
+    g.extend_recipe("QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;")
+        .await
+        .unwrap();
+    let mut getter = g.query("SELECT id FROM Car WHERE brand = ?").await.unwrap();
+    assert_eq!(g.outputs().await.unwrap().len(), 2);
+    assert_eq!(
+        getter.lookup(&["Volvo".into()], true).await.unwrap(),
+        vec![vec![1.into()]]
+    );
+
+    assert!(g
+        .query("INSERT INTO Car (id, brand) VALUES (2, 'Saab')")
+        .await
+        .is_err());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_removes_idle_adhoc_queries() {
+    let mut b = Builder::default();
+    b.set_sharding(DEFAULT_SHARDING);
+    b.set_persistence(get_persistence_params("it_removes_idle_adhoc_queries"));
+    b.set_adhoc_query_idle_timeout(Some(Duration::from_secs(60)));
+    let mut g = b.start_local().await.unwrap().0;
+    g.install_recipe("CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+
+    let _ = g
+        .query("SELECT id, brand FROM Car WHERE id = ?")
+        .await
+        .unwrap();
+    assert_eq!(g.outputs().await.unwrap().len(), 1);
+
+    // the query is kept until it has been idle for longer than the timeout
+    g.remove_idle_adhoc_queries(Duration::from_secs(30)).await;
+    assert_eq!(g.outputs().await.unwrap().len(), 1);
+    g.remove_idle_adhoc_queries(Duration::from_secs(61)).await;
+    assert_eq!(g.outputs().await.unwrap().len(), 0);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_keeps_adhoc_queries_that_are_read() {
+    let mut b = Builder::default();
+    b.set_sharding(DEFAULT_SHARDING);
+    b.set_persistence(get_persistence_params(
+        "it_keeps_adhoc_queries_that_are_read",
+    ));
+    b.set_adhoc_query_idle_timeout(Some(Duration::from_secs(2)));
+    let mut g = b.start_local().await.unwrap().0;
+    g.install_recipe("CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+
+    let mut by_id = g
+        .query("SELECT id, brand FROM Car WHERE id = ?")
+        .await
+        .unwrap();
+    for _ in 0..25 {
+        by_id.lookup(&[1.into()], true).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+    }
+    // reads kept the query around for longer than the idle timeout
+    assert_eq!(g.outputs().await.unwrap().len(), 1);
+
+    tokio::time::delay_for(Duration::from_secs(5)).await;
+    assert_eq!(g.outputs().await.unwrap().len(), 0);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_explains_queries() {
+    let mut g = start_simple("it_explains_queries").await;
+    g.install_recipe("CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+
+    let mut mutator = g.table("Car").await.unwrap();
+    mutator
+        .insert(vec![1.into(), "Volvo".into()])
+        .await
+        .unwrap();
+    mutator.insert(vec![2.into(), "Saab".into()]).await.unwrap();
+    sleep().await;
+
+    let sql = "SELECT id FROM Car WHERE brand = ?";
+    let e = g.explain(&format!("EXPLAIN {}", sql)).await.unwrap();
+    assert!(!e.installed);
+    assert!(e.mir.is_some());
+    // the base table is reused, and everything below it is new
+    assert!(e.nodes.iter().any(|n| n.name == "Car" && !n.new));
+    assert_eq!(
+        e.nodes
+            .iter()
+            .find(|n| n.name == "Car")
+            .unwrap()
+            .estimated_rows,
+        Some(2)
+    );
+    assert!(e.nodes.iter().any(|n| n.new));
+    // there is no query to reuse yet, only the base table to build on
+    assert_eq!(e.reuse_strategy, "finkelstein");
+    assert_eq!(e.reuse, None);
+    let car = e.nodes.iter().find(|n| n.name == "Car").unwrap().node;
+    assert!(e.reused_nodes.contains(&car));
+    // explaining must not install anything
+    assert_eq!(g.outputs().await.unwrap().len(), 0);
+
+    g.extend_recipe(&format!("QUERY CarsByBrand: {};", sql))
+        .await
+        .unwrap();
+    let e = g.explain(sql).await.unwrap();
+    assert!(e.installed);
+    assert_eq!(e.name, "CarsByBrand");
+    assert!(e.nodes.iter().all(|n| !n.new));
+    assert_eq!(e.estimated_new_rows(), 0);
+    assert_eq!(e.reuse_strategy, "finkelstein");
+
+    // the same query, written differently, reuses the installed one
+    let e = g
+        .explain("SELECT Car.id FROM Car WHERE Car.brand = ?")
+        .await
+        .unwrap();
+    assert!(!e.installed);
+    assert!(e.reuse.is_some());
+    assert!(!e.reused_nodes.is_empty());
+    for ni in &e.reused_nodes {
+        assert!(e.nodes.iter().any(|n| n.node == *ni && !n.new));
+    }
+
+    assert!(g.explain("DELETE FROM Car WHERE id = 1").await.is_err());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_plans_recipes_without_applying_them() {
+    let mut g = start_simple("it_plans_recipes_without_applying_them").await;
+    g.install_recipe("CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+
+    let plan = g
+        .extend_recipe_dry_run("QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;")
+        .await
+        .unwrap();
+    assert_eq!(plan.activation.expressions_added, 1);
+    assert!(plan.activation.new_nodes.contains_key("CarsByBrand"));
+    assert!(plan.nodes.iter().all(|n| n.new));
+    assert!(plan.nodes.iter().any(|n| n.name == "CarsByBrand"));
+    assert_eq!(g.outputs().await.unwrap().len(), 0);
+
+    let plan = g
+        .install_recipe_dry_run("CREATE TABLE Bike (id int, PRIMARY KEY(id));")
+        .await
+        .unwrap();
+    assert_eq!(plan.activation.expressions_removed, 1);
+    assert!(plan.nodes.iter().any(|n| n.name == "Bike"));
+    assert_eq!(g.inputs().await.unwrap().len(), 1);
+
+    // a recipe that fails to activate is rejected before it reaches the graph
+    assert!(g
+        .extend_recipe_dry_run("QUERY Broken: SELECT id FROM Truck;")
+        .await
+        .is_err());
+    assert!(g
+        .extend_recipe("QUERY Broken: SELECT id FROM Truck;")
+        .await
+        .is_err());
+
+    // and leaves the existing recipe intact
+    g.extend_recipe("QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;")
+        .await
+        .unwrap();
+    let mut mutator = g.table("Car").await.unwrap();
+    mutator
+        .insert(vec![1.into(), "Volvo".into()])
+        .await
+        .unwrap();
+    sleep().await;
+    let mut getter = g.view("CarsByBrand").await.unwrap();
+    assert_eq!(
+        getter.lookup(&["Volvo".into()], true).await.unwrap(),
+        vec![vec![1.into()]]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_abandons_invalid_migrations() {
+    use futures_util::FutureExt;
+    use std::panic::AssertUnwindSafe;
+
+    let mut g = start_simple("it_abandons_invalid_migrations").await;
+    let a = g
+        .migrate(|mig| mig.add_base("a", &["a", "b"], Base::new(vec![]).with_key(vec![0])))
+        .await;
+
+    // base tables are always fully materialized, so they cannot be placed beyond the
+    // materialization frontier
+    let invalid = AssertUnwindSafe(g.migrate(|mig| {
+        let b = mig.add_base("b", &["a", "b"], Base::new(vec![]).with_key(vec![0]));
+        mig.mark_shallow(b);
+    }))
+    .catch_unwind()
+    .await;
+    assert!(invalid.is_err());
+    assert!(!g.inputs().await.unwrap().contains_key("b"));
+
+    // the abandoned migration left nothing behind that gets in the way of later ones
+    g.migrate(move |mig| {
+        let c = mig.add_ingredient("c", &["a", "b"], Identity::new(a));
+        mig.maintain_anonymous(c, &[0]);
+    })
+    .await;
+
+    let mut muta = g.table("a").await.unwrap();
+    muta.insert(vec![1.into(), 2.into()]).await.unwrap();
+    sleep().await;
+
+    let mut cq = g.view("c").await.unwrap();
+    assert_eq!(
+        cq.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+}
+
 #[tokio::test(threaded_scheduler)]
 async fn it_works_with_vote() {
     let mut g = start_simple("it_works_with_vote").await;
@@ -716,86 +1593,366 @@ async fn forced_shuffle_despite_same_shard() {
         .await
         .unwrap();
 
-    // Let writes propagate:
-    sleep().await;
+    // Let writes propagate:
+    sleep().await;
+
+    // Retrieve the result of the count query:
+    let result = getter.lookup(&[cid.into()], true).await.unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0][1], price.into());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn double_shuffle() {
+    let mut g = start_simple("double_shuffle").await;
+    let sql = "
+        CREATE TABLE Car (cid int, pid int, PRIMARY KEY(cid));
+        CREATE TABLE Price (pid int, price int, PRIMARY KEY(pid));
+        QUERY CarPrice: SELECT cid, price FROM Car \
+            JOIN Price ON Car.pid = Price.pid WHERE cid = ?;
+    ";
+    g.install_recipe(sql).await.unwrap();
+
+    let mut car_mutator = g.table("Car").await.unwrap();
+    let mut price_mutator = g.table("Price").await.unwrap();
+    let mut getter = g.view("CarPrice").await.unwrap();
+    let cid = 1;
+    let pid = 1;
+    let price = 100;
+
+    price_mutator
+        .insert(vec![pid.into(), price.into()])
+        .await
+        .unwrap();
+    car_mutator
+        .insert(vec![cid.into(), pid.into()])
+        .await
+        .unwrap();
+
+    // Let writes propagate:
+    sleep().await;
+
+    // Retrieve the result of the count query:
+    let result = getter.lookup(&[cid.into()], true).await.unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0][1], price.into());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_works_with_arithmetic_aliases() {
+    let mut g = start_simple("it_works_with_arithmetic_aliases").await;
+    let sql = "
+        CREATE TABLE Price (pid int, cent_price int, PRIMARY KEY(pid));
+        ModPrice: SELECT pid, cent_price / 100 AS price FROM Price;
+        QUERY AltPrice: SELECT pid, price FROM ModPrice WHERE pid = ?;
+    ";
+    g.install_recipe(sql).await.unwrap();
+
+    let mut price_mutator = g.table("Price").await.unwrap();
+    let mut getter = g.view("AltPrice").await.unwrap();
+    let pid = 1;
+    let price = 10000;
+    price_mutator
+        .insert(vec![pid.into(), price.into()])
+        .await
+        .unwrap();
+
+    // Let writes propagate:
+    sleep().await;
+
+    // Retrieve the result of the count query:
+    let result = getter.lookup(&[pid.into()], true).await.unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0][1], (price / 100).into());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_recovers_persisted_bases() {
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("it_recovers_persisted_bases");
+    let persistence_params = PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    );
+
+    {
+        let mut g = Builder::default();
+        g.set_persistence(persistence_params.clone());
+        let (mut g, done) = g.start(authority.clone()).await.unwrap();
+
+        {
+            let sql = "
+            CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+            QUERY CarPrice: SELECT price FROM Car WHERE id = ?;
+        ";
+            g.install_recipe(sql).await.unwrap();
+
+            let mut mutator = g.table("Car").await.unwrap();
+
+            for i in 1..10 {
+                let price = i * 10;
+                mutator.insert(vec![i.into(), price.into()]).await.unwrap();
+            }
+        }
+
+        // Let writes propagate:
+        sleep().await;
+        drop(g);
+        done.await;
+    }
+
+    let mut g = Builder::default();
+    g.set_persistence(persistence_params);
+    let (mut g, done) = g.start(authority.clone()).await.unwrap();
+    {
+        let mut getter = g.view("CarPrice").await.unwrap();
+
+        // Make sure that the new graph contains the old writes
+        for i in 1..10 {
+            let price = i * 10;
+            let result = getter.lookup(&[i.into()], true).await.unwrap();
+            assert_eq!(result.len(), 1);
+            assert_eq!(result[0][0], price.into());
+        }
+    }
+    drop(g);
+    done.await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_recovers_from_snapshots() {
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("it_recovers_from_snapshots");
+    let mut persistence_params = PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    );
+    // only take the snapshots the test asks for
+    persistence_params.snapshot_interval = Some(Duration::from_secs(3600));
+    let start = |authority: Arc<LocalAuthority>, params: PersistenceParameters| async move {
+        let mut g = Builder::default();
+        g.set_sharding(DEFAULT_SHARDING);
+        // the counts must be fully materialized to be in the snapshot, and are grouped by another
+        // column than the one the base table is sharded by, so that their domain is fed through
+        // a shuffle
+        g.disable_partial();
+        g.set_persistence(params);
+        g.start(authority).await.unwrap()
+    };
+
+    {
+        let (mut g, done) = start(authority.clone(), persistence_params.clone()).await;
+        g.install_recipe(
+            "CREATE TABLE Vote (id int, aid int, PRIMARY KEY(id));
+             QUERY VoteCount: SELECT aid, COUNT(id) AS votes FROM Vote WHERE aid = ? GROUP BY aid;",
+        )
+        .await
+        .unwrap();
+
+        let mut vote = g.table("Vote").await.unwrap();
+        for id in 0..20 {
+            vote.insert(vec![id.into(), (id % 4).into()]).await.unwrap();
+        }
+        sleep().await;
+        assert_eq!(g.snapshot().await.unwrap(), 0);
+
+        // writes made after the snapshot are replayed on top of it
+        for id in 20..24 {
+            vote.insert(vec![id.into(), (id % 4).into()]).await.unwrap();
+        }
+        sleep().await;
+        drop(vote);
+        drop(g);
+        done.await;
+    }
 
-    // Retrieve the result of the count query:
-    let result = getter.lookup(&[cid.into()], true).await.unwrap();
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0][1], price.into());
+    let (mut g, done) = start(authority.clone(), persistence_params.clone()).await;
+    {
+        let mut count = g.view("VoteCount").await.unwrap();
+        for aid in 0..4 {
+            let rows = count.lookup(&[aid.into()], true).await.unwrap();
+            assert_eq!(rows, vec![vec![aid.into(), 6.into()]]);
+        }
+
+        // and the recovered state can be snapshotted again
+        let mut vote = g.table("Vote").await.unwrap();
+        vote.insert(vec![24.into(), 0.into()]).await.unwrap();
+        sleep().await;
+        assert_eq!(g.snapshot().await.unwrap(), 1);
+        let rows = count.lookup(&[0.into()], true).await.unwrap();
+        assert_eq!(rows, vec![vec![0.into(), 7.into()]]);
+    }
+    drop(g);
+    done.await;
 }
 
 #[tokio::test(threaded_scheduler)]
-async fn double_shuffle() {
-    let mut g = start_simple("double_shuffle").await;
+async fn it_refuses_to_place_domains_without_labelled_workers() {
+    let authority = Arc::new(LocalAuthority::new());
+    let mut g = Builder::default();
+    g.add_placement_constraint("Car=ssd".parse().unwrap());
+    g.add_label("edge");
+    let (mut g, done) = g.start(authority).await.unwrap();
+
+    let sql = "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));";
+    assert!(g.extend_recipe(sql).await.is_err());
+
+    // domains without constraints are still placed, and the failed migration left nothing behind
     let sql = "
-        CREATE TABLE Car (cid int, pid int, PRIMARY KEY(cid));
-        CREATE TABLE Price (pid int, price int, PRIMARY KEY(pid));
-        QUERY CarPrice: SELECT cid, price FROM Car \
-            JOIN Price ON Car.pid = Price.pid WHERE cid = ?;
+        CREATE TABLE Dealer (id int, name text, PRIMARY KEY(id));
+        QUERY DealerName: SELECT name FROM Dealer WHERE id = ?;
     ";
-    g.install_recipe(sql).await.unwrap();
+    g.extend_recipe(sql).await.unwrap();
+    assert!(g.table("Car").await.is_err());
 
-    let mut car_mutator = g.table("Car").await.unwrap();
-    let mut price_mutator = g.table("Price").await.unwrap();
-    let mut getter = g.view("CarPrice").await.unwrap();
-    let cid = 1;
-    let pid = 1;
-    let price = 100;
+    let mut dealer = g.table("Dealer").await.unwrap();
+    dealer.insert(vec![1.into(), "Bob".into()]).await.unwrap();
+    sleep().await;
+    let mut getter = g.view("DealerName").await.unwrap();
+    assert_eq!(
+        getter.lookup(&[1.into()], true).await.unwrap(),
+        vec![vec!["Bob".into()]]
+    );
 
-    price_mutator
-        .insert(vec![pid.into(), price.into()])
-        .await
-        .unwrap();
-    car_mutator
-        .insert(vec![cid.into(), pid.into()])
+    drop(g);
+    done.await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_keeps_workers_that_fail_to_drain() {
+    let authority = Arc::new(LocalAuthority::new());
+    let mut builder = Builder::default();
+    builder.set_quorum(2);
+    builder.set_persistence(get_persistence_params(
+        "it_keeps_workers_that_fail_to_drain",
+    ));
+    builder.add_placement_constraint("Car=ssd".parse().unwrap());
+    let (_b, _) = builder.start(authority.clone()).await.unwrap();
+    builder.add_label("ssd");
+    let (mut g, _) = builder.start(authority).await.unwrap();
+    let status = loop {
+        if let Ok(status) = g.cluster_status().await {
+            if status.workers.len() == 2 {
+                break status;
+            }
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    };
+    let ssd = status
+        .workers
+        .iter()
+        .find(|w| w.labels.iter().any(|l| l == "ssd"))
+        .unwrap()
+        .id;
+
+    g.install_recipe("CREATE TABLE Car (id int, price int, PRIMARY KEY(id));")
         .await
         .unwrap();
 
-    // Let writes propagate:
-    sleep().await;
+    // no other worker may run Car, so it stays where it is
+    assert!(g.drain_worker(ssd).await.is_err());
+    let status = g.cluster_status().await.unwrap();
+    let worker = status.workers.iter().find(|w| w.id == ssd).unwrap();
+    assert!(!worker.draining);
+    assert!(!worker.shards.is_empty());
 
-    // Retrieve the result of the count query:
-    let result = getter.lookup(&[cid.into()], true).await.unwrap();
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0][1], price.into());
+    let mut cars = g.table("Car").await.unwrap();
+    cars.insert(vec![1.into(), 100.into()]).await.unwrap();
 }
 
 #[tokio::test(threaded_scheduler)]
-async fn it_works_with_arithmetic_aliases() {
-    let mut g = start_simple("it_works_with_arithmetic_aliases").await;
+async fn it_tunes_and_compacts_rocksdb() {
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("it_tunes_and_compacts_rocksdb");
+    let mut persistence_params = PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    );
+    persistence_params.rocksdb.write_buffer_size = Some(1 << 20);
+    persistence_params.log_prefix = String::from("tunes");
+
+    let mut g = Builder::default();
+    g.set_persistence(persistence_params);
+    let (mut g, done) = g.start(authority.clone()).await.unwrap();
+
     let sql = "
-        CREATE TABLE Price (pid int, cent_price int, PRIMARY KEY(pid));
-        ModPrice: SELECT pid, cent_price / 100 AS price FROM Price;
-        QUERY AltPrice: SELECT pid, price FROM ModPrice WHERE pid = ?;
+        Car /* block_cache_size=8MB, compression=none:zstd, bloom_bits_per_key=16 */: \
+            CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+        CREATE TABLE Dealer (id int, name text, PRIMARY KEY(id));
+        QUERY CarPrice: SELECT price FROM Car WHERE id = ?;
     ";
     g.install_recipe(sql).await.unwrap();
 
-    let mut price_mutator = g.table("Price").await.unwrap();
-    let mut getter = g.view("AltPrice").await.unwrap();
-    let pid = 1;
-    let price = 10000;
-    price_mutator
-        .insert(vec![pid.into(), price.into()])
-        .await
-        .unwrap();
+    let mut car = g.table("Car").await.unwrap();
+    let mut dealer = g.table("Dealer").await.unwrap();
+    for i in 1..100 {
+        car.insert(vec![i.into(), (i * 10).into()]).await.unwrap();
+        dealer.insert(vec![i.into(), "Bob".into()]).await.unwrap();
+    }
+    for i in 1..50 {
+        car.delete(vec![i.into()]).await.unwrap();
+    }
+    sleep().await;
 
-    // Let writes propagate:
+    g.compact(Some("Car")).await.unwrap();
+    g.compact(None).await.unwrap();
+    assert!(g.compact(Some("Truck")).await.is_err());
     sleep().await;
 
-    // Retrieve the result of the count query:
-    let result = getter.lookup(&[pid.into()], true).await.unwrap();
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0][1], (price / 100).into());
+    let mut getter = g.view("CarPrice").await.unwrap();
+    assert!(getter.lookup(&[1.into()], true).await.unwrap().is_empty());
+    assert_eq!(
+        getter.lookup(&[50.into()], true).await.unwrap(),
+        vec![vec![500.into()]]
+    );
+
+    // RocksDB records the options that each table was opened with next to its files
+    let rocksdb_options = |db: &str| {
+        let mut files: Vec<_> = std::fs::read_dir(db)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_string_lossy();
+                name.starts_with("OPTIONS-") && !name.ends_with(".dbtmp")
+            })
+            .collect();
+        files.sort();
+        std::fs::read_to_string(files.last().unwrap()).unwrap()
+    };
+    let car = rocksdb_options("tunes-Car-0.db");
+    assert!(car.contains("table_factory=BlockBasedTable"));
+    assert!(car.contains("compression_per_level=kNoCompression:kZSTD"));
+    assert!(car.contains("write_buffer_size=1048576"));
+    // tables that aren't tuned keep the format that existing tables were written in
+    let dealer = rocksdb_options("tunes-Dealer-0.db");
+    assert!(dealer.contains("table_factory=PlainTable"));
+    assert!(dealer.contains("write_buffer_size=1048576"));
+
+    drop(g);
+    done.await;
+
+    std::fs::remove_dir_all("tunes-Car-0.db").unwrap();
+    std::fs::remove_dir_all("tunes-Dealer-0.db").unwrap();
 }
 
 #[tokio::test(threaded_scheduler)]
-async fn it_recovers_persisted_bases() {
+async fn it_recovers_logged_bases() {
+    use noria::Modification;
+
     let authority = Arc::new(LocalAuthority::new());
     let dir = tempfile::tempdir().unwrap();
-    let path = dir.path().join("it_recovers_persisted_bases");
+    let path = dir.path().join("it_recovers_logged_bases");
     let persistence_params = PersistenceParameters::new(
-        DurabilityMode::Permanent,
+        DurabilityMode::Logged,
         Duration::from_millis(1),
         Some(path.to_string_lossy().into()),
         1,
@@ -814,11 +1971,15 @@ async fn it_recovers_persisted_bases() {
             g.install_recipe(sql).await.unwrap();
 
             let mut mutator = g.table("Car").await.unwrap();
-
             for i in 1..10 {
-                let price = i * 10;
-                mutator.insert(vec![i.into(), price.into()]).await.unwrap();
+                mutator.insert(vec![i.into(), i.into()]).await.unwrap();
             }
+            // later writes must be replayed in order
+            mutator
+                .update(vec![1.into()], vec![(1, Modification::Set(100.into()))])
+                .await
+                .unwrap();
+            mutator.delete(vec![2.into()]).await.unwrap();
         }
 
         // Let writes propagate:
@@ -832,14 +1993,129 @@ async fn it_recovers_persisted_bases() {
     let (mut g, done) = g.start(authority.clone()).await.unwrap();
     {
         let mut getter = g.view("CarPrice").await.unwrap();
+        let result = getter.lookup(&[1.into()], true).await.unwrap();
+        assert_eq!(result, vec![vec![DataType::from(100)]]);
+        let result = getter.lookup(&[2.into()], true).await.unwrap();
+        assert!(result.is_empty());
+        for i in 3..10 {
+            let result = getter.lookup(&[i.into()], true).await.unwrap();
+            assert_eq!(result, vec![vec![DataType::from(i)]]);
+        }
+    }
+    drop(g);
+    done.await;
+}
 
-        // Make sure that the new graph contains the old writes
+#[tokio::test(threaded_scheduler)]
+async fn it_keeps_expired_rows_deleted_after_recovery() {
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("it_keeps_expired_rows_deleted_after_recovery");
+    let persistence_params = PersistenceParameters::new(
+        DurabilityMode::Logged,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    );
+
+    {
+        let mut g = Builder::default();
+        g.set_persistence(persistence_params.clone());
+        let (mut g, done) = g.start(authority.clone()).await.unwrap();
+
+        let sql = "
+            CREATE TABLE Visit (page varchar(255), at bigint) TTL 10 SECONDS ON at;
+            QUERY VisitCount: SELECT COUNT(*) FROM Visit WHERE page = ?;
+        ";
+        g.install_recipe(sql).await.unwrap();
+
+        let mut visits = g.table("Visit").await.unwrap();
+        let now = chrono::Local::now();
+        let hour_ago = now - chrono::Duration::hours(1);
+        for at in vec![hour_ago, hour_ago, now] {
+            visits
+                .insert(vec!["index".into(), at.timestamp().into()])
+                .await
+                .unwrap();
+        }
+
+        // rows are checked for expiry every second
+        tokio::time::delay_for(Duration::from_secs(2)).await;
+        sleep().await;
+        drop(g);
+        done.await;
+    }
+
+    // the deletions were logged along with the inserts, so the expired rows don't come back
+    // before the table is next checked for them
+    let mut g = Builder::default();
+    g.set_persistence(persistence_params);
+    let (mut g, done) = g.start(authority.clone()).await.unwrap();
+    let mut visit_count = g.view("VisitCount").await.unwrap();
+    assert_eq!(
+        visit_count.lookup(&["index".into()], true).await.unwrap(),
+        vec![vec![1.into()]]
+    );
+    drop(g);
+    done.await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_restores_backups() {
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup");
+    let params = |name: &str| {
+        PersistenceParameters::new(
+            DurabilityMode::Permanent,
+            Duration::from_millis(1),
+            Some(dir.path().join(name).to_string_lossy().into()),
+            1,
+        )
+    };
+
+    {
+        let mut g = Builder::default();
+        g.set_persistence(params("original"));
+        let (mut g, done) = g.start(Arc::new(LocalAuthority::new())).await.unwrap();
+
+        {
+            let sql = "
+            CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+            QUERY CarPrice: SELECT price FROM Car WHERE id = ?;
+        ";
+            g.install_recipe(sql).await.unwrap();
+
+            let mut mutator = g.table("Car").await.unwrap();
+            for i in 1..10 {
+                mutator.insert(vec![i.into(), i.into()]).await.unwrap();
+            }
+            sleep().await;
+            g.backup(&backup).await.unwrap();
+
+            // writes made after the backup aren't part of it
+            mutator.insert(vec![10.into(), 10.into()]).await.unwrap();
+        }
+
+        sleep().await;
+        drop(g);
+        done.await;
+    }
+
+    // the restored deployment has its own authority and files
+    let mut g = Builder::default();
+    g.set_persistence(params("restored"));
+    g.set_restore_from(backup);
+    let (mut g, done) = g.start(Arc::new(LocalAuthority::new())).await.unwrap();
+    {
+        let mut getter = g.view("CarPrice").await.unwrap();
         for i in 1..10 {
-            let price = i * 10;
             let result = getter.lookup(&[i.into()], true).await.unwrap();
-            assert_eq!(result.len(), 1);
-            assert_eq!(result[0][0], price.into());
+            assert_eq!(result, vec![vec![DataType::from(i)]]);
         }
+        let result = getter.lookup(&[10.into()], true).await.unwrap();
+        assert!(result.is_empty());
     }
     drop(g);
     done.await;
@@ -2609,6 +3885,185 @@ async fn remove_query() {
     }
 }
 
+#[tokio::test(threaded_scheduler)]
+async fn memory_budgets() {
+    let r_txt = "CREATE TABLE b (a int, c text);\n
+                 QUERY qa /* budget=1B */: SELECT a, c FROM b WHERE a = ?;";
+
+    let mut g = start_simple("memory_budgets").await;
+    g.install_recipe(r_txt).await.unwrap();
+    assert!(g.set_memory_budget("nonexistent", Some(1)).await.is_err());
+
+    let mut mutb = g.table("b").await.unwrap();
+    let mut qa = g.view("qa").await.unwrap();
+    for i in 0..10 {
+        mutb.insert(vec![i.into(), "x".into()]).await.unwrap();
+    }
+    sleep().await;
+
+    // keys evicted to stay within the budget must be replayed again when read
+    for _ in 0..2 {
+        for i in 0..10 {
+            let rows = qa.lookup(&[i.into()], true).await.unwrap();
+            assert_eq!(rows, vec![vec![i.into(), "x".into()]]);
+        }
+        mutb.insert(vec![10.into(), "y".into()]).await.unwrap();
+        sleep().await;
+    }
+
+    // the keys read hold far more than the budget allows, so they must have been evicted again
+    let stats = g.statistics().await.unwrap();
+    let partial: Vec<_> = stats
+        .domains
+        .values()
+        .flat_map(|(_, nodes)| nodes.values())
+        .filter(|n| {
+            if let MaterializationStatus::Partial { .. } = n.materialized {
+                true
+            } else {
+                false
+            }
+        })
+        .collect();
+    assert!(!partial.is_empty());
+    assert!(partial.iter().all(|n| n.mem_size <= 1));
+
+    // a budget set through the controller overrides the one in the recipe
+    g.set_memory_budget("qa", None).await.unwrap();
+    g.set_memory_budget("qa", Some(1 << 30)).await.unwrap();
+    for i in 0..10 {
+        let rows = qa.lookup(&[i.into()], true).await.unwrap();
+        assert_eq!(rows, vec![vec![i.into(), "x".into()]]);
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_exports_base_tables() {
+    let mut g = start_simple("it_exports_base_tables").await;
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarById: SELECT id, brand FROM Car WHERE id = ?;",
+    )
+    .await
+    .unwrap();
+    let mut car = g.table("Car").await.unwrap();
+    for i in 0..10 {
+        car.insert(vec![i.into(), "Volvo".into()]).await.unwrap();
+    }
+    sleep().await;
+
+    // every row of every shard is read, a few at a time
+    let mut rows = Vec::new();
+    let mut shard = 0;
+    while let Some(batch) = g.export_rows("Car", 1, shard, 3).await.unwrap() {
+        assert!(batch.len() <= 3);
+        if batch.is_empty() {
+            shard += 1;
+        }
+        rows.extend(batch);
+    }
+    rows.sort();
+    let expected: Vec<Vec<DataType>> = (0..10).map(|i| vec![i.into(), "Volvo".into()]).collect();
+    assert_eq!(rows, expected);
+    assert!(g.export_rows("Truck", 1, 0, 3).await.is_err());
+
+    // only the filled keys of a partial view could be read
+    let mut by_id = g.view("CarById").await.unwrap();
+    by_id.lookup(&[1.into()], true).await.unwrap();
+    match by_id.scan().await {
+        Err(noria::error::ViewError::PartiallyMaterialized) => {}
+        r => panic!("partial view was scanned: {:?}", r),
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_cancels_exports_part_way() {
+    let mut g = start_simple_unsharded("it_cancels_exports_part_way").await;
+    g.install_recipe("CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));")
+        .await
+        .unwrap();
+    let mut car = g.table("Car").await.unwrap();
+    for i in 0..10 {
+        car.insert(vec![i.into(), "Volvo".into()]).await.unwrap();
+    }
+    sleep().await;
+
+    // the export reads from the rows the table held when it started, until it is cancelled
+    let first = g.export_rows("Car", 1, 0, 3).await.unwrap().unwrap();
+    assert_eq!(first.len(), 3);
+    car.insert(vec![10.into(), "Saab".into()]).await.unwrap();
+    sleep().await;
+    g.cancel_export("Car", 1).await.unwrap();
+    assert!(g.cancel_export("Truck", 1).await.is_err());
+
+    // so the same export now starts over, and sees the row written since
+    let mut rows = Vec::new();
+    while let Some(batch) = g.export_rows("Car", 1, 0, 3).await.unwrap() {
+        if batch.is_empty() {
+            break;
+        }
+        rows.extend(batch);
+    }
+    assert_eq!(rows.len(), 11);
+    assert!(rows.contains(&vec![10.into(), "Saab".into()]));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn it_spills_partial_state() {
+    let mut b = Builder::default();
+    b.set_sharding(DEFAULT_SHARDING);
+    let mut params = get_persistence_params("it_spills_partial_state");
+    params.partial_storage = PartialStorage::Disk;
+    b.set_persistence(params);
+    let mut g = b.start_local().await.unwrap().0;
+    g.install_recipe(
+        "CREATE TABLE Vote (aid int, uid int);
+         QUERY VoteCount: SELECT aid, COUNT(uid) AS votes FROM Vote WHERE aid = ? GROUP BY aid;",
+    )
+    .await
+    .unwrap();
+
+    let mut vote = g.table("Vote").await.unwrap();
+    let mut count = g.view("VoteCount").await.unwrap();
+    for aid in 0..10 {
+        for uid in 0..aid {
+            vote.insert(vec![aid.into(), uid.into()]).await.unwrap();
+        }
+    }
+    sleep().await;
+
+    // the counts are replayed into state on disk, and kept up to date there
+    for round in 0..2 {
+        for aid in 1..10 {
+            let rows = count.lookup(&[aid.into()], true).await.unwrap();
+            assert_eq!(rows, vec![vec![aid.into(), (aid + round).into()]]);
+        }
+        for aid in 1..10 {
+            vote.insert(vec![aid.into(), 100.into()]).await.unwrap();
+        }
+        sleep().await;
+    }
+
+    // spilled rows live on disk, so they must not count towards memory use
+    let stats = g.statistics().await.unwrap();
+    assert!(stats
+        .domains
+        .values()
+        .flat_map(|(_, nodes)| nodes.values())
+        .any(|n| match n.materialized {
+            MaterializationStatus::Partial { .. } => n.rows > 0 && n.mem_size == 0,
+            _ => false,
+        }));
+
+    // evicting all partial state from memory leaves the spilled counts in place
+    g.flush_partial().await.unwrap();
+    sleep().await;
+    for aid in 1..10 {
+        let rows = count.lookup(&[aid.into()], true).await.unwrap();
+        assert_eq!(rows, vec![vec![aid.into(), (aid + 2).into()]]);
+    }
+}
+
 macro_rules! get {
     ($private:ident, $public:ident, $uid:expr, $aid:expr) => {{
         // combine private and public results
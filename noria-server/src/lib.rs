@@ -380,7 +380,11 @@ pub enum ReuseConfigType {
 pub use crate::builder::Builder;
 pub use crate::handle::Handle;
 pub use controller::migrate::materialization::FrontierStrategy;
-pub use dataflow::{DurabilityMode, PersistenceParameters};
+pub use controller::placement::{PlacementConstraint, PlacementPolicy};
+pub use dataflow::{
+    Compression, DurabilityMode, EvictionPolicy, FsyncPolicy, PartialStorage,
+    PersistenceParameters, RocksDbOptions,
+};
 pub use noria::consensus::LocalAuthority;
 pub use noria::*;
 pub use petgraph::graph::NodeIndex;
@@ -407,6 +411,9 @@ pub(crate) struct Config {
     pub(crate) quorum: usize,
     pub(crate) reuse: ReuseConfigType,
     pub(crate) threads: Option<usize>,
+    pub(crate) adhoc_query_idle_timeout: Option<time::Duration>,
+    pub(crate) placement_policy: PlacementPolicy,
+    pub(crate) placement_constraints: Vec<PlacementConstraint>,
 }
 impl Default for Config {
     fn default() -> Self {
@@ -420,6 +427,8 @@ impl Default for Config {
             domain_config: DomainConfig {
                 concurrent_replays: 512,
                 replay_batch_timeout: time::Duration::new(0, 100_000),
+                eviction_policy: Default::default(),
+                node_eviction_policies: Default::default(),
             },
             persistence: Default::default(),
             heartbeat_every: time::Duration::from_secs(1),
@@ -430,6 +439,9 @@ impl Default for Config {
             threads: Some(2),
             #[cfg(not(any(debug_assertions, test)))]
             threads: None,
+            adhoc_query_idle_timeout: Some(time::Duration::from_secs(300)),
+            placement_policy: Default::default(),
+            placement_constraints: Vec::new(),
         }
     }
 }
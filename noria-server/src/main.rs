@@ -1,11 +1,14 @@
-use clap::value_t_or_exit;
-use noria_server::{Builder, ReuseConfigType, ZookeeperAuthority};
+use clap::{value_t_or_exit, ArgMatches};
+use noria_server::consensus::Authority;
+use noria_server::{
+    Builder, ControllerHandle, EtcdAuthority, FileAuthority, ReuseConfigType, ZookeeperAuthority,
+};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 fn main() {
-    use clap::{App, Arg};
+    use clap::{App, Arg, SubCommand};
     let matches = App::new("noria-server")
         .version("0.0.1")
         .arg(
@@ -27,10 +30,27 @@ fn main() {
             Arg::with_name("durability")
                 .long("durability")
                 .takes_value(true)
-                .possible_values(&["persistent", "ephemeral", "memory"])
+                .possible_values(&["persistent", "ephemeral", "memory", "logged"])
                 .default_value("persistent")
                 .help("How to maintain base logs."),
         )
+        .arg(
+            Arg::with_name("fsync")
+                .long("fsync")
+                .takes_value(true)
+                .default_value("batch")
+                .validator(|v| match &*v {
+                    "batch" | "never" => Ok(()),
+                    ms => ms
+                        .parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("expected batch, never, or milliseconds")),
+                })
+                .help(
+                    "When to sync the write-ahead logs of logged base tables: after every batch, \
+                     never, or at most once every this many milliseconds.",
+                ),
+        )
         .arg(
             Arg::with_name("persistence-threads")
                 .long("persistence-threads")
@@ -38,6 +58,39 @@ fn main() {
                 .default_value("1")
                 .help("Number of background threads used by RocksDB."),
         )
+        .arg(
+            Arg::with_name("rocksdb")
+                .long("rocksdb")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| {
+                    let eq = v.find('=').ok_or("expected <option>=<value>")?;
+                    noria_server::RocksDbOptions::default().set(&v[..eq], &v[eq + 1..])
+                })
+                .help(
+                    "Tune RocksDB with <option>=<value>. Options are block_cache_size, \
+                     compression (e.g. none:lz4:zstd, one per level), bloom_bits_per_key, \
+                     write_buffer_size and compaction_rate_limit (bytes per second).",
+                ),
+        )
+        .arg(
+            Arg::with_name("partial-storage")
+                .long("partial-storage")
+                .takes_value(true)
+                .possible_values(&["memory", "disk", "tiered"])
+                .default_value("memory")
+                .help("Where to keep partially materialized internal state."),
+        )
+        .arg(
+            Arg::with_name("snapshot-interval")
+                .long("snapshot-interval")
+                .takes_value(true)
+                .help(
+                    "Snapshot materialized state every this many seconds, so that recovery only \
+                     replays recent writes. Requires persistent durability.",
+                ),
+        )
         .arg(
             Arg::with_name("flush-timeout")
                 .long("flush-timeout")
@@ -51,6 +104,15 @@ fn main() {
                 .takes_value(true)
                 .help("Absolute path to the directory where the log files will be written."),
         )
+        .arg(
+            Arg::with_name("restore")
+                .long("restore")
+                .takes_value(true)
+                .help(
+                    "Start a new deployment from the backup in this directory. Only the first \
+                     worker of the deployment should be given this.",
+                ),
+        )
         .arg(
             Arg::with_name("zookeeper")
                 .short("z")
@@ -59,6 +121,28 @@ fn main() {
                 .default_value("127.0.0.1:2181")
                 .help("Zookeeper connection info."),
         )
+        .arg(
+            Arg::with_name("etcd")
+                .long("etcd")
+                .takes_value(true)
+                .default_value("127.0.0.1:2379")
+                .help("etcd connection info."),
+        )
+        .arg(
+            Arg::with_name("authority-dir")
+                .long("authority-dir")
+                .takes_value(true)
+                .default_value("/tmp/noria")
+                .help("Directory shared by all workers on this host, for the file authority."),
+        )
+        .arg(
+            Arg::with_name("authority")
+                .long("authority")
+                .takes_value(true)
+                .possible_values(&["zookeeper", "etcd", "file"])
+                .default_value("zookeeper")
+                .help("Where to coordinate with the rest of the deployment."),
+        )
         .arg(
             Arg::with_name("memory")
                 .short("m")
@@ -75,6 +159,14 @@ fn main() {
                 .requires("memory")
                 .help("Frequency at which to check the state size against the memory limit [in milliseconds]."),
         )
+        .arg(
+            Arg::with_name("eviction")
+                .long("eviction")
+                .takes_value(true)
+                .possible_values(&["random", "lru", "lfu", "largest"])
+                .default_value("random")
+                .help("How to choose keys to evict from partially materialized state."),
+        )
         .arg(
             Arg::with_name("noreuse")
                 .long("no-reuse")
@@ -100,6 +192,34 @@ fn main() {
                 .default_value("0")
                 .help("Shard the graph this many ways (0 = disable sharding)."),
         )
+        .arg(
+            Arg::with_name("placement")
+                .long("placement")
+                .takes_value(true)
+                .possible_values(&["balanced", "round-robin"])
+                .default_value("balanced")
+                .help("How to choose the workers that new domains run on."),
+        )
+        .arg(
+            Arg::with_name("pin")
+                .long("pin")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| v.parse::<noria_server::PlacementConstraint>().map(|_| ()))
+                .help(
+                    "Run the domains of nodes with a name only on workers with a label, given as \
+                     [readers:]<name>=<label> (e.g. readers:feed=edge).",
+                ),
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Label this worker, so that domains pinned to the label can run on it."),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -107,13 +227,24 @@ fn main() {
                 .takes_value(false)
                 .help("Verbose log output."),
         )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about(
+                    "Print the workers in the deployment, the domain shards they run, and how \
+                     they are doing, instead of joining the deployment.",
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the status as JSON."),
+                ),
+        )
         .get_matches();
 
     let log = noria_server::logger_pls();
 
     let durability = matches.value_of("durability").unwrap();
     let listen_addr = matches.value_of("address").unwrap().parse().unwrap();
-    let zookeeper_addr = matches.value_of("zookeeper").unwrap();
     let memory = value_t_or_exit!(matches, "memory", usize);
     let memory_check_freq = value_t_or_exit!(matches, "memory_check_freq", u64);
     let quorum = value_t_or_exit!(matches, "quorum", usize);
@@ -125,9 +256,8 @@ fn main() {
     };
     let verbose = matches.is_present("verbose");
     let deployment_name = matches.value_of("deployment").unwrap();
+    let status = matches.subcommand_matches("status");
 
-    let mut authority =
-        ZookeeperAuthority::new(&format!("{}/{}", zookeeper_addr, deployment_name)).unwrap();
     let mut builder = Builder::default();
     builder.set_listen_addr(listen_addr);
     if memory > 0 {
@@ -141,12 +271,27 @@ fn main() {
     if matches.is_present("noreuse") {
         builder.set_reuse(ReuseConfigType::NoReuse);
     }
+    builder.set_placement_policy(matches.value_of("placement").unwrap().parse().unwrap());
+    for constraint in matches.values_of("pin").into_iter().flatten() {
+        builder.add_placement_constraint(constraint.parse().unwrap());
+    }
+    for label in matches.values_of("label").into_iter().flatten() {
+        builder.add_label(label);
+    }
+    builder.set_eviction_policy(match matches.value_of("eviction").unwrap() {
+        "random" => noria_server::EvictionPolicy::Random,
+        "lru" => noria_server::EvictionPolicy::LeastRecentlyUsed,
+        "lfu" => noria_server::EvictionPolicy::LeastFrequentlyUsed,
+        "largest" => noria_server::EvictionPolicy::Largest,
+        _ => unreachable!(),
+    });
 
     let mut persistence_params = noria_server::PersistenceParameters::new(
         match durability {
             "persistent" => noria_server::DurabilityMode::Permanent,
             "ephemeral" => noria_server::DurabilityMode::DeleteOnExit,
             "memory" => noria_server::DurabilityMode::MemoryOnly,
+            "logged" => noria_server::DurabilityMode::Logged,
             _ => unreachable!(),
         },
         Duration::new(0, flush_ns),
@@ -156,13 +301,73 @@ fn main() {
     persistence_params.log_dir = matches
         .value_of("log-dir")
         .and_then(|p| Some(PathBuf::from(p)));
+    persistence_params.partial_storage = match matches.value_of("partial-storage").unwrap() {
+        "memory" => noria_server::PartialStorage::Memory,
+        "disk" => noria_server::PartialStorage::Disk,
+        "tiered" => noria_server::PartialStorage::Tiered,
+        _ => unreachable!(),
+    };
+    persistence_params.fsync = match matches.value_of("fsync").unwrap() {
+        "batch" => noria_server::FsyncPolicy::EveryBatch,
+        "never" => noria_server::FsyncPolicy::Never,
+        ms => noria_server::FsyncPolicy::Interval(Duration::from_millis(ms.parse().unwrap())),
+    };
+    for option in matches.values_of("rocksdb").into_iter().flatten() {
+        let eq = option.find('=').unwrap();
+        persistence_params
+            .rocksdb
+            .set(&option[..eq], &option[eq + 1..])
+            .unwrap();
+    }
+    if matches.is_present("snapshot-interval") {
+        persistence_params.snapshot_interval = Some(Duration::from_secs(value_t_or_exit!(
+            matches,
+            "snapshot-interval",
+            u64
+        )));
+    }
     builder.set_persistence(persistence_params);
+    if let Some(dir) = matches.value_of("restore") {
+        builder.set_restore_from(PathBuf::from(dir));
+    }
 
     if verbose {
-        authority.log_with(log.clone());
-        builder.log_with(log);
+        builder.log_with(log.clone());
+    }
+
+    match matches.value_of("authority").unwrap() {
+        "zookeeper" => {
+            let zookeeper_addr = matches.value_of("zookeeper").unwrap();
+            let mut authority =
+                ZookeeperAuthority::new(&format!("{}/{}", zookeeper_addr, deployment_name))
+                    .unwrap();
+            if verbose {
+                authority.log_with(log);
+            }
+            run(builder, authority, status);
+        }
+        "etcd" => {
+            let etcd_addr = matches.value_of("etcd").unwrap();
+            let mut authority =
+                EtcdAuthority::new(&format!("{}/{}", etcd_addr, deployment_name)).unwrap();
+            if verbose {
+                authority.log_with(log);
+            }
+            run(builder, authority, status);
+        }
+        "file" => {
+            let dir = PathBuf::from(matches.value_of("authority-dir").unwrap());
+            let mut authority = FileAuthority::new(dir.join(deployment_name)).unwrap();
+            if verbose {
+                authority.log_with(log);
+            }
+            run(builder, authority, status);
+        }
+        _ => unreachable!(),
     }
+}
 
+fn run<A: Authority + 'static>(builder: Builder, authority: A, status: Option<&ArgMatches>) {
     let mut rt = tokio::runtime::Builder::new();
     rt.enable_all();
     rt.threaded_scheduler();
@@ -171,7 +376,33 @@ fn main() {
         rt.core_threads(threads);
     }
     let mut rt = rt.build().unwrap();
+    if let Some(args) = status {
+        if let Err(e) = rt.block_on(print_status(authority, args.is_present("json"))) {
+            eprintln!("error: {}", e);
+            for cause in e.iter_causes() {
+                eprintln!("  caused by: {}", cause);
+            }
+            std::process::exit(1);
+        }
+        return;
+    }
     let (_server, done) = rt.block_on(builder.start(Arc::new(authority))).unwrap();
     rt.block_on(done);
     drop(rt);
 }
+
+/// Print the membership and health of the deployment that `authority` coordinates.
+async fn print_status<A: Authority + 'static>(
+    authority: A,
+    json: bool,
+) -> Result<(), failure::Error> {
+    let mut ch = ControllerHandle::new(authority).await?;
+    ch.ready().await?;
+    let status = ch.cluster_status().await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print!("{}", status);
+    }
+    Ok(())
+}
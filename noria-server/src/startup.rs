@@ -12,6 +12,7 @@ use noria::consensus::Authority;
 use noria::ControllerDescriptor;
 use std::io;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time;
 use std::{
@@ -40,6 +41,13 @@ pub(crate) enum Event {
     CampaignError(failure::Error),
     #[cfg(test)]
     IsReady(tokio::sync::oneshot::Sender<bool>),
+    /// Stop this instance's controller, but leave its worker running.
+    #[cfg(test)]
+    StopController,
+    /// Remove the ad-hoc queries that will have gone unused for longer than the idle timeout once
+    /// the given time has passed.
+    #[cfg(test)]
+    RemoveIdleAdhocQueries(time::Duration, tokio::sync::oneshot::Sender<()>),
     ManualMigration {
         f: Box<dyn FnOnce(&mut crate::controller::migrate::Migration) + Send + 'static>,
         done: tokio::sync::oneshot::Sender<()>,
@@ -57,6 +65,10 @@ impl fmt::Debug for Event {
             Event::CampaignError(ref e) => write!(f, "CampaignError({:?})", e),
             #[cfg(test)]
             Event::IsReady(..) => write!(f, "IsReady"),
+            #[cfg(test)]
+            Event::StopController => write!(f, "StopController"),
+            #[cfg(test)]
+            Event::RemoveIdleAdhocQueries(..) => write!(f, "RemoveIdleAdhocQueries"),
             Event::ManualMigration { .. } => write!(f, "ManualMigration{{..}}"),
         }
     }
@@ -70,8 +82,22 @@ pub(super) async fn start_instance<A: Authority + 'static>(
     config: Config,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    labels: Vec<String>,
     log: slog::Logger,
+    restore_from: Option<PathBuf>,
 ) -> Result<(Handle<A>, impl Future<Output = ()> + Unpin + Send), failure::Error> {
+    let restore = match restore_from {
+        Some(dir) => {
+            info!(log, "restoring deployment from backup"; "dir" => ?dir);
+            Some(crate::controller::backup::prepare_restore(
+                &dir,
+                &config,
+                &*authority,
+            )?)
+        }
+        None => None,
+    };
+
     let (trigger, valve) = Valve::new();
     let (alive, done) = tokio::sync::mpsc::channel(1);
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
@@ -132,10 +158,11 @@ pub(super) async fn start_instance<A: Authority + 'static>(
                 Event::InternalMessage(ref msg) => match msg.payload {
                     CoordinationPayload::Deregister => ctx.send(e),
                     CoordinationPayload::RemoveDomain => wtx.send(e),
+                    CoordinationPayload::Drained => wtx.send(e),
                     CoordinationPayload::AssignDomain(..) => wtx.send(e),
                     CoordinationPayload::DomainBooted(..) => wtx.send(e),
                     CoordinationPayload::Register { .. } => ctx.send(e),
-                    CoordinationPayload::Heartbeat => ctx.send(e),
+                    CoordinationPayload::Heartbeat { .. } => ctx.send(e),
                     CoordinationPayload::CreateUniverse(..) => ctx.send(e),
                 },
                 Event::ExternalRequest(..) => ctx.send(e),
@@ -145,6 +172,10 @@ pub(super) async fn start_instance<A: Authority + 'static>(
                 Event::CampaignError(..) => ctx.send(e),
                 #[cfg(test)]
                 Event::IsReady(..) => ctx.send(e),
+                #[cfg(test)]
+                Event::StopController => ctx.send(e),
+                #[cfg(test)]
+                Event::RemoveIdleAdhocQueries(..) => ctx.send(e),
             };
             // needed for https://gist.github.com/nikomatsakis/fee0e47e14c09c4202316d8ea51e50a0
             snd.unwrap();
@@ -167,6 +198,7 @@ pub(super) async fn start_instance<A: Authority + 'static>(
         log.clone(),
         authority.clone(),
         tx.clone(),
+        restore,
     ));
     tokio::spawn(crate::worker::main(
         alive.clone(),
@@ -175,6 +207,7 @@ pub(super) async fn start_instance<A: Authority + 'static>(
         waddr,
         memory_limit,
         memory_check_frequency,
+        labels,
         log.clone(),
     ));
 
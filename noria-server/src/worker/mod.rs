@@ -2,9 +2,10 @@ use crate::controller::ControllerState;
 use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
 use crate::startup::Event;
 use async_bincode::AsyncBincodeWriter;
-use dataflow::{DomainBuilder, Packet};
+use dataflow::{DomainBuilder, Packet, Readers};
 use futures_util::{future::FutureExt, future::TryFutureExt, sink::SinkExt, stream::StreamExt};
-use noria::channel::{self, TcpSender};
+use noria::channel::tcp::SendError;
+use noria::channel::{self, Sender, TcpSender};
 use noria::consensus::Epoch;
 use noria::internal::DomainIndex;
 use noria::ControllerDescriptor;
@@ -27,14 +28,11 @@ mod readers;
 mod replica;
 
 type ChannelCoordinator = channel::ChannelCoordinator<ReplicaAddr, Box<Packet>>;
+type StateSizes = Arc<Mutex<HashMap<(DomainIndex, usize), Arc<AtomicUsize>>>>;
 
 enum InstanceState {
     Pining,
-    Active {
-        epoch: Epoch,
-        trigger: Trigger,
-        add_domain: UnboundedSender<DomainBuilder>,
-    },
+    Active { epoch: Epoch, trigger: Trigger },
 }
 
 impl InstanceState {
@@ -42,6 +40,31 @@ impl InstanceState {
         ::std::mem::replace(self, InstanceState::Pining)
     }
 }
+
+/// The controller that the worker currently answers to.
+struct Leader {
+    /// The address that domains send their control replies to.
+    domain_addr: SocketAddr,
+    /// Messages for the controller.
+    ctrl_tx: UnboundedSender<CoordinationPayload>,
+}
+
+/// The domains that a worker runs, along with what serves them.
+///
+/// These outlive the controller that they were started under, so that a controller that takes
+/// over from it can adopt them.
+struct Dataflow {
+    /// Shuts the domains down when dropped.
+    _trigger: Trigger,
+    add_domain: UnboundedSender<DomainBuilder>,
+    leader: Arc<Mutex<Option<Leader>>>,
+    /// The address of every domain shard that runs on the worker.
+    domains: Arc<Mutex<HashMap<(DomainIndex, usize), SocketAddr>>>,
+    state_sizes: StateSizes,
+    readers: Readers,
+    read_addr: SocketAddr,
+}
+
 pub(super) async fn main(
     alive: tokio::sync::mpsc::Sender<()>,
     mut worker_rx: tokio::sync::mpsc::UnboundedReceiver<Event>,
@@ -49,10 +72,14 @@ pub(super) async fn main(
     waddr: SocketAddr,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    labels: Vec<String>,
     log: slog::Logger,
 ) {
     // shared df state
     let coord = Arc::new(ChannelCoordinator::new());
+    let mut df: Option<Dataflow> = None;
+    // once drained, the worker stays out of the deployment
+    let mut drained = false;
 
     let mut worker_state = InstanceState::Pining;
     let log = log.clone();
@@ -63,14 +90,10 @@ pub(super) async fn main(
                     unimplemented!();
                 }
                 CoordinationPayload::AssignDomain(d) => {
-                    if let InstanceState::Active {
-                        epoch,
-                        ref mut add_domain,
-                        ..
-                    } = worker_state
-                    {
+                    if let InstanceState::Active { epoch, .. } = worker_state {
                         if epoch == msg.epoch {
-                            add_domain.send(d).unwrap_or_else(|d| {
+                            let df = df.as_ref().unwrap();
+                            df.add_domain.send(d).unwrap_or_else(|d| {
                                 panic!("could not add new domain {:?}", d);
                             });
                         }
@@ -78,6 +101,24 @@ pub(super) async fn main(
                         unreachable!();
                     }
                 }
+                CoordinationPayload::Drained => {
+                    if let InstanceState::Active { epoch, .. } = worker_state {
+                        if epoch == msg.epoch {
+                            warn!(log, "worker was drained, leaving the deployment");
+                            let df = df.as_ref().unwrap();
+                            tokio::task::block_in_place(|| {
+                                if let Some(ref leader) = *df.leader.lock().unwrap() {
+                                    let _ = leader.ctrl_tx.send(CoordinationPayload::Deregister);
+                                }
+                            });
+                            // no more heartbeats
+                            if let InstanceState::Active { trigger, .. } = worker_state.take() {
+                                trigger.cancel();
+                            }
+                            drained = true;
+                        }
+                    }
+                }
                 CoordinationPayload::DomainBooted(dd) => {
                     if let InstanceState::Active { epoch, .. } = worker_state {
                         if epoch == msg.epoch {
@@ -91,23 +132,25 @@ pub(super) async fn main(
                                 shard,
                                 addr
                             );
-                            coord.insert_remote((domain, shard), addr);
+                            let key = (domain, shard);
+                            if coord.get_addr(&key).map_or(false, |old| old != addr) {
+                                // the domain shard moved away from this worker
+                                coord.remove_local(&key);
+                            }
+                            coord.insert_remote(key, addr);
                         }
                     }
                 }
                 _ => unreachable!(),
             },
             Event::LeaderChange(state, descriptor) => {
-                if let InstanceState::Active {
-                    add_domain,
-                    trigger,
-                    ..
-                } = worker_state.take()
-                {
-                    // XXX: should we wait for current DF to be fully shut down?
-                    // FIXME: what about messages in listen_df's ctrl_tx?
+                if drained {
+                    info!(log, "not joining new leader, since this worker was drained");
+                    continue;
+                }
+                if let InstanceState::Active { trigger, .. } = worker_state.take() {
+                    // the domains keep running, so that the new leader can adopt them
                     info!(log, "detected leader change");
-                    drop(add_domain);
                     trigger.cancel();
                 } else {
                     info!(log, "found initial leader");
@@ -126,23 +169,40 @@ pub(super) async fn main(
                     "leader's domain listen address: {:?}", descriptor.domain_addr
                 );
 
+                if df.is_none() {
+                    // TODO: memory stuff should probably also be in config?
+                    let started = start_dataflow(
+                        alive.clone(),
+                        log.clone(),
+                        (memory_limit, memory_check_frequency),
+                        coord.clone(),
+                        listen_addr,
+                    )
+                    .await;
+                    match started {
+                        Ok(started) => df = Some(started),
+                        Err(e) => {
+                            error!(log, "failed to start data-flow");
+                            eprintln!("{:?}", e);
+                            continue;
+                        }
+                    }
+                }
+
                 // we need to make a new valve that we can use to shut down *just* the
-                // worker in the case of controller failover.
+                // connection to the controller in the case of controller failover.
                 let (trigger, valve) = Valve::new();
 
-                // TODO: memory stuff should probably also be in config?
-                let (rep_tx, rep_rx) = tokio::sync::mpsc::unbounded_channel();
                 let ctrl = listen_df(
                     alive.clone(),
                     valve,
                     log.clone(),
-                    (memory_limit, memory_check_frequency),
+                    labels.clone(),
                     &state,
                     &descriptor,
                     waddr,
                     coord.clone(),
-                    listen_addr,
-                    rep_rx,
+                    df.as_ref().unwrap(),
                 )
                 .await;
 
@@ -153,7 +213,6 @@ pub(super) async fn main(
                     // now we can start accepting dataflow messages
                     worker_state = InstanceState::Active {
                         epoch: state.epoch,
-                        add_domain: rep_tx,
                         trigger,
                     };
                     warn!(log, "Connected to new leader");
@@ -165,105 +224,58 @@ pub(super) async fn main(
 
     // shutting down...
     //
-    // NOTE: the Trigger in InstanceState::Active is dropped when the for_each
-    // closure above is dropped, which will also shut down the worker.
+    // NOTE: the Trigger in the Dataflow is dropped when the for_each closure above is dropped,
+    // which will also shut down the domains.
     //
     // TODO: maybe flush things or something?
 }
 
-async fn listen_df<'a>(
+/// Start serving reads and accepting domains, which keep running across changes of leader.
+async fn start_dataflow(
     alive: tokio::sync::mpsc::Sender<()>,
-    valve: Valve,
     log: slog::Logger,
     (memory_limit, evict_every): (Option<usize>, Option<Duration>),
-    state: &'a ControllerState,
-    desc: &'a ControllerDescriptor,
-    waddr: SocketAddr,
     coord: Arc<ChannelCoordinator>,
     on: IpAddr,
-    mut replicas: tokio::sync::mpsc::UnboundedReceiver<DomainBuilder>,
-) -> Result<(), failure::Error> {
-    // first, try to connect to controller
-    let ctrl = tokio::net::TcpStream::connect(&desc.worker_addr).await?;
-    let ctrl_addr = ctrl.local_addr()?;
-    info!(log, "connected to controller"; "src" => ?ctrl_addr);
-
-    let log_prefix = state.config.persistence.log_prefix.clone();
-    let prefix = format!("{}-log-", log_prefix);
-    let log_files: Vec<String> = fs::read_dir(".")
-        .unwrap()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().ok().map(|t| t.is_file()).unwrap_or(false))
-        .map(|e| e.path().to_string_lossy().into_owned())
-        .filter(|path| path.starts_with(&prefix))
-        .collect();
-
-    // extract important things from state config
-    let epoch = state.epoch;
-    let heartbeat_every = state.config.heartbeat_every;
-
-    let (ctrl_tx, mut ctrl_rx) = tokio::sync::mpsc::unbounded_channel();
+) -> Result<Dataflow, failure::Error> {
+    let (trigger, valve) = Valve::new();
+    let leader: Arc<Mutex<Option<Leader>>> = Arc::new(Mutex::new(None));
+    let domains = Arc::new(Mutex::new(HashMap::new()));
+    let state_sizes: StateSizes = Arc::new(Mutex::new(HashMap::new()));
 
     // reader setup
-    let readers = Arc::new(Mutex::new(HashMap::new()));
+    let readers: Readers = Arc::new(Mutex::new(HashMap::new()));
+    let moved_readers = Arc::new(Mutex::new(HashMap::new()));
     let rport = tokio::net::TcpListener::bind(&SocketAddr::new(on, 0)).await?;
     let raddr = rport.local_addr()?;
     info!(log, "listening for reads"; "on" => ?raddr);
 
-    // start controller message handler
-    let mut ctrl = AsyncBincodeWriter::from(ctrl).for_async();
-    let a = alive.clone();
-    tokio::spawn(async move {
-        let _alive = a;
-        while let Some(cm) = ctrl_rx.next().await {
-            if let Err(e) = ctrl
-                .send(CoordinationMessage {
-                    source: ctrl_addr,
-                    payload: cm,
-                    epoch,
-                })
-                .await
-            {
-                // if the controller goes away, another will be elected, and the worker will be
-                // restarted, so there's no reason to do anything too drastic here.
-                eprintln!("controller went away: {:?}", e);
-            }
-        }
-    });
-
-    // also start readers
     tokio::spawn(readers::listen(
         alive.clone(),
         valve.clone(),
         rport,
         readers.clone(),
+        moved_readers.clone(),
     ));
 
-    // and tell the controller about us
-    let mut timer = valve.wrap(tokio::time::interval_at(
-        tokio::time::Instant::now() + heartbeat_every,
-        heartbeat_every,
-    ));
+    // what the domains have to say goes to whichever controller is the leader at the time
+    let (up_tx, mut up_rx) = tokio::sync::mpsc::unbounded_channel();
     let a = alive.clone();
-    let ctx = ctrl_tx.clone();
+    let l = leader.clone();
+    let ulog = log.clone();
     tokio::spawn(async move {
         let _alive = a;
-        let _ = ctx.send(CoordinationPayload::Register {
-            addr: waddr,
-            read_listen_addr: raddr,
-            log_files,
-        });
-
-        // start sending heartbeats
-        while let Some(_) = timer.next().await {
-            if let Err(_) = ctx.send(CoordinationPayload::Heartbeat) {
-                // if we error we're probably just shutting down
-                break;
+        while let Some(cm) = up_rx.next().await {
+            let sent = tokio::task::block_in_place(|| match *l.lock().unwrap() {
+                Some(ref leader) => leader.ctrl_tx.send(cm).is_ok(),
+                None => false,
+            });
+            if !sent {
+                warn!(ulog, "no controller to pass domain message on to");
             }
         }
     });
 
-    let state_sizes = Arc::new(Mutex::new(HashMap::new()));
     if let Some(evict_every) = evict_every {
         let log = log.clone();
         let mut domain_senders = HashMap::new();
@@ -282,7 +294,11 @@ async fn listen_df<'a>(
     }
 
     // Now we're ready to accept new domains.
-    let dcaddr = desc.domain_addr;
+    let (add_domain, mut replicas) = tokio::sync::mpsc::unbounded_channel::<DomainBuilder>();
+    let l = leader.clone();
+    let running = domains.clone();
+    let sizes = state_sizes.clone();
+    let rs = readers.clone();
     tokio::spawn(
         async move {
             let alive = alive;
@@ -293,10 +309,17 @@ async fn listen_df<'a>(
                 let on = tokio::net::TcpListener::bind(&SocketAddr::new(on, 0)).await?;
                 let addr = on.local_addr()?;
 
+                // domains are only assigned by a controller the worker is connected to
+                let dcaddr = tokio::task::block_in_place(|| {
+                    l.lock().unwrap().as_ref().map(|leader| leader.domain_addr)
+                })
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no controller"))?;
+
                 let state_size = Arc::new(AtomicUsize::new(0));
                 let d = d.build(
                     log.clone(),
-                    readers.clone(),
+                    rs.clone(),
+                    moved_readers.clone(),
                     coord.clone(),
                     dcaddr,
                     &valve,
@@ -312,7 +335,11 @@ async fn listen_df<'a>(
                 coord.insert_remote((idx, shard), addr);
 
                 tokio::task::block_in_place(|| {
-                    state_sizes.lock().unwrap().insert((idx, shard), state_size)
+                    sizes
+                        .lock()
+                        .unwrap()
+                        .insert((idx, shard), state_size.clone());
+                    running.lock().unwrap().insert((idx, shard), addr);
                 });
 
                 let replica = replica::Replica::new(
@@ -320,17 +347,34 @@ async fn listen_df<'a>(
                     d,
                     on,
                     rx,
-                    ctrl_tx.clone(),
+                    up_tx.clone(),
                     log.clone(),
                     coord.clone(),
                 );
                 let a = alive.clone();
+                let running = running.clone();
+                let sizes = sizes.clone();
                 tokio::spawn(async move {
                     let _alive = a;
                     let log = replica.log.clone();
                     if let Err(e) = replica.await {
                         crit!(log, "replica failure: {:?}", e);
                     }
+
+                    // the shard may since have been started here anew
+                    tokio::task::block_in_place(|| {
+                        let mut running = running.lock().unwrap();
+                        if running.get(&(idx, shard)) == Some(&addr) {
+                            running.remove(&(idx, shard));
+                        }
+                        let mut sizes = sizes.lock().unwrap();
+                        if sizes
+                            .get(&(idx, shard))
+                            .map_or(false, |size| Arc::ptr_eq(size, &state_size))
+                        {
+                            sizes.remove(&(idx, shard));
+                        }
+                    });
                 });
 
                 info!(
@@ -341,7 +385,7 @@ async fn listen_df<'a>(
                     addr
                 );
 
-                ctrl_tx
+                up_tx
                     .send(CoordinationPayload::DomainBooted(DomainDescriptor::new(
                         idx, shard, addr,
                     )))
@@ -357,6 +401,145 @@ async fn listen_df<'a>(
         .map(|_| ()),
     );
 
+    Ok(Dataflow {
+        _trigger: trigger,
+        add_domain,
+        leader,
+        domains,
+        state_sizes,
+        readers,
+        read_addr: raddr,
+    })
+}
+
+async fn listen_df<'a>(
+    alive: tokio::sync::mpsc::Sender<()>,
+    valve: Valve,
+    log: slog::Logger,
+    labels: Vec<String>,
+    state: &'a ControllerState,
+    desc: &'a ControllerDescriptor,
+    waddr: SocketAddr,
+    coord: Arc<ChannelCoordinator>,
+    df: &'a Dataflow,
+) -> Result<(), failure::Error> {
+    // first, try to connect to controller
+    let ctrl = tokio::net::TcpStream::connect(&desc.worker_addr).await?;
+    let ctrl_addr = ctrl.local_addr()?;
+    info!(log, "connected to controller"; "src" => ?ctrl_addr);
+
+    let log_prefix = state.config.persistence.log_prefix.clone();
+    let prefix = format!("{}-log-", log_prefix);
+    let log_files: Vec<String> = fs::read_dir(".")
+        .unwrap()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().ok().map(|t| t.is_file()).unwrap_or(false))
+        .map(|e| e.path().to_string_lossy().into_owned())
+        .filter(|path| path.starts_with(&prefix))
+        .collect();
+
+    // extract important things from state config
+    let epoch = state.epoch;
+    let heartbeat_every = state.config.heartbeat_every;
+
+    let (ctrl_tx, mut ctrl_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    // start controller message handler
+    let mut ctrl = AsyncBincodeWriter::from(ctrl).for_async();
+    let a = alive.clone();
+    tokio::spawn(async move {
+        let _alive = a;
+        while let Some(cm) = ctrl_rx.next().await {
+            if let Err(e) = ctrl
+                .send(CoordinationMessage {
+                    source: ctrl_addr,
+                    payload: cm,
+                    epoch,
+                })
+                .await
+            {
+                // if the controller goes away, another will be elected, and the worker will
+                // connect to that one instead, so there's no reason to do anything too drastic
+                // here.
+                eprintln!("controller went away: {:?}", e);
+            }
+        }
+    });
+
+    // the domains that are still running answer to this controller from now on
+    let running: Vec<_> = tokio::task::block_in_place(|| {
+        *df.leader.lock().unwrap() = Some(Leader {
+            domain_addr: desc.domain_addr,
+            ctrl_tx: ctrl_tx.clone(),
+        });
+        df.domains
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(idx, shard), &addr)| DomainDescriptor::new(idx, shard, addr))
+            .collect()
+    });
+    for dd in &running {
+        let sent = tokio::task::block_in_place(|| {
+            coord
+                .builder_for(&(dd.domain(), dd.shard()))
+                .unwrap()
+                .build_sync()
+                .map_err(SendError::from)
+                .and_then(|mut tx| {
+                    tx.send(Box::new(Packet::UpdateController {
+                        addr: desc.domain_addr,
+                    }))
+                })
+        });
+        if let Err(e) = sent {
+            warn!(log, "failed to tell domain about new controller: {:?}", e);
+        }
+    }
+
+    // and tell the controller about us
+    let mut timer = valve.wrap(tokio::time::interval_at(
+        tokio::time::Instant::now() + heartbeat_every,
+        heartbeat_every,
+    ));
+    let a = alive.clone();
+    let ctx = ctrl_tx;
+    let sizes = df.state_sizes.clone();
+    let readers = df.readers.clone();
+    let raddr = df.read_addr;
+    tokio::spawn(async move {
+        let _alive = a;
+        let _ = ctx.send(CoordinationPayload::Register {
+            addr: waddr,
+            read_listen_addr: raddr,
+            log_files,
+            cpus: num_cpus::get(),
+            labels,
+            domains: running,
+        });
+
+        // start sending heartbeats
+        while let Some(_) = timer.next().await {
+            // domains update their sizes whenever they have handled a packet or timed out
+            let state_size = sizes
+                .lock()
+                .unwrap()
+                .values()
+                .map(|s: &Arc<AtomicUsize>| s.load(Ordering::Relaxed))
+                .sum();
+            let reads = readers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&target, r)| (target, r.requests()))
+                .collect();
+            if let Err(_) = ctx.send(CoordinationPayload::Heartbeat { state_size, reads }) {
+                // if we error we're probably just shutting down
+                break;
+            }
+        }
+    });
+
     Ok(())
 }
 
@@ -365,7 +548,7 @@ async fn do_eviction(
     log: &slog::Logger,
     memory_limit: Option<usize>,
     domain_senders: &mut HashMap<(DomainIndex, usize), TcpSender<Box<Packet>>>,
-    state_sizes: &Arc<Mutex<HashMap<(DomainIndex, usize), Arc<AtomicUsize>>>>,
+    state_sizes: &StateSizes,
 ) {
     use std::cmp;
 
@@ -395,24 +578,40 @@ async fn do_eviction(
         None => (),
         Some(limit) => {
             if total >= limit {
-                // evict from the largest domain
-                let largest = sizes.into_iter().max_by_key(|&(_, s)| s).unwrap();
+                // evict from every domain in proportion to its size, so that each one can give up
+                // its own least valuable keys rather than the largest domain giving up all of them
+                let excess = total - limit;
                 debug!(
+                    log,
+                    "memory footprint ({} bytes) exceeds limit ({} bytes); evicting {} bytes",
+                    total,
+                    limit,
+                    excess,
+                );
+
+                for (ds, size) in sizes {
+                    let share = (excess as f64 * size as f64 / total as f64).ceil() as usize;
+                    let num_bytes = cmp::min(size, share);
+                    if num_bytes == 0 {
+                        continue;
+                    }
+
+                    trace!(
                         log,
-                        "memory footprint ({} bytes) exceeds limit ({} bytes); evicting from largest domain {}",
-                        total,
-                        limit,
-                        (largest.0).0.index(),
+                        "evicting {} bytes from domain {}.{}",
+                        num_bytes,
+                        ds.0.index(),
+                        ds.1
                     );
-
-                let tx = domain_senders.get_mut(&largest.0).unwrap();
-                tokio::task::block_in_place(|| {
-                    tx.send(Box::new(Packet::Evict {
-                        node: None,
-                        num_bytes: cmp::min(largest.1, total - limit),
-                    }))
-                    .unwrap()
-                });
+                    let tx = domain_senders.get_mut(&ds).unwrap();
+                    tokio::task::block_in_place(|| {
+                        tx.send(Box::new(Packet::Evict {
+                            node: None,
+                            num_bytes,
+                        }))
+                        .unwrap()
+                    });
+                }
             }
         }
     }
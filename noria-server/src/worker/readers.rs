@@ -1,8 +1,8 @@
 use async_bincode::AsyncBincodeStream;
 use dataflow::prelude::DataType;
 use dataflow::prelude::*;
-use dataflow::Readers;
 use dataflow::SingleReadHandle;
+use dataflow::{MovedReaders, ReaderMoved, Readers};
 use futures_util::{
     future,
     future::Either,
@@ -13,7 +13,7 @@ use futures_util::{
 use noria::{ReadQuery, ReadReply, Tagged};
 use pin_project::pin_project;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{hash_map::Entry, HashMap};
 use std::mem;
 use std::time;
 use std::{
@@ -45,6 +45,7 @@ pub(super) async fn listen(
     valve: Valve,
     mut on: tokio::net::TcpListener,
     readers: Readers,
+    moved: MovedReaders,
 ) {
     // future that ensures all blocking reads are handled in FIFO order
     // and avoid hogging the executors with read retries
@@ -67,13 +68,14 @@ pub(super) async fn listen(
 
         let stream = stream.unwrap();
         let readers = readers.clone();
+        let moved = moved.clone();
         stream.set_nodelay(true).expect("could not set TCP_NODELAY");
         let alive = alive.clone();
         let mut tx = tx.clone();
         tokio::spawn(
             server::Server::new(
                 AsyncBincodeStream::from(stream).for_async(),
-                service_fn(move |req| handle_message(req, &readers, &mut tx)),
+                service_fn(move |req| handle_message(req, &readers, &moved, &mut tx)),
             )
             .map_err(|e| {
                 match e {
@@ -116,9 +118,56 @@ fn dup<'a>(rs: impl IntoIterator<Item = &'a Vec<DataType>>) -> Vec<Vec<DataType>
     outer
 }
 
+/// Call `f` with the reader for `target`.
+///
+/// If the reader isn't here, where it went is returned instead, if it is known yet.
+fn with_reader<F, T>(
+    target: (NodeIndex, usize),
+    s: &Readers,
+    moved: &MovedReaders,
+    f: F,
+) -> Result<T, Option<ReaderMoved>>
+where
+    F: FnOnce(&SingleReadHandle) -> T,
+{
+    READERS.with(|readers_cache| {
+        let mut readers_cache = readers_cache.borrow_mut();
+        if readers_cache
+            .get(&target)
+            .map_or(false, SingleReadHandle::is_destroyed)
+        {
+            // the reader's domain has gone away, or moved to another worker
+            readers_cache.remove(&target);
+        }
+        let reader = match readers_cache.entry(target) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let reader = s.lock().unwrap().get(&target).cloned();
+                match reader {
+                    Some(reader) => e.insert(reader),
+                    None => return Err(moved.lock().unwrap().get(&target).cloned()),
+                }
+            }
+        };
+        Ok(f(reader))
+    })
+}
+
+/// The reply to a read for a reader that isn't here.
+fn not_here(tag: u32, to: Option<ReaderMoved>, query: ReadQuery) -> Tagged<ReadReply> {
+    let v = match to {
+        Some(ReaderMoved::To(addr)) => ReadReply::Moved(addr, query),
+        Some(ReaderMoved::Resharded(addrs)) => ReadReply::Resharded(addrs, query),
+        // the reader is being handed off to another worker
+        None => ReadReply::Normal(Err(())),
+    };
+    Tagged { tag, v }
+}
+
 fn handle_message(
     m: Tagged<ReadQuery>,
     s: &Readers,
+    moved: &MovedReaders,
     wait: &mut tokio::sync::mpsc::UnboundedSender<(
         BlockingRead,
         tokio::sync::oneshot::Sender<Result<Tagged<ReadReply>, ()>>,
@@ -131,13 +180,8 @@ fn handle_message(
             mut keys,
             block,
         } => {
-            let immediate = READERS.with(|readers_cache| {
-                let mut readers_cache = readers_cache.borrow_mut();
-                let reader = readers_cache.entry(target).or_insert_with(|| {
-                    let readers = s.lock().unwrap();
-                    readers.get(&target).unwrap().clone()
-                });
-
+            let immediate = with_reader(target, s, moved, |reader| {
+                reader.count_request();
                 let mut ret = Vec::with_capacity(keys.len());
 
                 // first do non-blocking reads for all keys to see if we can return immediately
@@ -191,12 +235,20 @@ fn handle_message(
                 // trigger backfills for all the keys we missed on
                 reader.trigger(keys.iter().map(Vec::as_slice));
 
-                Err((keys, ret, pending))
+                Err((ret, pending))
             });
 
             match immediate {
-                Ok(reply) => Either::Left(Either::Left(future::ready(Ok(reply)))),
-                Err((keys, ret, pending)) => {
+                Err(to) => {
+                    let query = ReadQuery::Normal {
+                        target,
+                        keys,
+                        block,
+                    };
+                    Either::Left(Either::Left(future::ready(Ok(not_here(tag, to, query)))))
+                }
+                Ok(Ok(reply)) => Either::Left(Either::Left(future::ready(Ok(reply)))),
+                Ok(Err((ret, pending))) => {
                     if !block {
                         Either::Left(Either::Left(future::ready(Ok(Tagged {
                             tag,
@@ -238,20 +290,32 @@ fn handle_message(
             }
         }
         ReadQuery::Size { target } => {
-            let size = READERS.with(|readers_cache| {
-                let mut readers_cache = readers_cache.borrow_mut();
-                let reader = readers_cache.entry(target).or_insert_with(|| {
-                    let readers = s.lock().unwrap();
-                    readers.get(&target).unwrap().clone()
-                });
+            let reply = match with_reader(target, s, moved, SingleReadHandle::len) {
+                Ok(size) => Tagged {
+                    tag,
+                    v: ReadReply::Size(size),
+                },
+                Err(to) => not_here(tag, to, ReadQuery::Size { target }),
+            };
 
-                reader.len()
-            });
+            Either::Right(future::ready(Ok(reply)))
+        }
+        ReadQuery::All { target } => {
+            // only the rows of filled keys could be read from a partial reader
+            let all_rows = |r: &SingleReadHandle| Some(r.all_rows()).filter(|_| !r.is_partial());
+            let reply = match with_reader(target, s, moved, all_rows) {
+                Ok(Some(rows)) => Tagged {
+                    tag,
+                    v: ReadReply::Normal(rows.map(|rows| vec![rows])),
+                },
+                Ok(None) => Tagged {
+                    tag,
+                    v: ReadReply::Partial,
+                },
+                Err(to) => not_here(tag, to, ReadQuery::All { target }),
+            };
 
-            Either::Right(future::ready(Ok(Tagged {
-                tag,
-                v: ReadReply::Size(size),
-            })))
+            Either::Right(future::ready(Ok(reply)))
         }
     }
 }
@@ -284,14 +348,17 @@ impl Future for BlockingRead {
         loop {
             ready!(this.retry.as_mut().poll_next(cx));
 
-            READERS.with(|readers_cache| {
+            let r = READERS.with(|readers_cache| {
                 let mut readers_cache = readers_cache.borrow_mut();
                 let s = &this.truth;
                 let target = &this.target;
-                let reader = readers_cache.entry(*this.target).or_insert_with(|| {
-                    let readers = s.lock().unwrap();
-                    readers.get(target).unwrap().clone()
-                });
+                let reader = match readers_cache.entry(*this.target) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => match s.lock().unwrap().get(target) {
+                        Some(reader) => e.insert(reader.clone()),
+                        None => return Err(true),
+                    },
+                };
 
                 let now = time::Instant::now();
                 let read = &mut this.read;
@@ -311,10 +378,13 @@ impl Future for BlockingRead {
                             read[read_i] = rs;
                         }
                         Err(()) => {
-                            // map has been deleted, so server is shutting down
+                            // map has been deleted, so server is shutting down, or the reader
+                            // moved to another worker
                             this.pending.clear();
                             this.keys.clear();
-                            return Err(());
+                            return Err(
+                                reader.is_destroyed() && !s.lock().unwrap().contains_key(target)
+                            );
                         }
                         Ok(None) => {
                             // we still missed! restore key + pending
@@ -330,7 +400,7 @@ impl Future for BlockingRead {
                     // maybe the key got filled, then evicted, and we missed it?
                     if !reader.trigger(this.keys.iter().map(Vec::as_slice)) {
                         // server is shutting down and won't do the backfill
-                        return Err(());
+                        return Err(false);
                     }
 
                     *this.trigger_timeout *= 2;
@@ -349,7 +419,20 @@ impl Future for BlockingRead {
                 }
 
                 Ok(())
-            })?;
+            });
+
+            match r {
+                Ok(()) => {}
+                Err(true) => {
+                    // the reader moved to another worker while we were waiting, so the client
+                    // will have to try again there
+                    return Poll::Ready(Ok(Tagged {
+                        tag: *this.tag,
+                        v: ReadReply::Normal(Err(())),
+                    }));
+                }
+                Err(false) => return Poll::Ready(Err(())),
+            }
 
             if this.keys.is_empty() {
                 return Poll::Ready(Ok(Tagged {
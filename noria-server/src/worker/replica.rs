@@ -23,8 +23,9 @@ use noria::internal::LocalOrNot;
 use noria::{Input, Tagged};
 use pin_project::pin_project;
 use slog;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time;
 use std::{
@@ -133,8 +134,22 @@ impl Replica {
         let this = self.project();
 
         let mut inputs = this.inputs;
+
+        // the clients whose writes failed find out when their connections close
+        let failed: Vec<_> = this.out.failed.drain().collect();
+        for streami in failed {
+            let conn = &mut this.out.connections[streami];
+            conn.unacked = 0;
+            conn.tag_acks.clear();
+            conn.pending_flush = false;
+            conn.epoch += 1;
+            this.out.pending.remove(&streami);
+            inputs.as_mut().remove(streami);
+        }
+
         let conns = &mut this.out.connections;
         let pending = &mut this.out.pending;
+        let redirect = &this.out.redirect;
 
         // first, queue up any additional writes we have to do
         let mut err = Vec::new();
@@ -153,7 +168,8 @@ impl Replica {
                     }
                 }
 
-                if let Err(e) = stream.as_mut().start_send(Tagged { tag, v: () }) {
+                let v = redirect.clone();
+                if let Err(e) = stream.as_mut().start_send(Tagged { tag, v }) {
                     // start_send shouldn't generally error
                     err.push(e.into());
                     break;
@@ -260,17 +276,30 @@ impl Replica {
 
         // just like in try_acks:
         // first, queue up any additional writes we have to do
-        let mut err = Vec::new();
+        let mut failed = Vec::new();
         for (&ri, ms) in &mut this.out.domains {
             if ms.is_empty() {
                 continue;
             }
+            if this.out.lost.contains(&ri) {
+                // the domain shard went away with its worker, and hasn't been started anew yet
+                ms.clear();
+                continue;
+            }
 
-            let &mut (ref mut tx, ref mut pending) = outputs.entry(ri).or_insert_with(|| {
-                while !cc.has(&ri) {}
-                let tx = cc.builder_for(&ri).unwrap().build_async().unwrap();
-                (tx, true)
-            });
+            let &mut (ref mut tx, ref mut pending) = match outputs.entry(ri) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    while !cc.has(&ri) {}
+                    match cc.builder_for(&ri).unwrap().build_async() {
+                        Ok(tx) => e.insert((tx, true)),
+                        Err(e) => {
+                            failed.push((ri, failure::Error::from(e)));
+                            continue;
+                        }
+                    }
+                }
+            };
 
             let mut tx = Pin::new(tx);
 
@@ -279,7 +308,7 @@ impl Replica {
                     Poll::Ready(Ok(())) => {}
                     Poll::Pending => break,
                     Poll::Ready(Err(e)) => {
-                        err.push(e);
+                        failed.push((ri, e.into()));
                         break;
                     }
                 }
@@ -291,19 +320,15 @@ impl Replica {
                         *pending = true;
                     }
                     Err(e) => {
-                        err.push(e);
+                        failed.push((ri, e.into()));
                         break;
                     }
                 }
             }
         }
 
-        if !err.is_empty() {
-            return Err(err.swap_remove(0).into());
-        }
-
         // then, try to do any sends that are still pending
-        for &mut (ref mut tx, ref mut pending) in outputs.values_mut() {
+        for (&ri, &mut (ref mut tx, ref mut pending)) in outputs.iter_mut() {
             if !*pending {
                 continue;
             }
@@ -313,14 +338,38 @@ impl Replica {
                     *pending = false;
                 }
                 Poll::Pending => {}
-                Poll::Ready(Err(e)) => err.push(e),
+                Poll::Ready(Err(e)) => failed.push((ri, e.into())),
             }
         }
 
-        if !err.is_empty() {
-            return Err(err.swap_remove(0).into());
+        // a domain shard we can't send to must have gone away with its worker. the controller
+        // starts it anew elsewhere, and tells us to reconnect to it once it has, so until then,
+        // everything sent to it is dropped.
+        for (ri, e) in failed {
+            warn!(this.log, "lost connection to domain shard";
+                  "domain" => ri.0.index(),
+                  "shard" => ri.1,
+                  "error" => %e);
+            outputs.remove(&ri);
+            if let Some(ms) = this.out.domains.get_mut(&ri) {
+                ms.clear();
+            }
+            this.out.lost.insert(ri);
         }
 
+        // domains that moved are connected to at their new address the next time we send to them
+        let domains = &this.out.domains;
+        this.out.reconnect.retain(|ri| {
+            let sent = domains.get(ri).map_or(true, VecDeque::is_empty);
+            match outputs.get(ri) {
+                Some(&(_, pending)) if pending || !sent => true,
+                _ => {
+                    outputs.remove(ri);
+                    false
+                }
+            }
+        });
+
         Ok(())
     }
 
@@ -433,12 +482,24 @@ struct Outboxes {
     // messages for other domains
     domains: FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
 
+    // domains to connect to anew once everything queued for them has been sent
+    reconnect: FnvHashSet<ReplicaAddr>,
+
+    // domains that can't be reached since their worker failed
+    lost: FnvHashSet<ReplicaAddr>,
+
     // connection state for each stream
     connections: slab::Slab<ConnState>,
 
     // which connections have pending writes
     pending: FnvHashSet<usize>,
 
+    // where the table that clients write to lives since it moved, to tell them in acks
+    redirect: Option<Vec<SocketAddr>>,
+
+    // connections whose writes failed, and that should be closed
+    failed: FnvHashSet<usize>,
+
     // for sending messages to the controller
     ctrl_tx: tokio::sync::mpsc::UnboundedSender<CoordinationPayload>,
 }
@@ -457,8 +518,12 @@ impl Outboxes {
 
         Outboxes {
             domains: Default::default(),
+            reconnect: Default::default(),
+            lost: Default::default(),
             connections,
             pending: Default::default(),
+            redirect: None,
+            failed: Default::default(),
             ctrl_tx,
             dirty: false,
         }
@@ -518,14 +583,29 @@ impl Executor for Outboxes {
         self.dirty = true;
         self.domains.entry(dest).or_default().push_back(m);
     }
+
+    fn reconnect(&mut self, dest: ReplicaAddr) {
+        self.dirty = true;
+        self.lost.remove(&dest);
+        self.reconnect.insert(dest);
+    }
+
+    fn redirect_writes(&mut self, to: Vec<SocketAddr>) {
+        self.redirect = Some(to);
+    }
+
+    fn fail(&mut self, id: SourceChannelIdentifier) {
+        self.dirty = true;
+        if id.epoch == self.connections[id.token].epoch {
+            self.failed.insert(id.token);
+        }
+    }
 }
 
 impl Future for Replica {
     type Output = Result<(), failure::Error>;
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         'process: loop {
-            // FIXME: check if we should call update_state_sizes (every evict_every)
-
             // are there are any new connections?
             if !self
                 .as_mut()
@@ -600,10 +680,9 @@ impl Future for Replica {
                                 .on_event(out, PollEvent::Process(p),));
                         }
                         Poll::Ready(None) => {
-                            // local input stream finished
-                            // TODO: should we finish up remaining work?
-                            warn!(this.log, "local input stream ended");
-                            return Poll::Ready(Ok(()));
+                            // a shard that took over from this one is reached locally now, but
+                            // this shard is still reached over the network, and stops on its own
+                            local_done = true;
                         }
                         Poll::Pending => {
                             local_done = true;
@@ -695,6 +774,10 @@ impl Future for Replica {
                             continue 'process;
                         }
                     }
+                    ProcessResult::StopPolling => {
+                        // the domain shard was handed off, and is done forwarding
+                        return Poll::Ready(Ok(()));
+                    }
                     pr => {
                         // TODO: just have resume_polling be a method...
                         unreachable!("unexpected ResumePolling result {:?}", pr)
@@ -70,6 +70,16 @@ impl<T> DomainConnectionBuilder<Remote, T> {
             _marker: Remote,
         }
     }
+
+    pub fn for_domain(addr: SocketAddr) -> Self {
+        DomainConnectionBuilder {
+            sport: None,
+            chan: None,
+            addr,
+            is_for_base: false,
+            _marker: Remote,
+        }
+    }
 }
 
 impl<D, T> DomainConnectionBuilder<D, T> {
@@ -293,6 +303,17 @@ impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
         inner.locals.insert(key, chan);
     }
 
+    /// Stop handing out the local channel for `key`, so that it is reached at its remote
+    /// address instead.
+    pub fn remove_local<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut inner = self.inner.write().unwrap();
+        inner.locals.remove(key);
+    }
+
     pub fn has<Q>(&self, key: &Q) -> bool
     where
         K: Borrow<Q>,
@@ -3,7 +3,7 @@ use std::io::{self, Write};
 use std::marker::PhantomData;
 use std::net::{Ipv4Addr, SocketAddr};
 
-use crate::Tagged;
+use crate::{Tagged, WriteAck};
 use async_bincode::{AsyncBincodeStream, AsyncDestination};
 use bufstream::BufStream;
 use byteorder::{NetworkEndian, WriteBytesExt};
@@ -142,9 +142,9 @@ pub enum RecvError {
 
 #[pin_project]
 pub enum DualTcpStream<S, T, T2, D> {
-    Passthrough(#[pin] AsyncBincodeStream<S, T, Tagged<()>, D>),
+    Passthrough(#[pin] AsyncBincodeStream<S, T, Tagged<WriteAck>, D>),
     Upgrade(
-        #[pin] AsyncBincodeStream<S, T2, Tagged<()>, D>,
+        #[pin] AsyncBincodeStream<S, T2, Tagged<WriteAck>, D>,
         Box<dyn FnMut(T2) -> T + Send + Sync>,
     ),
 }
@@ -157,7 +157,7 @@ impl<S, T, T2> From<S> for DualTcpStream<S, T, T2, AsyncDestination> {
 
 impl<S, T, T2> DualTcpStream<S, T, T2, AsyncDestination> {
     pub fn upgrade<F: 'static + FnMut(T2) -> T + Send + Sync>(stream: S, f: F) -> Self {
-        let s: AsyncBincodeStream<S, T2, Tagged<()>, AsyncDestination> =
+        let s: AsyncBincodeStream<S, T2, Tagged<WriteAck>, AsyncDestination> =
             AsyncBincodeStream::from(stream).for_async();
         DualTcpStream::Upgrade(s, Box::new(f))
     }
@@ -170,11 +170,11 @@ impl<S, T, T2> DualTcpStream<S, T, T2, AsyncDestination> {
     }
 }
 
-impl<S, T, T2, D> Sink<Tagged<()>> for DualTcpStream<S, T, T2, D>
+impl<S, T, T2, D> Sink<Tagged<WriteAck>> for DualTcpStream<S, T, T2, D>
 where
     S: AsyncWrite,
-    AsyncBincodeStream<S, T, Tagged<()>, D>: Sink<Tagged<()>, Error = bincode::Error>,
-    AsyncBincodeStream<S, T2, Tagged<()>, D>: Sink<Tagged<()>, Error = bincode::Error>,
+    AsyncBincodeStream<S, T, Tagged<WriteAck>, D>: Sink<Tagged<WriteAck>, Error = bincode::Error>,
+    AsyncBincodeStream<S, T2, Tagged<WriteAck>, D>: Sink<Tagged<WriteAck>, Error = bincode::Error>,
 {
     type Error = bincode::Error;
 
@@ -188,7 +188,7 @@ where
     }
 
     #[project]
-    fn start_send(self: Pin<&mut Self>, item: Tagged<()>) -> Result<(), Self::Error> {
+    fn start_send(self: Pin<&mut Self>, item: Tagged<WriteAck>) -> Result<(), Self::Error> {
         #[project]
         match self.project() {
             DualTcpStream::Passthrough(abs) => abs.start_send(item),
@@ -220,8 +220,8 @@ where
     for<'a> T: Deserialize<'a>,
     for<'a> T2: Deserialize<'a>,
     S: AsyncRead,
-    AsyncBincodeStream<S, T, Tagged<()>, D>: Stream<Item = Result<T, bincode::Error>>,
-    AsyncBincodeStream<S, T2, Tagged<()>, D>: Stream<Item = Result<T2, bincode::Error>>,
+    AsyncBincodeStream<S, T, Tagged<WriteAck>, D>: Stream<Item = Result<T, bincode::Error>>,
+    AsyncBincodeStream<S, T2, Tagged<WriteAck>, D>: Stream<Item = Result<T2, bincode::Error>>,
 {
     type Item = Result<T, bincode::Error>;
 
@@ -0,0 +1,588 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use failure::{Error, ResultExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::Authority;
+use super::Epoch;
+use super::CONTROLLER_KEY;
+
+/// How long, in seconds, the leader's lease on the controller key outlives the last time it was
+/// kept alive.
+const LEASE_TTL: i64 = 5;
+
+/// How often to check whether the controller key has changed, since the JSON gateway can't be
+/// asked to wait for that.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A key as it was last read.
+struct KeyValue {
+    value: Vec<u8>,
+    create_revision: i64,
+    mod_revision: i64,
+}
+
+/// The lease that the controller key is held under while this instance is the leader.
+struct Lease {
+    id: i64,
+    stop: Arc<AtomicBool>,
+    keepalive: JoinHandle<()>,
+}
+
+/// Coordinator that shares connection information between workers and clients using etcd.
+///
+/// This talks to etcd's v3 API through its JSON gateway. The leader holds the controller key
+/// under a lease, which it keeps alive for as long as it runs; if it can't, it aborts, as another
+/// controller may already have taken over. The epoch of a leader is the revision at which it
+/// created the key.
+pub struct EtcdAuthority {
+    addr: String,
+    prefix: String,
+    lease: Mutex<Option<Lease>>,
+    log: slog::Logger,
+}
+
+impl EtcdAuthority {
+    /// Create a new instance.
+    ///
+    /// `connect_string` is the address of an etcd server, optionally followed by a path that all
+    /// keys are kept under (e.g. `127.0.0.1:2379/mydeployment`).
+    pub fn new(connect_string: &str) -> Result<Self, Error> {
+        let (addr, prefix) = match connect_string.find('/') {
+            Some(i) => (
+                &connect_string[..i],
+                connect_string[i..].trim_end_matches('/'),
+            ),
+            None => (connect_string, ""),
+        };
+        let authority = Self {
+            addr: addr.to_owned(),
+            prefix: prefix.to_owned(),
+            lease: Mutex::new(None),
+            log: slog::Logger::root(slog::Discard, o!()),
+        };
+        authority
+            .range(CONTROLLER_KEY)
+            .context(format!("Failed to connect to etcd at {}", addr))?;
+        Ok(authority)
+    }
+
+    /// Enable logging
+    pub fn log_with(&mut self, log: slog::Logger) {
+        self.log = log;
+    }
+
+    fn key(&self, key: &str) -> String {
+        encode(format!("{}{}", self.prefix, key).as_bytes())
+    }
+
+    fn range(&self, key: &str) -> Result<Option<KeyValue>, Error> {
+        let response = post(&self.addr, "/v3/kv/range", &json!({ "key": self.key(key) }))?;
+        match response["kvs"].get(0) {
+            Some(kv) => Ok(Some(KeyValue {
+                value: decode(&kv["value"])?,
+                create_revision: int(&kv["create_revision"]),
+                mod_revision: int(&kv["mod_revision"]),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Put `value` at `key` if `compare` holds, and return whether it did.
+    fn put_if(&self, key: &str, compare: Value, value: &[u8], lease: i64) -> Result<bool, Error> {
+        let response = post(
+            &self.addr,
+            "/v3/kv/txn",
+            &json!({
+                "compare": [compare],
+                "success": [{
+                    "request_put": {
+                        "key": self.key(key),
+                        "value": encode(value),
+                        "lease": lease.to_string(),
+                    }
+                }],
+            }),
+        )?;
+        Ok(response["succeeded"].as_bool().unwrap_or(false))
+    }
+
+    /// Wait until `done` returns something for the controller key as it is then.
+    fn poll_leader<F, T>(&self, mut done: F) -> Result<T, Error>
+    where
+        F: FnMut(Option<KeyValue>) -> Option<T>,
+    {
+        loop {
+            if let Some(t) = done(self.range(CONTROLLER_KEY)?) {
+                return Ok(t);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Authority for EtcdAuthority {
+    fn become_leader(&self, payload_data: Vec<u8>) -> Result<Option<Epoch>, Error> {
+        // the lease's time to live counts from when it is granted
+        let granted = Instant::now();
+        let response = post(
+            &self.addr,
+            "/v3/lease/grant",
+            &json!({ "TTL": LEASE_TTL.to_string() }),
+        )?;
+        let id = int(&response["ID"]);
+        let revoke = || {
+            post(
+                &self.addr,
+                "/v3/lease/revoke",
+                &json!({ "ID": id.to_string() }),
+            )
+        };
+
+        let absent = json!({
+            "key": self.key(CONTROLLER_KEY),
+            "target": "CREATE",
+            "result": "EQUAL",
+            "create_revision": "0",
+        });
+        let elected = self
+            .put_if(CONTROLLER_KEY, absent, &payload_data, id)
+            .and_then(|put| {
+                if put {
+                    self.range(CONTROLLER_KEY)
+                } else {
+                    Ok(None)
+                }
+            });
+        let epoch = match elected {
+            Ok(Some(ref kv)) if kv.value == payload_data => Epoch(kv.create_revision),
+            Ok(_) => {
+                // the key, if this instance put it there, goes away with the lease
+                revoke()?;
+                return Ok(None);
+            }
+            Err(e) => {
+                let _ = revoke();
+                return Err(e);
+            }
+        };
+        info!(self.log, "became leader at epoch {}", epoch.0);
+
+        // keep the lease alive for as long as this instance leads
+        let stop = Arc::new(AtomicBool::new(false));
+        let keepalive = {
+            let addr = self.addr.clone();
+            let stop = stop.clone();
+            let log = self.log.clone();
+            thread::Builder::new()
+                .name("etcd-keepalive".to_owned())
+                .spawn(move || {
+                    let ttl = Duration::from_secs(LEASE_TTL as u64);
+                    let every = ttl / 3;
+                    let mut last_alive = granted;
+                    while !stop.load(Ordering::SeqCst) {
+                        let sent = Instant::now();
+                        let alive = post_within(
+                            &addr,
+                            "/v3/lease/keepalive",
+                            &json!({ "ID": id.to_string() }),
+                            every,
+                        );
+                        match alive {
+                            Ok(ref r) if int(&r["result"]["TTL"]) > 0 => last_alive = sent,
+                            Ok(_) => {
+                                eprintln!("Lost leadership lease in etcd! Aborting");
+                                process::abort();
+                            }
+                            Err(e) => warn!(log, "failed to keep leadership lease alive: {}", e),
+                        }
+                        if last_alive.elapsed() >= ttl {
+                            // the lease may have expired, and another controller taken over
+                            eprintln!("Leadership lease in etcd was not kept alive! Aborting");
+                            process::abort();
+                        }
+                        thread::park_timeout(every);
+                    }
+                })?
+        };
+        *self.lease.lock().unwrap() = Some(Lease {
+            id,
+            stop,
+            keepalive,
+        });
+        Ok(Some(epoch))
+    }
+
+    fn surrender_leadership(&self) -> Result<(), Error> {
+        if let Some(lease) = self.lease.lock().unwrap().take() {
+            lease.stop.store(true, Ordering::SeqCst);
+            lease.keepalive.thread().unpark();
+            let _ = lease.keepalive.join();
+            // revoking the lease also deletes the controller key
+            post(
+                &self.addr,
+                "/v3/lease/revoke",
+                &json!({ "ID": lease.id.to_string() }),
+            )?;
+        } else {
+            post(
+                &self.addr,
+                "/v3/kv/deleterange",
+                &json!({ "key": self.key(CONTROLLER_KEY) }),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_leader(&self) -> Result<(Epoch, Vec<u8>), Error> {
+        let mut warned = false;
+        self.poll_leader(|kv| match kv {
+            Some(kv) => Some((Epoch(kv.create_revision), kv.value)),
+            None => {
+                if !warned {
+                    warn!(
+                        self.log,
+                        "no controller present, waiting for one to appear..."
+                    );
+                    warned = true;
+                }
+                None
+            }
+        })
+    }
+
+    fn try_get_leader(&self) -> Result<Option<(Epoch, Vec<u8>)>, Error> {
+        Ok(self
+            .range(CONTROLLER_KEY)?
+            .map(|kv| (Epoch(kv.create_revision), kv.value)))
+    }
+
+    fn await_new_epoch(&self, current_epoch: Epoch) -> Result<Option<(Epoch, Vec<u8>)>, Error> {
+        self.poll_leader(|kv| match kv {
+            Some(ref kv) if kv.create_revision <= current_epoch.0 => None,
+            Some(kv) => Some(Some((Epoch(kv.create_revision), kv.value))),
+            None => Some(None),
+        })
+    }
+
+    fn try_read(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.range(path)?.map(|kv| kv.value))
+    }
+
+    fn read_modify_write<F, P, E>(&self, path: &str, mut f: F) -> Result<Result<P, E>, Error>
+    where
+        F: FnMut(Option<P>) -> Result<P, E>,
+        P: Serialize + DeserializeOwned,
+    {
+        loop {
+            // the write only goes through if nobody else wrote in the meantime
+            let (result, compare) = match self.range(path)? {
+                Some(kv) => {
+                    let p = serde_json::from_slice(&kv.value)?;
+                    let compare = json!({
+                        "key": self.key(path),
+                        "target": "MOD",
+                        "result": "EQUAL",
+                        "mod_revision": kv.mod_revision.to_string(),
+                    });
+                    (f(Some(p)), compare)
+                }
+                None => {
+                    let compare = json!({
+                        "key": self.key(path),
+                        "target": "CREATE",
+                        "result": "EQUAL",
+                        "create_revision": "0",
+                    });
+                    (f(None), compare)
+                }
+            };
+            if result.is_err() {
+                return Ok(result);
+            }
+
+            let data = serde_json::to_vec(result.as_ref().ok().unwrap())?;
+            if self.put_if(path, compare, &data, 0)? {
+                return Ok(result);
+            }
+        }
+    }
+}
+
+impl Drop for EtcdAuthority {
+    fn drop(&mut self) {
+        if let Some(lease) = self.lease.lock().unwrap().take() {
+            lease.stop.store(true, Ordering::SeqCst);
+            lease.keepalive.thread().unpark();
+        }
+    }
+}
+
+/// Send `body` to the JSON gateway of the etcd server at `addr`, and return what it responds with.
+fn post(addr: &str, path: &str, body: &Value) -> Result<Value, Error> {
+    post_within(addr, path, body, Duration::from_secs(10))
+}
+
+/// Like `post`, but gives up if etcd takes longer than `timeout` to respond.
+fn post_within(addr: &str, path: &str, body: &Value, timeout: Duration) -> Result<Value, Error> {
+    let body = serde_json::to_vec(body)?;
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        addr,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => bail!("malformed response from etcd"),
+    };
+    let head = String::from_utf8_lossy(&response[..end]).to_lowercase();
+    let mut body = response.split_off(end + 4);
+    if head.contains("transfer-encoding: chunked") {
+        body = dechunk(&body)?;
+    }
+
+    // streaming calls, like keepalive, may respond with more than one message
+    let value = serde_json::Deserializer::from_slice(&body)
+        .into_iter::<Value>()
+        .next()
+        .unwrap_or(Ok(Value::Null))?;
+    if !head.starts_with("http/1.1 200") {
+        bail!("etcd responded to {} with {}", path, value);
+    }
+    Ok(value)
+}
+
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    loop {
+        let line = match body.windows(2).position(|w| w == b"\r\n") {
+            Some(line) => line,
+            None => return Ok(data),
+        };
+        let size = String::from_utf8_lossy(&body[..line]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap().trim(), 16)?;
+        if size == 0 || body.len() < line + 2 + size {
+            return Ok(data);
+        }
+        data.extend_from_slice(&body[line + 2..line + 2 + size]);
+        body = &body[(line + 2 + size + 2).min(body.len())..];
+    }
+}
+
+/// The gateway encodes 64-bit integers as strings, and leaves out those that are zero.
+fn int(v: &Value) -> i64 {
+    match *v {
+        Value::String(ref s) => s.parse().unwrap_or(0),
+        Value::Number(ref n) => n.as_i64().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode(data)
+}
+
+fn decode(v: &Value) -> Result<Vec<u8>, Error> {
+    Ok(base64::decode(v.as_str().unwrap_or(""))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    /// An in-process stand-in for the parts of etcd's JSON gateway that `EtcdAuthority` uses.
+    /// Leases never expire on their own.
+    #[derive(Default)]
+    struct FakeEtcd {
+        revision: i64,
+        next_lease: i64,
+        // key => (value, create revision, mod revision, lease)
+        keys: BTreeMap<String, (String, i64, i64, i64)>,
+        leases: HashMap<i64, i64>,
+    }
+
+    impl FakeEtcd {
+        fn start() -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let etcd = Arc::new(Mutex::new(FakeEtcd::default()));
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let etcd = etcd.clone();
+                    thread::spawn(move || serve(stream.unwrap(), &etcd));
+                }
+            });
+            addr
+        }
+
+        fn handle(&mut self, path: &str, req: &Value) -> Value {
+            match path {
+                "/v3/kv/range" => {
+                    let kvs: Vec<_> = self
+                        .keys
+                        .get(req["key"].as_str().unwrap())
+                        .map(|&(ref v, c, m, _)| {
+                            json!({
+                                "key": req["key"],
+                                "value": v,
+                                "create_revision": c.to_string(),
+                                "mod_revision": m.to_string(),
+                            })
+                        })
+                        .into_iter()
+                        .collect();
+                    json!({ "kvs": kvs })
+                }
+                "/v3/kv/txn" => {
+                    let holds = req["compare"].as_array().unwrap().iter().all(|c| {
+                        let kv = self.keys.get(c["key"].as_str().unwrap());
+                        match c["target"].as_str().unwrap() {
+                            "CREATE" => kv.map_or(0, |kv| kv.1) == int(&c["create_revision"]),
+                            "MOD" => kv.map_or(0, |kv| kv.2) == int(&c["mod_revision"]),
+                            t => unimplemented!("{}", t),
+                        }
+                    });
+                    if holds {
+                        for op in req["success"].as_array().unwrap() {
+                            let put = &op["request_put"];
+                            let key = put["key"].as_str().unwrap().to_owned();
+                            self.revision += 1;
+                            let created = self.keys.get(&key).map_or(self.revision, |kv| kv.1);
+                            let value = put["value"].as_str().unwrap().to_owned();
+                            let kv = (value, created, self.revision, int(&put["lease"]));
+                            self.keys.insert(key, kv);
+                        }
+                    }
+                    json!({ "succeeded": holds })
+                }
+                "/v3/kv/deleterange" => {
+                    self.keys.remove(req["key"].as_str().unwrap());
+                    json!({})
+                }
+                "/v3/lease/grant" => {
+                    self.next_lease += 1;
+                    self.leases.insert(self.next_lease, int(&req["TTL"]));
+                    json!({ "ID": self.next_lease.to_string(), "TTL": req["TTL"] })
+                }
+                "/v3/lease/keepalive" => {
+                    let ttl = self.leases.get(&int(&req["ID"])).cloned().unwrap_or(0);
+                    json!({ "result": { "ID": req["ID"], "TTL": ttl.to_string() } })
+                }
+                "/v3/lease/revoke" => {
+                    let id = int(&req["ID"]);
+                    self.leases.remove(&id);
+                    self.keys.retain(|_, kv| kv.3 != id);
+                    json!({})
+                }
+                p => unimplemented!("{}", p),
+            }
+        }
+    }
+
+    fn serve(stream: TcpStream, etcd: &Mutex<FakeEtcd>) {
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        reader.read_line(&mut request).unwrap();
+        let path = request.split(' ').nth(1).unwrap().to_owned();
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if header.to_lowercase().starts_with("content-length:") {
+                length = header[15..].trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let req = serde_json::from_slice(&body).unwrap();
+        let response = serde_json::to_vec(&etcd.lock().unwrap().handle(&path, &req)).unwrap();
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            response.len()
+        )
+        .unwrap();
+        stream.write_all(&response).unwrap();
+    }
+
+    #[test]
+    fn it_works() {
+        let addr = FakeEtcd::start();
+        let authority = Arc::new(EtcdAuthority::new(&format!("{}/it_works", addr)).unwrap());
+        assert!(authority.try_read(CONTROLLER_KEY).unwrap().is_none());
+        assert_eq!(
+            authority
+                .read_modify_write("/a", |arg: Option<u32>| -> Result<u32, u32> {
+                    assert!(arg.is_none());
+                    Ok(12)
+                })
+                .unwrap(),
+            Ok(12)
+        );
+        assert_eq!(
+            authority
+                .read_modify_write("/a", |arg: Option<u32>| -> Result<u32, u32> {
+                    Ok(arg.unwrap() + 1)
+                })
+                .unwrap(),
+            Ok(13)
+        );
+        assert_eq!(
+            authority.try_read("/a").unwrap(),
+            Some("13".bytes().collect())
+        );
+
+        let epoch = authority.become_leader(vec![15]).unwrap().unwrap();
+        assert_eq!(authority.get_leader().unwrap(), (epoch, vec![15]));
+
+        // another deployment's keys are kept apart
+        let other = EtcdAuthority::new(&format!("{}/other", addr)).unwrap();
+        assert!(other.try_read("/a").unwrap().is_none());
+        assert!(other.try_get_leader().unwrap().is_none());
+
+        let standby = Arc::new(EtcdAuthority::new(&format!("{}/it_works", addr)).unwrap());
+        assert_eq!(standby.become_leader(vec![20]).unwrap(), None);
+        assert_eq!(standby.get_leader().unwrap(), (epoch, vec![15]));
+
+        let waiting = {
+            let standby = standby.clone();
+            thread::spawn(move || standby.await_new_epoch(epoch).unwrap())
+        };
+        authority.surrender_leadership().unwrap();
+        assert_eq!(waiting.join().unwrap(), None);
+        assert!(standby.try_get_leader().unwrap().is_none());
+
+        let new_epoch = standby.become_leader(vec![20]).unwrap().unwrap();
+        assert!(new_epoch > epoch);
+        assert_eq!(
+            authority.await_new_epoch(epoch).unwrap(),
+            Some((new_epoch, vec![20]))
+        );
+    }
+}
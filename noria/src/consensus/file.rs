@@ -0,0 +1,275 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::Authority;
+use super::Epoch;
+use super::CONTROLLER_KEY;
+
+/// Held while electing a leader or checking on it, so that the two don't race.
+const ELECTION_LOCK: &str = ".election.lock";
+/// Held by the leader for as long as it runs.
+const LEADER_LOCK: &str = ".leader.lock";
+/// The epoch of the most recent leader.
+const EPOCH_FILE: &str = ".epoch";
+
+/// How often to check whether the leader has changed.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Coordinator that shares connection information between processes on a single host through a
+/// directory they all have access to.
+///
+/// Every key is kept in a file of its own, which is only ever replaced by an atomic rename. The
+/// leader holds an advisory lock for as long as it runs, which the operating system releases if
+/// it goes away without surrendering leadership, much like an ephemeral node in ZooKeeper. Each
+/// new leader's epoch is one more than the last one's.
+pub struct FileAuthority {
+    dir: PathBuf,
+    leader: Mutex<Option<File>>,
+    log: slog::Logger,
+}
+
+impl FileAuthority {
+    /// Create a new instance that keeps its state in `dir`, creating it if it does not exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir).context(format!(
+            "Failed to create authority directory {}",
+            dir.display()
+        ))?;
+        Ok(Self {
+            dir,
+            leader: Mutex::new(None),
+            log: slog::Logger::root(slog::Discard, o!()),
+        })
+    }
+
+    /// Enable logging
+    pub fn log_with(&mut self, log: slog::Logger) {
+        self.log = log;
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key.trim_start_matches('/').replace('/', "_"))
+    }
+
+    fn open(&self, name: &str) -> Result<File, Error> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(self.dir.join(name))?)
+    }
+
+    /// Take the given lock, waiting for whoever holds it to release it.
+    fn lock(&self, name: &str) -> Result<File, Error> {
+        let f = self.open(name)?;
+        f.lock_exclusive()?;
+        Ok(f)
+    }
+
+    /// Take the given lock if nobody holds it.
+    fn try_lock(&self, name: &str) -> Result<Option<File>, Error> {
+        let f = self.open(name)?;
+        match f.try_lock_exclusive() {
+            Ok(()) => Ok(Some(f)),
+            Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Ok(None)
+            }
+            Err(e) => bail!(e),
+        }
+    }
+
+    fn read<P: DeserializeOwned>(&self, path: &Path) -> Result<Option<P>, Error> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => bail!(e),
+        }
+    }
+
+    /// Replace the file at `path` with one that holds `p`, so that readers see either the old
+    /// value or the new one, and never part of either.
+    fn write<P: Serialize>(&self, path: &Path, p: &P) -> Result<(), Error> {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+        let mut f = File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec(p)?)?;
+        f.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// The current leader, if it is still running. Must be called with the election lock held.
+    fn live_leader(&self) -> Result<Option<(Epoch, Vec<u8>)>, Error> {
+        if self.try_lock(LEADER_LOCK)?.is_some() {
+            // whoever wrote the controller key last has gone away
+            return Ok(None);
+        }
+        self.read(&self.path(CONTROLLER_KEY))
+    }
+}
+
+impl Authority for FileAuthority {
+    fn become_leader(&self, payload_data: Vec<u8>) -> Result<Option<Epoch>, Error> {
+        let _election = self.lock(ELECTION_LOCK)?;
+        let leader = match self.try_lock(LEADER_LOCK)? {
+            Some(leader) => leader,
+            None => return Ok(None),
+        };
+
+        let epoch_path = self.dir.join(EPOCH_FILE);
+        let epoch = Epoch(self.read(&epoch_path)?.unwrap_or(0) + 1);
+        self.write(&epoch_path, &epoch.0)?;
+        self.write(&self.path(CONTROLLER_KEY), &(epoch, payload_data))?;
+        *self.leader.lock().unwrap() = Some(leader);
+
+        info!(self.log, "became leader at epoch {}", epoch.0);
+        Ok(Some(epoch))
+    }
+
+    fn surrender_leadership(&self) -> Result<(), Error> {
+        let _election = self.lock(ELECTION_LOCK)?;
+        match fs::remove_file(self.path(CONTROLLER_KEY)) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => bail!(e),
+        }
+        // closing the lock file releases the lock
+        self.leader.lock().unwrap().take();
+        Ok(())
+    }
+
+    fn get_leader(&self) -> Result<(Epoch, Vec<u8>), Error> {
+        let mut warned = false;
+        loop {
+            if let Some(leader) = self.try_get_leader()? {
+                return Ok(leader);
+            }
+            if !warned {
+                warn!(
+                    self.log,
+                    "no controller present, waiting for one to appear..."
+                );
+                warned = true;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn try_get_leader(&self) -> Result<Option<(Epoch, Vec<u8>)>, Error> {
+        let _election = self.lock(ELECTION_LOCK)?;
+        self.live_leader()
+    }
+
+    fn await_new_epoch(&self, current_epoch: Epoch) -> Result<Option<(Epoch, Vec<u8>)>, Error> {
+        loop {
+            match self.try_get_leader()? {
+                Some((epoch, _)) if epoch <= current_epoch => thread::sleep(POLL_INTERVAL),
+                leader => return Ok(leader),
+            }
+        }
+    }
+
+    fn try_read(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(self.path(path)) {
+            Ok(data) => Ok(Some(data)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => bail!(e),
+        }
+    }
+
+    fn read_modify_write<F, P, E>(&self, path: &str, mut f: F) -> Result<Result<P, E>, Error>
+    where
+        F: FnMut(Option<P>) -> Result<P, E>,
+        P: Serialize + DeserializeOwned,
+    {
+        let path = self.path(path);
+        let name = path.file_name().unwrap().to_string_lossy();
+        let _lock = self.lock(&format!(".{}.lock", name))?;
+
+        let result = f(self.read(&path)?);
+        if let Ok(ref p) = result {
+            self.write(&path, p)?;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn it_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = Arc::new(FileAuthority::new(dir.path()).unwrap());
+        assert!(authority.try_read(CONTROLLER_KEY).unwrap().is_none());
+        assert!(authority.try_read("/a").unwrap().is_none());
+        assert_eq!(
+            authority
+                .read_modify_write("/a", |arg: Option<u32>| -> Result<u32, u32> {
+                    assert!(arg.is_none());
+                    Ok(12)
+                })
+                .unwrap(),
+            Ok(12)
+        );
+        assert_eq!(
+            authority.try_read("/a").unwrap(),
+            Some("12".bytes().collect())
+        );
+        assert_eq!(authority.become_leader(vec![15]).unwrap(), Some(Epoch(1)));
+        assert_eq!(authority.get_leader().unwrap(), (Epoch(1), vec![15]));
+
+        // another process sharing the directory sees the same leader, and can't take over
+        let standby = Arc::new(FileAuthority::new(dir.path()).unwrap());
+        assert_eq!(
+            standby.try_read("/a").unwrap(),
+            Some("12".bytes().collect())
+        );
+        assert_eq!(standby.become_leader(vec![20]).unwrap(), None);
+        assert_eq!(standby.get_leader().unwrap(), (Epoch(1), vec![15]));
+
+        let waiting = {
+            let standby = standby.clone();
+            thread::spawn(move || standby.await_new_epoch(Epoch(1)).unwrap())
+        };
+        authority.surrender_leadership().unwrap();
+        assert_eq!(waiting.join().unwrap(), None);
+
+        assert_eq!(standby.become_leader(vec![20]).unwrap(), Some(Epoch(2)));
+        assert_eq!(
+            authority.await_new_epoch(Epoch(1)).unwrap(),
+            Some((Epoch(2), vec![20]))
+        );
+    }
+
+    #[test]
+    fn leader_that_goes_away_loses_leadership() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = FileAuthority::new(dir.path()).unwrap();
+        assert_eq!(leader.become_leader(vec![15]).unwrap(), Some(Epoch(1)));
+
+        let standby = FileAuthority::new(dir.path()).unwrap();
+        assert_eq!(
+            standby.try_get_leader().unwrap(),
+            Some((Epoch(1), vec![15]))
+        );
+
+        // as if the leader's process died without surrendering
+        drop(leader);
+        assert_eq!(standby.try_get_leader().unwrap(), None);
+        assert_eq!(standby.become_leader(vec![20]).unwrap(), Some(Epoch(2)));
+    }
+}
@@ -1,17 +1,23 @@
-//! Code for interacting with ZooKeeper to determine which Noria worker acts as the controller, and
-//! for detecting failed controllers which necessitate a controller changeover.
+//! Code for interacting with ZooKeeper, etcd, or a shared directory to determine which Noria
+//! worker acts as the controller, and for detecting failed controllers which necessitate a
+//! controller changeover.
 
 use failure::Error;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
+mod etcd;
+mod file;
 mod local;
 mod zk;
+pub use self::etcd::EtcdAuthority;
+pub use self::file::FileAuthority;
 pub use self::local::LocalAuthority;
 pub use self::zk::ZookeeperAuthority;
 
 pub const CONTROLLER_KEY: &str = "/controller";
 pub const STATE_KEY: &str = "/state";
+pub const METADATA_KEY: &str = "/metadata";
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Epoch(i64);
@@ -1,14 +1,16 @@
 use crate::consensus::{self, Authority};
-use crate::debug::stats;
+use crate::debug::{explain, stats, status};
+use crate::internal::DomainIndex;
 use crate::table::{Table, TableBuilder, TableRpc};
 use crate::view::{View, ViewBuilder, ViewRpc};
-use crate::ActivationResult;
+use crate::{ActivationResult, DataType};
 use failure::{self, ResultExt};
 use futures_util::future;
 use petgraph::graph::NodeIndex;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{
@@ -127,7 +129,7 @@ where
 ///
 /// To establish a new connection to Noria, use `ControllerHandle::new`, and pass in the
 /// appropriate `Authority`. In the likely case that you are using Zookeeper, use
-/// `ControllerHandle::from_zk`.
+/// `ControllerHandle::from_zk`, or `ControllerHandle::from_etcd` if you are using etcd.
 ///
 /// Note that whatever Tokio Runtime you use to execute the `Future` that resolves into the
 /// `ControllerHandle` will also be the one that executes all your reads and writes through `View`
@@ -170,6 +172,15 @@ impl ControllerHandle<consensus::ZookeeperAuthority> {
     }
 }
 
+impl ControllerHandle<consensus::EtcdAuthority> {
+    /// Fetch information about the current Soup controller from etcd running at the given
+    /// address, and create a `ControllerHandle` from that.
+    pub async fn from_etcd(etcd_address: &str) -> Result<Self, failure::Error> {
+        let auth = consensus::EtcdAuthority::new(etcd_address)?;
+        ControllerHandle::new(auth).await
+    }
+}
+
 // this alias is needed to work around -> impl Trait capturing _all_ lifetimes by default
 // the A parameter is needed so it gets captured into the impl Trait
 type RpcFuture<A, R> = impl Future<Output = Result<R, failure::Error>>;
@@ -317,6 +328,48 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         }
     }
 
+    /// Obtain a `View` for the given `SELECT` query.
+    ///
+    /// If an identical query is already installed, its view is reused. Otherwise, the query is
+    /// installed on the fly. Queries installed this way are removed again if they go unused
+    /// (that is, no `View` is requested for them) for longer than the server's configured idle
+    /// timeout.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn query(&mut self, sql: &str) -> impl Future<Output = Result<View, failure::Error>> {
+        let views = self.views.clone();
+        let sql = sql.to_string();
+        let fut = self
+            .handle
+            .call(ControllerRequest::new("query_builder", &sql).unwrap());
+        async move {
+            let body: hyper::body::Bytes = fut
+                .await
+                .map_err(failure::Context::new)
+                .context("failed to fetch view builder")?;
+
+            match serde_json::from_slice::<ViewBuilder>(&body) {
+                Ok(vb) => Ok(vb.build(views)?),
+                Err(e) => Err(failure::Error::from(e)),
+            }
+            .map_err(move |e| e.context(format!("building view for query {}", sql)).into())
+        }
+    }
+
+    /// Explain how the given `SELECT` query is executed, or would be executed if it were added to
+    /// the recipe. The query may optionally be prefixed with `EXPLAIN`.
+    ///
+    /// For queries that are not yet installed, the migration that would install them is only
+    /// planned, and never applied.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn explain(
+        &mut self,
+        sql: &str,
+    ) -> impl Future<Output = Result<explain::Explanation, failure::Error>> {
+        self.rpc("explain", sql, "failed to explain query")
+    }
+
     /// Obtain a `Table` that allows you to perform writes, deletes, and other operations on the
     /// given base table.
     ///
@@ -373,6 +426,16 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         self.rpc("get_statistics", (), "failed to get stats")
     }
 
+    /// Get the membership and health of the deployment: which domain shards each worker runs, how
+    /// much memory they use, how many replays they have waiting, and how busy its readers are.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn cluster_status(
+        &mut self,
+    ) -> impl Future<Output = Result<status::ClusterStatus, failure::Error>> {
+        self.rpc("cluster_status", (), "failed to get cluster status")
+    }
+
     /// Flush all partial state, evicting all rows present.
     ///
     /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
@@ -400,6 +463,38 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         self.rpc("install_recipe", new_recipe, "failed to install recipe")
     }
 
+    /// Plan extending the existing recipe with the given set of queries, without applying it.
+    ///
+    /// This fails if the extension would fail to apply.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn extend_recipe_dry_run(
+        &mut self,
+        recipe_addition: &str,
+    ) -> impl Future<Output = Result<explain::RecipePlan, failure::Error>> {
+        self.rpc(
+            "extend_recipe?dry_run=true",
+            recipe_addition,
+            "failed to plan recipe extension",
+        )
+    }
+
+    /// Plan replacing the existing recipe with this one, without applying it.
+    ///
+    /// This fails if the new recipe would fail to apply.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn install_recipe_dry_run(
+        &mut self,
+        new_recipe: &str,
+    ) -> impl Future<Output = Result<explain::RecipePlan, failure::Error>> {
+        self.rpc(
+            "install_recipe?dry_run=true",
+            new_recipe,
+            "failed to plan recipe installation",
+        )
+    }
+
     /// Fetch a graphviz description of the dataflow graph.
     ///
     /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
@@ -418,6 +513,173 @@ impl<A: Authority + 'static> ControllerHandle<A> {
         )
     }
 
+    /// Set the memory budget, in bytes, for the partially materialized state of the given view.
+    ///
+    /// The budget covers the view's reader along with all partially materialized state that it
+    /// reads from. This overrides any budget given in the recipe. Passing `None` removes the
+    /// override.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn set_memory_budget(
+        &mut self,
+        view: &str,
+        budget: Option<usize>,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc(
+            "set_memory_budget",
+            (view, budget),
+            "failed to set memory budget",
+        )
+    }
+
+    /// Serve reads of the given view from `replicas` readers, placed on different workers where
+    /// possible. `View` handles spread their lookups across the readers, and move on to another
+    /// one when a reader goes away. Passing 1 goes back to a single reader.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn set_read_replicas(
+        &mut self,
+        view: &str,
+        replicas: usize,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc(
+            "set_read_replicas",
+            (view, replicas),
+            "failed to set read replicas",
+        )
+    }
+
+    /// Take a consistent snapshot of all fully materialized state, and return its id.
+    ///
+    /// After a restart, materialized state is loaded from the latest snapshot, and only writes
+    /// made since are replayed. Requires `DurabilityMode::Permanent` and a snapshot interval.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn snapshot(&mut self) -> impl Future<Output = Result<u64, failure::Error>> {
+        self.rpc("snapshot", (), "failed to take snapshot")
+    }
+
+    /// Write a backup of the deployment to the directory `dir` on the server, from which a new
+    /// deployment can be started with `noria-server --restore`.
+    ///
+    /// The backup holds a consistent checkpoint of every base table, and writes to base tables
+    /// are paused while it is taken. Requires base tables to be kept in RocksDB.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn backup<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc("backup", dir.as_ref(), "failed to take backup")
+    }
+
+    /// Read the next `count` rows of shard `shard` of the given base table that the export with
+    /// id `export` hasn't read yet, straight from the table's state. The rows are those the shard
+    /// held when the export first read from it; an empty result means that the export has read
+    /// them all, and `None` that the table has no such shard.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    #[doc(hidden)]
+    pub fn export_rows(
+        &mut self,
+        table: &str,
+        export: u64,
+        shard: usize,
+        count: usize,
+    ) -> impl Future<Output = Result<Option<Vec<Vec<DataType>>>, failure::Error>> {
+        self.rpc(
+            "export_rows",
+            (table, export, shard, count),
+            "failed to read base table",
+        )
+    }
+
+    /// Have the shards of the given base table drop the rows that the export with id `export`
+    /// hasn't read yet, such as when it fails part-way. Shards also drop them on their own once
+    /// the export hasn't read from them for a while.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    #[doc(hidden)]
+    pub fn cancel_export(
+        &mut self,
+        table: &str,
+        export: u64,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc("cancel_export", (table, export), "failed to cancel export")
+    }
+
+    /// Compact the on-disk state of the given base table, or of all base tables if `table` is
+    /// `None`.
+    ///
+    /// This returns as soon as the domains that hold the tables have been told to compact them,
+    /// without waiting for the compaction to finish. The tables compact in the background, and
+    /// keep taking reads and writes meanwhile. RocksDB compacts its files in the background as
+    /// they are written to anyway, so this is mostly useful after deleting or updating many rows.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn compact(
+        &mut self,
+        table: Option<&str>,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc("compact", table, "failed to compact base tables")
+    }
+
+    /// Move the given shard of a domain, along with its state, to the worker at `worker`.
+    ///
+    /// Writes are paused while the shard moves. Tables and views that are connected to the old
+    /// worker are sent on to the new one, so clients don't need to do anything.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn move_domain(
+        &mut self,
+        domain: DomainIndex,
+        shard: usize,
+        worker: SocketAddr,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc(
+            "move_domain",
+            (domain, shard, worker),
+            "failed to move domain",
+        )
+    }
+
+    /// Move every domain shard off the worker at `worker`, and then have it leave the deployment.
+    ///
+    /// No new domain shards are placed on the worker once this is called. Writes are paused while
+    /// the shards move, as they are for `move_domain`. The worker can be shut down once this
+    /// returns, without anything having to be recovered; tables and views that were connected to
+    /// it should be fetched again before then.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn drain_worker(
+        &mut self,
+        worker: SocketAddr,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc("drain_worker", worker, "failed to drain worker")
+    }
+
+    /// Reshard the named base table, and every query downstream of it, across `shards` shards.
+    ///
+    /// Rows are redistributed among the new shards while writes are paused. Sharded queries that
+    /// share shards with the table's queries are resharded along with them. Existing `Table` and
+    /// `View` handles find out about the new shards the next time they are used. If the new shards
+    /// can't be started, or can't be given all of the rows, the table keeps its old shards.
+    ///
+    /// Tables are only resharded if their contents are lost on restart, that is, with
+    /// `DurabilityMode::MemoryOnly` or `DurabilityMode::DeleteOnExit`. A restarted deployment
+    /// builds its tables from the recipe, across the configured number of shards, and opens each
+    /// shard's RocksDB database or write-ahead log by its shard index. The rows that a resharded
+    /// table left on disk would then be read by the wrong shards.
+    ///
+    /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
+    pub fn reshard(
+        &mut self,
+        table: &str,
+        shards: usize,
+    ) -> impl Future<Output = Result<(), failure::Error>> {
+        self.rpc("reshard", (table, shards), "failed to reshard table")
+    }
+
     /// Remove the given external view from the graph.
     ///
     /// `Self::poll_ready` must have returned `Async::Ready` before you call this method.
@@ -0,0 +1,145 @@
+use crate::ActivationResult;
+use crate::MaterializationStatus;
+use petgraph::graph::NodeIndex;
+use std::fmt;
+
+/// How a `SELECT` query is, or would be, executed by the data-flow.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Explanation {
+    /// The name of the query.
+    ///
+    /// For queries that are not installed, this is the name they would be installed under.
+    pub name: String,
+    /// Whether the query is already installed.
+    pub installed: bool,
+    /// The query's MIR graph in GraphViz format, if it has one of its own.
+    pub mir: Option<String>,
+    /// The strategy that the query was planned with to reuse the nodes of other queries:
+    /// `finkelstein`, `relaxed`, `full`, or `none` if reuse was disabled.
+    pub reuse_strategy: String,
+    /// How the query reuses another query, if the strategy found one to reuse.
+    pub reuse: Option<String>,
+    /// The existing data-flow nodes that the query's own nodes were planned onto.
+    pub reused_nodes: Vec<NodeIndex>,
+    /// All data-flow nodes that the query reads from, in topological order.
+    pub nodes: Vec<NodeExplanation>,
+}
+
+/// A single data-flow node that a query reads from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeExplanation {
+    /// The node's global index.
+    ///
+    /// For nodes that would be added, this is the index the node was given during planning, and
+    /// may differ from the one it gets once the query is installed.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// A textual description of the node.
+    pub description: String,
+    /// Whether installing the query would add this node, rather than reuse an existing one.
+    pub new: bool,
+    /// The domain the node is (or would be) assigned to.
+    pub domain: usize,
+    /// How the node's output is sharded.
+    pub sharding: String,
+    /// The materialization type of the node's state.
+    pub materialization: MaterializationStatus,
+    /// The columns that each of the node's indices is keyed by.
+    pub indices: Vec<Vec<usize>>,
+    /// The number of rows in the node's output, if known or estimated.
+    ///
+    /// For existing nodes, this is the number of rows in their state. For new nodes, it is an
+    /// estimate based on the sizes of their ancestors.
+    pub estimated_rows: Option<u64>,
+}
+
+/// The changes that applying a recipe would make to the data-flow, as planned by a dry run.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecipePlan {
+    /// What activating the recipe would report.
+    ///
+    /// Node indices in it refer to planned nodes, and may differ from the ones the nodes get once
+    /// the recipe is applied.
+    pub activation: ActivationResult,
+    /// All data-flow nodes that applying the recipe would add, in topological order.
+    pub nodes: Vec<NodeExplanation>,
+}
+
+impl Explanation {
+    /// Estimated number of rows that new fully materialized nodes would need to hold.
+    ///
+    /// Partially materialized nodes start out empty, and so do not count towards this.
+    pub fn estimated_new_rows(&self) -> u64 {
+        self.nodes
+            .iter()
+            .filter(|n| n.new)
+            .filter(|n| match n.materialization {
+                MaterializationStatus::Full => true,
+                _ => false,
+            })
+            .filter_map(|n| n.estimated_rows)
+            .sum()
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} ({})",
+            self.name,
+            if self.installed {
+                "installed"
+            } else {
+                "not installed"
+            }
+        )?;
+        let reused: Vec<_> = self
+            .reused_nodes
+            .iter()
+            .map(|ni| format!("n{}", ni.index()))
+            .collect();
+        writeln!(
+            f,
+            "reuse: {}, {}, onto [{}]",
+            self.reuse_strategy,
+            self.reuse
+                .as_ref()
+                .map(String::as_str)
+                .unwrap_or("no query reused"),
+            reused.join(", ")
+        )?;
+        for n in &self.nodes {
+            let materialization = match n.materialization {
+                MaterializationStatus::Not => "not materialized".to_owned(),
+                MaterializationStatus::Full => format!("full {:?}", n.indices),
+                MaterializationStatus::Partial { .. } => format!("partial {:?}", n.indices),
+            };
+            let rows = n
+                .estimated_rows
+                .map(|r| format!("~{} rows", r))
+                .unwrap_or_else(|| "? rows".to_owned());
+            writeln!(
+                f,
+                "  {} n{} {} [{}] domain {}, {}, {}, {}",
+                if n.new { "+" } else { "=" },
+                n.node.index(),
+                n.name,
+                n.description,
+                n.domain,
+                n.sharding,
+                materialization,
+                rows,
+            )?;
+        }
+        if !self.installed {
+            writeln!(
+                f,
+                "estimated new state: ~{} rows",
+                self.estimated_new_rows()
+            )?;
+        }
+        Ok(())
+    }
+}
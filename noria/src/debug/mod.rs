@@ -1,5 +1,11 @@
+/// Types related to explaining queries and planned recipe changes.
+pub mod explain;
+
 /// Types related to graph statistics.
 pub mod stats;
 
+/// Types related to the membership and health of the workers in a deployment.
+pub mod status;
+
 /// Types related to operator tracing.
 pub mod trace;
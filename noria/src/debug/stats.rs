@@ -21,6 +21,9 @@ pub struct DomainStats {
     pub total_forward_time: u64,
     /// Total wall-clock time spent waiting for work in this domain.
     pub wait_time: u64,
+    /// Number of keys that replays have been asked for, but whose replays have not yet started.
+    #[serde(default)]
+    pub replay_backlog: u64,
 }
 
 /// Statistics about a node.
@@ -36,6 +39,10 @@ pub struct NodeStats {
     pub process_ptime: u64,
     /// Total memory size of this node's state.
     pub mem_size: u64,
+    /// Number of rows in this node's state.
+    pub rows: u64,
+    /// Number of distinct keys in each of this node's indices, by the columns they are keyed on.
+    pub key_counts: Vec<(Vec<usize>, u64)>,
     /// The materialization type of this node's state.
     pub materialized: MaterializationStatus,
     /// The value returned from Ingredient::probe.
@@ -0,0 +1,139 @@
+use petgraph::graph::NodeIndex;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The membership and health of a Noria deployment, as its controller sees it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    /// Every worker that has registered with the controller and not since left, ordered by
+    /// address.
+    pub workers: Vec<WorkerStatus>,
+}
+
+/// A single worker in a deployment.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorkerStatus {
+    /// The address that identifies the worker to the controller, as given to
+    /// `ControllerHandle::drain_worker`.
+    pub id: SocketAddr,
+    /// The address the worker listens on for the controller.
+    pub addr: SocketAddr,
+    /// Whether the worker has kept up its heartbeats.
+    pub healthy: bool,
+    /// Whether the worker's domain shards are being moved elsewhere, so that it can leave.
+    pub draining: bool,
+    /// How long ago the controller last heard from the worker.
+    pub heartbeat_age: Duration,
+    /// The number of cores on the worker's machine.
+    pub cpus: usize,
+    /// The labels that placement constraints can require of the worker.
+    pub labels: Vec<String>,
+    /// The size, in bytes, of the state of the worker's domains, as of its last heartbeat.
+    pub state_size: u64,
+    /// The domain shards that run on the worker, ordered by domain and shard.
+    pub shards: Vec<ShardStatus>,
+    /// The reader shards that the worker serves reads from, ordered by node and shard.
+    pub readers: Vec<ReaderStatus>,
+}
+
+/// A single domain shard.
+///
+/// Shards whose worker has failed do not answer, so all that is known about them is where they
+/// run.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShardStatus {
+    /// The domain's index.
+    pub domain: usize,
+    /// The shard's index within the domain.
+    pub shard: usize,
+    /// The address the shard listens on for messages from other domains, if it has started.
+    pub addr: Option<SocketAddr>,
+    /// The total memory size, in bytes, of the state of the shard's nodes.
+    pub mem_size: Option<u64>,
+    /// The number of keys that replays have been asked for, but whose replays have not yet
+    /// started.
+    pub replay_backlog: Option<u64>,
+}
+
+/// A single shard of a reader, which serves the reads of a view.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReaderStatus {
+    /// The reader's global index.
+    pub node: NodeIndex,
+    /// The name of the view that the reader serves.
+    pub name: String,
+    /// The shard's index within the reader.
+    pub shard: usize,
+    /// The number of read requests the shard has served since it started.
+    pub requests: u64,
+    /// The read requests per second that the shard served between the worker's last two
+    /// heartbeats.
+    pub requests_per_sec: f64,
+}
+
+impl WorkerStatus {
+    fn state(&self) -> &'static str {
+        if !self.healthy {
+            "failed"
+        } else if self.draining {
+            "draining"
+        } else {
+            "healthy"
+        }
+    }
+}
+
+impl fmt::Display for ClusterStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} workers, {} healthy",
+            self.workers.len(),
+            self.workers.iter().filter(|w| w.healthy).count()
+        )?;
+        for w in &self.workers {
+            writeln!(
+                f,
+                "worker {} at {} {}, last heartbeat {}ms ago, {} cpus, {} bytes of state{}",
+                w.id,
+                w.addr,
+                w.state(),
+                w.heartbeat_age.as_millis(),
+                w.cpus,
+                w.state_size,
+                if w.labels.is_empty() {
+                    String::new()
+                } else {
+                    format!(", labels [{}]", w.labels.join(", "))
+                }
+            )?;
+            for s in &w.shards {
+                let unknown = || "?".to_owned();
+                writeln!(
+                    f,
+                    "  domain {}.{} at {}: {} bytes, {} keys waiting to replay",
+                    s.domain,
+                    s.shard,
+                    s.addr.map(|a| a.to_string()).unwrap_or_else(unknown),
+                    s.mem_size.map(|m| m.to_string()).unwrap_or_else(unknown),
+                    s.replay_backlog
+                        .map(|b| b.to_string())
+                        .unwrap_or_else(unknown),
+                )?;
+            }
+            for r in &w.readers {
+                writeln!(
+                    f,
+                    "  reader {} (n{}) shard {}: {:.1} reads/s, {} reads in total",
+                    r.name,
+                    r.node.index(),
+                    r.shard,
+                    r.requests_per_sec,
+                    r.requests,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
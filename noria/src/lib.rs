@@ -136,7 +136,7 @@ pub mod consensus;
 #[allow(unreachable_pub)] // https://github.com/rust-lang/rust/issues/57411
 pub mod internal;
 
-pub use crate::consensus::ZookeeperAuthority;
+pub use crate::consensus::{EtcdAuthority, FileAuthority, ZookeeperAuthority};
 use crate::internal::*;
 use std::cell::RefCell;
 use std::pin::Pin;
@@ -213,7 +213,7 @@ pub use crate::table::Table;
 pub use crate::view::View;
 
 #[doc(hidden)]
-pub use crate::table::Input;
+pub use crate::table::{Input, WriteAck};
 
 #[doc(hidden)]
 pub use crate::view::{ReadQuery, ReadReply};
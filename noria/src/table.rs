@@ -24,9 +24,16 @@ use tower_buffer::Buffer;
 use tower_service::Service;
 use vec_map::VecMap;
 
+/// What a base table domain shard acknowledges a write with.
+///
+/// This is `None` unless the table has been resharded away from the shard, in which case it holds
+/// the addresses of the shards that the table now lives on.
+#[doc(hidden)]
+pub type WriteAck = Option<Vec<SocketAddr>>;
+
 type Transport = AsyncBincodeStream<
     tokio::net::TcpStream,
-    Tagged<()>,
+    Tagged<WriteAck>,
     Tagged<LocalOrNot<Input>>,
     AsyncDestination,
 >;
@@ -139,37 +146,8 @@ impl TableBuilder {
         let mut addrs = Vec::with_capacity(self.txs.len());
         let mut conns = Vec::with_capacity(self.txs.len());
         for (shardi, &addr) in self.txs.iter().enumerate() {
-            use std::collections::hash_map::Entry;
-
             addrs.push(addr);
-
-            // one entry per shard so that we can send sharded requests in parallel even if
-            // they happen to be targeting the same machine.
-            let mut rpcs = rpcs.lock().unwrap();
-            let s = match rpcs.entry((addr, shardi)) {
-                Entry::Occupied(e) => e.get().clone(),
-                Entry::Vacant(h) => {
-                    // TODO: maybe always use the same local port?
-                    let (c, w) = Buffer::pair(
-                        pool::Builder::new()
-                            .urgency(0.01)
-                            .loaded_above(0.2)
-                            .underutilized_below(0.000_000_001)
-                            .max_services(Some(32))
-                            .build(multiplex::client::Maker::new(TableEndpoint(addr)), ()),
-                        50,
-                    );
-                    use tracing_futures::Instrument;
-                    tokio::spawn(w.instrument(tracing::debug_span!(
-                        "table_worker",
-                        addr = %addr,
-                        shard = shardi
-                    )));
-                    h.insert(c.clone());
-                    c
-                }
-            };
-            conns.push(s);
+            conns.push(rpc_for(&rpcs, addr, shardi));
         }
 
         let dispatch = tracing::dispatcher::get_default(|d| d.clone());
@@ -187,12 +165,64 @@ impl TableBuilder {
 
             shard_addrs: addrs,
             shards: conns,
+            rpcs,
+            resharded: Default::default(),
 
             dispatch,
         })
     }
 }
 
+/// The connection to use for writes to the given shard at `addr`.
+fn rpc_for(
+    rpcs: &Mutex<HashMap<(SocketAddr, usize), TableRpc>>,
+    addr: SocketAddr,
+    shardi: usize,
+) -> TableRpc {
+    use std::collections::hash_map::Entry;
+
+    // one entry per shard so that we can send sharded requests in parallel even if
+    // they happen to be targeting the same machine.
+    let mut rpcs = rpcs.lock().unwrap();
+    match rpcs.entry((addr, shardi)) {
+        Entry::Occupied(e) => e.get().clone(),
+        Entry::Vacant(h) => {
+            // TODO: maybe always use the same local port?
+            let (c, w) = Buffer::pair(
+                pool::Builder::new()
+                    .urgency(0.01)
+                    .loaded_above(0.2)
+                    .underutilized_below(0.000_000_001)
+                    .max_services(Some(32))
+                    .build(multiplex::client::Maker::new(TableEndpoint(addr)), ()),
+                50,
+            );
+            use tracing_futures::Instrument;
+            tokio::spawn(w.instrument(tracing::debug_span!(
+                "table_worker",
+                addr = %addr,
+                shard = shardi
+            )));
+            h.insert(c.clone());
+            c
+        }
+    }
+}
+
+/// Where the shards of a table are since it was resharded, once a write has found out.
+type Resharded = Arc<Mutex<Option<Vec<SocketAddr>>>>;
+
+/// Note the new shards of the table if the acknowledgement of a write says it was resharded.
+fn check_resharded(ack: Tagged<WriteAck>, resharded: &Resharded) -> Tagged<()> {
+    if let Some(addrs) = ack.v {
+        *resharded.lock().unwrap() = Some(addrs);
+    }
+    Tagged {
+        tag: ack.tag,
+        v: (),
+    }
+}
+
 /// A `Table` is used to perform writes, deletes, and other operations to data in base tables.
 ///
 /// If you create multiple `Table` handles from a single `ControllerHandle`, they may share
@@ -214,6 +244,8 @@ pub struct Table {
 
     shards: Vec<TableRpc>,
     shard_addrs: Vec<SocketAddr>,
+    rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), TableRpc>>>,
+    resharded: Resharded,
 
     dispatch: tracing::Dispatch,
 }
@@ -237,11 +269,25 @@ impl fmt::Debug for Table {
 
 impl Service<Input> for Table {
     type Error = TableError;
-    type Response = <TableRpc as Service<Tagged<LocalOrNot<Input>>>>::Response;
+    type Response = Tagged<()>;
     // have to repeat types because https://github.com/rust-lang/rust/issues/57807
     type Future = impl Future<Output = Result<Tagged<()>, TableError>> + Send;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let resharded = self.resharded.lock().unwrap().take();
+        if let Some(addrs) = resharded {
+            if addrs != self.shard_addrs {
+                // a write found that the table was resharded, so write to the new shards directly
+                // from now on
+                self.shards = addrs
+                    .iter()
+                    .enumerate()
+                    .map(|(shardi, &addr)| rpc_for(&self.rpcs, addr, shardi))
+                    .collect();
+                self.shard_addrs = addrs;
+            }
+        }
+
         for s in &mut self.shards {
             ready!(s.poll_ready(cx)).map_err(TableError::from)?;
         }
@@ -272,7 +318,13 @@ impl Service<Input> for Table {
 
             let _guard = span.as_ref().map(tracing::Span::enter);
             tracing::trace!("submit request");
-            future::Either::Left(self.shards[0].call(request).map_err(TableError::from))
+            let resharded = self.resharded.clone();
+            future::Either::Left(
+                self.shards[0]
+                    .call(request)
+                    .map_err(TableError::from)
+                    .map_ok(move |ack| check_resharded(ack, &resharded)),
+            )
         } else {
             if self.key.is_empty() {
                 unreachable!("sharded base without a key?");
@@ -337,9 +389,13 @@ impl Service<Input> for Table {
                 }
             }
 
+            let resharded = self.resharded.clone();
             future::Either::Right(
                 wait_for
-                    .try_for_each(|_| async { Ok(()) })
+                    .try_for_each(move |ack| {
+                        check_resharded(ack, &resharded);
+                        async { Ok(()) }
+                    })
                     .map_err(TableError::from)
                     .map_ok(Tagged::from),
             )
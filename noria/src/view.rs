@@ -12,8 +12,10 @@ use std::fmt;
 use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{self, AtomicUsize};
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio_tower::multiplex;
 use tower_balance::pool::{self, Pool};
 use tower_buffer::Buffer;
@@ -65,6 +67,9 @@ pub enum ViewError {
     /// The given view is not yet available.
     #[fail(display = "the view is not yet available")]
     NotYetAvailable,
+    /// The given view is partially materialized, so not all of its rows can be read at once.
+    #[fail(display = "the view is partially materialized")]
+    PartiallyMaterialized,
     /// A lower-level error occurred while communicating with Soup.
     #[fail(display = "{}", _0)]
     TransportError(#[cause] failure::Error),
@@ -77,7 +82,7 @@ impl From<Box<dyn std::error::Error + Send + Sync>> for ViewError {
 }
 
 #[doc(hidden)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ReadQuery {
     /// Read from a leaf view
     Normal {
@@ -93,6 +98,11 @@ pub enum ReadQuery {
         /// Where to read from
         target: (NodeIndex, usize),
     },
+    /// Read every row of a leaf view
+    All {
+        /// Where to read from
+        target: (NodeIndex, usize),
+    },
 }
 
 #[doc(hidden)]
@@ -102,6 +112,13 @@ pub enum ReadReply {
     Normal(Result<Vec<Datas>, ()>),
     /// Read size of view
     Size(usize),
+    /// The view is partially materialized, so its rows can't all be read.
+    Partial,
+    /// The reader moved to the worker at the given address, which the query should be sent to.
+    Moved(SocketAddr, ReadQuery),
+    /// The reader was resharded, and its shards are now read from the workers at the given
+    /// addresses, which the query should be split among.
+    Resharded(Vec<SocketAddr>, ReadQuery),
 }
 
 #[doc(hidden)]
@@ -111,8 +128,14 @@ pub struct ViewBuilder {
     pub columns: Vec<String>,
     pub schema: Option<Vec<ColumnSpecification>>,
     pub shards: Vec<SocketAddr>,
+    /// The replicas of the view's reader, each with the addresses of its shards.
+    #[serde(default)]
+    pub replicas: Vec<(NodeIndex, Vec<SocketAddr>)>,
 }
 
+/// The reader that the next `View` to be built starts reading from, modulo the number of readers.
+static NEXT_READER: AtomicUsize = AtomicUsize::new(0);
+
 impl ViewBuilder {
     /// Build a `View` out of a `ViewBuilder`
     #[doc(hidden)]
@@ -120,100 +143,344 @@ impl ViewBuilder {
         &self,
         rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
     ) -> Result<View, io::Error> {
-        let node = self.node;
         let columns = self.columns.clone();
-        let shards = self.shards.clone();
         let schema = self.schema.clone();
 
-        let mut addrs = Vec::with_capacity(shards.len());
-        let mut conns = Vec::with_capacity(shards.len());
-
-        for (shardi, &addr) in shards.iter().enumerate() {
-            use std::collections::hash_map::Entry;
-
-            addrs.push(addr);
-
-            // one entry per shard so that we can send sharded requests in parallel even if
-            // they happen to be targeting the same machine.
-            let mut rpcs = rpcs.lock().unwrap();
-            let s = match rpcs.entry((addr, shardi)) {
-                Entry::Occupied(e) => e.get().clone(),
-                Entry::Vacant(h) => {
-                    // TODO: maybe always use the same local port?
-                    let (c, w) = Buffer::pair(
-                        pool::Builder::new()
-                            .urgency(0.03)
-                            .loaded_above(0.2)
-                            .underutilized_below(0.000_000_001)
-                            .max_services(Some(32))
-                            .build(multiplex::client::Maker::new(ViewEndpoint(addr)), ()),
-                        50,
-                    );
-                    use tracing_futures::Instrument;
-                    tokio::spawn(w.instrument(tracing::debug_span!(
-                        "view_worker",
-                        addr = %addr,
-                        shard = shardi
-                    )));
-                    h.insert(c.clone());
-                    c
-                }
-            };
-            conns.push(s);
+        let mut readers = Vec::with_capacity(1 + self.replicas.len());
+        readers.push(ReaderHandle::new(self.node, &self.shards, rpcs.clone()));
+        for (node, shards) in &self.replicas {
+            readers.push(ReaderHandle::new(*node, shards, rpcs.clone()));
         }
 
+        // views built one after the other start out on different readers, so that short-lived
+        // views don't all read from the same one
+        let next = NEXT_READER.fetch_add(1, atomic::Ordering::Relaxed) % readers.len();
+
         let tracer = tracing::dispatcher::get_default(|d| d.clone());
         Ok(View {
-            node,
             schema,
             columns,
-            shard_addrs: addrs,
-            shards: conns,
+            readers,
+            next,
+            unreachable: Default::default(),
             tracer,
         })
     }
 }
 
+/// The connection to use for reads from the given shard at `addr`.
+fn rpc_for(
+    rpcs: &Mutex<HashMap<(SocketAddr, usize), ViewRpc>>,
+    addr: SocketAddr,
+    shardi: usize,
+) -> ViewRpc {
+    use std::collections::hash_map::Entry;
+
+    // one entry per shard so that we can send sharded requests in parallel even if
+    // they happen to be targeting the same machine.
+    let mut rpcs = rpcs.lock().unwrap();
+    match rpcs.entry((addr, shardi)) {
+        Entry::Occupied(e) => e.get().clone(),
+        Entry::Vacant(h) => {
+            // TODO: maybe always use the same local port?
+            let (c, w) = Buffer::pair(
+                pool::Builder::new()
+                    .urgency(0.03)
+                    .loaded_above(0.2)
+                    .underutilized_below(0.000_000_001)
+                    .max_services(Some(32))
+                    .build(multiplex::client::Maker::new(ViewEndpoint(addr)), ()),
+                50,
+            );
+            use tracing_futures::Instrument;
+            tokio::spawn(w.instrument(tracing::debug_span!(
+                "view_worker",
+                addr = %addr,
+                shard = shardi
+            )));
+            h.insert(c.clone());
+            c
+        }
+    }
+}
+
+/// Like `rpc_for`, but first replaces the connection to the given shard at `addr` if it has failed.
+fn reconnect_rpc(
+    rpcs: &Mutex<HashMap<(SocketAddr, usize), ViewRpc>>,
+    addr: SocketAddr,
+    shardi: usize,
+) -> ViewRpc {
+    {
+        let mut rpcs = rpcs.lock().unwrap();
+        if let Some(rpc) = rpcs.get(&(addr, shardi)) {
+            // a connection that has failed stays failed, so this doesn't have to wait
+            let waker = futures_util::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            if let Poll::Ready(Err(_)) = rpc.clone().poll_ready(&mut cx) {
+                rpcs.remove(&(addr, shardi));
+            }
+        }
+    }
+    rpc_for(rpcs, addr, shardi)
+}
+
+/// How long a reader that a read found could not be reached is left out, before it is reconnected
+/// and read from again.
+const UNREACHABLE_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Where the shards of a view that have moved to other workers are now, by shard.
+type MovedShards = Arc<Mutex<HashMap<usize, SocketAddr>>>;
+
+/// Where the shards of a view are since it was resharded, once a read has found out.
+type Resharded = Arc<Mutex<Option<Vec<SocketAddr>>>>;
+
+/// Send the query in `reply` on to wherever the reader moved, for as long as it keeps moving.
+async fn follow_moves(
+    mut reply: Tagged<ReadReply>,
+    shardi: usize,
+    rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
+    moved: MovedShards,
+    resharded: Resharded,
+) -> Result<ReadReply, ViewError> {
+    loop {
+        match reply.v {
+            ReadReply::Moved(addr, query) => {
+                moved.lock().unwrap().insert(shardi, addr);
+                let mut rpc = rpc_for(&rpcs, addr, shardi);
+                future::poll_fn(|cx| rpc.poll_ready(cx))
+                    .await
+                    .map_err(ViewError::from)?;
+                reply = rpc
+                    .call(Tagged::from(query))
+                    .await
+                    .map_err(ViewError::from)?;
+            }
+            ReadReply::Resharded(addrs, query) => {
+                *resharded.lock().unwrap() = Some(addrs.clone());
+                return read_resharded(query, shardi, &addrs, &rpcs).await;
+            }
+            reply => return Ok(reply),
+        }
+    }
+}
+
+/// Split up a query for shard `shardi` of a reader that has since been resharded among the new
+/// shards at `addrs`, and combine their replies.
+async fn read_resharded(
+    query: ReadQuery,
+    shardi: usize,
+    addrs: &[SocketAddr],
+    rpcs: &Mutex<HashMap<(SocketAddr, usize), ViewRpc>>,
+) -> Result<ReadReply, ViewError> {
+    let nshards = addrs.len();
+    let queries: Vec<_> = match query {
+        ReadQuery::Normal {
+            target: (node, _),
+            keys,
+            block,
+        } => {
+            let mut shard_keys = vec![Vec::new(); nshards];
+            for key in keys {
+                let shard = if nshards == 1 {
+                    0
+                } else {
+                    crate::shard_by(&key[0], nshards)
+                };
+                shard_keys[shard].push(key);
+            }
+            shard_keys
+                .into_iter()
+                .enumerate()
+                .filter(|(_, keys)| !keys.is_empty())
+                .map(|(shard, keys)| ReadQuery::Normal {
+                    target: (node, shard),
+                    keys,
+                    block,
+                })
+                .collect()
+        }
+        // every old shard finds that the reader was resharded, so only the first one asks the new
+        // shards for everything
+        ReadQuery::Size { .. } if shardi != 0 => return Ok(ReadReply::Size(0)),
+        ReadQuery::All { .. } if shardi != 0 => return Ok(ReadReply::Normal(Ok(Vec::new()))),
+        ReadQuery::Size { target: (node, _) } => (0..nshards)
+            .map(|shard| ReadQuery::Size {
+                target: (node, shard),
+            })
+            .collect(),
+        ReadQuery::All { target: (node, _) } => (0..nshards)
+            .map(|shard| ReadQuery::All {
+                target: (node, shard),
+            })
+            .collect(),
+    };
+
+    let mut replies = queries
+        .into_iter()
+        .map(|query| {
+            let shard = match query {
+                ReadQuery::Normal { target, .. }
+                | ReadQuery::Size { target }
+                | ReadQuery::All { target } => target.1,
+            };
+            let mut rpc = rpc_for(rpcs, addrs[shard], shard);
+            async move {
+                future::poll_fn(|cx| rpc.poll_ready(cx))
+                    .await
+                    .map_err(ViewError::from)?;
+                let reply = rpc
+                    .call(Tagged::from(query))
+                    .await
+                    .map_err(ViewError::from)?;
+                Ok::<_, ViewError>(reply.v)
+            }
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut merged = None;
+    while let Some(reply) = replies.next().await.transpose()? {
+        merged = Some(match (merged, reply) {
+            (None, reply) => reply,
+            (Some(ReadReply::Normal(Ok(mut rows))), ReadReply::Normal(Ok(more))) => {
+                rows.extend(more);
+                ReadReply::Normal(Ok(rows))
+            }
+            (Some(ReadReply::Size(rows)), ReadReply::Size(more)) => ReadReply::Size(rows + more),
+            (Some(ReadReply::Partial), _) | (_, ReadReply::Partial) => ReadReply::Partial,
+            // one of the new shards isn't ready yet, or has already moved on
+            _ => ReadReply::Normal(Err(())),
+        });
+    }
+    match merged {
+        Some(ReadReply::Moved(..)) | Some(ReadReply::Resharded(..)) => {
+            Ok(ReadReply::Normal(Err(())))
+        }
+        Some(reply) => Ok(reply),
+        None => Ok(ReadReply::Normal(Ok(Vec::new()))),
+    }
+}
+
 /// A `View` is used to query previously defined external views.
 ///
 /// Note that if you create multiple `View` handles from a single `ControllerHandle`, they may
 /// share connections to the Soup workers.
+///
+/// A view that has replicas of its reader (see `ControllerHandle::set_read_replicas`) spreads its
+/// lookups across them in turn, and leaves out a replica for a while once it can no longer be
+/// reached.
 #[derive(Clone)]
 pub struct View {
-    node: NodeIndex,
     columns: Vec<String>,
     schema: Option<Vec<ColumnSpecification>>,
 
-    shards: Vec<ViewRpc>,
-    shard_addrs: Vec<SocketAddr>,
+    /// The readers that the view is read from. The next read goes to the one at `next`.
+    readers: Vec<ReaderHandle>,
+    next: usize,
+    /// When reads found each of the readers that could no longer be reached.
+    unreachable: Arc<Mutex<HashMap<NodeIndex, Instant>>>,
 
     tracer: tracing::Dispatch,
 }
 
+/// One of the readers that a `View` is read from.
+#[derive(Clone)]
+struct ReaderHandle {
+    node: NodeIndex,
+    shards: Vec<ViewRpc>,
+    shard_addrs: Vec<SocketAddr>,
+    rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
+    moved: MovedShards,
+    resharded: Resharded,
+}
+
 impl fmt::Debug for View {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("View")
-            .field("node", &self.node)
             .field("columns", &self.columns)
+            .field("readers", &self.readers)
+            .finish()
+    }
+}
+
+impl fmt::Debug for ReaderHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReaderHandle")
+            .field("node", &self.node)
             .field("shard_addrs", &self.shard_addrs)
             .finish()
     }
 }
 
-impl Service<(Vec<Vec<DataType>>, bool)> for View {
-    type Response = Vec<Datas>;
-    type Error = ViewError;
-    // have to repeat types because https://github.com/rust-lang/rust/issues/57807
-    type Future = impl Future<Output = Result<Vec<Datas>, ViewError>> + Send;
+impl ReaderHandle {
+    fn new(
+        node: NodeIndex,
+        shard_addrs: &[SocketAddr],
+        rpcs: Arc<Mutex<HashMap<(SocketAddr, usize), ViewRpc>>>,
+    ) -> Self {
+        let shards = shard_addrs
+            .iter()
+            .enumerate()
+            .map(|(shardi, &addr)| rpc_for(&rpcs, addr, shardi))
+            .collect();
+
+        ReaderHandle {
+            node,
+            shards,
+            shard_addrs: shard_addrs.to_vec(),
+            rpcs,
+            moved: Default::default(),
+            resharded: Default::default(),
+        }
+    }
+
+    /// Replace the connections to the shards of this reader that have failed.
+    fn reconnect(&mut self) {
+        for (shardi, &addr) in self.shard_addrs.iter().enumerate() {
+            self.shards[shardi] = reconnect_rpc(&self.rpcs, addr, shardi);
+        }
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ViewError>> {
+        let resharded = self.resharded.lock().unwrap().take();
+        if let Some(addrs) = resharded {
+            if addrs != self.shard_addrs {
+                // a read found that the view was resharded, so read from the new shards directly
+                // from now on
+                self.shards = addrs
+                    .iter()
+                    .enumerate()
+                    .map(|(shardi, &addr)| rpc_for(&self.rpcs, addr, shardi))
+                    .collect();
+                self.shard_addrs = addrs;
+                self.moved.lock().unwrap().clear();
+            }
+        }
+
+        let moved: Vec<_> = self
+            .moved
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(&shardi, addr)| self.shard_addrs[shardi] != *addr)
+            .map(|(&shardi, &addr)| (shardi, addr))
+            .collect();
+        for (shardi, addr) in moved {
+            // a read found that the shard moved, so go straight there from now on
+            self.shard_addrs[shardi] = addr;
+            self.shards[shardi] = rpc_for(&self.rpcs, addr, shardi);
+        }
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         for s in &mut self.shards {
             ready!(s.poll_ready(cx)).map_err(ViewError::from)?;
         }
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, (keys, block): (Vec<Vec<DataType>>, bool)) -> Self::Future {
+    fn call(
+        &mut self,
+        keys: Vec<Vec<DataType>>,
+        block: bool,
+    ) -> impl Future<Output = Result<Vec<Datas>, ViewError>> + Send {
         let span = if crate::trace_next_op() {
             Some(tracing::trace_span!(
                 "view-request",
@@ -234,12 +501,16 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
             let _guard = span.as_ref().map(tracing::Span::enter);
             tracing::trace!("submit request");
 
+            let rpcs = self.rpcs.clone();
+            let moved = self.moved.clone();
+            let resharded = self.resharded.clone();
             return future::Either::Left(
                 self.shards[0]
                     .call(request)
                     .map_err(ViewError::from)
+                    .and_then(move |reply| follow_moves(reply, 0, rpcs, moved, resharded))
                     .and_then(|reply| async move {
-                        match reply.v {
+                        match reply {
                             ReadReply::Normal(Ok(rows)) => Ok(rows),
                             ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
                             _ => unreachable!(),
@@ -259,6 +530,9 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
         }
 
         let node = self.node;
+        let rpcs = self.rpcs.clone();
+        let moved = self.moved.clone();
+        let resharded = self.resharded.clone();
         future::Either::Right(
             self.shards
                 .iter_mut()
@@ -292,11 +566,15 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
                     let _guard = span.as_ref().map(tracing::Span::enter);
                     tracing::trace!("submit request shard");
 
+                    let rpcs = rpcs.clone();
+                    let moved = moved.clone();
+                    let resharded = resharded.clone();
                     shard
                         .call(request)
                         .map_err(ViewError::from)
+                        .and_then(move |reply| follow_moves(reply, shardi, rpcs, moved, resharded))
                         .and_then(|reply| async move {
-                            match reply.v {
+                            match reply {
                                 ReadReply::Normal(Ok(rows)) => Ok(rows),
                                 ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
                                 _ => unreachable!(),
@@ -307,6 +585,194 @@ impl Service<(Vec<Vec<DataType>>, bool)> for View {
                 .try_concat(),
         )
     }
+
+    async fn size(&mut self) -> Result<usize, ViewError> {
+        future::poll_fn(|cx| self.poll_ready(cx)).await?;
+
+        let node = self.node;
+        let (rpcs, moved, resharded) = (&self.rpcs, &self.moved, &self.resharded);
+        let mut rsps = self
+            .shards
+            .iter_mut()
+            .enumerate()
+            .map(|(shardi, shard)| {
+                shard
+                    .call(Tagged::from(ReadQuery::Size {
+                        target: (node, shardi),
+                    }))
+                    .map_err(ViewError::from)
+                    .and_then(move |reply| {
+                        let (rpcs, moved, resharded) =
+                            (rpcs.clone(), moved.clone(), resharded.clone());
+                        follow_moves(reply, shardi, rpcs, moved, resharded)
+                    })
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut nrows = 0;
+        while let Some(reply) = rsps.next().await.transpose()? {
+            match reply {
+                ReadReply::Size(rows) => nrows += rows,
+                ReadReply::Normal(Err(())) => return Err(ViewError::NotYetAvailable),
+                _ => unreachable!(),
+            }
+        }
+
+        Ok(nrows)
+    }
+
+    async fn scan(&mut self) -> Result<Datas, ViewError> {
+        future::poll_fn(|cx| self.poll_ready(cx)).await?;
+
+        let node = self.node;
+        let (rpcs, moved, resharded) = (&self.rpcs, &self.moved, &self.resharded);
+        let mut rsps = self
+            .shards
+            .iter_mut()
+            .enumerate()
+            .map(|(shardi, shard)| {
+                shard
+                    .call(Tagged::from(ReadQuery::All {
+                        target: (node, shardi),
+                    }))
+                    .map_err(ViewError::from)
+                    .and_then(move |reply| {
+                        let (rpcs, moved, resharded) =
+                            (rpcs.clone(), moved.clone(), resharded.clone());
+                        follow_moves(reply, shardi, rpcs, moved, resharded)
+                    })
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut rows = Vec::new();
+        while let Some(reply) = rsps.next().await.transpose()? {
+            match reply {
+                ReadReply::Normal(Ok(shard)) => rows.extend(shard.into_iter().flatten()),
+                ReadReply::Normal(Err(())) => return Err(ViewError::NotYetAvailable),
+                ReadReply::Partial => return Err(ViewError::PartiallyMaterialized),
+                _ => unreachable!(),
+            }
+        }
+
+        Ok(rows)
+    }
+}
+
+impl View {
+    /// Move `next` on to a reader that reads haven't recently found could no longer be reached,
+    /// and return whether there is one. Readers that were left out for long enough are reconnected
+    /// and read from again.
+    fn skip_unreachable(&mut self) -> bool {
+        let mut unreachable = self.unreachable.lock().unwrap();
+        if unreachable.is_empty() {
+            return true;
+        }
+
+        let now = Instant::now();
+        for reader in &mut self.readers {
+            if let Some(&since) = unreachable.get(&reader.node) {
+                if now.duration_since(since) >= UNREACHABLE_BACKOFF {
+                    unreachable.remove(&reader.node);
+                    reader.reconnect();
+                }
+            }
+        }
+
+        let n = self.readers.len();
+        let readers = &self.readers;
+        let reachable = (0..n)
+            .map(|j| (self.next + j) % n)
+            .find(|&i| !unreachable.contains_key(&readers[i].node));
+        match reachable {
+            Some(i) => {
+                self.next = i;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Note that the current reader could not be reached, if there are others to read from
+    /// instead.
+    fn fail_over(&mut self, e: ViewError) -> Result<(), ViewError> {
+        match e {
+            ViewError::TransportError(_) if self.readers.len() > 1 => {
+                let node = self.readers[self.next].node;
+                self.unreachable
+                    .lock()
+                    .unwrap()
+                    .insert(node, Instant::now());
+                if self.skip_unreachable() {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            }
+            e => Err(e),
+        }
+    }
+}
+
+impl Service<(Vec<Vec<DataType>>, bool)> for View {
+    type Response = Vec<Datas>;
+    type Error = ViewError;
+    // have to repeat types because https://github.com/rust-lang/rust/issues/57807
+    type Future = impl Future<Output = Result<Vec<Datas>, ViewError>> + Send;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.skip_unreachable();
+        loop {
+            match ready!(self.readers[self.next].poll_ready(cx)) {
+                Ok(()) => return Poll::Ready(Ok(())),
+                Err(e) => self.fail_over(e)?,
+            }
+        }
+    }
+
+    fn call(&mut self, (keys, block): (Vec<Vec<DataType>>, bool)) -> Self::Future {
+        let i = self.next;
+        self.next = (i + 1) % self.readers.len();
+
+        // should the reader turn out to be unreachable, the others are tried in turn
+        let others: Vec<_> = (1..self.readers.len())
+            .map(|j| self.readers[(i + j) % self.readers.len()].clone())
+            .collect();
+        let retry = if others.is_empty() {
+            None
+        } else {
+            Some((keys.clone(), others))
+        };
+
+        let node = self.readers[i].node;
+        let read = self.readers[i].call(keys, block);
+        let unreachable = self.unreachable.clone();
+        async move {
+            let (keys, others) = match (read.await, retry) {
+                (Err(ViewError::TransportError(_)), Some(retry)) => retry,
+                (r, _) => return r,
+            };
+            unreachable.lock().unwrap().insert(node, Instant::now());
+
+            let mut last = None;
+            for mut reader in others {
+                let read = async {
+                    future::poll_fn(|cx| reader.poll_ready(cx)).await?;
+                    reader.call(keys.clone(), block).await
+                };
+                match read.await {
+                    Err(ViewError::TransportError(e)) => {
+                        unreachable
+                            .lock()
+                            .unwrap()
+                            .insert(reader.node, Instant::now());
+                        last = Some(e);
+                    }
+                    r => return r,
+                }
+            }
+            Err(ViewError::TransportError(last.unwrap()))
+        }
+    }
 }
 
 #[allow(clippy::len_without_is_empty)]
@@ -325,30 +791,27 @@ impl View {
     ///
     /// Note that you must also continue to poll this `View` for the returned future to resolve.
     pub async fn len(&mut self) -> Result<usize, ViewError> {
-        future::poll_fn(|cx| self.poll_ready(cx)).await?;
-
-        let node = self.node;
-        let mut rsps = self
-            .shards
-            .iter_mut()
-            .enumerate()
-            .map(|(shardi, shard)| {
-                shard.call(Tagged::from(ReadQuery::Size {
-                    target: (node, shardi),
-                }))
-            })
-            .collect::<FuturesUnordered<_>>();
-
-        let mut nrows = 0;
-        while let Some(reply) = rsps.next().await.transpose()? {
-            if let ReadReply::Size(rows) = reply.v {
-                nrows += rows;
-            } else {
-                unreachable!();
+        loop {
+            future::poll_fn(|cx| self.poll_ready(cx)).await?;
+            match self.readers[self.next].size().await {
+                Err(e) => self.fail_over(e)?,
+                r => return r,
             }
         }
+    }
 
-        Ok(nrows)
+    /// Retrieve every row in this view.
+    ///
+    /// Fails with `ViewError::PartiallyMaterialized` for partially materialized views, since only
+    /// the rows of keys that have already been filled could be read from them.
+    pub async fn scan(&mut self) -> Result<Datas, ViewError> {
+        loop {
+            future::poll_fn(|cx| self.poll_ready(cx)).await?;
+            match self.readers[self.next].scan().await {
+                Err(e) => self.fail_over(e)?,
+                r => return r,
+            }
+        }
     }
 
     /// Retrieve the query results for the given parameter values.